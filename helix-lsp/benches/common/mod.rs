@@ -0,0 +1,81 @@
+//! An in-process server shared by the benchmarks, which answers right away so the transport
+//! itself is the bottleneck.
+
+use helix_lsp::{jsonrpc, LanguageServerId, StartedTransport, Transport, TransportConfig};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+/// Answers every request with a `null` result.
+async fn serve(
+    reader: impl tokio::io::AsyncRead + Unpin,
+    mut writer: impl tokio::io::AsyncWrite + Unpin,
+) {
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    let mut body = Vec::new();
+    loop {
+        let mut content_length = 0;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(len) = line.trim().strip_prefix("Content-Length: ") {
+                content_length = len.parse().unwrap();
+            }
+        }
+        body.resize(content_length, 0);
+        reader.read_exact(&mut body).await.unwrap();
+        let message: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let Some(id) = message.get("id") else {
+            continue;
+        };
+        let response = format!(r#"{{"jsonrpc":"2.0","result":null,"id":{id}}}"#);
+        let framed = format!("Content-Length: {}\r\n\r\n{response}", response.len());
+        if writer.write_all(framed.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// A transport connected to the server.
+pub struct Connection {
+    started: StartedTransport,
+}
+
+impl Connection {
+    /// Starts a transport with `config` connected to the server, and initializes it.
+    pub async fn start(config: TransportConfig) -> Self {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let (client_reader, client_writer) = tokio::io::split(client);
+        tokio::spawn(serve(server_reader, server_writer));
+        let started = Transport::start_without_stderr(
+            BufReader::new(client_reader),
+            client_writer,
+            LanguageServerId::default(),
+            "bench".to_string(),
+            config,
+        );
+        let connection = Self { started };
+        let initialize = connection.send("initialize", jsonrpc::Params::None);
+        initialize.await;
+        connection.started.2.notify();
+        connection
+    }
+
+    /// Sends a request, returning its response once it arrives.
+    pub fn send(
+        &self,
+        method: &str,
+        params: jsonrpc::Params,
+    ) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let (_, tx, _, transport) = &self.started;
+        let (_, mut response) = transport.send_request(tx, method, params).unwrap();
+        async move {
+            response.recv().await.unwrap().unwrap();
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Sweeps `TransportConfig::serialize_offload_threshold` over a mix of small requests and
+//! requests carrying a whole document, like full syncs of large files, to show what moving
+//! their serialization to the blocking thread pool costs and gains.
+//!
+//! Every round reports the time to get all the requests answered and the slowest round trip
+//! of a small request, which is what inline serialization of large messages holds up.
+//!
+//! Run with `cargo bench -p helix-lsp --bench serialize_offload`.
+
+mod common;
+
+use common::Connection;
+use helix_lsp::{jsonrpc, TransportConfig};
+use serde_json::json;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Requests sent per round.
+const REQUESTS: usize = 5_000;
+/// Every this many requests carries a document.
+const LARGE_EVERY: usize = 50;
+/// The size of the documents.
+const DOCUMENT_SIZE: usize = 512 * 1024;
+/// Requests in flight at once.
+const IN_FLIGHT: usize = 64;
+const ROUNDS: usize = 3;
+const THRESHOLDS: [Option<usize>; 5] = [
+    None,
+    Some(1024 * 1024),
+    Some(64 * 1024),
+    Some(4 * 1024),
+    Some(0),
+];
+
+struct Round {
+    total: Duration,
+    slowest_small: Duration,
+}
+
+async fn round(threshold: Option<usize>, document: &str) -> Round {
+    let config = TransportConfig::default().serialize_offload_threshold(threshold);
+    let connection = Connection::start(config).await;
+    let params = |text: &str| {
+        let serde_json::Value::Object(params) =
+            json!({ "textDocument": { "uri": "file:///bench.rs", "text": text } })
+        else {
+            unreachable!()
+        };
+        jsonrpc::Params::Map(params)
+    };
+
+    let start = Instant::now();
+    let mut slowest_small = Duration::ZERO;
+    let mut in_flight = VecDeque::with_capacity(IN_FLIGHT);
+    for i in 0..REQUESTS {
+        if in_flight.len() == IN_FLIGHT {
+            let (large, sent, response): (bool, Instant, _) = in_flight.pop_front().unwrap();
+            response.await;
+            if !large {
+                slowest_small = slowest_small.max(sent.elapsed());
+            }
+        }
+        let large = i % LARGE_EVERY == 0;
+        let response = if large {
+            connection.send("textDocument/diagnostic", params(document))
+        } else {
+            connection.send("textDocument/hover", params(""))
+        };
+        in_flight.push_back((large, Instant::now(), response));
+    }
+    for (large, sent, response) in in_flight {
+        response.await;
+        if !large {
+            slowest_small = slowest_small.max(sent.elapsed());
+        }
+    }
+    Round {
+        total: start.elapsed(),
+        slowest_small,
+    }
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let document = "fn main() {}\n".repeat(DOCUMENT_SIZE / 13);
+    for threshold in THRESHOLDS {
+        let mut best = Duration::MAX;
+        let mut slowest_small = Duration::MAX;
+        for _ in 0..ROUNDS {
+            let round = runtime.block_on(round(threshold, &document));
+            best = best.min(round.total);
+            slowest_small = slowest_small.min(round.slowest_small);
+        }
+        let threshold = match threshold {
+            Some(bytes) => format!("{bytes} bytes"),
+            None => "none".to_string(),
+        };
+        println!(
+            "threshold {threshold:>13}: {REQUESTS} requests in {best:?}, slowest small request {slowest_small:?} (best of {ROUNDS})"
+        );
+    }
+}
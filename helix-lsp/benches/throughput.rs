@@ -0,0 +1,51 @@
+//! Measures how many requests per second a `Transport` gets answered by an in-process server
+//! that replies right away, so the transport itself is the bottleneck.
+//!
+//! Run with `cargo bench -p helix-lsp --bench throughput`.
+
+mod common;
+
+use common::Connection;
+use helix_lsp::{jsonrpc, TransportConfig};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Requests sent per round.
+const REQUESTS: u64 = 50_000;
+/// Requests in flight at once.
+const IN_FLIGHT: usize = 256;
+const ROUNDS: usize = 5;
+
+async fn round() -> Duration {
+    let connection = Connection::start(TransportConfig::default()).await;
+
+    let start = Instant::now();
+    let mut in_flight = VecDeque::with_capacity(IN_FLIGHT);
+    for _ in 0..REQUESTS {
+        if in_flight.len() == IN_FLIGHT {
+            in_flight.pop_front().unwrap().await;
+        }
+        in_flight.push_back(connection.send("textDocument/hover", jsonrpc::Params::None));
+    }
+    for response in in_flight {
+        response.await;
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        best = best.min(runtime.block_on(round()));
+    }
+    println!(
+        "{REQUESTS} requests in {best:?} (best of {ROUNDS}): {:.0} requests/s",
+        REQUESTS as f64 / best.as_secs_f64()
+    );
+}
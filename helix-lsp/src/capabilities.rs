@@ -0,0 +1,200 @@
+//! Comparing the capabilities of a server across sessions, e.g. to find out which feature a
+//! server update dropped.
+//!
+//! A [`CapabilitiesSnapshot`] of the current session is taken with
+//! [`Client::capabilities_snapshot`](crate::Client::capabilities_snapshot), saved with
+//! [`CapabilitiesSnapshot::save`] and compared against the snapshot of a later session with
+//! [`diff_capabilities`].
+
+use crate::{lsp, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{fmt, path::Path};
+
+/// The capabilities a server announced in a session.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CapabilitiesSnapshot {
+    /// The name of the language server.
+    pub server: String,
+    pub capabilities: lsp::ServerCapabilities,
+}
+
+impl CapabilitiesSnapshot {
+    pub fn new(server: impl Into<String>, capabilities: lsp::ServerCapabilities) -> Self {
+        Self {
+            server: server.into(),
+            capabilities,
+        }
+    }
+
+    /// Writes the snapshot to `path` as JSON.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// Reads a snapshot written by [`CapabilitiesSnapshot::save`].
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+}
+
+/// A capability that differs between two snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CapabilityChange {
+    Added {
+        path: String,
+        value: Value,
+    },
+    Removed {
+        path: String,
+        value: Value,
+    },
+    Changed {
+        path: String,
+        old: Value,
+        new: Value,
+    },
+}
+
+impl CapabilityChange {
+    /// The dot separated path of the capability, e.g. `completionProvider.triggerCharacters`.
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Added { path, .. } | Self::Removed { path, .. } | Self::Changed { path, .. } => {
+                path
+            }
+        }
+    }
+}
+
+impl fmt::Display for CapabilityChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Added { path, value } => write!(f, "+ {path}: {value}"),
+            Self::Removed { path, value } => write!(f, "- {path}: {value}"),
+            Self::Changed { path, old, new } => write!(f, "~ {path}: {old} -> {new}"),
+        }
+    }
+}
+
+/// The differences between two sets of capabilities, sorted by path. Displayed as one line per
+/// change, prefixed with `+`, `-` or `~`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapabilitiesDiff {
+    pub changes: Vec<CapabilityChange>,
+}
+
+impl CapabilitiesDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl fmt::Display for CapabilitiesDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            writeln!(f, "{change}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the capabilities of `old` and `new`, field by field. Objects are compared
+/// recursively while any other value, including arrays like the trigger characters of
+/// completions, is compared as a whole.
+pub fn diff_capabilities(
+    old: &lsp::ServerCapabilities,
+    new: &lsp::ServerCapabilities,
+) -> CapabilitiesDiff {
+    // capabilities always serialize to an object
+    let old = serde_json::to_value(old).unwrap_or_default();
+    let new = serde_json::to_value(new).unwrap_or_default();
+    let mut diff = CapabilitiesDiff::default();
+    diff_values(&mut diff.changes, String::new(), &old, &new);
+    diff.changes.sort_by(|a, b| a.path().cmp(b.path()));
+    diff
+}
+
+fn diff_values(changes: &mut Vec<CapabilityChange>, path: String, old: &Value, new: &Value) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_value) in old {
+                let path = join(&path, key);
+                match new.get(key) {
+                    Some(new_value) => diff_values(changes, path, old_value, new_value),
+                    None => changes.push(CapabilityChange::Removed {
+                        path,
+                        value: old_value.clone(),
+                    }),
+                }
+            }
+            for (key, new_value) in new {
+                if !old.contains_key(key) {
+                    changes.push(CapabilityChange::Added {
+                        path: join(&path, key),
+                        value: new_value.clone(),
+                    });
+                }
+            }
+        }
+        (old, new) if old != new => changes.push(CapabilityChange::Changed {
+            path,
+            old: old.clone(),
+            new: new.clone(),
+        }),
+        _ => (),
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_and_round_trip() {
+        let old: lsp::ServerCapabilities = serde_json::from_value(serde_json::json!({
+            "hoverProvider": true,
+            "renameProvider": true,
+            "completionProvider": { "triggerCharacters": ["."] },
+        }))
+        .unwrap();
+        let new: lsp::ServerCapabilities = serde_json::from_value(serde_json::json!({
+            "hoverProvider": true,
+            "completionProvider": { "triggerCharacters": [".", ":"], "resolveProvider": true },
+            "inlayHintProvider": true,
+        }))
+        .unwrap();
+
+        let diff = diff_capabilities(&old, &new);
+        assert_eq!(
+            diff.to_string(),
+            r#"+ completionProvider.resolveProvider: true
+~ completionProvider.triggerCharacters: ["."] -> [".",":"]
++ inlayHintProvider: true
+- renameProvider: true
+"#
+        );
+        assert!(diff_capabilities(&new, &new).is_empty());
+
+        let path = std::env::temp_dir().join(format!(
+            "helix-lsp-capabilities-{}.json",
+            std::process::id()
+        ));
+        let snapshot = CapabilitiesSnapshot::new("rust-analyzer", old);
+        snapshot.save(&path).unwrap();
+        let loaded = CapabilitiesSnapshot::load(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded.unwrap(), snapshot);
+    }
+}
@@ -1,8 +1,11 @@
 use crate::{
     file_operations::FileOperationsInterest,
     find_lsp_workspace, jsonrpc,
-    transport::{Payload, Transport},
-    Call, Error, LanguageServerId, OffsetEncoding, Result,
+    transport::{
+        FullChannelStrategy, InboundReceiver, InitializeSignal, Payload, RequestGroup, Transport,
+        TransportConfig,
+    },
+    Error, LanguageServerId, OffsetEncoding, Result, ShutdownStep,
 };
 
 use crate::lsp::{
@@ -17,24 +20,38 @@
 use serde::Deserialize;
 use serde_json::Value;
 use std::{collections::HashMap, path::PathBuf};
-use std::{
-    ffi::OsStr,
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
-    },
-};
+use std::{ffi::OsStr, sync::Arc};
 use std::{future::Future, sync::OnceLock};
 use std::{path::Path, process::Stdio};
 use tokio::{
     io::{BufReader, BufWriter},
     process::{Child, Command},
     sync::{
-        mpsc::{channel, UnboundedReceiver, UnboundedSender},
-        Notify, OnceCell,
+        mpsc::{channel, UnboundedSender},
+        OnceCell,
     },
 };
 
+/// Options of [`Client::call_with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct RequestOptions {
+    /// The operation the request is part of, to correlate it with the other requests of the
+    /// operation in logs and metrics.
+    pub group: Option<RequestGroup>,
+}
+
+/// The `$/progress` route of [`Client::call_with_progress`], removed once dropped.
+struct ProgressRoute {
+    transport: Arc<Transport>,
+    token: lsp::ProgressToken,
+}
+
+impl Drop for ProgressRoute {
+    fn drop(&mut self) {
+        self.transport.unroute_progress(&self.token);
+    }
+}
+
 fn workspace_for_uri(uri: lsp::Url) -> WorkspaceFolder {
     lsp::WorkspaceFolder {
         name: uri
@@ -50,16 +67,16 @@ fn workspace_for_uri(uri: lsp::Url) -> WorkspaceFolder {
 pub struct Client {
     id: LanguageServerId,
     name: String,
-    _process: Child,
+    _process: Option<Child>,
     server_tx: UnboundedSender<Payload>,
-    request_counter: AtomicU64,
+    transport: Arc<Transport>,
     pub(crate) capabilities: OnceCell<lsp::ServerCapabilities>,
     pub(crate) file_operation_interest: OnceLock<FileOperationsInterest>,
     config: Option<Value>,
     root_path: std::path::PathBuf,
     root_uri: Option<lsp::Url>,
     workspace_folders: Mutex<Vec<lsp::WorkspaceFolder>>,
-    initialize_notify: Arc<Notify>,
+    initialize_notify: Arc<InitializeSignal>,
     /// workspace folders added while the server is still initializing
     req_timeout: u64,
 }
@@ -210,11 +227,8 @@ pub fn start(
         id: LanguageServerId,
         name: String,
         req_timeout: u64,
-    ) -> Result<(
-        Self,
-        UnboundedReceiver<(LanguageServerId, Call)>,
-        Arc<Notify>,
-    )> {
+        transport_config: TransportConfig,
+    ) -> Result<(Self, InboundReceiver, Arc<InitializeSignal>)> {
         // Resolve path to the binary
         let cmd_binary_path = helix_stdx::env::which(cmd)?;
 
@@ -252,8 +266,8 @@ pub fn start(
         let reader = BufReader::new(process.stdout.take().expect("Failed to open stdout"));
         let stderr = BufReader::new(process.stderr.take().expect("Failed to open stderr"));
 
-        let (server_rx, server_tx, initialize_notify) =
-            Transport::start(reader, writer, stderr, id, name.clone());
+        let (server_rx, server_tx, initialize_notify, transport) =
+            Transport::start(reader, writer, stderr, id, name.clone(), transport_config);
 
         let workspace_folders = root_uri
             .clone()
@@ -263,9 +277,9 @@ pub fn start(
         let client = Self {
             id,
             name,
-            _process: process,
+            _process: Some(process),
             server_tx,
-            request_counter: AtomicU64::new(0),
+            transport,
             capabilities: OnceCell::new(),
             file_operation_interest: OnceLock::new(),
             config,
@@ -287,9 +301,12 @@ pub fn id(&self) -> LanguageServerId {
         self.id
     }
 
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
     fn next_request_id(&self) -> jsonrpc::Id {
-        let id = self.request_counter.fetch_add(1, Ordering::Relaxed);
-        jsonrpc::Id::Num(id)
+        self.transport.next_id()
     }
 
     fn value_into_params(value: Value) -> jsonrpc::Params {
@@ -313,6 +330,16 @@ pub fn capabilities(&self) -> &lsp::ServerCapabilities {
             .expect("language server not yet initialized!")
     }
 
+    /// A snapshot of the capabilities of the server, to compare them with those of a later
+    /// session. `None` until the server is initialized.
+    pub fn capabilities_snapshot(&self) -> Option<crate::capabilities::CapabilitiesSnapshot> {
+        let capabilities = self.capabilities.get()?.clone();
+        Some(crate::capabilities::CapabilitiesSnapshot::new(
+            self.name(),
+            capabilities,
+        ))
+    }
+
     pub(crate) fn file_operations_intests(&self) -> &FileOperationsInterest {
         self.file_operation_interest
             .get_or_init(|| FileOperationsInterest::new(self.capabilities()))
@@ -463,51 +490,141 @@ fn call_with_timeout<R: lsp::request::Request>(
     where
         R::Params: serde::Serialize,
     {
-        let server_tx = self.server_tx.clone();
         let id = self.next_request_id();
+        let params = serde_json::to_value(params).map_err(Error::from);
+        self.call_value::<R>(id, params, timeout_secs, None)
+    }
 
-        // It's important that this is not part of the future so that it gets executed right away
-        // and the request order stays consistent.
-        let rx = serde_json::to_value(params)
+    /// Execute a RPC request on the language server with [`RequestOptions`].
+    pub fn call_with_options<R: lsp::request::Request>(
+        &self,
+        params: R::Params,
+        options: RequestOptions,
+    ) -> impl Future<Output = Result<R::Result>>
+    where
+        R::Params: serde::Serialize,
+    {
+        let id = self.next_request_id();
+        let params = serde_json::to_value(params).map_err(Error::from);
+        self.call_value::<R>(id, params, self.req_timeout, options.group)
+    }
+
+    /// Send a RPC request to the language server with a generated `workDoneToken`, returning
+    /// the response along with the work done progress the server reports for the request.
+    ///
+    /// The token is the string `helix/work-done/<id>`, where `<id>` is the id of the request.
+    /// The `$/progress` notifications for it are only delivered to the returned receiver, which
+    /// is closed once the progress ends or the response arrives. `R::Params` must serialize to
+    /// an object, like the params of every request that supports work done progress.
+    pub fn call_with_progress<R: lsp::request::Request>(
+        &self,
+        params: R::Params,
+    ) -> (
+        impl Future<Output = Result<R::Result>>,
+        tokio::sync::mpsc::UnboundedReceiver<lsp::WorkDoneProgress>,
+    )
+    where
+        R::Params: serde::Serialize,
+    {
+        let id = self.next_request_id();
+        let token = lsp::ProgressToken::String(format!("helix/work-done/{id}"));
+        let progress = self.transport.route_progress(token.clone());
+        let params = serde_json::to_value(params)
             .map_err(Error::from)
-            .and_then(|params| {
-                let request = jsonrpc::MethodCall {
-                    jsonrpc: Some(jsonrpc::Version::V2),
-                    id: id.clone(),
-                    method: R::METHOD.to_string(),
-                    params: Self::value_into_params(params),
-                };
-                let (tx, rx) = channel::<Result<Value>>(1);
-                server_tx
-                    .send(Payload::Request {
-                        chan: tx,
-                        value: request,
-                    })
-                    .map_err(|e| Error::Other(e.into()))?;
-                Ok(rx)
+            .and_then(|mut params| {
+                let params_object = params.as_object_mut().ok_or_else(|| {
+                    Error::Other(anyhow::anyhow!(
+                        "{} params can't carry a workDoneToken",
+                        R::METHOD
+                    ))
+                })?;
+                params_object.insert("workDoneToken".to_string(), serde_json::to_value(&token)?);
+                Ok(params)
             });
 
+        let response = self.call_value::<R>(id, params, self.req_timeout, None);
+        // unroutes the progress even if the response is dropped before it arrives
+        let route = ProgressRoute {
+            transport: self.transport.clone(),
+            token,
+        };
+        let response = async move {
+            let response = response.await;
+            drop(route);
+            response
+        };
+        (response, progress)
+    }
+
+    fn call_value<R: lsp::request::Request>(
+        &self,
+        id: jsonrpc::Id,
+        params: Result<Value>,
+        timeout_secs: u64,
+        group: Option<RequestGroup>,
+    ) -> impl Future<Output = Result<R::Result>> {
+        let server_tx = self.server_tx.clone();
+        let transport = self.transport.clone();
+
+        // It's important that this is not part of the future so that it gets executed right away
+        // and the request order stays consistent.
+        let rx = params.and_then(|params| {
+            let request = jsonrpc::MethodCall {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                id: id.clone(),
+                method: R::METHOD.to_string(),
+                params: Self::value_into_params(params),
+            };
+            let (tx, rx) = channel::<Result<Value>>(1);
+            let payload = Payload::grouped_request(tx, request, group);
+            transport.try_enqueue(&server_tx, payload, None)?;
+            Ok(rx)
+        });
+
         async move {
             use std::time::Duration;
-            use tokio::time::timeout;
             // TODO: delay other calls until initialize success
-            timeout(Duration::from_secs(timeout_secs), rx?.recv())
+            transport
+                .timeout(Duration::from_secs(timeout_secs), rx?.recv())
                 .await
-                .map_err(|_| Error::Timeout(id))? // return Timeout
+                .ok_or(Error::Timeout(id))? // return Timeout
                 .ok_or(Error::StreamClosed)?
                 .and_then(|value| serde_json::from_value(value).map_err(Into::into))
         }
     }
 
+    /// Send a RPC request to the language server without waiting for its response.
+    ///
+    /// Unlike a notification this is still a request: the server is expected to answer it. The
+    /// answer is matched to the request and quietly discarded rather than being reported as a
+    /// response without a request.
+    pub fn request_ignore_response<R: lsp::request::Request>(&self, params: R::Params) -> Result<()>
+    where
+        R::Params: serde::Serialize,
+    {
+        let params = serde_json::to_value(params)?;
+        let request = jsonrpc::MethodCall {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            id: self.next_request_id(),
+            method: R::METHOD.to_string(),
+            params: Self::value_into_params(params),
+        };
+        self.transport
+            .try_enqueue(&self.server_tx, Payload::DetachedRequest(request), None)
+    }
+
     /// Send a RPC notification to the language server.
+    ///
+    /// The notification is never dropped or failed for a full outbound queue, it's queued
+    /// beyond the [`TransportConfig::outbound_capacity`] instead: dropping a notification like
+    /// `textDocument/didChange` would desync the server. Use [`Client::notify_with`] for
+    /// notifications that can be dropped.
     pub fn notify<R: lsp::notification::Notification>(&self, params: R::Params)
     where
         R::Params: serde::Serialize,
     {
-        let server_tx = self.server_tx.clone();
-
-        let params = match serde_json::to_value(params) {
-            Ok(params) => params,
+        let notification = match Self::notification::<R>(params) {
+            Ok(notification) => notification,
             Err(err) => {
                 log::error!(
                     "Failed to serialize params for notification '{}' for server '{}': {err}",
@@ -518,13 +635,11 @@ pub fn notify<R: lsp::notification::Notification>(&self, params: R::Params)
             }
         };
 
-        let notification = jsonrpc::Notification {
-            jsonrpc: Some(jsonrpc::Version::V2),
-            method: R::METHOD.to_string(),
-            params: Self::value_into_params(params),
-        };
-
-        if let Err(err) = server_tx.send(Payload::Notification(notification)) {
+        if let Err(err) = self.transport.try_enqueue(
+            &self.server_tx,
+            Payload::Notification(notification),
+            Some(FullChannelStrategy::Wait),
+        ) {
             log::error!(
                 "Failed to send notification '{}' to server '{}': {err}",
                 R::METHOD,
@@ -533,6 +648,43 @@ pub fn notify<R: lsp::notification::Notification>(&self, params: R::Params)
         }
     }
 
+    /// Send a RPC notification to the language server, applying `strategy` instead of
+    /// [`TransportConfig::full_channel_strategy`] when the outbound queue is full. Unlike
+    /// [`Client::notify`] this waits for room with [`FullChannelStrategy::Wait`], and the other
+    /// strategies apply. See [`FullChannelStrategy`] for which strategy is safe for which
+    /// notification.
+    pub async fn notify_with<R: lsp::notification::Notification>(
+        &self,
+        params: R::Params,
+        strategy: FullChannelStrategy,
+    ) -> Result<()>
+    where
+        R::Params: serde::Serialize,
+    {
+        let notification = Self::notification::<R>(params)?;
+        self.transport
+            .enqueue(
+                &self.server_tx,
+                Payload::Notification(notification),
+                Some(strategy),
+            )
+            .await
+    }
+
+    fn notification<R: lsp::notification::Notification>(
+        params: R::Params,
+    ) -> Result<jsonrpc::Notification>
+    where
+        R::Params: serde::Serialize,
+    {
+        let params = serde_json::to_value(params)?;
+        Ok(jsonrpc::Notification {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: R::METHOD.to_string(),
+            params: Self::value_into_params(params),
+        })
+    }
+
     /// Reply to a language server RPC call.
     pub fn reply(
         &self,
@@ -541,8 +693,6 @@ pub fn reply(
     ) -> Result<()> {
         use jsonrpc::{Failure, Output, Success, Version};
 
-        let server_tx = self.server_tx.clone();
-
         let output = match result {
             Ok(result) => Output::Success(Success {
                 jsonrpc: Some(Version::V2),
@@ -556,11 +706,12 @@ pub fn reply(
             }),
         };
 
-        server_tx
-            .send(Payload::Response(output))
-            .map_err(|e| Error::Other(e.into()))?;
-
-        Ok(())
+        // the server is waiting for the response, it's never dropped
+        self.transport.try_enqueue(
+            &self.server_tx,
+            Payload::Response(output),
+            Some(FullChannelStrategy::Wait),
+        )
     }
 
     // -------------------------------------------------------------------------------------------
@@ -756,20 +907,59 @@ pub fn exit(&self) {
     /// Tries to shut down the language server but returns
     /// early if server responds with an error.
     pub async fn shutdown_and_exit(&self) -> Result<()> {
-        self.shutdown().await?;
-        self.exit();
-        Ok(())
+        match self.shutdown_steps(false).await.pop() {
+            Some((_, err)) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Asks the language server to shut down and exit, resolving once the server acknowledged
+    /// the `shutdown` request (or it timed out) and `exit` was sent, e.g. to show that servers
+    /// are being shut down until they are.
+    ///
+    /// Like [`Client::force_shutdown`], `exit` is sent even if `shutdown` failed. Every step that
+    /// failed is reported in [`Error::Shutdown`].
+    pub async fn shutdown_sequence(&self) -> Result<()> {
+        let failures = self.shutdown_steps(true).await;
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Shutdown(failures))
+        }
     }
 
     /// Forcefully shuts down the language server ignoring any errors.
     pub async fn force_shutdown(&self) -> Result<()> {
-        if let Err(e) = self.shutdown().await {
-            log::warn!("language server failed to terminate gracefully - {}", e);
+        for (step, err) in self.shutdown_steps(true).await {
+            log::warn!("language server failed to terminate gracefully - {step}: {err}");
         }
-        self.exit();
         Ok(())
     }
 
+    /// Sends `shutdown` then `exit`, returning the steps that failed. `exit` is skipped when
+    /// `shutdown` failed unless `exit_anyway`.
+    async fn shutdown_steps(&self, exit_anyway: bool) -> Vec<(ShutdownStep, Error)> {
+        let mut failures = Vec::new();
+        if let Err(err) = self.shutdown().await {
+            failures.push((ShutdownStep::Shutdown, err));
+            if !exit_anyway {
+                return failures;
+            }
+        }
+
+        let exit = Self::notification::<lsp::notification::Exit>(()).and_then(|exit| {
+            self.transport.try_enqueue(
+                &self.server_tx,
+                Payload::Notification(exit),
+                Some(FullChannelStrategy::Wait),
+            )
+        });
+        if let Err(err) = exit {
+            failures.push((ShutdownStep::Exit, err));
+        }
+        failures
+    }
+
     // -------------------------------------------------------------------------------------------
     // Workspace
     // -------------------------------------------------------------------------------------------
@@ -1602,3 +1792,125 @@ pub fn did_change_watched_files(&self, changes: Vec<lsp::FileEvent>) {
         })
     }
 }
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::{MockTransport, WrittenMessage},
+        transport::InboundReceiver,
+    };
+    use serde_json::json;
+
+    fn client() -> (Client, InboundReceiver, MockTransport) {
+        let ((incoming, server_tx, initialize_notify, transport), server) =
+            MockTransport::start(TransportConfig::default());
+        let client = Client {
+            id: LanguageServerId::default(),
+            name: "mock".to_string(),
+            _process: None,
+            server_tx,
+            transport,
+            capabilities: OnceCell::new(),
+            file_operation_interest: OnceLock::new(),
+            config: None,
+            root_path: PathBuf::from("/"),
+            root_uri: None,
+            workspace_folders: Mutex::new(Vec::new()),
+            initialize_notify,
+            req_timeout: 5,
+        };
+        (client, incoming, server)
+    }
+
+    async fn initialize(client: &Client, server: &mut MockTransport) {
+        let (initialized, _) = tokio::join!(client.initialize(false), async {
+            server
+                .initialize(json!({ "capabilities": {} }))
+                .await
+                .unwrap();
+        });
+        initialized.unwrap();
+        client.initialize_notify.notify();
+    }
+
+    #[tokio::test]
+    async fn shutdown_sequence_reports_failed_steps() {
+        let (client, _incoming, mut server) = client();
+        initialize(&client, &mut server).await;
+
+        let (result, exit) = tokio::join!(client.shutdown_sequence(), async {
+            let WrittenMessage::Request(shutdown) = server.recv().await.unwrap() else {
+                panic!("expected the shutdown request");
+            };
+            assert_eq!(shutdown.method, "shutdown");
+            server
+                .send(&json!({
+                    "jsonrpc": "2.0",
+                    "id": shutdown.id,
+                    "error": { "code": -32603, "message": "busy" },
+                }))
+                .await
+                .unwrap();
+            server.recv().await.unwrap()
+        });
+        // `exit` is still sent after `shutdown` failed.
+        assert_eq!(exit.method(), Some("exit"));
+        let Err(Error::Shutdown(failures)) = result else {
+            panic!("expected the failed steps, got {result:?}");
+        };
+        let steps: Vec<_> = failures.iter().map(|(step, _)| *step).collect();
+        assert_eq!(steps, [ShutdownStep::Shutdown]);
+        assert!(matches!(failures[0].1, Error::Rpc(_)));
+    }
+
+    #[tokio::test]
+    async fn progress_route_ends_with_the_response() {
+        let (client, _incoming, mut server) = client();
+        initialize(&client, &mut server).await;
+
+        let (response, mut progress) = client
+            .call_with_progress::<lsp::request::WorkspaceSymbolRequest>(
+                lsp::WorkspaceSymbolParams::default(),
+            );
+        let WrittenMessage::Request(call) = server.recv().await.unwrap() else {
+            panic!("expected the request");
+        };
+        let jsonrpc::Params::Map(params) = &call.params else {
+            panic!("expected params");
+        };
+        assert_eq!(
+            params["workDoneToken"],
+            json!(format!("helix/work-done/{}", call.id))
+        );
+
+        // dropped without waiting for the response
+        drop(response);
+        assert!(progress.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_and_exit_stops_at_the_failed_step() {
+        let (client, _incoming, mut server) = client();
+        initialize(&client, &mut server).await;
+
+        let (result, _) = tokio::join!(client.shutdown_and_exit(), async {
+            let WrittenMessage::Request(shutdown) = server.recv().await.unwrap() else {
+                panic!("expected the shutdown request");
+            };
+            server
+                .send(&json!({
+                    "jsonrpc": "2.0",
+                    "id": shutdown.id,
+                    "error": { "code": -32603, "message": "busy" },
+                }))
+                .await
+                .unwrap();
+        });
+        assert!(matches!(result, Err(Error::Rpc(_))));
+
+        // `exit` isn't sent, the next message is the notification below.
+        client.exit();
+        assert_eq!(server.recv().await.unwrap().method(), Some("exit"));
+    }
+}
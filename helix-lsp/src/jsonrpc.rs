@@ -6,6 +6,8 @@
 // * `#[serde(deny_unknown_fields)]` annotations have been removed on response types
 //   for compatibility with non-strict language server implementations like Ruby Sorbet
 //   (see https://github.com/helix-editor/helix/issues/2786)
+// * they have also been removed on `MethodCall` and `Notification`, so requests and
+//   notifications from servers adding vendor fields aren't rejected either
 // * some variable names have been lengthened for readability
 
 use serde::de::{self, DeserializeOwned, Visitor};
@@ -224,7 +226,6 @@ fn from(params: Params) -> Value {
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
 pub struct MethodCall {
     pub jsonrpc: Option<Version>,
     pub method: String,
@@ -234,7 +235,6 @@ pub struct MethodCall {
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
 pub struct Notification {
     pub jsonrpc: Option<Version>,
     pub method: String,
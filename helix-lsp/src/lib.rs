@@ -1,3 +1,4 @@
+pub mod capabilities;
 mod client;
 pub mod file_event;
 mod file_operations;
@@ -5,11 +6,27 @@
 mod transport;
 
 use arc_swap::ArcSwap;
-pub use client::Client;
+pub use client::{Client, RequestOptions};
 pub use futures_executor::block_on;
 pub use helix_lsp_types as lsp;
 pub use jsonrpc::Call;
 pub use lsp::{Position, Url};
+#[cfg(feature = "test-util")]
+pub use transport::mock;
+#[cfg(feature = "inbound-hook")]
+pub use transport::InboundMessage;
+pub use transport::{
+    estimate_serialized_size, failover, reconnect, replay, BoundedStartedTransport, CodecFuture,
+    CodecReader, CodecWriter, Direction, DocumentLifecycleCheck, ExitReason, FullChannelStrategy,
+    HandlerFuture, HealthCheck, InboundReceiver, InitializeSignal, JsonRpcVersionCheck, LogBodies,
+    LogNameFormat, LspCodec, MessageCodec, NewlineJsonCodec, NonRpcJsonHandling, OutboundMessage,
+    ParseFailureLimit, PreInitRequestHandling, RateLimit, RawMessage, ReceivedMessage,
+    RequestGroup, RequestRouter, ServerRequestHandler, ShutdownOutcome, SlowRequestWarning,
+    StartedTransport, Transport, TransportConfig, TransportMiddleware, TransportState,
+    TransportStats, TransportTasks, TypedFuture, TypedRequestHandler,
+};
+#[cfg(feature = "metrics")]
+pub use transport::{Histogram, LatencyStats, MethodMetrics, MetricsSnapshot};
 
 use futures_util::stream::select_all::SelectAll;
 use helix_core::syntax::config::{
@@ -17,16 +34,14 @@
 };
 use helix_stdx::path;
 use slotmap::SlotMap;
-use tokio::sync::mpsc::UnboundedReceiver;
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     path::{Path, PathBuf},
     sync::Arc,
 };
 
 use thiserror::Error;
-use tokio_stream::wrappers::UnboundedReceiverStream;
 
 pub type Result<T, E = Error> = core::result::Result<T, E>;
 pub type LanguageServerName = String;
@@ -42,8 +57,40 @@ pub enum Error {
     IO(#[from] std::io::Error),
     #[error("request {0} timed out")]
     Timeout(jsonrpc::Id),
+    #[error("request {0} was cancelled")]
+    Cancelled(jsonrpc::Id),
+    #[error("connecting to {0} timed out")]
+    ConnectTimeout(String),
     #[error("server closed the stream")]
     StreamClosed,
+    #[error("the transport was closed")]
+    TransportClosed,
+    #[error("server stopped responding")]
+    ServerUnresponsive,
+    #[error("server wasn't initialized within {0:?}")]
+    InitializeTimeout(std::time::Duration),
+    #[error("too many messages are waiting to be sent to the server")]
+    ChannelFull,
+    #[error("too many requests are waiting for the server to initialize")]
+    QueueFull,
+    #[error("exceeded the quota of {limit} bytes exchanged with the server")]
+    QuotaExceeded { limit: u64 },
+    #[error("server announced a message of {advertised} bytes, more than the limit of {limit}")]
+    MessageTooLarge { advertised: usize, limit: usize },
+    #[error("server sent a header line longer than the limit of {limit} bytes")]
+    HeaderTooLong { limit: usize },
+    #[error("server sent {failures} malformed messages in a row")]
+    ProtocolBroken { failures: usize },
+    #[error("server sent a response with neither a result nor an error")]
+    ResultMissing,
+    #[error("server sent non-RPC JSON: {0}")]
+    NonRpcJson(String),
+    #[error("the {task} task of the transport panicked: {message}")]
+    TaskPanicked { task: &'static str, message: String },
+    #[error("server failed to start: {stderr}")]
+    ServerFailedToStart { stderr: String },
+    #[error("failed to shut down the server: {}", shutdown_failures(.0))]
+    Shutdown(Vec<(ShutdownStep, Error)>),
     #[error("Unhandled")]
     Unhandled,
     #[error(transparent)]
@@ -52,6 +99,32 @@ pub enum Error {
     Other(#[from] anyhow::Error),
 }
 
+/// A step of [`Client::shutdown_sequence`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownStep {
+    /// The `shutdown` request, asking the server to prepare for exiting.
+    Shutdown,
+    /// The `exit` notification, asking the server to exit.
+    Exit,
+}
+
+impl std::fmt::Display for ShutdownStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Shutdown => f.write_str("shutdown"),
+            Self::Exit => f.write_str("exit"),
+        }
+    }
+}
+
+fn shutdown_failures(failures: &[(ShutdownStep, Error)]) -> String {
+    failures
+        .iter()
+        .map(|(step, err)| format!("{step}: {err}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 impl From<serde_json::Error> for Error {
     fn from(value: serde_json::Error) -> Self {
         Self::Parse(Box::new(value))
@@ -566,7 +639,7 @@ pub struct Registry {
     inner: SlotMap<LanguageServerId, Arc<Client>>,
     inner_by_name: HashMap<LanguageServerName, Vec<Arc<Client>>>,
     syn_loader: Arc<ArcSwap<helix_core::syntax::Loader>>,
-    pub incoming: SelectAll<UnboundedReceiverStream<(LanguageServerId, Call)>>,
+    pub incoming: SelectAll<InboundReceiver>,
     pub file_event_handler: file_event::Handler,
 }
 
@@ -625,7 +698,7 @@ fn start_client(
                 enable_snippets,
             )
             .map(|client| {
-                self.incoming.push(UnboundedReceiverStream::new(client.1));
+                self.incoming.push(client.1);
                 client.0
             })
         })?;
@@ -762,27 +835,62 @@ pub fn progress(&self) -> Option<&lsp::WorkDoneProgress> {
     }
 }
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 /// Acts as a container for progress reported by language servers. Each server
 /// has a unique id assigned at creation through [`Registry`]. This id is then used
 /// to store the progress in this map.
-pub struct LspProgressMap(HashMap<LanguageServerId, HashMap<lsp::ProgressToken, ProgressStatus>>);
+///
+/// A server may create progress tokens but never end them, so only the most recent
+/// [`LspProgressMap::DEFAULT_MAX_TOKENS`] tokens of each server are kept: the oldest
+/// ones are evicted with a warning.
+pub struct LspProgressMap {
+    servers: HashMap<LanguageServerId, HashMap<lsp::ProgressToken, ProgressStatus>>,
+    /// The tokens of each server, oldest first.
+    order: HashMap<LanguageServerId, VecDeque<lsp::ProgressToken>>,
+    max_tokens: usize,
+}
+
+impl Default for LspProgressMap {
+    fn default() -> Self {
+        Self::with_max_tokens(Self::DEFAULT_MAX_TOKENS)
+    }
+}
 
 impl LspProgressMap {
+    /// The number of tokens tracked per server by default.
+    pub const DEFAULT_MAX_TOKENS: usize = 256;
+
     pub fn new() -> Self {
         Self::default()
     }
 
+    /// Creates a map tracking at most `max_tokens` tokens per server.
+    pub fn with_max_tokens(max_tokens: usize) -> Self {
+        Self {
+            servers: HashMap::new(),
+            order: HashMap::new(),
+            max_tokens: max_tokens.max(1),
+        }
+    }
+
     /// Returns a map of all tokens corresponding to the language server with `id`.
     pub fn progress_map(
         &self,
         id: LanguageServerId,
     ) -> Option<&HashMap<lsp::ProgressToken, ProgressStatus>> {
-        self.0.get(&id)
+        self.servers.get(&id)
     }
 
     pub fn is_progressing(&self, id: LanguageServerId) -> bool {
-        self.0.get(&id).map(|it| !it.is_empty()).unwrap_or_default()
+        self.servers
+            .get(&id)
+            .map(|it| !it.is_empty())
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of tokens tracked across all servers.
+    pub fn active_tokens(&self) -> usize {
+        self.servers.values().map(HashMap::len).sum()
     }
 
     /// Returns last progress status for a given server with `id` and `token`.
@@ -791,7 +899,7 @@ pub fn progress(
         id: LanguageServerId,
         token: &lsp::ProgressToken,
     ) -> Option<&ProgressStatus> {
-        self.0.get(&id).and_then(|values| values.get(token))
+        self.servers.get(&id).and_then(|values| values.get(token))
     }
 
     pub fn title(&self, id: LanguageServerId, token: &lsp::ProgressToken) -> Option<&String> {
@@ -803,17 +911,14 @@ pub fn title(&self, id: LanguageServerId, token: &lsp::ProgressToken) -> Option<
 
     /// Checks if progress `token` for server with `id` is created.
     pub fn is_created(&mut self, id: LanguageServerId, token: &lsp::ProgressToken) -> bool {
-        self.0
+        self.servers
             .get(&id)
             .map(|values| values.get(token).is_some())
             .unwrap_or_default()
     }
 
     pub fn create(&mut self, id: LanguageServerId, token: lsp::ProgressToken) {
-        self.0
-            .entry(id)
-            .or_default()
-            .insert(token, ProgressStatus::Created);
+        self.insert(id, token, ProgressStatus::Created);
     }
 
     /// Ends the progress by removing the `token` from server with `id`, if removed returns the value.
@@ -822,7 +927,14 @@ pub fn end_progress(
         id: LanguageServerId,
         token: &lsp::ProgressToken,
     ) -> Option<ProgressStatus> {
-        self.0.get_mut(&id).and_then(|vals| vals.remove(token))
+        let status = self
+            .servers
+            .get_mut(&id)
+            .and_then(|vals| vals.remove(token))?;
+        if let Some(order) = self.order.get_mut(&id) {
+            order.retain(|t| t != token);
+        }
+        Some(status)
     }
 
     /// Updates the progress of `token` for server with `id` to begin state `status`
@@ -832,7 +944,8 @@ pub fn begin(
         token: lsp::ProgressToken,
         status: lsp::WorkDoneProgressBegin,
     ) {
-        self.0.entry(id).or_default().insert(
+        self.insert(
+            id,
             token,
             ProgressStatus::Started {
                 title: status.title.clone(),
@@ -848,7 +961,7 @@ pub fn update(
         token: lsp::ProgressToken,
         status: lsp::WorkDoneProgressReport,
     ) {
-        self.0
+        self.servers
             .entry(id)
             .or_default()
             .entry(token)
@@ -859,9 +972,29 @@ pub fn update(
                 }
             });
     }
+
+    /// Sets the status of `token`, evicting the oldest token of the server if it's a new one
+    /// and the server already has as many tokens as allowed.
+    fn insert(&mut self, id: LanguageServerId, token: lsp::ProgressToken, status: ProgressStatus) {
+        let tokens = self.servers.entry(id).or_default();
+        if tokens.insert(token.clone(), status).is_some() {
+            return;
+        }
+        let order = self.order.entry(id).or_default();
+        order.push_back(token);
+        while order.len() > self.max_tokens {
+            let Some(evicted) = order.pop_front() else {
+                break;
+            };
+            tokens.remove(&evicted);
+            log::warn!(
+                "evicting progress token {evicted:?} of language server {id:?} that was never ended"
+            );
+        }
+    }
 }
 
-struct NewClient(Arc<Client>, UnboundedReceiver<(LanguageServerId, Call)>);
+struct NewClient(Arc<Client>, InboundReceiver);
 
 enum StartupError {
     NoRequiredRootFound,
@@ -923,6 +1056,7 @@ fn start_client(
         id,
         name,
         ls_config.timeout,
+        TransportConfig::default(),
     )?;
 
     let client = Arc::new(client);
@@ -948,7 +1082,7 @@ fn start_client(
         // next up, notify<initialized>
         _client.notify::<lsp::notification::Initialized>(lsp::InitializedParams {});
 
-        initialize_notify.notify_one();
+        initialize_notify.notify();
     });
 
     Ok(NewClient(client, incoming))
@@ -1015,9 +1149,24 @@ pub fn find_lsp_workspace(
 
 #[cfg(test)]
 mod tests {
-    use super::{lsp, util::*, OffsetEncoding};
+    use super::{
+        jsonrpc, lsp, util::*, Error, LanguageServerId, LspProgressMap, OffsetEncoding,
+        ShutdownStep,
+    };
     use helix_core::Rope;
 
+    #[test]
+    fn shutdown_error_lists_failed_steps() {
+        let err = Error::Shutdown(vec![
+            (ShutdownStep::Shutdown, Error::Timeout(jsonrpc::Id::Num(3))),
+            (ShutdownStep::Exit, Error::StreamClosed),
+        ]);
+        assert_eq!(
+            err.to_string(),
+            "failed to shut down the server: shutdown: request 3 timed out, exit: server closed the stream"
+        );
+    }
+
     #[test]
     fn converts_lsp_pos_to_pos() {
         macro_rules! test_case {
@@ -1082,4 +1231,27 @@ fn emoji_format_gh_4791() {
         assert!(transaction.apply(&mut source));
         assert_eq!(source, "[\n  \"🇺🇸\",\n  \"🎄\",\n]");
     }
+
+    #[test]
+    fn progress_map_evicts_oldest_tokens() {
+        let id = LanguageServerId::default();
+        let token = |n| lsp::ProgressToken::Number(n);
+        let mut map = LspProgressMap::with_max_tokens(2);
+
+        map.create(id, token(1));
+        map.create(id, token(2));
+        map.end_progress(id, &token(1));
+        map.create(id, token(3));
+        assert_eq!(map.active_tokens(), 2);
+
+        // updating a tracked token doesn't evict anything
+        map.create(id, token(2));
+        assert_eq!(map.active_tokens(), 2);
+
+        map.create(id, token(4));
+        assert_eq!(map.active_tokens(), 2);
+        assert!(!map.is_created(id, &token(2)));
+        assert!(map.is_created(id, &token(3)));
+        assert!(map.is_created(id, &token(4)));
+    }
 }
@@ -4,434 +4,6639 @@
     Error, LanguageServerId, Result,
 };
 use anyhow::Context;
-use log::{error, info};
+use futures_util::{
+    future::{self, BoxFuture},
+    stream::FuturesOrdered,
+    FutureExt, StreamExt,
+};
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+use std::time::Instant;
 use tokio::{
-    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
-    process::{ChildStderr, ChildStdin, ChildStdout},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
     sync::{
-        mpsc::{unbounded_channel, Sender, UnboundedReceiver, UnboundedSender},
-        Mutex, Notify,
+        mpsc::{
+            error::TrySendError, unbounded_channel, Sender, UnboundedReceiver, UnboundedSender,
+            WeakUnboundedSender,
+        },
+        watch, Mutex,
     },
 };
 
+mod breaker;
+mod cancel;
+mod coalesce;
+mod codec;
+mod completed;
+#[cfg(feature = "gzip")]
+mod encoding;
+mod exit;
+pub mod failover;
+mod garbage;
+mod group;
+mod handler;
+mod health;
+#[cfg(feature = "inbound-hook")]
+mod hook;
+mod inbound;
+mod initialize;
+#[cfg(feature = "metrics")]
+mod latency;
+mod lifecycle;
+mod log_name;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod middleware;
+#[cfg(feature = "test-util")]
+pub mod mock;
+mod net;
+#[cfg(feature = "response-order")]
+mod order;
+mod outbound;
+mod panic;
+mod priority;
+mod progress;
+mod quota;
+mod rate_limit;
+pub mod reconnect;
+pub mod replay;
+mod salvage;
+mod shutdown;
+mod size;
+mod slow;
+mod startup;
+mod state;
+mod stats;
+mod stderr;
+mod suspend;
+mod traffic_log;
+mod waiters;
+
+pub use breaker::ParseFailureLimit;
+pub use codec::{CodecFuture, CodecReader, CodecWriter, LspCodec, MessageCodec, NewlineJsonCodec};
+pub use exit::ExitReason;
+pub use group::RequestGroup;
+pub use handler::{
+    HandlerFuture, RequestRouter, ServerRequestHandler, TypedFuture, TypedRequestHandler,
+};
+pub use health::HealthCheck;
+#[cfg(feature = "inbound-hook")]
+pub use hook::InboundMessage;
+pub use inbound::InboundReceiver;
+pub use initialize::InitializeSignal;
+#[cfg(feature = "metrics")]
+pub use latency::LatencyStats;
+pub use lifecycle::DocumentLifecycleCheck;
+pub use log_name::LogNameFormat;
+#[cfg(feature = "metrics")]
+pub use metrics::{Histogram, MethodMetrics, MetricsSnapshot};
+pub use middleware::{OutboundMessage, ReceivedMessage, TransportMiddleware};
+pub use outbound::FullChannelStrategy;
+pub use rate_limit::RateLimit;
+pub use shutdown::ShutdownOutcome;
+pub use size::estimate_serialized_size;
+pub use slow::SlowRequestWarning;
+pub use state::TransportState;
+pub use stats::TransportStats;
+pub use traffic_log::LogBodies;
+
+/// The channels and handle returned by [`Transport::start`].
+pub type StartedTransport = (
+    InboundReceiver,
+    UnboundedSender<Payload>,
+    Arc<InitializeSignal>,
+    Arc<Transport>,
+);
+
+/// The tasks spawned by [`Transport::start`], see [`Transport::take_tasks`].
+///
+/// The tasks end once the transport stopped, after the server closed the stream or
+/// [`Transport::shutdown`]. With [`TransportConfig::catch_panics`] a panic stops the transport
+/// and the task ends normally; otherwise it's reported by awaiting the handle.
+#[derive(Debug)]
+pub struct TransportTasks {
+    /// Reads and dispatches the messages from the server.
+    pub recv: tokio::task::JoinHandle<()>,
+    /// Writes the payloads to the server.
+    pub send: tokio::task::JoinHandle<()>,
+    /// Reads the stderr output of the server, `None` without a stderr stream.
+    pub stderr: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl TransportTasks {
+    /// Waits for every task to end, logging those that panicked or were cancelled. Returns
+    /// whether they all ended normally.
+    pub async fn join(self) -> bool {
+        let mut ok = true;
+        let tasks = [
+            ("recv", Some(self.recv)),
+            ("send", Some(self.send)),
+            ("stderr", self.stderr),
+        ];
+        for (task, handle) in tasks {
+            let Some(handle) = handle else { continue };
+            if let Err(err) = handle.await {
+                error!("transport {task} task ended unexpectedly: {err}");
+                ok = false;
+            }
+        }
+        ok
+    }
+}
+
+/// What [`Transport::start_bounded`] returns: like a [`StartedTransport`], with a bounded
+/// sender for the payloads to the server.
+pub type BoundedStartedTransport = (
+    InboundReceiver,
+    Sender<Payload>,
+    Arc<InitializeSignal>,
+    Arc<Transport>,
+);
+
 #[derive(Debug)]
 pub enum Payload {
+    /// Built with [`Payload::request`] or [`Payload::grouped_request`].
+    #[non_exhaustive]
     Request {
         chan: Sender<Result<Value>>,
         value: jsonrpc::MethodCall,
+        /// The operation the request is part of, see [`RequestGroup`].
+        group: Option<RequestGroup>,
     },
+    /// A request whose response is matched and discarded without being reported.
+    DetachedRequest(jsonrpc::MethodCall),
     Notification(jsonrpc::Notification),
     Response(jsonrpc::Output),
 }
 
+impl Payload {
+    /// A request whose response is sent to `chan`.
+    pub fn request(chan: Sender<Result<Value>>, value: jsonrpc::MethodCall) -> Self {
+        Self::grouped_request(chan, value, None)
+    }
+
+    /// A request that is part of the operation `group`, see [`RequestGroup`].
+    pub fn grouped_request(
+        chan: Sender<Result<Value>>,
+        value: jsonrpc::MethodCall,
+        group: Option<RequestGroup>,
+    ) -> Self {
+        Self::Request { chan, value, group }
+    }
+
+    /// Serializes the payload exactly as it is sent to the server, without the
+    /// `Content-Length` header.
+    pub fn to_json_string(&self) -> Result<String> {
+        let mut json = Vec::new();
+        self.write_json(&mut json)?;
+        // serde_json only writes valid UTF-8
+        String::from_utf8(json).map_err(|err| Error::Other(err.into()))
+    }
+
+    /// Serializes the payload into `buffer` with sonic-rs, the library messages from the server
+    /// are parsed with, replacing its contents but keeping its capacity.
+    /// The buffer is left empty if serialization fails, so a partially written payload can't
+    /// end up in the next message.
+    fn write_json(&self, buffer: &mut Vec<u8>) -> Result<()> {
+        buffer.clear();
+        let written = match self {
+            Payload::Request { value, .. } | Payload::DetachedRequest(value) => {
+                sonic_rs::to_writer(&mut *buffer, value)
+            }
+            Payload::Notification(value) => sonic_rs::to_writer(&mut *buffer, value),
+            Payload::Response(output) => sonic_rs::to_writer(&mut *buffer, output),
+        };
+        if written.is_err() {
+            buffer.clear();
+        }
+        Ok(written?)
+    }
+
+    /// Estimates the size of the serialized parameters or result, see
+    /// [`estimate_serialized_size`].
+    fn estimated_size(&self) -> usize {
+        match self {
+            Payload::Request { value, .. } | Payload::DetachedRequest(value) => {
+                size::estimate_params(&value.params)
+            }
+            Payload::Notification(value) => size::estimate_params(&value.params),
+            Payload::Response(jsonrpc::Output::Success(success)) => {
+                estimate_serialized_size(&success.result)
+            }
+            Payload::Response(jsonrpc::Output::Failure(failure)) => failure
+                .error
+                .data
+                .as_ref()
+                .map_or(0, estimate_serialized_size),
+        }
+    }
+}
+
 /// A type representing all possible values sent from the server to the client.
+///
+/// Unknown fields, e.g. vendor extensions, are ignored by every variant but
+/// [`ServerMessage::ResultMissing`]: `deny_unknown_fields` only applies to struct variants of
+/// an untagged enum, and keeps that one from matching a request, which has an `id` too.
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 #[serde(untagged)]
 enum ServerMessage {
-    /// A regular JSON-RPC request output (single response).
+    /// A regular JSON-RPC request output (single response). A `null` result is a valid
+    /// result, e.g. a hover without any information.
     Output(jsonrpc::Output),
+    /// A response with neither a `result` nor an `error`, which is a protocol error. Without
+    /// this variant it would be mistaken for an invalid call.
+    ResultMissing {
+        jsonrpc: Option<jsonrpc::Version>,
+        id: jsonrpc::Id,
+    },
     /// A JSON-RPC request or notification.
     Call(jsonrpc::Call),
+    /// A JSON-RPC batch: a top-level array of outputs and calls.
+    ///
+    /// The elements are processed strictly in array order, each one completely before the
+    /// next, exactly as if they had been sent as separate messages. In particular a response
+    /// completes its pending request before any later notification of the same batch is
+    /// forwarded.
+    #[serde(skip)]
+    Batch(Vec<ServerMessage>),
+    /// An object that failed to parse, with the `id` recovered from it if possible so the
+    /// request it answers can be failed with [`Error::Parse`].
+    #[serde(skip)]
+    Malformed {
+        id: Option<jsonrpc::Id>,
+        error: String,
+    },
+    /// A request or notification from the server that failed to parse. The `id` of a request
+    /// is the server's own and is answered with `code`, it never refers to a request of the
+    /// client.
+    #[serde(skip)]
+    MalformedCall {
+        id: Option<jsonrpc::Id>,
+        code: jsonrpc::ErrorCode,
+        error: String,
+    },
 }
 
-#[derive(Debug)]
-pub struct Transport {
-    id: LanguageServerId,
-    name: String,
-    pending_requests: Mutex<HashMap<jsonrpc::Id, Sender<Result<Value>>>>,
-}
+impl ServerMessage {
+    fn jsonrpc(&self) -> Option<jsonrpc::Version> {
+        match self {
+            Self::Output(jsonrpc::Output::Success(jsonrpc::Success { jsonrpc, .. }))
+            | Self::Output(jsonrpc::Output::Failure(jsonrpc::Failure { jsonrpc, .. }))
+            | Self::Call(jsonrpc::Call::MethodCall(jsonrpc::MethodCall { jsonrpc, .. }))
+            | Self::Call(jsonrpc::Call::Notification(jsonrpc::Notification { jsonrpc, .. }))
+            | Self::ResultMissing { jsonrpc, .. } => *jsonrpc,
+            Self::Call(jsonrpc::Call::Invalid { .. })
+            | Self::Batch(_)
+            | Self::Malformed { .. }
+            | Self::MalformedCall { .. } => None,
+        }
+    }
 
-impl Transport {
-    pub fn start(
-        server_stdout: BufReader<ChildStdout>,
-        server_stdin: BufWriter<ChildStdin>,
-        server_stderr: BufReader<ChildStderr>,
-        id: LanguageServerId,
-        name: String,
-    ) -> (
-        UnboundedReceiver<(LanguageServerId, jsonrpc::Call)>,
-        UnboundedSender<Payload>,
-        Arc<Notify>,
-    ) {
-        let (client_tx, rx) = unbounded_channel();
-        let (tx, client_rx) = unbounded_channel();
-        let notify = Arc::new(Notify::new());
+    fn parse(content: &[u8]) -> Result<Self> {
+        let content = strip_bom(content).trim_ascii_start();
+        if content.starts_with(b"[") {
+            // the elements are parsed one by one so that a malformed one fails on its own
+            // rather than taking the whole batch down with it
+            let elements: Vec<sonic_rs::LazyValue<'_>> = sonic_rs::from_slice(content)?;
+            Ok(Self::Batch(
+                elements
+                    .iter()
+                    .map(|element| Self::parse_object(element.as_raw_str().as_bytes()))
+                    .collect::<Result<_>>()?,
+            ))
+        } else if content.starts_with(b"{") {
+            Self::parse_object(content)
+        } else {
+            // A bare string, number, ... is valid JSON but never a message, which is worth
+            // telling apart from a malformed message.
+            let err = sonic_rs::from_slice::<sonic_rs::Value>(content).err();
+            match err {
+                Some(err) => Err(err.into()),
+                None => Err(Error::NonRpcJson(preview(content))),
+            }
+        }
+    }
 
-        let transport = Self {
-            id,
-            name,
-            pending_requests: Mutex::new(HashMap::default()),
+    /// Parses a single message, which is malformed unless it's an object. Messages of another
+    /// JSON-RPC version fail to parse rather than being salvaged, like any other invalid input.
+    fn parse_object(content: &[u8]) -> Result<Self> {
+        let err = match sonic_rs::from_slice(content) {
+            Ok(msg) => return Ok(msg),
+            Err(err) => err,
         };
+        if !salvage::is_current_version(content) {
+            return Err(err.into());
+        }
+        let id = salvage::recover_id(content);
+        let error = err.to_string();
+        if !salvage::has_method(content) {
+            return Ok(Self::Malformed { id, error });
+        }
+        // the body is well-formed and names a method, so what doesn't fit are the parameters
+        let code = if sonic_rs::from_slice::<serde::de::IgnoredAny>(content).is_ok()
+            && salvage::recover_method(content).is_some()
+        {
+            jsonrpc::ErrorCode::InvalidParams
+        } else {
+            jsonrpc::ErrorCode::InvalidRequest
+        };
+        Ok(Self::MalformedCall { id, code, error })
+    }
+}
 
-        let transport = Arc::new(transport);
-
-        tokio::spawn(Self::recv(
-            transport.clone(),
-            server_stdout,
-            client_tx.clone(),
-        ));
-        tokio::spawn(Self::err(transport.clone(), server_stderr));
-        tokio::spawn(Self::send(
-            transport,
-            server_stdin,
-            client_tx,
-            client_rx,
-            notify.clone(),
-        ));
+/// Strips the UTF-8 byte order mark some servers, mostly on Windows, write at the start of the
+/// body. It's still counted in the `Content-Length`.
+fn strip_bom(content: &[u8]) -> &[u8] {
+    content.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(content)
+}
 
-        (rx, tx, notify)
+/// The start of a message body, for error messages.
+fn preview(content: &[u8]) -> String {
+    const PREVIEW_LEN: usize = 64;
+    let preview = String::from_utf8_lossy(&content[..content.len().min(PREVIEW_LEN)]);
+    if content.len() > PREVIEW_LEN {
+        format!("{preview}...")
+    } else {
+        preview.into_owned()
     }
+}
 
-    async fn recv_server_message(
-        reader: &mut (impl AsyncBufRead + Unpin + Send),
-        buffer: &mut String,
-        content: &mut Vec<u8>,
-        language_server_name: &str,
-    ) -> Result<ServerMessage> {
-        let mut content_length = None;
-        loop {
-            buffer.clear();
-            if reader.read_line(buffer).await? == 0 {
-                return Err(Error::StreamClosed);
-            }
+/// The start of the headers of a message.
+const HEADER_START: &[u8] = b"Content-";
 
-            // debug!("<- header {:?}", buffer);
+/// Whether `bytes` start with a header, or with as much of one as is there.
+fn starts_with_header(bytes: &[u8]) -> bool {
+    let len = bytes.len().min(HEADER_START.len());
+    bytes[..len].eq_ignore_ascii_case(&HEADER_START[..len])
+}
 
-            if buffer == "\r\n" {
-                // look for an empty CRLF line
-                break;
-            }
+/// What to do with messages that are valid JSON but neither an object nor an array, e.g. a bare
+/// string written to stdout by a broken server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NonRpcJsonHandling {
+    /// Stop the transport with [`Error::NonRpcJson`], like for any message that fails to parse.
+    #[default]
+    Close,
+    /// Log and skip the message, continuing with the next one.
+    Skip,
+}
 
-            let header = buffer.trim();
+/// The LSP error code for requests sent before the `initialize` handshake completed.
+const SERVER_NOT_INITIALIZED: i64 = -32002;
 
-            let parts = header.split_once(": ");
+/// What to do with requests the server sends before it's initialized, which the specification
+/// forbids but some servers do anyway, e.g. `window/workDoneProgress/create`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PreInitRequestHandling {
+    /// Forward them to the consumer right away, like any other request.
+    #[default]
+    Forward,
+    /// Hold them back until the server is initialized, then forward them in order before any
+    /// later request.
+    Buffer,
+    /// Answer them with a `ServerNotInitialized` error without involving the consumer.
+    Reject,
+}
 
-            match parts {
-                Some(("Content-Length", value)) => {
-                    content_length = Some(value.parse().context("invalid content length")?);
-                }
-                Some((_, _)) => {}
-                None => {
-                    // Workaround: Some non-conformant language servers will output logging and other garbage
-                    // into the same stream as JSON-RPC messages. This can also happen from shell scripts that spawn
-                    // the server. Skip such lines and log a warning.
+/// Whether a notification from the server is forwarded right away even with
+/// [`TransportConfig::buffer_pre_init_notifications`]: the messages meant for the user, and the
+/// `initialized` and `exit` notifications the transport injects itself.
+fn forwarded_before_init(notification: &jsonrpc::Notification) -> bool {
+    use lsp::notification::{
+        Exit, Initialized, LogMessage, LogTrace, Notification as _, ShowMessage,
+    };
+    [
+        LogMessage::METHOD,
+        ShowMessage::METHOD,
+        LogTrace::METHOD,
+        Initialized::METHOD,
+        Exit::METHOD,
+    ]
+    .contains(&notification.method.as_str())
+}
 
-                    // warn!("Failed to parse header: {:?}", header);
-                }
-            }
-        }
+/// How the `"jsonrpc": "2.0"` member of incoming messages is checked.
+///
+/// Messages with a version other than `2.0` always fail to parse.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JsonRpcVersionCheck {
+    /// Accept messages without a version, warning once per transport.
+    #[default]
+    Lenient,
+    /// Reject messages without a version. Rejected messages are logged and skipped, a rejected
+    /// response fails the request it answers.
+    Strict,
+}
+
+/// The direction a message travels between the client and the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// From the client to the server.
+    Outgoing,
+    /// From the server to the client.
+    Incoming,
+}
 
-        let content_length = content_length.context("missing content length")?;
-        content.resize(content_length, 0);
-        reader.read_exact(content).await?;
-        let msg = std::str::from_utf8(content).context("invalid utf8 from server")?;
+/// A message exactly as it was read from or written to the stream, without the header, see
+/// [`TransportConfig::raw_tap`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawMessage {
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+    /// When the message was read, or right before it was written.
+    pub timestamp: Instant,
+}
 
-        info!("{language_server_name} <- {msg}");
+/// A request sent to the server that is waiting for a response.
+#[derive(Debug)]
+struct PendingRequest {
+    /// `None` for [`Payload::DetachedRequest`].
+    chan: Option<Sender<Result<Value>>>,
+    method: String,
+    sent: tokio::time::Instant,
+    group: Option<RequestGroup>,
+    /// The timer of [`TransportConfig::request_timeout`].
+    timeout: Option<tokio::task::AbortHandle>,
+    /// Whether the request was reported by [`TransportConfig::slow_request_warning`].
+    warned_slow: bool,
+}
 
-        // NOTE: We avoid using `?` here, since it would return early on error
-        // and skip clearing `content`. By returning the result directly instead,
-        // we ensure `content.clear()` is always called.
-        let output = sonic_rs::from_slice(content).map_err(Into::into);
+impl PendingRequest {
+    fn cancel_timeout(&self) {
+        if let Some(timeout) = &self.timeout {
+            timeout.abort();
+        }
+    }
+}
 
-        content.clear();
+/// Options controlling the behavior of a [`Transport`].
+///
+/// The defaults match the behavior of a transport started without any configuration.
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    log_elapsed: bool,
+    log_name: LogNameFormat,
+    traffic_log_level: log::Level,
+    log_bodies: LogBodies,
+    freeze_capacity: usize,
+    jsonrpc_version_check: JsonRpcVersionCheck,
+    non_rpc_json: NonRpcJsonHandling,
+    parse_offload_threshold: Option<usize>,
+    serialize_offload_threshold: Option<usize>,
+    slow_consumer_threshold: Option<usize>,
+    extend_deadlines_on_suspend: bool,
+    notification_limits: HashMap<String, RateLimit>,
+    document_lifecycle_check: DocumentLifecycleCheck,
+    serialize_requests: bool,
+    request_handler: Option<Arc<dyn ServerRequestHandler>>,
+    middleware: Option<Arc<dyn TransportMiddleware>>,
+    codec: Option<Arc<dyn MessageCodec>>,
+    connect_timeout: std::time::Duration,
+    pending_shrink_threshold: Option<usize>,
+    duplicate_response_window: usize,
+    byte_quota: Option<u64>,
+    parse_failure_limit: Option<ParseFailureLimit>,
+    max_message_size: usize,
+    lsp_codec: LspCodec,
+    outbound_capacity: Option<usize>,
+    full_channel_strategy: FullChannelStrategy,
+    stderr_channel: Option<Sender<String>>,
+    raw_tap: Option<UnboundedSender<RawMessage>>,
+    latency_channel: Option<Sender<(String, std::time::Duration)>>,
+    log_stderr: bool,
+    stderr_history: Option<usize>,
+    collapse_repeated_stderr: bool,
+    health_check: Option<HealthCheck>,
+    on_exit: Option<exit::ExitCallback>,
+    slow_request_warning: Option<SlowRequestWarning>,
+    on_slow_request: Option<slow::SlowRequestCallback>,
+    notification_batch_size: Option<usize>,
+    coalesce_did_change: Option<std::time::Duration>,
+    warm_up_request: Option<(String, jsonrpc::Params)>,
+    capture_initialize_params: bool,
+    request_timeout: Option<std::time::Duration>,
+    initialize_timeout: Option<std::time::Duration>,
+    max_pre_init_requests: Option<usize>,
+    pre_init_requests: PreInitRequestHandling,
+    buffer_pre_init_notifications: bool,
+    catch_panics: bool,
+    #[cfg(feature = "inbound-hook")]
+    inbound_hook: Option<hook::InboundHook>,
+    #[cfg(feature = "response-order")]
+    response_order_window: usize,
+    #[cfg(feature = "gzip")]
+    gzip_threshold: Option<usize>,
+}
 
-        output
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            log_elapsed: false,
+            log_name: LogNameFormat::default(),
+            traffic_log_level: log::Level::Info,
+            log_bodies: LogBodies::default(),
+            freeze_capacity: 1024,
+            jsonrpc_version_check: JsonRpcVersionCheck::default(),
+            non_rpc_json: NonRpcJsonHandling::default(),
+            // sonic-rs parses roughly a gigabyte per second, so a 1MiB body takes about a
+            // millisecond: well above the tens of microseconds spent handing it to the blocking
+            // pool, while small messages never pay for the hand-off.
+            parse_offload_threshold: Some(1024 * 1024),
+            // serde_json serializes at a similar rate
+            serialize_offload_threshold: Some(1024 * 1024),
+            slow_consumer_threshold: None,
+            extend_deadlines_on_suspend: false,
+            notification_limits: HashMap::new(),
+            document_lifecycle_check: DocumentLifecycleCheck::default(),
+            serialize_requests: false,
+            request_handler: None,
+            middleware: None,
+            codec: None,
+            connect_timeout: std::time::Duration::from_secs(5),
+            pending_shrink_threshold: Some(1024),
+            duplicate_response_window: 256,
+            byte_quota: None,
+            parse_failure_limit: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            lsp_codec: LspCodec::default(),
+            outbound_capacity: None,
+            full_channel_strategy: FullChannelStrategy::default(),
+            stderr_channel: None,
+            raw_tap: None,
+            latency_channel: None,
+            log_stderr: true,
+            stderr_history: None,
+            collapse_repeated_stderr: false,
+            health_check: None,
+            on_exit: None,
+            slow_request_warning: None,
+            on_slow_request: None,
+            notification_batch_size: None,
+            coalesce_did_change: None,
+            warm_up_request: None,
+            capture_initialize_params: false,
+            request_timeout: None,
+            initialize_timeout: None,
+            max_pre_init_requests: None,
+            pre_init_requests: PreInitRequestHandling::default(),
+            buffer_pre_init_notifications: false,
+            catch_panics: true,
+            #[cfg(feature = "inbound-hook")]
+            inbound_hook: None,
+            #[cfg(feature = "response-order")]
+            response_order_window: 8,
+            #[cfg(feature = "gzip")]
+            gzip_threshold: None,
+        }
     }
+}
 
-    async fn recv_server_error(
-        err: &mut (impl AsyncBufRead + Unpin + Send),
-        buffer: &mut String,
-        language_server_name: &str,
-    ) -> Result<()> {
-        buffer.truncate(0);
-        if err.read_line(buffer).await? == 0 {
-            return Err(Error::StreamClosed);
-        };
-        error!("{language_server_name} err <- {buffer:?}");
+impl TransportConfig {
+    /// Prefix the transport's log lines with the time elapsed since it was started
+    /// (e.g. `+1.234s`), independent of any timestamps added by the logger.
+    pub fn log_elapsed(mut self, enabled: bool) -> Self {
+        self.log_elapsed = enabled;
+        self
+    }
 
-        Ok(())
+    /// How the server's name appears in the transport's log lines, see [`LogNameFormat`].
+    pub fn log_name(mut self, format: LogNameFormat) -> Self {
+        self.log_name = format;
+        self
     }
 
-    async fn send_payload_to_server(
-        &self,
-        server_stdin: &mut BufWriter<ChildStdin>,
-        payload: Payload,
-    ) -> Result<()> {
-        //TODO: reuse string
-        let json = match payload {
-            Payload::Request { chan, value } => {
-                self.pending_requests
-                    .lock()
-                    .await
-                    .insert(value.id.clone(), chan);
-                serde_json::to_string(&value)?
-            }
-            Payload::Notification(value) => serde_json::to_string(&value)?,
-            Payload::Response(error) => serde_json::to_string(&error)?,
-        };
-        self.send_string_to_server(server_stdin, json, &self.name)
-            .await
+    /// The level at which the messages exchanged with the server (the `->` and `<-` lines)
+    /// are logged, e.g. [`log::Level::Debug`] to keep them out of the info logs.
+    pub fn traffic_log_level(mut self, level: log::Level) -> Self {
+        self.traffic_log_level = level;
+        self
     }
 
-    async fn send_string_to_server(
-        &self,
-        server_stdin: &mut BufWriter<ChildStdin>,
-        request: String,
-        language_server_name: &str,
-    ) -> Result<()> {
-        info!("{language_server_name} -> {request}");
+    /// How much of each message exchanged with the server is logged, see [`LogBodies`].
+    /// Defaults to the first 2 KiB of each message.
+    pub fn log_bodies(mut self, policy: LogBodies) -> Self {
+        self.log_bodies = policy;
+        self
+    }
 
-        // send the headers
-        server_stdin
-            .write_all(format!("Content-Length: {}\r\n\r\n", request.len()).as_bytes())
-            .await?;
+    /// The maximum number of messages buffered while the transport is frozen
+    /// (see [`Transport::freeze`]).
+    pub fn freeze_capacity(mut self, capacity: usize) -> Self {
+        self.freeze_capacity = capacity;
+        self
+    }
 
-        // send the body
-        server_stdin.write_all(request.as_bytes()).await?;
+    /// How strictly to check the `jsonrpc` member of incoming messages.
+    pub fn jsonrpc_version_check(mut self, check: JsonRpcVersionCheck) -> Self {
+        self.jsonrpc_version_check = check;
+        self
+    }
 
-        server_stdin.flush().await?;
+    /// What to do with messages that are valid JSON but not JSON-RPC.
+    pub fn non_rpc_json(mut self, handling: NonRpcJsonHandling) -> Self {
+        self.non_rpc_json = handling;
+        self
+    }
 
-        Ok(())
+    /// Parse message bodies of at least this many bytes on the blocking thread pool so that the
+    /// transport can read the following messages in the meantime. `None` parses every message
+    /// inline. Messages are still processed in the order they were received.
+    pub fn parse_offload_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.parse_offload_threshold = threshold;
+        self
     }
 
-    async fn process_server_message(
-        &self,
-        client_tx: &UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
-        msg: ServerMessage,
-        language_server_name: &str,
-    ) -> Result<()> {
-        match msg {
-            ServerMessage::Output(output) => {
-                self.process_request_response(output, language_server_name)
-                    .await?
-            }
-            ServerMessage::Call(call) => {
-                client_tx
-                    .send((self.id, call))
-                    .context("failed to send a message to server")?;
-                // let notification = Notification::parse(&method, params);
-            }
-        };
-        Ok(())
+    /// Serialize messages whose parameters are estimated to take at least this many bytes on
+    /// the blocking thread pool, e.g. full syncs of large documents. The size is estimated
+    /// with [`estimate_serialized_size`] before serializing. `None` serializes every message
+    /// inline.
+    pub fn serialize_offload_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.serialize_offload_threshold = threshold;
+        self
     }
 
-    async fn process_request_response(
-        &self,
-        output: jsonrpc::Output,
-        language_server_name: &str,
-    ) -> Result<()> {
-        let (id, result) = match output {
-            jsonrpc::Output::Success(jsonrpc::Success { id, result, .. }) => (id, Ok(result)),
-            jsonrpc::Output::Failure(jsonrpc::Failure { id, error, .. }) => {
-                error!("{language_server_name} <- {error}");
-                (id, Err(error.into()))
-            }
-        };
+    /// Warn when more than this many server calls are waiting in the [`InboundReceiver`] and
+    /// the backlog keeps growing, which points at a slow consumer rather than a slow server.
+    pub fn slow_consumer_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.slow_consumer_threshold = threshold;
+        self
+    }
 
-        if let Some(tx) = self.pending_requests.lock().await.remove(&id) {
-            match tx.send(result).await {
-                Ok(_) => (),
-                Err(_) => error!(
-                    "Tried sending response into a closed channel (id={:?}), original request likely timed out",
-                    id
-                ),
-            };
-        } else {
-            log::error!(
-                "Discarding Language Server response without a request (id={:?}) {:?}",
-                id,
-                result
-            );
-        }
+    /// Extend the deadlines of [`Transport::timeout`], [`TransportConfig::request_timeout`]
+    /// and [`TransportConfig::initialize_timeout`] by the time the system spent suspended,
+    /// instead of timing out every in-flight request once it resumes. Disabled by default.
+    pub fn extend_deadlines_on_suspend(mut self, enabled: bool) -> Self {
+        self.extend_deadlines_on_suspend = enabled;
+        self
+    }
 
-        Ok(())
+    /// Forward at most `max` notifications of `method` from the server every `per`, dropping
+    /// the rest. This protects the consumer from a server flooding a single notification
+    /// (e.g. `$/progress`) without affecting any other messages. Requests from the server are
+    /// never dropped, and neither are `$/progress` notifications ending a progress. The number
+    /// of dropped notifications is logged once the window they were dropped in ends.
+    ///
+    /// # Panics
+    ///
+    /// If `per` is zero.
+    pub fn limit_notifications(
+        mut self,
+        method: impl Into<String>,
+        max: u32,
+        per: std::time::Duration,
+    ) -> Self {
+        assert!(
+            !per.is_zero(),
+            "the window of a notification rate limit must not be zero"
+        );
+        self.notification_limits
+            .insert(method.into(), RateLimit { max, per });
+        self
     }
 
-    async fn recv(
-        transport: Arc<Self>,
-        mut server_stdout: BufReader<ChildStdout>,
-        client_tx: UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
-    ) {
-        let mut recv_buffer = String::new();
-        let mut content_buffer = Vec::new();
-        loop {
-            match Self::recv_server_message(
-                &mut server_stdout,
-                &mut recv_buffer,
-                &mut content_buffer,
-                &transport.name,
-            )
-            .await
-            {
-                Ok(msg) => {
-                    match transport
-                        .process_server_message(&client_tx, msg, &transport.name)
-                        .await
-                    {
-                        Ok(_) => {}
-                        Err(err) => {
-                            error!("{} err: <- {err:?}", transport.name);
-                            break;
-                        }
-                    };
-                }
-                Err(err) => {
-                    if !matches!(err, Error::StreamClosed) {
-                        error!(
-                            "Exiting {} after unexpected error: {err:?}",
-                            &transport.name
-                        );
-                    }
+    /// Track the documents opened on the server through the outgoing `textDocument/didOpen`
+    /// and `textDocument/didClose` notifications, and check that the other document
+    /// notifications only concern open documents. This catches consumer bugs that some servers
+    /// handle poorly.
+    pub fn document_lifecycle_check(mut self, check: DocumentLifecycleCheck) -> Self {
+        self.document_lifecycle_check = check;
+        self
+    }
 
-                    // Close any outstanding requests.
-                    for (id, tx) in transport.pending_requests.lock().await.drain() {
-                        match tx.send(Err(Error::StreamClosed)).await {
-                            Ok(_) => (),
-                            Err(_) => {
-                                error!("Could not close request on a closed channel (id={:?})", id)
-                            }
-                        }
-                    }
+    /// Send requests one at a time, waiting for the response to the previous request before
+    /// sending the next one, for servers that misbehave when requests are pipelined.
+    /// Notifications and responses are still sent immediately, possibly before requests queued
+    /// earlier. Every request then waits for all the requests before it to complete, so
+    /// latency adds up when a slow request is followed by more requests.
+    pub fn serialize_requests(mut self, enabled: bool) -> Self {
+        self.serialize_requests = enabled;
+        self
+    }
 
-                    // Hack: inject a terminated notification so we trigger code that needs to happen after exit
-                    let notification =
-                        ServerMessage::Call(jsonrpc::Call::Notification(jsonrpc::Notification {
-                            jsonrpc: None,
-                            method: lsp::notification::Exit::METHOD.to_string(),
-                            params: jsonrpc::Params::None,
-                        }));
-                    match transport
-                        .process_server_message(&client_tx, notification, &transport.name)
-                        .await
-                    {
-                        Ok(_) => {}
-                        Err(err) => {
-                            error!("err: <- {:?}", err);
-                        }
-                    }
-                    break;
-                }
-            }
-        }
+    /// Answer the server's requests handled by `handler` (e.g. a [`RequestRouter`])
+    /// automatically instead of forwarding them to the [`InboundReceiver`]. Requests the
+    /// handler doesn't handle are still forwarded.
+    pub fn request_handler(mut self, handler: Arc<dyn ServerRequestHandler>) -> Self {
+        self.request_handler = Some(handler);
+        self
     }
 
-    async fn err(transport: Arc<Self>, mut server_stderr: BufReader<ChildStderr>) {
-        let mut recv_buffer = String::new();
-        loop {
-            match Self::recv_server_error(&mut server_stderr, &mut recv_buffer, &transport.name)
-                .await
-            {
-                Ok(_) => {}
-                Err(err) => {
-                    error!("{} err: <- {err:?}", transport.name);
-                    break;
-                }
-            }
-        }
+    /// Call `middleware` with every message exchanged with the server, see
+    /// [`TransportMiddleware`].
+    pub fn middleware(mut self, middleware: Arc<dyn TransportMiddleware>) -> Self {
+        self.middleware = Some(middleware);
+        self
     }
 
-    async fn send(
-        transport: Arc<Self>,
-        mut server_stdin: BufWriter<ChildStdin>,
-        client_tx: UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
-        mut client_rx: UnboundedReceiver<Payload>,
-        initialize_notify: Arc<Notify>,
-    ) {
-        let mut pending_messages: Vec<Payload> = Vec::new();
-        let mut is_pending = true;
+    /// Frame the messages exchanged with the server with `codec` rather than with
+    /// `Content-Length` headers, e.g. [`NewlineJsonCodec`] for servers writing one message per
+    /// line. The options configuring the default [`LspCodec`], like
+    /// [`TransportConfig::max_header_line`], don't apply to a codec.
+    pub fn codec(mut self, codec: Arc<dyn MessageCodec>) -> Self {
+        self.codec = Some(codec);
+        self
+    }
 
-        // Determine if a message is allowed to be sent early
-        fn is_initialize(payload: &Payload) -> bool {
-            use lsp::{
-                notification::Initialized,
-                request::{Initialize, Request},
-            };
-            match payload {
-                Payload::Request {
-                    value: jsonrpc::MethodCall { method, .. },
-                    ..
-                } if method == Initialize::METHOD => true,
-                Payload::Notification(jsonrpc::Notification { method, .. })
-                    if method == Initialized::METHOD =>
-                {
-                    true
-                }
-                _ => false,
-            }
-        }
+    /// How long [`Transport::connect_tcp`] and [`Transport::connect_unix`] wait for the
+    /// connection to be established, rather than relying on the OS timeouts which can take
+    /// minutes for an unreachable host.
+    pub fn connect_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
 
-        fn is_shutdown(payload: &Payload) -> bool {
-            use lsp::request::{Request, Shutdown};
-            matches!(payload, Payload::Request { value: jsonrpc::MethodCall { method, .. }, .. } if method == Shutdown::METHOD)
-        }
+    /// Release the memory held by the map of pending requests once it has room for more than
+    /// this many requests but is mostly drained, so a burst of requests doesn't pin that memory
+    /// for the lifetime of the server. `None` never shrinks the map.
+    pub fn pending_shrink_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.pending_shrink_threshold = threshold;
+        self
+    }
 
-        // TODO: events that use capabilities need to do the right thing
+    /// Remember the ids of the last `requests` answered, so that a response to one of them is
+    /// logged as a duplicate response, a bug of the server, rather than as a response to a
+    /// request that was never sent. Requests that timed out or were cancelled are remembered
+    /// too, their late responses are only logged at debug level. Defaults to 256, 0 remembers
+    /// none.
+    pub fn duplicate_response_window(mut self, requests: usize) -> Self {
+        self.duplicate_response_window = requests;
+        self
+    }
 
-        loop {
-            tokio::select! {
-                biased;
-                _ = initialize_notify.notified() => { // TODO: notified is technically not cancellation safe
-                    // server successfully initialized
-                    is_pending = false;
+    /// Stop the transport with [`Error::QuotaExceeded`] once more than this many bytes were
+    /// exchanged with the server, counting the messages sent (headers included) and the bodies
+    /// of the messages received, e.g. to cap the traffic to a hosted server. The message that
+    /// would exceed the quota is not sent, and pending requests fail with the same error.
+    pub fn byte_quota(mut self, bytes: Option<u64>) -> Self {
+        self.byte_quota = bytes;
+        self
+    }
 
-                    // Hack: inject an initialized notification so we trigger code that needs to happen after init
-                    let notification = ServerMessage::Call(jsonrpc::Call::Notification(jsonrpc::Notification {
-                        jsonrpc: None,
+    /// Stop the transport with [`Error::ProtocolBroken`] once [`ParseFailureLimit::max_failures`]
+    /// messages in a row failed to parse, e.g. because a wrapper script writes garbage to
+    /// stdout, rather than skipping them forever. Only bodies count, each element of a batch on
+    /// its own: header lines skipped as garbage, e.g. a startup banner, don't. Any message that
+    /// parses ends the streak. Pending requests fail with the same error.
+    pub fn parse_failure_limit(mut self, limit: Option<ParseFailureLimit>) -> Self {
+        self.parse_failure_limit = limit;
+        self
+    }
+
+    /// Stop the transport with [`Error::MessageTooLarge`] when the server announces a message
+    /// body larger than `bytes`, before allocating room for it, rather than trusting a
+    /// `Content-Length` that may be corrupted. Pending requests fail with the same error.
+    /// Defaults to 128 MiB.
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = bytes;
+        self
+    }
+
+    /// Stop the transport with [`Error::HeaderTooLong`] when a header line, or a line of
+    /// garbage skipped between messages, is longer than `bytes`, so a server writing without
+    /// ever ending the line can't make the transport buffer it all. Pending requests fail with
+    /// the same error. Defaults to 1 MiB.
+    pub fn max_header_line(mut self, bytes: usize) -> Self {
+        self.lsp_codec = self.lsp_codec.max_header_line(bytes);
+        self
+    }
+
+    /// Skip up to a few extra blank lines between the headers and the body, which some proxies
+    /// write and would otherwise end up at the start of the body. A body that really starts with
+    /// a line break loses it, which is why this is off by default.
+    pub fn skip_extra_blank_lines(mut self, enabled: bool) -> Self {
+        self.lsp_codec = self.lsp_codec.skip_extra_blank_lines(enabled);
+        self
+    }
+
+    /// How many payloads queued with [`Transport::enqueue`] or [`Transport::try_enqueue`] may
+    /// wait for the send task before the [`TransportConfig::full_channel_strategy`] applies.
+    /// `None` queues without limit. Adjustable at runtime with
+    /// [`Transport::set_outbound_capacity`].
+    pub fn outbound_capacity(mut self, capacity: Option<usize>) -> Self {
+        self.outbound_capacity = capacity;
+        self
+    }
+
+    /// What to do with payloads queued while the [`TransportConfig::outbound_capacity`] is
+    /// reached, unless the caller picks a strategy. [`Client::notify`](crate::Client::notify)
+    /// always picks [`FullChannelStrategy::Wait`], so document synchronization is never dropped.
+    pub fn full_channel_strategy(mut self, strategy: FullChannelStrategy) -> Self {
+        self.full_channel_strategy = strategy;
+        self
+    }
+
+    /// Forward every line the server writes to stderr, without the line terminator, to
+    /// `channel`, e.g. to show them live in a dedicated panel. Lines are dropped with a warning
+    /// rather than waiting for room when the channel is full, so the channel's capacity bounds
+    /// the lines buffered for a consumer that falls behind. Forwarding stops once the receiver
+    /// is dropped.
+    pub fn stderr_channel(mut self, channel: Sender<String>) -> Self {
+        self.stderr_channel = Some(channel);
+        self
+    }
+
+    /// Send the method and round-trip latency of every request the server answers to
+    /// `channel`, measured from the request being written to its response being read, e.g. to
+    /// warn about a slow completion in the editor. Like with
+    /// [`TransportConfig::stderr_channel`], events are dropped when the channel is full.
+    pub fn latency_channel(mut self, channel: Sender<(String, std::time::Duration)>) -> Self {
+        self.latency_channel = Some(channel);
+        self
+    }
+
+    /// Send a copy of every message body read from and written to the stream to `tap`, before
+    /// it's parsed or after it's serialized, e.g. for a protocol inspector showing the exact
+    /// bytes exchanged. Unlike [`TransportConfig::middleware`] it includes messages that fail
+    /// to parse. Nothing is copied without a tap.
+    pub fn raw_tap(mut self, tap: UnboundedSender<RawMessage>) -> Self {
+        self.raw_tap = Some(tap);
+        self
+    }
+
+    /// Log the lines the server writes to stderr, which is the default. This can be disabled
+    /// when they're forwarded to a [`TransportConfig::stderr_channel`] instead.
+    pub fn log_stderr(mut self, enabled: bool) -> Self {
+        self.log_stderr = enabled;
+        self
+    }
+
+    /// Keep the last `lines` lines the server wrote to stderr, for
+    /// [`Transport::recent_stderr`], e.g. to show why a server exited. `None`, the default,
+    /// keeps none.
+    pub fn stderr_history(mut self, lines: Option<usize>) -> Self {
+        self.stderr_history = lines;
+        self
+    }
+
+    /// Collapse consecutive identical lines of the [`TransportConfig::stderr_history`] into a
+    /// single line with a repeat count, e.g. `retrying (×42)`, so a server writing the same
+    /// line in a loop doesn't push every other line out of the history. Off by default.
+    pub fn collapse_repeated_stderr(mut self, enabled: bool) -> Self {
+        self.collapse_repeated_stderr = enabled;
+        self
+    }
+
+    /// Ping the server once it sent nothing for [`HealthCheck::idle`] while requests are
+    /// pending, and stop the transport with [`Error::ServerUnresponsive`] if the ping isn't
+    /// answered within [`HealthCheck::timeout`] either. A server that is silent because nothing
+    /// is expected from it is never pinged.
+    ///
+    /// # Panics
+    ///
+    /// If [`HealthCheck::idle`] or [`HealthCheck::timeout`] is zero.
+    pub fn health_check(mut self, check: Option<HealthCheck>) -> Self {
+        if let Some(check) = &check {
+            assert!(
+                !check.idle.is_zero() && !check.timeout.is_zero(),
+                "the durations of the health check must not be zero"
+            );
+        }
+        self.health_check = check;
+        self
+    }
+
+    /// Call `callback` once the transport stopped talking to the server, with the reason why.
+    /// It's called exactly once, from the receiving task, before the pending requests are
+    /// failed and the synthetic `exit` notification is delivered to the [`InboundReceiver`].
+    pub fn on_exit(mut self, callback: impl Fn(ExitReason) + Send + Sync + 'static) -> Self {
+        self.on_exit = Some(exit::ExitCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Report requests that are still pending after [`SlowRequestWarning::threshold`], once
+    /// each, while they keep waiting for their response, e.g. to tell the user why a hover is
+    /// taking long. They are logged unless [`TransportConfig::on_slow_request`] is set.
+    ///
+    /// # Panics
+    ///
+    /// If [`SlowRequestWarning::sweep_interval`] is zero.
+    pub fn slow_request_warning(mut self, warning: Option<SlowRequestWarning>) -> Self {
+        if let Some(warning) = &warning {
+            assert!(
+                !warning.sweep_interval.is_zero(),
+                "the sweep interval of the slow request warning must not be zero"
+            );
+        }
+        self.slow_request_warning = warning;
+        self
+    }
+
+    /// Call `callback` with the method and id of every request reported by
+    /// [`TransportConfig::slow_request_warning`] and how long it has been pending, instead of
+    /// logging it. It's called from a background task and must not block.
+    pub fn on_slow_request(
+        mut self,
+        callback: impl Fn(&str, &jsonrpc::Id, std::time::Duration) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_slow_request = Some(slow::SlowRequestCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Send notifications that are queued back to back as a single JSON-RPC batch array of at
+    /// most `size` notifications, in the order they were queued, rather than as one message
+    /// each. Only enable this for servers that accept batches, which the LSP specification
+    /// doesn't require. `None`, the default, sends every notification on its own.
+    ///
+    /// The `exit` notification is never batched.
+    pub fn notification_batch_size(mut self, size: Option<usize>) -> Self {
+        self.notification_batch_size = size;
+        self
+    }
+
+    /// Hold back a `textDocument/didChange` notification replacing the whole text of a document
+    /// for `window`, sending only the latest one if more changes to the same document are
+    /// queued right after it, to spare the server the intermediate versions when typing fast.
+    /// This only applies to servers using full document sync: incremental changes are always
+    /// sent as they are. Other messages are never dropped or reordered, a held back change is
+    /// sent before them. `None`, the default, sends every change right away.
+    pub fn coalesce_did_change(mut self, window: Option<std::time::Duration>) -> Self {
+        self.coalesce_did_change = window;
+        self
+    }
+
+    /// Send a `method` request with `params` once the server is initialized, right after the
+    /// messages held back until then, to prime a server that is slow to answer its first
+    /// request, e.g. `textDocument/documentSymbol` for a small file. The response, or error, is
+    /// discarded.
+    pub fn warm_up_request(mut self, method: impl Into<String>, params: jsonrpc::Params) -> Self {
+        self.warm_up_request = Some((method.into(), params));
+        self
+    }
+
+    /// Keep a copy of the params of the `initialize` request as it was sent, for
+    /// [`Transport::sent_initialize_params`]. Off by default, since the params can be large and
+    /// are rarely needed after initialization.
+    pub fn capture_initialize_params(mut self, capture: bool) -> Self {
+        self.capture_initialize_params = capture;
+        self
+    }
+
+    /// Fail requests the server didn't answer within `timeout` of being sent with
+    /// [`Error::Timeout`], and send a `$/cancelRequest` notification for them so the server can
+    /// stop working on them. A response arriving afterwards is discarded. `None`, the default,
+    /// waits for responses indefinitely, leaving timeouts to the caller.
+    pub fn request_timeout(mut self, timeout: Option<std::time::Duration>) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Stop the transport with [`Error::InitializeTimeout`] if the server isn't initialized
+    /// within `timeout` of the transport starting. The `initialize` request and everything
+    /// held back until the server is initialized fail with that error. Unlike
+    /// [`TransportConfig::request_timeout`] this covers the whole handshake, including the
+    /// time before the `initialize` request is even sent. `None`, the default, waits
+    /// indefinitely.
+    pub fn initialize_timeout(mut self, timeout: Option<std::time::Duration>) -> Self {
+        self.initialize_timeout = timeout;
+        self
+    }
+
+    /// Hold back at most `max` requests until the server is initialized. Once more are queued,
+    /// the oldest one fails with [`Error::QueueFull`], or isn't sent if nothing awaits its
+    /// response, so a server that is slow to start doesn't accumulate requests without bound.
+    /// `None`, the default, holds back every request.
+    pub fn max_pre_init_requests(mut self, max: Option<usize>) -> Self {
+        self.max_pre_init_requests = max;
+        self
+    }
+
+    /// What to do with requests the server sends before it's initialized.
+    pub fn pre_init_requests(mut self, handling: PreInitRequestHandling) -> Self {
+        self.pre_init_requests = handling;
+        self
+    }
+
+    /// Hold back the notifications the server sends before it's initialized, e.g. diagnostics
+    /// published while the consumer doesn't expect them yet, and forward them in order once it
+    /// is, after the `initialized` notification. `window/logMessage`, `window/showMessage` and
+    /// `$/logTrace` are always forwarded right away. Off by default.
+    pub fn buffer_pre_init_notifications(mut self, enabled: bool) -> Self {
+        self.buffer_pre_init_notifications = enabled;
+        self
+    }
+
+    /// Catch a panic in one of the tasks of the transport, e.g. caused by a bug in a
+    /// [`ServerRequestHandler`], and stop the transport cleanly: the panic is logged, pending
+    /// requests fail with [`Error::TaskPanicked`] and the exit is reported. Enabled by default;
+    /// when disabled a panicking task dies on its own, leaving the rest of the transport
+    /// running.
+    pub fn catch_panics(mut self, enabled: bool) -> Self {
+        self.catch_panics = enabled;
+        self
+    }
+
+    /// Send a copy of every message received from the server to `hook`, after it's parsed and
+    /// before it's processed, e.g. to assert on the traffic in tests without consuming the
+    /// [`InboundReceiver`]. Messages are dropped with a warning rather than waiting for room
+    /// when the channel is full, so a slow observer never holds up the transport.
+    #[cfg(feature = "inbound-hook")]
+    pub fn inbound_hook(mut self, hook: Sender<InboundMessage>) -> Self {
+        self.inbound_hook = Some(hook::InboundHook(hook));
+        self
+    }
+
+    /// Warn when the response to a request arrives before the responses to more than this many
+    /// earlier requests of the same method. Answering out of order is legal, but answering far
+    /// out of order can point at queueing issues in the server.
+    #[cfg(feature = "response-order")]
+    pub fn response_order_window(mut self, window: usize) -> Self {
+        self.response_order_window = window;
+        self
+    }
+
+    /// Compress the bodies of at least `bytes` sent to the server with gzip, once the server
+    /// listed `"gzip"` in the `capabilities.experimental.contentEncodings` of its `initialize`
+    /// response. Compressed bodies from the server are accepted regardless. `None`, the
+    /// default, never compresses. Doesn't apply to a [`TransportConfig::codec`]. Bodies
+    /// reaching [`TransportConfig::serialize_offload_threshold`] are compressed on the blocking
+    /// thread pool.
+    #[cfg(feature = "gzip")]
+    pub fn gzip_threshold(mut self, bytes: Option<usize>) -> Self {
+        self.gzip_threshold = bytes;
+        self
+    }
+}
+
+/// Shrinks `map` once its capacity exceeds `threshold` while it's at most a quarter full,
+/// keeping room for it to double again before reallocating.
+fn shrink_drained<K: Eq + std::hash::Hash, V>(map: &mut HashMap<K, V>, threshold: Option<usize>) {
+    let Some(threshold) = threshold else {
+        return;
+    };
+    if map.capacity() > threshold && map.len() <= map.capacity() / 4 {
+        map.shrink_to(map.len() * 2);
+    }
+}
+
+/// The elapsed-time prefix of a log line, empty unless [`TransportConfig::log_elapsed`] is set.
+struct Elapsed(Option<std::time::Duration>);
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(elapsed) => write!(f, "+{:.3}s ", elapsed.as_secs_f64()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Transport {
+    id: LanguageServerId,
+    log_name: log_name::LogName,
+    config: TransportConfig,
+    started: Instant,
+    /// Requests waiting for a response, keyed by id. `1` and `"1"` are distinct ids, as in the
+    /// JSON-RPC spec. An entry is removed as soon as its response is processed, so a response
+    /// only ever completes the request that was pending with its id when it arrived: a later
+    /// duplicate of it is discarded, and the id can be reused by a new request afterwards
+    /// without that request receiving a stale response.
+    /// The lock is never held across an `.await`, so inserting and removing requests doesn't
+    /// have to wait for an async lock on every message.
+    pending_requests: parking_lot::Mutex<HashMap<jsonrpc::Id, PendingRequest>>,
+    /// The next id handed out by [`Transport::next_id`].
+    request_counter: AtomicU64,
+    /// Whether any request is pending, updated while `pending_requests` is locked.
+    busy: watch::Sender<bool>,
+    /// Messages held back while the transport is frozen, `None` when it isn't.
+    frozen: Mutex<Option<VecDeque<ServerMessage>>>,
+    /// Used to release frozen messages. This is weak so that the receiving end still closes
+    /// once the transport's tasks exit.
+    client_tx: WeakUnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+    /// Used to answer requests handled by [`TransportConfig::request_handler`].
+    server_tx: WeakUnboundedSender<Payload>,
+    warned_missing_version: AtomicBool,
+    warned_stray_bytes: AtomicBool,
+    garbage_lines: garbage::GarbageLines,
+    /// Whether the server accepts gzip compressed bodies, see [`TransportConfig::gzip_threshold`].
+    #[cfg(feature = "gzip")]
+    server_accepts_gzip: AtomicBool,
+    inbound_depth: inbound::InboundDepth,
+    suspend: Arc<suspend::SuspendDetector>,
+    startup: startup::StartupWatch,
+    notification_limiter: rate_limit::NotificationLimiter,
+    notification_waiters: waiters::NotificationWaiters,
+    documents: lifecycle::DocumentTracker,
+    /// Lets [`Transport::pause_reader`] and [`Transport::replace_reader`] reach the recv task.
+    reader_control: UnboundedSender<ReaderControl>,
+    /// `None` unless [`TransportConfig::byte_quota`] is set.
+    quota: Option<quota::ByteQuota>,
+    /// With [`TransportConfig::parse_failure_limit`].
+    parse_failures: Option<breaker::ParseFailures>,
+    outbound: outbound::OutboundQueue,
+    progress: progress::ProgressRoutes,
+    activity: health::Activity,
+    /// Whether the `exit` notification was sent, making closing the stream expected.
+    exit_sent: AtomicBool,
+    exit: exit::ExitOnce,
+    /// With [`TransportConfig::capture_initialize_params`].
+    sent_initialize_params: parking_lot::Mutex<Option<Value>>,
+    lifecycle: shutdown::Lifecycle,
+    state: state::StateCell,
+    stats: stats::StatsCounters,
+    completed: completed::RecentlyCompleted,
+    /// Until taken with [`Transport::take_tasks`].
+    tasks: parking_lot::Mutex<Option<TransportTasks>>,
+    /// With [`TransportConfig::stderr_history`].
+    stderr_history: Option<stderr::StderrHistory>,
+    /// Outgoing messages are serialized into this buffer, taken out while a message is being
+    /// sent, so its allocation is reused from one message to the next.
+    send_buffer: parking_lot::Mutex<Vec<u8>>,
+    /// The server requests and notifications held back by
+    /// [`TransportConfig::pre_init_requests`] and
+    /// [`TransportConfig::buffer_pre_init_notifications`] in the order they were received,
+    /// `None` once the server is initialized or if they are forwarded right away.
+    pre_init_calls: Mutex<Option<Vec<jsonrpc::Call>>>,
+    /// The server requests being answered by [`TransportConfig::request_handler`].
+    handled_requests: cancel::HandledRequests,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<metrics::Metrics>,
+    #[cfg(feature = "response-order")]
+    response_order: order::ResponseOrder,
+}
+
+/// The default [`TransportConfig::max_header_line`].
+const DEFAULT_MAX_HEADER_LINE: usize = 1024 * 1024;
+
+/// The default [`TransportConfig::max_message_size`].
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 128 * 1024 * 1024;
+
+/// The stream messages from the server are read from, boxed so it can be replaced by a stream
+/// of another type.
+type ServerReader = Box<dyn AsyncBufRead + Unpin + Send>;
+
+/// Instructions to the recv task, applied between two messages.
+enum ReaderControl {
+    /// Stop reading until a new reader is provided.
+    Pause,
+    /// Continue with another reader.
+    Replace(ServerReader),
+    /// Stop the transport with an error.
+    Close(Error),
+}
+
+impl Transport {
+    fn new(
+        id: LanguageServerId,
+        name: String,
+        config: TransportConfig,
+        client_tx: &UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+        server_tx: &UnboundedSender<Payload>,
+        reader_control: UnboundedSender<ReaderControl>,
+    ) -> Self {
+        Self {
+            id,
+            log_name: log_name::LogName::new(name, id, config.log_name),
+            started: Instant::now(),
+            pending_requests: parking_lot::Mutex::new(HashMap::default()),
+            busy: watch::Sender::new(false),
+            frozen: Mutex::new(None),
+            client_tx: client_tx.downgrade(),
+            server_tx: server_tx.downgrade(),
+            warned_missing_version: AtomicBool::new(false),
+            warned_stray_bytes: AtomicBool::new(false),
+            garbage_lines: garbage::GarbageLines::default(),
+            #[cfg(feature = "gzip")]
+            server_accepts_gzip: AtomicBool::new(false),
+            inbound_depth: inbound::InboundDepth::default(),
+            suspend: Arc::new(suspend::SuspendDetector::new()),
+            startup: startup::StartupWatch::new(),
+            notification_limiter: rate_limit::NotificationLimiter::default(),
+            notification_waiters: waiters::NotificationWaiters::default(),
+            documents: lifecycle::DocumentTracker::default(),
+            reader_control,
+            quota: config.byte_quota.map(quota::ByteQuota::new),
+            parse_failures: config.parse_failure_limit.map(breaker::ParseFailures::new),
+            outbound: outbound::OutboundQueue::new(config.outbound_capacity),
+            progress: progress::ProgressRoutes::default(),
+            activity: health::Activity::new(),
+            request_counter: AtomicU64::new(0),
+            exit_sent: AtomicBool::new(false),
+            exit: exit::ExitOnce::default(),
+            sent_initialize_params: parking_lot::Mutex::new(None),
+            send_buffer: parking_lot::Mutex::new(Vec::new()),
+            lifecycle: shutdown::Lifecycle::new(),
+            state: state::StateCell::new(),
+            stats: stats::StatsCounters::default(),
+            completed: completed::RecentlyCompleted::new(config.duplicate_response_window),
+            tasks: parking_lot::Mutex::new(None),
+            stderr_history: config
+                .stderr_history
+                .map(|lines| stderr::StderrHistory::new(lines, config.collapse_repeated_stderr)),
+            pre_init_calls: Mutex::new(
+                (config.pre_init_requests != PreInitRequestHandling::Forward
+                    || config.buffer_pre_init_notifications)
+                    .then(Vec::new),
+            ),
+            handled_requests: cancel::HandledRequests::default(),
+            config,
+            #[cfg(feature = "metrics")]
+            metrics: Arc::default(),
+            #[cfg(feature = "response-order")]
+            response_order: order::ResponseOrder::default(),
+        }
+    }
+
+    /// Starts talking to a language server over the given streams: typically the child process'
+    /// stdout, stdin and stderr.
+    ///
+    /// Any [`AsyncBufRead`] and [`AsyncWrite`] can be used, so the streams can be wrapped in an
+    /// adapter to instrument the traffic, for example to count the bytes written:
+    ///
+    /// ```no_run
+    /// # use std::{pin::Pin, sync::{atomic::{AtomicUsize, Ordering}, Arc}, task::{Context, Poll}};
+    /// # use tokio::io::AsyncWrite;
+    /// struct Counting<W> {
+    ///     inner: W,
+    ///     written: Arc<AtomicUsize>,
+    /// }
+    ///
+    /// impl<W: AsyncWrite + Unpin> AsyncWrite for Counting<W> {
+    ///     fn poll_write(
+    ///         mut self: Pin<&mut Self>,
+    ///         cx: &mut Context<'_>,
+    ///         buf: &[u8],
+    ///     ) -> Poll<std::io::Result<usize>> {
+    ///         let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+    ///         if let Poll::Ready(Ok(written)) = poll {
+    ///             self.written.fetch_add(written, Ordering::Relaxed);
+    ///         }
+    ///         poll
+    ///     }
+    ///
+    ///     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    ///         Pin::new(&mut self.inner).poll_flush(cx)
+    ///     }
+    ///
+    ///     fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    ///         Pin::new(&mut self.inner).poll_shutdown(cx)
+    ///     }
+    /// }
+    ///
+    /// # async fn example(mut process: tokio::process::Child) {
+    /// use helix_lsp::{Transport, TransportConfig};
+    /// use tokio::io::{BufReader, BufWriter};
+    ///
+    /// let written = Arc::new(AtomicUsize::new(0));
+    /// let stdin = Counting {
+    ///     inner: BufWriter::new(process.stdin.take().unwrap()),
+    ///     written: written.clone(),
+    /// };
+    /// let stdout = BufReader::new(process.stdout.take().unwrap());
+    /// let stderr = BufReader::new(process.stderr.take().unwrap());
+    /// let (incoming, outgoing, initialize_notify, transport) = Transport::start(
+    ///     stdout,
+    ///     stdin,
+    ///     stderr,
+    ///     Default::default(),
+    ///     "server".to_string(),
+    ///     TransportConfig::default(),
+    /// );
+    /// # }
+    /// ```
+    ///
+    /// Writes are flushed after every message, so a writer that buffers internally should be
+    /// wrapped in a [`tokio::io::BufWriter`] rather than the other way around when the adapter
+    /// needs to observe individual messages.
+    pub fn start(
+        server_stdout: impl AsyncBufRead + Unpin + Send + 'static,
+        server_stdin: impl AsyncWrite + Unpin + Send + 'static,
+        server_stderr: impl AsyncBufRead + Unpin + Send + 'static,
+        id: LanguageServerId,
+        name: String,
+        config: TransportConfig,
+    ) -> StartedTransport {
+        let (started, _) = Self::start_with(
+            server_stdout,
+            server_stdin,
+            Some(server_stderr),
+            None,
+            id,
+            name,
+            config,
+        );
+        started
+    }
+
+    /// Like [`Transport::start`], but the payloads for the server are queued on a bounded
+    /// channel of `capacity` payloads, so a producer outpacing the server, e.g. keystrokes
+    /// generating `textDocument/didChange` notifications, waits in [`Sender::send`] instead of
+    /// growing the queue without limit.
+    ///
+    /// The returned [`Sender`] is the only way to queue payloads; [`Transport::enqueue`] and
+    /// [`Transport::try_enqueue`] don't apply to it. The payloads the transport sends on its
+    /// own, like the responses of a [`TransportConfig::request_handler`] or `$/cancelRequest`
+    /// notifications, still go through an internal unbounded channel, so the receiving task
+    /// never waits for room in the bounded one, which could deadlock with a consumer waiting
+    /// for a response. The transport stops sending once the returned sender is dropped.
+    ///
+    /// Payloads sent before the server is initialized are taken off the channel and held back
+    /// until then as usual.
+    pub fn start_bounded(
+        server_stdout: impl AsyncBufRead + Unpin + Send + 'static,
+        server_stdin: impl AsyncWrite + Unpin + Send + 'static,
+        server_stderr: impl AsyncBufRead + Unpin + Send + 'static,
+        capacity: usize,
+        id: LanguageServerId,
+        name: String,
+        config: TransportConfig,
+    ) -> BoundedStartedTransport {
+        let ((rx, _, notify, transport), tx) = Self::start_with(
+            server_stdout,
+            server_stdin,
+            Some(server_stderr),
+            Some(capacity),
+            id,
+            name,
+            config,
+        );
+        let tx = tx.expect("a bounded channel was requested");
+        (rx, tx, notify, transport)
+    }
+
+    /// Like [`Transport::start`], for a server that has no stderr to read, e.g. one listening
+    /// on a socket. The read and write halves of a [`tokio::net::TcpStream`] can be passed
+    /// directly (the read half wrapped in a [`tokio::io::BufReader`]), see also
+    /// [`Transport::connect_tcp`].
+    pub fn start_without_stderr(
+        server_stdout: impl AsyncBufRead + Unpin + Send + 'static,
+        server_stdin: impl AsyncWrite + Unpin + Send + 'static,
+        id: LanguageServerId,
+        name: String,
+        config: TransportConfig,
+    ) -> StartedTransport {
+        let (started, _) = Self::start_with(
+            server_stdout,
+            server_stdin,
+            None::<tokio::io::Empty>,
+            None,
+            id,
+            name,
+            config,
+        );
+        started
+    }
+
+    fn start_with(
+        server_stdout: impl AsyncBufRead + Unpin + Send + 'static,
+        server_stdin: impl AsyncWrite + Unpin + Send + 'static,
+        server_stderr: Option<impl AsyncBufRead + Unpin + Send + 'static>,
+        bounded: Option<usize>,
+        id: LanguageServerId,
+        name: String,
+        config: TransportConfig,
+    ) -> (StartedTransport, Option<Sender<Payload>>) {
+        let (client_tx, rx) = unbounded_channel();
+        let (tx, client_rx) = unbounded_channel();
+        let (reader_tx, reader_rx) = unbounded_channel();
+        let notify = Arc::new(InitializeSignal::new());
+
+        let transport = Arc::new(Self::new(id, name, config, &client_tx, &tx, reader_tx));
+        let rx = InboundReceiver::new(rx, &transport.inbound_depth);
+        let (bounded_tx, client_rx) = match bounded {
+            Some(capacity) => {
+                let (bounded_tx, bounded_rx) = tokio::sync::mpsc::channel(capacity);
+                let client_rx = outbound::OutgoingReceiver::bounded(
+                    &transport.outbound,
+                    client_rx,
+                    bounded_rx,
+                    &tx,
+                );
+                (Some(bounded_tx), client_rx)
+            }
+            None => (
+                None,
+                outbound::OutgoingReceiver::unbounded(&transport.outbound, client_rx),
+            ),
+        };
+
+        // accounted before either can end, for `shutdown`
+        transport.lifecycle.task_started();
+        transport.lifecycle.task_started();
+        let recv = tokio::spawn(Self::isolate(
+            transport.clone(),
+            panic::Task::Recv,
+            Self::recv(
+                transport.clone(),
+                Box::new(server_stdout),
+                reader_rx,
+                client_tx.clone(),
+            ),
+        ));
+        let stderr = match server_stderr {
+            Some(server_stderr) => Some(tokio::spawn(Self::isolate(
+                transport.clone(),
+                panic::Task::Stderr,
+                Self::err(transport.clone(), server_stderr),
+            ))),
+            None => {
+                // there's no stderr output to wait for when the server fails to start
+                transport.startup.record_stderr_closed();
+                None
+            }
+        };
+        let send = tokio::spawn(Self::isolate(
+            transport.clone(),
+            panic::Task::Send,
+            shutdown::until_stopped(
+                transport.lifecycle.subscribe(),
+                Self::send(
+                    transport.clone(),
+                    server_stdin,
+                    client_tx,
+                    client_rx,
+                    notify.clone(),
+                ),
+            ),
+        ));
+        *transport.tasks.lock() = Some(TransportTasks { recv, send, stderr });
+        if transport.config.extend_deadlines_on_suspend {
+            tokio::spawn(Self::watch_suspend(Arc::downgrade(&transport)));
+        }
+        if let Some(period) = transport
+            .config
+            .notification_limits
+            .values()
+            .map(|limit| limit.per)
+            .min()
+        {
+            tokio::spawn(Self::watch_dropped_notifications(
+                Arc::downgrade(&transport),
+                period,
+            ));
+        }
+        if let Some(check) = transport.config.health_check {
+            tokio::spawn(Self::watch_health(Arc::downgrade(&transport), check));
+        }
+        if let Some(warning) = transport.config.slow_request_warning {
+            tokio::spawn(Self::watch_slow_requests(
+                Arc::downgrade(&transport),
+                warning,
+            ));
+        }
+
+        ((rx, tx, notify, transport), bounded_tx)
+    }
+
+    /// Waits for the server to send a `method` notification for which `predicate` returns
+    /// true, e.g. the `$/progress` notification ending the indexing of a workspace. Returns
+    /// `None` if no such notification arrives within `timeout` (see [`Transport::timeout`]).
+    ///
+    /// Only notifications arriving after the call are considered. Matching notifications are
+    /// still delivered to the [`InboundReceiver`] as usual: waiting observes them, it doesn't
+    /// consume them. They are matched as they are forwarded to the receiver, so they are
+    /// held back while the transport is frozen but seen even if a rate limit drops them.
+    pub async fn wait_for_notification(
+        &self,
+        method: &str,
+        timeout: std::time::Duration,
+        predicate: impl Fn(&jsonrpc::Notification) -> bool + Send + 'static,
+    ) -> Option<jsonrpc::Notification> {
+        let rx = self
+            .notification_waiters
+            .register(method, Box::new(predicate));
+        self.timeout(timeout, rx).await?.ok()
+    }
+
+    /// Queues `payload` on `tx`, the sender returned by [`Transport::start`], applying
+    /// `strategy` (or [`TransportConfig::full_channel_strategy`]) when the
+    /// [`TransportConfig::outbound_capacity`] is reached. A dropped notification is logged and
+    /// isn't an error.
+    pub async fn enqueue(
+        &self,
+        tx: &UnboundedSender<Payload>,
+        payload: Payload,
+        strategy: Option<FullChannelStrategy>,
+    ) -> Result<()> {
+        if self.lifecycle.is_closing() {
+            return Err(Error::StreamClosed);
+        }
+        let strategy = strategy.unwrap_or(self.config.full_channel_strategy);
+        let reserved = self.outbound.wait_reserve(&payload, strategy).await?;
+        self.send_reserved(tx, payload, reserved)
+    }
+
+    /// Like [`Transport::enqueue`], but never waits: [`FullChannelStrategy::Wait`] queues the
+    /// payload beyond the capacity instead. Used by the synchronous helpers of
+    /// [`Client`](crate::Client), which must queue in call order.
+    pub fn try_enqueue(
+        &self,
+        tx: &UnboundedSender<Payload>,
+        payload: Payload,
+        strategy: Option<FullChannelStrategy>,
+    ) -> Result<()> {
+        if self.lifecycle.is_closing() {
+            return Err(Error::StreamClosed);
+        }
+        let strategy = strategy.unwrap_or(self.config.full_channel_strategy);
+        let reserved = self.outbound.try_reserve(&payload, strategy)?;
+        self.send_reserved(tx, payload, reserved)
+    }
+
+    /// Allocates a request id that no other request sent through this transport uses, counting
+    /// up from `0`. Callers that supply their own request ids must make sure they don't clash
+    /// with these.
+    pub fn next_id(&self) -> jsonrpc::Id {
+        jsonrpc::Id::Num(self.request_counter.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Sends a request with an id from [`Transport::next_id`], queued like
+    /// [`Transport::try_enqueue`]. Returns the id together with the receiver of the response.
+    pub fn send_request(
+        &self,
+        tx: &UnboundedSender<Payload>,
+        method: impl Into<String>,
+        params: jsonrpc::Params,
+    ) -> Result<(jsonrpc::Id, tokio::sync::mpsc::Receiver<Result<Value>>)> {
+        let id = self.next_id();
+        let (chan, rx) = tokio::sync::mpsc::channel(1);
+        let payload = Payload::request(
+            chan,
+            jsonrpc::MethodCall {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                id: id.clone(),
+                method: method.into(),
+                params,
+            },
+        );
+        self.try_enqueue(tx, payload, None)?;
+        Ok((id, rx))
+    }
+
+    /// Sends a request like [`Transport::send_request`] and returns its response, without
+    /// dealing with channels. The request is queued right away, when the call is made: dropping
+    /// the future without awaiting it discards the response once it arrives, which is logged at
+    /// debug level, but doesn't cancel the request.
+    pub fn request(
+        &self,
+        method: impl Into<String>,
+        params: jsonrpc::Params,
+    ) -> impl std::future::Future<Output = Result<Value>> + Send + 'static {
+        let sent = match self.server_tx.upgrade() {
+            Some(tx) => self.send_request(&tx, method, params),
+            None => Err(Error::StreamClosed),
+        };
+        async move {
+            let (_, mut rx) = sent?;
+            rx.recv().await.unwrap_or(Err(Error::StreamClosed))
+        }
+    }
+
+    fn send_reserved(
+        &self,
+        tx: &UnboundedSender<Payload>,
+        payload: Payload,
+        reserved: outbound::Reserved,
+    ) -> Result<()> {
+        match (reserved, &payload) {
+            (outbound::Reserved::Drop, Payload::Notification(notification)) => {
+                warn!(
+                    "{} outbound queue is full, dropping {}",
+                    self.log_name, notification.method
+                );
+                Ok(())
+            }
+            // the room is reserved in this transport's queue, the payload must go to its
+            // send task
+            _ if self
+                .server_tx
+                .upgrade()
+                .is_some_and(|own| own.same_channel(tx)) =>
+            {
+                self.outbound.send(payload)
+            }
+            _ => {
+                self.outbound.pop();
+                tx.send(payload).map_err(|_| Error::StreamClosed)
+            }
+        }
+    }
+
+    /// Routes the `$/progress` notifications for `token`, a `workDoneToken` the client attached
+    /// to a request, to the returned receiver instead of the [`InboundReceiver`]. The route is
+    /// removed once the progress ends, the receiver is dropped or
+    /// [`Transport::unroute_progress`] is called.
+    pub fn route_progress(
+        &self,
+        token: lsp::ProgressToken,
+    ) -> UnboundedReceiver<lsp::WorkDoneProgress> {
+        self.progress.register(token)
+    }
+
+    /// Stops routing the `$/progress` notifications for `token`, e.g. once the request that
+    /// carried it was answered.
+    pub fn unroute_progress(&self, token: &lsp::ProgressToken) {
+        self.progress.unregister(token)
+    }
+
+    /// Waits for `future` to complete for at most `timeout`, returning `None` if it doesn't.
+    ///
+    /// Time the system spends suspended doesn't count towards the timeout as long as
+    /// [`TransportConfig::extend_deadlines_on_suspend`] is enabled.
+    pub async fn timeout<F: std::future::Future>(
+        &self,
+        timeout: std::time::Duration,
+        future: F,
+    ) -> Option<F::Output> {
+        if self.config.extend_deadlines_on_suspend {
+            self.suspend.timeout(timeout, future).await
+        } else {
+            tokio::time::timeout(timeout, future).await.ok()
+        }
+    }
+
+    /// Sleeps for `duration`, not counting the time the system spends suspended as long as
+    /// [`TransportConfig::extend_deadlines_on_suspend`] is enabled.
+    async fn sleep(&self, duration: std::time::Duration) {
+        self.timeout(duration, future::pending::<()>()).await;
+    }
+
+    async fn watch_suspend(transport: std::sync::Weak<Self>) {
+        let mut interval = tokio::time::interval(suspend::PERIOD);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let Some(transport) = transport.upgrade() else {
+                break;
+            };
+            transport.suspend.observe();
+        }
+    }
+
+    /// Returns a snapshot of the metrics collected since the transport was started.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics
+            .snapshot(self.log_name.name(), self.inbound_depth.depth())
+    }
+
+    /// Returns the round-trip latency statistics of the `method` requests answered so far, or
+    /// `None` if none were. Quantiles are accurate to within about 3%. Safe to call while
+    /// requests are in flight, like [`Transport::metrics`].
+    #[cfg(feature = "metrics")]
+    pub fn latency_stats(&self, method: &str) -> Option<LatencyStats> {
+        self.metrics.latency_stats(method)
+    }
+
+    /// Holds back all incoming messages until [`Transport::thaw`] is called.
+    ///
+    /// The transport keeps reading and parsing messages from the server so that it isn't
+    /// blocked writing to a full pipe, but neither forwards server calls nor completes pending
+    /// requests. If more than [`TransportConfig::freeze_capacity`] messages are buffered the
+    /// transport is thawed automatically, so messages are delayed but never dropped.
+    pub async fn freeze(&self) {
+        let mut frozen = self.frozen.lock().await;
+        if frozen.is_none() {
+            *frozen = Some(VecDeque::new());
+        }
+    }
+
+    /// Stops the transport, giving it up to `timeout` to finish cleanly: new payloads are
+    /// refused by [`Transport::enqueue`] and [`Transport::try_enqueue`], the payloads already
+    /// queued are sent, and the write side of the stream is closed so the server exits and
+    /// closes its end, which completes the transport as usual. Send the `shutdown` request
+    /// and `exit` notification before, as the protocol requires.
+    ///
+    /// A server that doesn't close its end in time, e.g. because it hangs, doesn't block the
+    /// shutdown past the deadline: the transport is stopped regardless, failing the pending
+    /// requests, and [`ShutdownOutcome::TimedOut`] tells the caller to kill the server.
+    pub async fn shutdown(&self, timeout: std::time::Duration) -> ShutdownOutcome {
+        self.lifecycle.close();
+        self.state.advance(TransportState::Draining);
+        if tokio::time::timeout(timeout, self.lifecycle.finished())
+            .await
+            .is_ok()
+        {
+            return ShutdownOutcome::Clean;
+        }
+        warn!(
+            "{} didn't close the stream within {timeout:?} of shutting down",
+            self.log_name
+        );
+        self.lifecycle.stop();
+        let _ = self
+            .reader_control
+            .send(ReaderControl::Close(Error::StreamClosed));
+        ShutdownOutcome::TimedOut
+    }
+
+    /// Stops the transport right away, without sending the payloads still queued or waiting
+    /// for the server, e.g. one that is wedged and neither reads nor writes: every task exits,
+    /// the server's stdin is dropped so it reads EOF, the pending requests fail with
+    /// [`Error::TransportClosed`] and the consumer receives the `exit` notification. Does
+    /// nothing once the transport stopped.
+    ///
+    /// Prefer [`Transport::shutdown`], which lets a responsive server exit cleanly.
+    pub fn close(&self) {
+        self.lifecycle.stop();
+        self.state.advance(TransportState::Closed);
+        let _ = self
+            .reader_control
+            .send(ReaderControl::Close(Error::TransportClosed));
+    }
+
+    /// Takes the handles of the tasks spawned by [`Transport::start`], e.g. to await them
+    /// during shutdown. `None` if they were taken already.
+    pub fn take_tasks(&self) -> Option<TransportTasks> {
+        self.tasks.lock().take()
+    }
+
+    /// The current outbound capacity, see [`TransportConfig::outbound_capacity`].
+    pub fn outbound_capacity(&self) -> Option<usize> {
+        self.outbound.capacity()
+    }
+
+    /// Changes the [`TransportConfig::outbound_capacity`] of a running transport, e.g. to apply
+    /// backpressure under memory pressure. Payloads queued beyond a lowered capacity are still
+    /// sent, the [`TransportConfig::full_channel_strategy`] applies to new ones until the queue
+    /// drained below it. `None` removes the limit, taking effect for payloads waiting for room
+    /// too.
+    pub fn set_outbound_capacity(&self, capacity: Option<usize>) {
+        self.outbound.set_capacity(capacity);
+    }
+
+    /// The params of the `initialize` request exactly as they were sent to the server, e.g. to
+    /// check which client capabilities were advertised. Only available with
+    /// [`TransportConfig::capture_initialize_params`], once the request was sent.
+    pub fn sent_initialize_params(&self) -> Option<Value> {
+        self.sent_initialize_params.lock().clone()
+    }
+
+    /// The last lines the server wrote to stderr, oldest first, with
+    /// [`TransportConfig::stderr_history`]. Empty otherwise.
+    ///
+    /// The lines stay available once the transport stopped, e.g. to show why a server exited
+    /// from the [`TransportConfig::on_exit`] callback or after the `exit` notification injected
+    /// into the [`InboundReceiver`].
+    pub fn recent_stderr(&self) -> Vec<String> {
+        self.stderr_history
+            .as_ref()
+            .map_or_else(Vec::new, stderr::StderrHistory::lines)
+    }
+
+    /// Subscribes to whether the server has requests outstanding: the value changes to `true`
+    /// when a request is sent while none was pending, and back to `false` once the last pending
+    /// request is answered or abandoned. This is an edge-triggered alternative to polling,
+    /// e.g. to drive a busy indicator.
+    pub fn busy(&self) -> watch::Receiver<bool> {
+        self.busy.subscribe()
+    }
+
+    /// Where the transport is in its life, e.g. to show the status of the server or to tell
+    /// whether sending a request is still worthwhile.
+    pub fn state(&self) -> TransportState {
+        self.state.get()
+    }
+
+    /// How many messages were exchanged with the server so far, and how many of the server's
+    /// failed to parse.
+    pub fn stats(&self) -> TransportStats {
+        self.stats.snapshot()
+    }
+
+    /// How many requests were sent to the server and aren't answered yet, e.g. to detect a
+    /// server falling behind. Requests held back until the server is initialized aren't
+    /// counted.
+    pub fn pending_count(&self) -> usize {
+        self.pending_requests.lock().len()
+    }
+
+    /// The method of the request that has been waiting the longest for a response and how long
+    /// ago it was sent, e.g. to warn that a server has had a request outstanding for a while.
+    /// `None` if no request is pending.
+    pub fn oldest_pending(&self) -> Option<(String, std::time::Duration)> {
+        let pending_requests = self.pending_requests.lock();
+        let oldest = pending_requests
+            .values()
+            .min_by_key(|request| request.sent)?;
+        Some((oldest.method.clone(), oldest.sent.elapsed()))
+    }
+
+    /// Stops reading from the server's stream, e.g. while reconnecting to a network server, and
+    /// drops the stream. Messages already read are still processed; a message the server was
+    /// in the middle of sending is discarded. Reading resumes with [`Transport::replace_reader`].
+    ///
+    /// Pending requests stay pending rather than failing with [`Error::StreamClosed`], and
+    /// nothing is written to the server until it's asked to.
+    pub fn pause_reader(&self) -> Result<()> {
+        self.reader_control
+            .send(ReaderControl::Pause)
+            .map_err(|_| Error::StreamClosed)
+    }
+
+    /// Continues reading the server's messages from `reader` instead of the current stream,
+    /// which is dropped. Like with [`Transport::pause_reader`], a message partially read from
+    /// the previous stream is discarded rather than completed from the new one, so `reader`
+    /// must start at the beginning of a message.
+    ///
+    /// Fails with [`Error::StreamClosed`] if the transport already stopped reading because its
+    /// stream was closed.
+    pub fn replace_reader(&self, reader: impl AsyncBufRead + Unpin + Send + 'static) -> Result<()> {
+        self.reader_control
+            .send(ReaderControl::Replace(Box::new(reader)))
+            .map_err(|_| Error::StreamClosed)
+    }
+
+    /// Releases the messages buffered since [`Transport::freeze`] in the order they were
+    /// received and resumes normal processing.
+    pub async fn thaw(&self) {
+        let mut frozen = self.frozen.lock().await;
+        if let Some(buffered) = frozen.take() {
+            self.release_frozen(buffered).await;
+        }
+    }
+
+    async fn release_frozen(&self, buffered: VecDeque<ServerMessage>) {
+        let Some(client_tx) = self.client_tx.upgrade() else {
+            return;
+        };
+        for msg in buffered {
+            if let Err(err) = self
+                .process_server_message(&client_tx, msg, &self.log_name)
+                .await
+            {
+                error!("{} err: <- {err:?}", self.log_name);
+            }
+        }
+    }
+
+    /// Checks the `jsonrpc` member of a message according to
+    /// [`TransportConfig::jsonrpc_version_check`].
+    fn check_jsonrpc_version(&self, msg: &ServerMessage) -> Result<()> {
+        if msg.jsonrpc().is_some() {
+            return Ok(());
+        }
+        match self.config.jsonrpc_version_check {
+            JsonRpcVersionCheck::Lenient => {
+                if !self.warned_missing_version.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "{} sent a message without a jsonrpc version, accepting it anyway",
+                        self.log_name
+                    );
+                }
+                Ok(())
+            }
+            JsonRpcVersionCheck::Strict => Err(Error::Other(anyhow::anyhow!(
+                "message without a jsonrpc version"
+            ))),
+        }
+    }
+
+    /// Processes a message from the server, or buffers it if the transport is frozen.
+    async fn dispatch_server_message(
+        &self,
+        client_tx: &UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+        msg: ServerMessage,
+    ) -> Result<()> {
+        // The lock is held while processing so that thawing can't interleave with new messages.
+        let mut frozen = self.frozen.lock().await;
+        if let Some(buffered) = frozen.as_mut() {
+            if buffered.len() < self.config.freeze_capacity {
+                buffered.push_back(msg);
+                return Ok(());
+            }
+            warn!(
+                "{} freeze buffer is full ({} messages), thawing",
+                self.log_name,
+                buffered.len()
+            );
+            let buffered = frozen.take().unwrap_or_default();
+            self.release_frozen(buffered).await;
+        }
+        self.process_server_message(client_tx, msg, &self.log_name)
+            .await
+    }
+
+    fn elapsed(&self) -> Elapsed {
+        Elapsed(self.config.log_elapsed.then(|| self.started.elapsed()))
+    }
+
+    /// Copies a message to the [`TransportConfig::raw_tap`].
+    fn tap(&self, direction: Direction, bytes: &[u8]) {
+        if let Some(tap) = &self.config.raw_tap {
+            let _ = tap.send(RawMessage {
+                direction,
+                bytes: bytes.to_vec(),
+                timestamp: Instant::now(),
+            });
+        }
+    }
+
+    fn logged<'a>(&self, body: &'a [u8]) -> traffic_log::Logged<'a> {
+        traffic_log::Logged {
+            body,
+            policy: self.config.log_bodies,
+        }
+    }
+
+    /// Reads and parses a single message from the server.
+    #[cfg(test)]
+    async fn recv_server_message(
+        &self,
+        reader: &mut (impl AsyncBufRead + Unpin + Send),
+        buffer: &mut String,
+        content: &mut Vec<u8>,
+    ) -> Result<ServerMessage> {
+        self.recv_server_body(reader, buffer, content).await?;
+        self.parse_server_message(content).await
+    }
+
+    /// Reads the next message from the server into `content`.
+    async fn recv_server_body(
+        &self,
+        reader: &mut (impl AsyncBufRead + Unpin + Send),
+        buffer: &mut String,
+        content: &mut Vec<u8>,
+    ) -> Result<()> {
+        if let Some(codec) = &self.config.codec {
+            codec
+                .read_message(reader, content, self.config.max_message_size)
+                .await?;
+            self.activity.reset();
+            #[cfg(feature = "metrics")]
+            self.metrics.record_received(content.len());
+            if let Some(quota) = &self.quota {
+                quota.record_received(content.len())?;
+            }
+            return self.received(content);
+        }
+
+        let codec = &self.config.lsp_codec;
+        let mut headers = codec
+            .read_headers(reader, buffer, |line| {
+                self.skip_garbage_line(line);
+                Ok(())
+            })
+            .await?;
+        if let Some(summary) = self.garbage_lines.flush() {
+            warn!(
+                "{} skipped {} more lines that aren't headers ({} in total)",
+                self.log_name, summary.skipped, summary.total
+            );
+        }
+        codec
+            .read_body(reader, &mut headers, content, self.config.max_message_size)
+            .await?;
+        self.activity.reset();
+        #[cfg(feature = "metrics")]
+        self.metrics.record_received(headers.read);
+        if let Some(quota) = &self.quota {
+            quota.record_received(headers.content_length)?;
+        }
+        #[cfg(feature = "gzip")]
+        if let Some(encoding) = headers.encoding.map_err(codec::undecodable)? {
+            // the length of a compressed body can't be corrected by looking for the end of the
+            // JSON, so it's not recovered
+            encoding
+                .decode(content, self.config.max_message_size)
+                .map_err(codec::undecodable)?;
+            self.check_stray_bytes(reader);
+            return self.received(content);
+        }
+        self.recover_undercount(reader, content);
+        self.check_stray_bytes(reader);
+        self.received(content)
+    }
+
+    /// Taps, checks and logs the body of a message read from the server.
+    fn received(&self, content: &[u8]) -> Result<()> {
+        self.tap(Direction::Incoming, content);
+        std::str::from_utf8(content).context("invalid utf8 from server")?;
+
+        log::log!(
+            self.config.traffic_log_level,
+            "{}{} <- {}",
+            self.elapsed(),
+            self.log_name,
+            self.logged(content)
+        );
+
+        Ok(())
+    }
+
+    /// Logs and counts a line that isn't a header, skipped by the [`LspCodec`]. The warning is
+    /// throttled since a server logging to stdout may write thousands of them, the lines it
+    /// left out are summed up once the next message arrives.
+    fn skip_garbage_line(&self, line: &str) {
+        if let Some(warning) = self.garbage_lines.record(line) {
+            warn!(
+                "{} skipped {} lines that aren't headers ({} in total), e.g. {:?}",
+                self.log_name, warning.skipped, warning.total, warning.sample
+            );
+        }
+        self.stats.garbage_line_skipped();
+    }
+
+    /// Compresses `body` according to [`TransportConfig::gzip_threshold`], `None` if it's sent as
+    /// is. Like serialization, bodies reaching [`TransportConfig::serialize_offload_threshold`]
+    /// are compressed on the blocking thread pool.
+    #[cfg(feature = "gzip")]
+    async fn gzip_body(&self, body: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.config.gzip_threshold {
+            Some(threshold)
+                if body.len() >= threshold && self.server_accepts_gzip.load(Ordering::Relaxed) =>
+            {
+                let offload = self
+                    .config
+                    .serialize_offload_threshold
+                    .is_some_and(|threshold| body.len() >= threshold);
+                if !offload {
+                    return encoding::gzip(body).map(Some);
+                }
+                let body = body.to_vec();
+                tokio::task::spawn_blocking(move || encoding::gzip(&body))
+                    .await
+                    .map_err(|err| Error::Other(err.into()))?
+                    .map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Detects a `Content-Length` header announcing fewer bytes than the body actually has,
+    /// which would otherwise leave the rest of the body in front of the next header. If the
+    /// bytes already buffered up to the next header complete `content` into valid JSON, they
+    /// are appended to it so the stream stays in sync.
+    fn recover_undercount(&self, reader: &mut (impl AsyncBufRead + Unpin), content: &mut Vec<u8>) {
+        // only look at what was already received, this must not wait for the next message
+        let Some(Ok(buffered)) = reader.fill_buf().now_or_never() else {
+            return;
+        };
+        let end = buffered
+            .windows(b"Content-Length".len())
+            .position(|window| window.eq_ignore_ascii_case(b"Content-Length"))
+            .unwrap_or(buffered.len());
+        let rest = &buffered[..end];
+        // when the next header follows, a truncated body is left for the parse step to report
+        if starts_with_header(rest.trim_ascii_start()) || is_json(content) {
+            return;
+        }
+        let mut completed = Vec::with_capacity(content.len() + rest.len());
+        completed.extend_from_slice(content);
+        completed.extend_from_slice(rest.trim_ascii_end());
+        if !is_json(&completed) {
+            // not the rest of the body, e.g. garbage output skipped by the header parser
+            return;
+        }
+
+        warn!(
+            "{} Content-Length undercount: announced {} bytes but the body continued for {} more",
+            self.log_name,
+            content.len(),
+            completed.len() - content.len()
+        );
+        *content = completed;
+        #[cfg(feature = "metrics")]
+        self.metrics.record_received(end);
+        reader.consume(end);
+
+        fn is_json(content: &[u8]) -> bool {
+            serde_json::from_slice::<serde::de::IgnoredAny>(strip_bom(content)).is_ok()
+        }
+    }
+
+    /// Warns, once, if the bytes following a message don't start a header. Whitespace is
+    /// skipped by the header parser and non-header lines are skipped as garbage, but either
+    /// hints at a server writing more than the announced `Content-Length`.
+    fn check_stray_bytes(&self, reader: &mut (impl AsyncBufRead + Unpin)) {
+        // only look at what was already received, this must not wait for the next message
+        let Some(Ok(buffered)) = reader.fill_buf().now_or_never() else {
+            return;
+        };
+        // the start of a header may not be buffered in full yet
+        if starts_with_header(buffered) {
+            return;
+        }
+        if !self.warned_stray_bytes.swap(true, Ordering::Relaxed) {
+            let end = buffered.len().min(32);
+            warn!(
+                "{} sent stray bytes after a message, instead of the next header: {:?}",
+                self.log_name,
+                String::from_utf8_lossy(&buffered[..end])
+            );
+        }
+    }
+
+    /// Parses a message body read by [`Transport::recv_server_body`]. Bodies above
+    /// [`TransportConfig::parse_offload_threshold`] are moved out of `content` and parsed on
+    /// the blocking thread pool.
+    fn parse_server_message(
+        &self,
+        content: &mut Vec<u8>,
+    ) -> BoxFuture<'static, Result<ServerMessage>> {
+        match self.config.parse_offload_threshold {
+            Some(threshold) if content.len() >= threshold => {
+                let content = std::mem::take(content);
+                let parse = move || ServerMessage::parse(&content);
+                #[cfg(feature = "metrics")]
+                let parse = {
+                    let metrics = self.metrics.clone();
+                    move || metrics.time_parsing(parse)
+                };
+                tokio::task::spawn_blocking(parse)
+                    .map(|parsed| match parsed {
+                        Ok(parsed) => parsed,
+                        Err(err) => Err(Error::Other(err.into())),
+                    })
+                    .boxed()
+            }
+            _ => {
+                // NOTE: We avoid using `?` here, since it would return early on error
+                // and skip clearing `content`. By returning the result directly instead,
+                // we ensure `content.clear()` is always called.
+                let parse = || ServerMessage::parse(content);
+                #[cfg(feature = "metrics")]
+                let output = self.metrics.time_parsing(parse);
+                #[cfg(not(feature = "metrics"))]
+                let output = parse();
+
+                content.clear();
+
+                future::ready(output).boxed()
+            }
+        }
+    }
+
+    async fn recv_server_error(
+        &self,
+        err: &mut (impl AsyncBufRead + Unpin + Send),
+        buffer: &mut String,
+    ) -> Result<()> {
+        buffer.truncate(0);
+        if err.read_line(buffer).await? == 0 {
+            return Err(Error::StreamClosed);
+        };
+        self.startup.record_stderr(buffer);
+        if let Some(history) = &self.stderr_history {
+            history.record(buffer.trim_end_matches(['\r', '\n']));
+        }
+        if self.config.log_stderr {
+            error!("{}{} err <- {buffer:?}", self.elapsed(), self.log_name);
+        }
+
+        Ok(())
+    }
+
+    async fn send_payload_to_server(
+        self: &Arc<Self>,
+        server_stdin: &mut (impl AsyncWrite + Unpin + Send),
+        payload: Payload,
+    ) -> Result<()> {
+        match &payload {
+            Payload::Request { chan, value, group } => {
+                self.capture_initialize_params(value);
+                self.advance_state_on_request(value);
+                self.stats.request_sent();
+                self.insert_pending_request(value, Some(chan.clone()), group.clone())
+            }
+            Payload::DetachedRequest(value) => {
+                self.advance_state_on_request(value);
+                self.stats.request_sent();
+                self.insert_pending_request(value, None, None)
+            }
+            Payload::Notification(value) => {
+                self.check_document_lifecycle(value)?;
+                self.stats.notifications_sent(1);
+                if value.method == lsp::notification::Exit::METHOD {
+                    self.exit_sent.store(true, Ordering::Relaxed);
+                    self.state.advance(TransportState::Draining);
+                }
+            }
+            Payload::Response(_) => (),
+        }
+        let (json, payload) = self.serialize(payload).await?;
+        if let Some(middleware) = &self.config.middleware {
+            middleware.on_send(OutboundMessage::new(&payload));
+        }
+        let sent = self.send_bytes_to_server(server_stdin, &json).await;
+        self.recycle_send_buffer(json);
+        sent
+    }
+
+    fn advance_state_on_request(&self, request: &jsonrpc::MethodCall) {
+        use lsp::request::{Initialize, Request, Shutdown};
+        if request.method == Initialize::METHOD {
+            self.state.advance(TransportState::Initializing);
+        } else if request.method == Shutdown::METHOD {
+            self.state.advance(TransportState::Draining);
+        }
+    }
+
+    fn capture_initialize_params(&self, request: &jsonrpc::MethodCall) {
+        if self.config.capture_initialize_params
+            && request.method == <lsp::request::Initialize as lsp::request::Request>::METHOD
+        {
+            match serde_json::to_value(&request.params) {
+                Ok(params) => *self.sent_initialize_params.lock() = Some(params),
+                Err(err) => warn!(
+                    "{} failed to capture the initialize params: {err}",
+                    self.log_name
+                ),
+            }
+        }
+    }
+
+    /// The [`TransportConfig::warm_up_request`], if any.
+    fn warm_up_request(&self) -> Option<Payload> {
+        let (method, params) = self.config.warm_up_request.clone()?;
+        Some(Payload::DetachedRequest(jsonrpc::MethodCall {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method,
+            params,
+            id: jsonrpc::Id::Str("helix-warm-up".to_string()),
+        }))
+    }
+
+    /// Whether a notification may be held back for a batch, see
+    /// [`TransportConfig::notification_batch_size`].
+    fn is_batched(&self, payload: &Payload) -> bool {
+        self.config.notification_batch_size.is_some()
+            && matches!(payload, Payload::Notification(notification) if notification.method != lsp::notification::Exit::METHOD)
+    }
+
+    /// Sends the notifications held back for a batch, as a single message unless there's only
+    /// one of them.
+    async fn send_notification_batch(
+        self: &Arc<Self>,
+        server_stdin: &mut (impl AsyncWrite + Unpin + Send),
+        batch: &mut Vec<jsonrpc::Notification>,
+    ) -> Result<()> {
+        if batch.len() <= 1 {
+            return match batch.pop() {
+                Some(notification) => {
+                    self.send_payload_to_server(server_stdin, Payload::Notification(notification))
+                        .await
+                }
+                None => Ok(()),
+            };
+        }
+        let mut notifications = Vec::with_capacity(batch.len());
+        for notification in batch.drain(..) {
+            match self.check_document_lifecycle(&notification) {
+                Ok(()) => notifications.push(notification),
+                Err(err) => error!("{} err: <- {err:?}", self.log_name),
+            }
+        }
+        if notifications.is_empty() {
+            return Ok(());
+        }
+        let mut json = std::mem::take(&mut *self.send_buffer.lock());
+        json.clear();
+        if let Err(err) = sonic_rs::to_writer(&mut json, &notifications) {
+            self.recycle_send_buffer(json);
+            return Err(err.into());
+        }
+        self.stats.notifications_sent(notifications.len());
+        if let Some(middleware) = &self.config.middleware {
+            for notification in &notifications {
+                middleware.on_send(OutboundMessage::Notification(notification));
+            }
+        }
+        let sent = self.send_bytes_to_server(server_stdin, &json).await;
+        self.recycle_send_buffer(json);
+        sent
+    }
+
+    /// Applies [`TransportConfig::document_lifecycle_check`] to an outgoing notification.
+    fn check_document_lifecycle(&self, notification: &jsonrpc::Notification) -> Result<()> {
+        let check = self.config.document_lifecycle_check;
+        if check == DocumentLifecycleCheck::Disabled {
+            return Ok(());
+        }
+        match self.documents.check(notification) {
+            Ok(()) => Ok(()),
+            Err(err) if check == DocumentLifecycleCheck::Warn => {
+                warn!("{} sending out of order notification: {err}", self.log_name);
+                Ok(())
+            }
+            Err(err) => Err(Error::Other(anyhow::anyhow!(
+                "refusing to send out of order notification: {err}"
+            ))),
+        }
+    }
+
+    /// Serializes a payload, on the blocking thread pool if its estimated size reaches
+    /// [`TransportConfig::serialize_offload_threshold`].
+    /// The serialized payload is written into the reused [`Transport::send_buffer`], which is
+    /// to be handed back with [`Transport::recycle_send_buffer`] once sent, and returned with
+    /// the payload.
+    async fn serialize(&self, payload: Payload) -> Result<(Vec<u8>, Payload)> {
+        let offload = self
+            .config
+            .serialize_offload_threshold
+            .is_some_and(|threshold| payload.estimated_size() >= threshold);
+        let mut buffer = std::mem::take(&mut *self.send_buffer.lock());
+        let serialize = move || {
+            let written = payload.write_json(&mut buffer);
+            (buffer, written, payload)
+        };
+        #[cfg(feature = "metrics")]
+        let serialize = {
+            let metrics = self.metrics.clone();
+            move || metrics.time_serialization(serialize)
+        };
+        let (buffer, written, payload) = if offload {
+            tokio::task::spawn_blocking(serialize)
+                .await
+                .map_err(|err| Error::Other(err.into()))?
+        } else {
+            serialize()
+        };
+        match written {
+            Ok(()) => Ok((buffer, payload)),
+            Err(err) => {
+                self.recycle_send_buffer(buffer);
+                Err(err)
+            }
+        }
+    }
+
+    fn recycle_send_buffer(&self, mut buffer: Vec<u8>) {
+        buffer.clear();
+        *self.send_buffer.lock() = buffer;
+    }
+
+    fn insert_pending_request(
+        self: &Arc<Self>,
+        value: &jsonrpc::MethodCall,
+        chan: Option<Sender<Result<Value>>>,
+        group: Option<RequestGroup>,
+    ) {
+        if let Some(group) = &group {
+            log::debug!(
+                "{} sending {} request (id={:?}) of group {group}",
+                self.log_name,
+                value.method,
+                value.id
+            );
+        }
+        #[cfg(feature = "metrics")]
+        self.metrics.record_request(&value.method, group.as_ref());
+        let timeout = self.config.request_timeout.map(|timeout| {
+            let transport = Arc::downgrade(self);
+            let id = value.id.clone();
+            // the timer mustn't keep the transport alive
+            let suspend = self
+                .config
+                .extend_deadlines_on_suspend
+                .then(|| self.suspend.clone());
+            tokio::spawn(async move {
+                match suspend {
+                    Some(suspend) => suspend.sleep(timeout).await,
+                    None => tokio::time::sleep(timeout).await,
+                }
+                if let Some(transport) = transport.upgrade() {
+                    transport.expire_request(id, timeout).await;
+                }
+            })
+            .abort_handle()
+        });
+        let mut pending_requests = self.pending_requests.lock();
+        let replaced = pending_requests.insert(
+            value.id.clone(),
+            PendingRequest {
+                chan,
+                method: value.method.clone(),
+                sent: tokio::time::Instant::now(),
+                group,
+                timeout,
+                warned_slow: false,
+            },
+        );
+        if let Some(replaced) = replaced {
+            replaced.cancel_timeout();
+        }
+        self.update_busy(&pending_requests);
+        drop(pending_requests);
+        #[cfg(feature = "response-order")]
+        self.response_order.record_sent(&value.id, &value.method);
+    }
+
+    /// Fails request `id` after the [`TransportConfig::request_timeout`] and asks the server to
+    /// cancel it.
+    async fn expire_request(&self, id: jsonrpc::Id, timeout: std::time::Duration) {
+        let Some(request) = self.abandon_request(&id) else {
+            return;
+        };
+        warn!(
+            "{} didn't answer {} request (id={id:?}) within {timeout:?}, cancelled it",
+            self.log_name, request.method
+        );
+        if let Some(chan) = request.chan {
+            let _ = chan.send(Err(Error::Timeout(id))).await;
+        }
+    }
+
+    /// Cancels the pending request `id`: its receiver gets [`Error::Cancelled`] and the server
+    /// is sent a `$/cancelRequest` notification. A response the server still sends is
+    /// discarded. Returns `false` if no request with this id is pending, e.g. because it was
+    /// answered already.
+    pub async fn cancel(&self, id: &jsonrpc::Id) -> bool {
+        let Some(request) = self.abandon_request(id) else {
+            return false;
+        };
+        log::debug!(
+            "{} cancelled {} request (id={id:?})",
+            self.log_name,
+            request.method
+        );
+        if let Some(chan) = request.chan {
+            let _ = chan.send(Err(Error::Cancelled(id.clone()))).await;
+        }
+        true
+    }
+
+    /// Takes request `id` out of `pending_requests` without a response and asks the server to
+    /// cancel it. Whichever of this and the response takes the request out first completes it,
+    /// the other one finds nothing to do.
+    fn abandon_request(&self, id: &jsonrpc::Id) -> Option<PendingRequest> {
+        let request = {
+            let mut pending_requests = self.pending_requests.lock();
+            let request = pending_requests.remove(id);
+            self.update_busy(&pending_requests);
+            request
+        }?;
+        #[cfg(feature = "metrics")]
+        self.metrics
+            .record_abandoned_request(&request.method, request.group.as_ref());
+        #[cfg(feature = "response-order")]
+        self.response_order.record_response(id);
+        self.completed.record(id, &request.method, true);
+
+        if let (Some(notification), Some(server_tx)) =
+            (cancel::notification(id), self.server_tx.upgrade())
+        {
+            let _ = server_tx.send(Payload::Notification(notification));
+        }
+        Some(request)
+    }
+
+    /// Publishes whether requests are pending to [`Transport::busy`] when that changes. This
+    /// must be called with `pending_requests` locked so that transitions are never reordered.
+    fn update_busy(&self, pending_requests: &HashMap<jsonrpc::Id, PendingRequest>) {
+        let busy = !pending_requests.is_empty();
+        let changed = self.busy.send_if_modified(|current| {
+            let changed = *current != busy;
+            *current = busy;
+            changed
+        });
+        if changed && busy {
+            // the server is only expected to answer from now on
+            self.activity.reset();
+        }
+    }
+
+    /// Applies [`TransportConfig::max_pre_init_requests`] to the messages held back until the
+    /// server is initialized, `requests` of which are requests, failing the oldest requests
+    /// beyond the limit.
+    async fn limit_pre_init_requests(
+        &self,
+        pending_messages: &mut Vec<Payload>,
+        requests: &mut usize,
+    ) {
+        let Some(max) = self.config.max_pre_init_requests else {
+            return;
+        };
+        while *requests > max {
+            let oldest = pending_messages
+                .iter()
+                .position(|msg| {
+                    matches!(msg, Payload::Request { .. } | Payload::DetachedRequest(_))
+                })
+                .unwrap();
+            *requests -= 1;
+            match pending_messages.remove(oldest) {
+                Payload::Request { chan, value, .. } => {
+                    warn!(
+                        "{} too many requests are waiting for the server to initialize, failing {} request (id={:?})",
+                        self.log_name, value.method, value.id
+                    );
+                    let _ = chan.send(Err(Error::QueueFull)).await;
+                }
+                Payload::DetachedRequest(value) => {
+                    warn!(
+                        "{} too many requests are waiting for the server to initialize, not sending detached {} request (id={:?})",
+                        self.log_name, value.method, value.id
+                    );
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    async fn send_bytes_to_server(
+        &self,
+        server_stdin: &mut (impl AsyncWrite + Unpin + Send),
+        request: &[u8],
+    ) -> Result<()> {
+        self.tap(Direction::Outgoing, request);
+        log::log!(
+            self.config.traffic_log_level,
+            "{}{} -> {}",
+            self.elapsed(),
+            self.log_name,
+            self.logged(request)
+        );
+
+        if let Some(codec) = &self.config.codec {
+            let written = codec.write_message(server_stdin, request).await?;
+            #[cfg(feature = "metrics")]
+            self.metrics.record_sent(written);
+            server_stdin.flush().await?;
+            if let Some(quota) = &self.quota {
+                if let Err(err) = quota.record_sent(written) {
+                    let _ = self
+                        .reader_control
+                        .send(ReaderControl::Close(Error::QuotaExceeded {
+                            limit: quota.limit(),
+                        }));
+                    return Err(err);
+                }
+            }
+            return Ok(());
+        }
+
+        #[cfg(feature = "gzip")]
+        let compressed = self.gzip_body(request).await?;
+        #[cfg(feature = "gzip")]
+        let request = compressed.as_deref().unwrap_or(request);
+        #[cfg(feature = "gzip")]
+        let extra = match compressed {
+            Some(_) => encoding::GZIP_HEADER,
+            None => b"",
+        };
+        #[cfg(not(feature = "gzip"))]
+        let extra = b"";
+        let mut digits = itoa::Buffer::new();
+        let header = codec::content_length_header(request.len(), extra, &mut digits);
+        let header_len: usize = header.iter().map(|part| part.len()).sum();
+        if let Some(quota) = &self.quota {
+            if let Err(err) = quota.record_sent(header_len + request.len()) {
+                // stop reading too, failing the pending requests
+                let _ = self
+                    .reader_control
+                    .send(ReaderControl::Close(Error::QuotaExceeded {
+                        limit: quota.limit(),
+                    }));
+                return Err(err);
+            }
+        }
+        // send the headers and the body in one go
+        codec::write_framed(server_stdin, header, request).await?;
+        #[cfg(feature = "metrics")]
+        self.metrics.record_sent(header_len + request.len());
+
+        server_stdin.flush().await?;
+
+        Ok(())
+    }
+
+    async fn process_server_message(
+        &self,
+        client_tx: &UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+        msg: ServerMessage,
+        language_server_name: &impl fmt::Display,
+    ) -> Result<()> {
+        match msg {
+            ServerMessage::Output(output) => {
+                self.process_request_response(output, language_server_name)
+                    .await?
+            }
+            ServerMessage::ResultMissing { id, .. } => {
+                error!("{language_server_name} <- response to {id:?} has neither a result nor an error");
+                self.complete_request(id, Err(Error::ResultMissing), language_server_name)
+                    .await
+            }
+            ServerMessage::Malformed {
+                id: Some(id),
+                error,
+            } => {
+                error!("{language_server_name} <- malformed response to {id:?}: {error}");
+                self.complete_request(id, Err(Error::Parse(error.into())), language_server_name)
+                    .await
+            }
+            ServerMessage::Malformed { id: None, error } => {
+                error!("{language_server_name} <- skipping malformed message: {error}");
+            }
+            ServerMessage::MalformedCall {
+                id: Some(id),
+                code,
+                error,
+            } => {
+                error!("{language_server_name} <- malformed request {id:?}: {error}");
+                self.reject_server_request(id, code, error);
+            }
+            ServerMessage::MalformedCall {
+                id: None, error, ..
+            } => {
+                error!("{language_server_name} <- skipping malformed notification: {error}");
+            }
+            ServerMessage::Call(jsonrpc::Call::MethodCall(call))
+                if self.config.pre_init_requests != PreInitRequestHandling::Forward =>
+            {
+                let mut held = self.pre_init_calls.lock().await;
+                match held.as_mut() {
+                    Some(held) => self.hold_pre_init_request(held, call),
+                    None => self.forward_server_call(client_tx, jsonrpc::Call::MethodCall(call))?,
+                }
+            }
+            ServerMessage::Call(jsonrpc::Call::Notification(notification))
+                if cancel::is_cancel(&notification) =>
+            {
+                if !self.process_server_cancel(&notification).await {
+                    self.forward_server_call(client_tx, jsonrpc::Call::Notification(notification))?
+                }
+            }
+            ServerMessage::Call(jsonrpc::Call::Notification(notification))
+                if self.config.buffer_pre_init_notifications
+                    && !forwarded_before_init(&notification) =>
+            {
+                let mut held = self.pre_init_calls.lock().await;
+                match held.as_mut() {
+                    Some(held) => {
+                        log::info!(
+                            "{} sent a {} notification before it was initialized, delaying it",
+                            self.log_name,
+                            notification.method
+                        );
+                        held.push(jsonrpc::Call::Notification(notification));
+                    }
+                    None => self.forward_server_call(
+                        client_tx,
+                        jsonrpc::Call::Notification(notification),
+                    )?,
+                }
+            }
+            ServerMessage::Call(call) => self.forward_server_call(client_tx, call)?,
+            ServerMessage::Batch(batch) => {
+                for msg in batch {
+                    Box::pin(self.process_server_message(client_tx, msg, language_server_name))
+                        .await?;
+                }
+            }
+        };
+        Ok(())
+    }
+
+    /// Cancels a server request held back by [`PreInitRequestHandling::Buffer`] or being
+    /// answered by [`TransportConfig::request_handler`], answering it as cancelled. Returns
+    /// `false` if the request is unknown to the transport, leaving it to the consumer.
+    async fn process_server_cancel(&self, notification: &jsonrpc::Notification) -> bool {
+        let Some(id) = cancel::cancelled_id(notification) else {
+            return false;
+        };
+        let held = {
+            let mut held = self.pre_init_calls.lock().await;
+            held.as_mut().is_some_and(|held| {
+                let count = held.len();
+                held.retain(
+                    |call| !matches!(call, jsonrpc::Call::MethodCall(call) if call.id == id),
+                );
+                held.len() != count
+            })
+        };
+        if !held && !self.handled_requests.abort(&id) {
+            return false;
+        }
+        log::debug!("{} cancelled its request (id={id:?})", self.log_name);
+        if let Some(server_tx) = self.server_tx.upgrade() {
+            let _ = server_tx.send(Payload::Response(cancel::cancelled_response(id)));
+        }
+        true
+    }
+
+    /// Hands a request or notification from the server to the consumer, unless it's handled
+    /// by the transport itself.
+    fn forward_server_call(
+        &self,
+        client_tx: &UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+        call: jsonrpc::Call,
+    ) -> Result<()> {
+        let call = match call {
+            jsonrpc::Call::Notification(notification) => {
+                self.notification_waiters.notify(&notification);
+                if self.progress.route(&notification) {
+                    return Ok(());
+                }
+                jsonrpc::Call::Notification(notification)
+            }
+            jsonrpc::Call::MethodCall(call) => match &self.config.request_handler {
+                Some(handler) if handler.handles(&call.method) => {
+                    self.handle_server_request(handler.as_ref(), call);
+                    return Ok(());
+                }
+                _ => jsonrpc::Call::MethodCall(call),
+            },
+            call => call,
+        };
+        if !self.allow_call(&call) {
+            return Ok(());
+        }
+        client_tx
+            .send((self.id, call))
+            .context("failed to send a message to server")?;
+        // let notification = Notification::parse(&method, params);
+        if let Some(depth) = self
+            .inbound_depth
+            .record_forwarded(self.config.slow_consumer_threshold)
+        {
+            warn!(
+                "{}: the consumer of server messages is falling behind ({depth} messages queued and growing)",
+                self.log_name
+            );
+            #[cfg(feature = "metrics")]
+            self.metrics.record_slow_consumer();
+        }
+        Ok(())
+    }
+
+    /// Applies [`TransportConfig::pre_init_requests`] to a request the server sent before it
+    /// was initialized.
+    fn hold_pre_init_request(&self, held: &mut Vec<jsonrpc::Call>, call: jsonrpc::MethodCall) {
+        match self.config.pre_init_requests {
+            PreInitRequestHandling::Forward => unreachable!("forwarded requests aren't held"),
+            PreInitRequestHandling::Buffer => {
+                log::info!(
+                    "{} sent a {} request before it was initialized, delaying it",
+                    self.log_name,
+                    call.method
+                );
+                held.push(jsonrpc::Call::MethodCall(call));
+            }
+            PreInitRequestHandling::Reject => {
+                warn!(
+                    "{} sent a {} request before it was initialized, rejecting it",
+                    self.log_name, call.method
+                );
+                self.reject_server_request(
+                    call.id,
+                    jsonrpc::ErrorCode::ServerError(SERVER_NOT_INITIALIZED),
+                    "client is not initialized".to_string(),
+                );
+            }
+        }
+    }
+
+    /// Answers a request from the server with an error, without forwarding it to the client.
+    fn reject_server_request(&self, id: jsonrpc::Id, code: jsonrpc::ErrorCode, message: String) {
+        let output = jsonrpc::Output::Failure(jsonrpc::Failure {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            error: jsonrpc::Error {
+                code,
+                message,
+                data: None,
+            },
+            id,
+        });
+        if let Some(server_tx) = self.server_tx.upgrade() {
+            let _ = server_tx.send(Payload::Response(output));
+        }
+    }
+
+    /// Forwards the requests held back by [`PreInitRequestHandling::Buffer`] and the
+    /// notifications held back by [`TransportConfig::buffer_pre_init_notifications`] once the
+    /// server is initialized, in the order they were received and before any later call.
+    async fn release_pre_init_calls(
+        &self,
+        client_tx: &UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+    ) {
+        let mut held = self.pre_init_calls.lock().await;
+        for call in held.take().into_iter().flatten() {
+            if let Err(err) = self.forward_server_call(client_tx, call) {
+                error!("{} err: <- {err:?}", self.log_name);
+            }
+        }
+    }
+
+    /// Answers a request from the server with [`TransportConfig::request_handler`].
+    fn handle_server_request(&self, handler: &dyn ServerRequestHandler, call: jsonrpc::MethodCall) {
+        let response = handler.handle(call.method, call.params);
+        let server_tx = self.server_tx.clone();
+        let id = call.id;
+        let task_id = id.clone();
+        let task = tokio::spawn(async move {
+            let output = match response.await {
+                Ok(result) => jsonrpc::Output::Success(jsonrpc::Success {
+                    jsonrpc: Some(jsonrpc::Version::V2),
+                    result,
+                    id,
+                }),
+                Err(error) => jsonrpc::Output::Failure(jsonrpc::Failure {
+                    jsonrpc: Some(jsonrpc::Version::V2),
+                    error,
+                    id,
+                }),
+            };
+            // the transport may have been closed in the meantime
+            if let Some(server_tx) = server_tx.upgrade() {
+                let _ = server_tx.send(Payload::Response(output));
+            }
+        });
+        self.handled_requests.insert(task_id, task.abort_handle());
+    }
+
+    /// Applies [`TransportConfig::limit_notifications`] to a call from the server.
+    fn allow_call(&self, call: &jsonrpc::Call) -> bool {
+        let jsonrpc::Call::Notification(notification) = call else {
+            return true;
+        };
+        let Some(&limit) = self.config.notification_limits.get(&notification.method) else {
+            return true;
+        };
+        if progress::is_end(notification) {
+            // dropping it would leave the progress running forever
+            return true;
+        }
+        let method = &notification.method;
+        match self.notification_limiter.check(method, limit) {
+            rate_limit::Verdict::Forward => true,
+            rate_limit::Verdict::ForwardAfterDropping(dropped) => {
+                self.report_dropped_notifications(method, dropped);
+                true
+            }
+            rate_limit::Verdict::Drop { first } => {
+                if first {
+                    warn!("{}: rate limiting `{method}` notifications", self.log_name);
+                }
+                #[cfg(feature = "metrics")]
+                self.metrics.record_dropped_notification(method);
+                false
+            }
+        }
+    }
+
+    async fn process_request_response(
+        &self,
+        output: jsonrpc::Output,
+        language_server_name: &impl fmt::Display,
+    ) -> Result<()> {
+        let (id, result) = match output {
+            jsonrpc::Output::Success(jsonrpc::Success { id, result, .. }) => (id, Ok(result)),
+            jsonrpc::Output::Failure(jsonrpc::Failure { id, error, .. }) => {
+                error!("{language_server_name} <- {error}");
+                (id, Err(error.into()))
+            }
+        };
+        self.complete_request(id, result, language_server_name)
+            .await;
+        Ok(())
+    }
+
+    /// Hands `result` to the pending request `id`.
+    async fn complete_request(
+        &self,
+        id: jsonrpc::Id,
+        result: Result<Value>,
+        language_server_name: &impl fmt::Display,
+    ) {
+        let request = {
+            let mut pending_requests = self.pending_requests.lock();
+            let request = pending_requests.remove(&id);
+            self.update_busy(&pending_requests);
+            shrink_drained(&mut pending_requests, self.config.pending_shrink_threshold);
+            request
+        };
+        if let Some(request) = &request {
+            request.cancel_timeout();
+        }
+        if let Some(request) = request {
+            if request.method == <lsp::request::Initialize as lsp::request::Request>::METHOD {
+                self.startup.record_initialized(&id);
+                #[cfg(feature = "gzip")]
+                if let Ok(result) = &result {
+                    self.server_accepts_gzip
+                        .store(encoding::accepts_gzip(result), Ordering::Relaxed);
+                }
+            }
+            self.completed.record(&id, &request.method, false);
+            if let Some(group) = &request.group {
+                log::debug!(
+                    "{language_server_name} answered {} request (id={id:?}) of group {group} after {:?}",
+                    request.method,
+                    request.sent.elapsed()
+                );
+            }
+            let latency = request.sent.elapsed();
+            if let Some(channel) = &self.config.latency_channel {
+                if let Err(TrySendError::Full((method, _))) =
+                    channel.try_send((request.method.clone(), latency))
+                {
+                    warn!("{language_server_name} latency channel is full, dropping the latency of a {method} request");
+                }
+            }
+            #[cfg(feature = "metrics")]
+            self.metrics.record_response(
+                &request.method,
+                request.group.as_ref(),
+                latency,
+                result.is_err(),
+            );
+            #[cfg(feature = "response-order")]
+            {
+                let overtaken = self.response_order.record_response(&id);
+                if overtaken > self.config.response_order_window {
+                    warn!(
+                        "{language_server_name} answered {} request {id:?} before {overtaken} earlier {} requests",
+                        request.method, request.method
+                    );
+                }
+            }
+            let Some(chan) = request.chan else {
+                log::debug!(
+                    "Discarding ignored response (id={:?}, method={})",
+                    id,
+                    request.method
+                );
+                return;
+            };
+            // a request that timed out isn't pending anymore, the receiver was dropped
+            if chan.send(result).await.is_err() {
+                log::debug!(
+                    "Discarding response whose receiver was dropped (id={:?}, method={}) after {:?}",
+                    id,
+                    request.method,
+                    request.sent.elapsed()
+                );
+            }
+        } else if self.startup.is_initialize_response(&id) {
+            // the capabilities were taken from the first response already
+            warn!(
+                "{language_server_name} answered the initialize request (id={id:?}) twice, ignoring the second response"
+            );
+        } else if let Some(completed) = self.completed.get(&id) {
+            if completed.abandoned {
+                log::debug!(
+                    "{language_server_name} answered {} request (id={id:?}) after it timed out or was cancelled, ignoring the late response",
+                    completed.method
+                );
+            } else {
+                warn!(
+                    "{language_server_name} answered {} request (id={id:?}) twice, ignoring the duplicate response",
+                    completed.method
+                );
+            }
+        } else {
+            log::error!(
+                "Discarding Language Server response without a request (id={:?}) {:?}",
+                id,
+                result
+            );
+        }
+    }
+
+    /// Checks and dispatches a parsed message from the server. Errors are fatal to the transport.
+    ///
+    /// Batches are split up here so that each element is checked and, while the transport is
+    /// frozen, buffered on its own. See [`ServerMessage::Batch`] for the ordering guarantees.
+    async fn handle_server_message(
+        &self,
+        client_tx: &UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+        msg: ServerMessage,
+    ) -> Result<()> {
+        if let ServerMessage::Batch(batch) = msg {
+            if batch.is_empty() {
+                warn!("{} sent an empty batch", self.log_name);
+            }
+            for msg in batch {
+                Box::pin(self.handle_server_message(client_tx, msg)).await?;
+            }
+            return Ok(());
+        }
+        if let Some(message) = ReceivedMessage::new(&msg) {
+            #[cfg(feature = "inbound-hook")]
+            if let Some(hook) = &self.config.inbound_hook {
+                hook.on_recv(message);
+            }
+            if let Some(middleware) = &self.config.middleware {
+                middleware.on_recv(message);
+            }
+        }
+        if let Err(err) = self.check_jsonrpc_version(&msg) {
+            if !matches!(
+                msg,
+                ServerMessage::Malformed { .. } | ServerMessage::MalformedCall { .. }
+            ) {
+                error!("{} rejected message: {err}", self.log_name);
+                // the request it answers would otherwise wait for a response that never comes
+                let id = match msg {
+                    ServerMessage::Output(
+                        jsonrpc::Output::Success(jsonrpc::Success { id, .. })
+                        | jsonrpc::Output::Failure(jsonrpc::Failure { id, .. }),
+                    )
+                    | ServerMessage::ResultMissing { id, .. } => Some(id),
+                    _ => None,
+                };
+                if let Some(id) = id {
+                    self.complete_request(id, Err(err), &self.log_name).await;
+                }
+                return Ok(());
+            }
+        }
+        self.dispatch_server_message(client_tx, msg).await
+    }
+
+    /// Whether a message that failed to parse with `err` is skipped rather than stopping the
+    /// transport, according to [`TransportConfig::non_rpc_json`].
+    fn skips_parse_error(&self, err: &Error) -> bool {
+        let skip = matches!(err, Error::NonRpcJson(_))
+            && self.config.non_rpc_json == NonRpcJsonHandling::Skip;
+        if skip {
+            error!("{}: {err}, skipping it", self.log_name);
+        }
+        skip
+    }
+
+    /// Counts a message from the server for [`Transport::stats`].
+    fn count_received(&self, msg: &ServerMessage) {
+        match msg {
+            ServerMessage::Output(_) | ServerMessage::ResultMissing { .. } => {
+                self.stats.response_received()
+            }
+            ServerMessage::Malformed { id, .. } => {
+                self.stats.parse_error();
+                if id.is_some() {
+                    self.stats.response_received();
+                }
+            }
+            ServerMessage::MalformedCall { .. } => {
+                self.stats.parse_error();
+                self.stats.server_call_received();
+            }
+            ServerMessage::Call(_) => self.stats.server_call_received(),
+            ServerMessage::Batch(batch) => batch.iter().for_each(|msg| self.count_received(msg)),
+        }
+    }
+
+    /// Counts a message that failed to parse against [`TransportConfig::parse_failure_limit`].
+    fn record_parse_failure(&self) -> Result<()> {
+        match &self.parse_failures {
+            Some(failures) => failures.record_failure(),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts `msg` against [`TransportConfig::parse_failure_limit`], a failure if it's
+    /// malformed, and each element of a batch on its own.
+    fn record_parse_result(&self, msg: &ServerMessage) -> Result<()> {
+        let Some(failures) = &self.parse_failures else {
+            return Ok(());
+        };
+        match msg {
+            ServerMessage::Batch(batch) => batch
+                .iter()
+                .try_for_each(|msg| self.record_parse_result(msg)),
+            ServerMessage::Malformed { .. } | ServerMessage::MalformedCall { .. } => {
+                failures.record_failure()
+            }
+            _ => {
+                failures.record_success();
+                Ok(())
+            }
+        }
+    }
+
+    fn report_exit(&self, reason: impl FnOnce() -> ExitReason) {
+        self.exit.report(self.config.on_exit.as_ref(), reason);
+    }
+
+    async fn recv(
+        transport: Arc<Self>,
+        server_stdout: ServerReader,
+        mut control: UnboundedReceiver<ReaderControl>,
+        client_tx: UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+    ) {
+        // `None` while paused.
+        let mut reader = Some(server_stdout);
+        let mut recv_buffer = String::new();
+        let mut content_buffer = Vec::new();
+        // Messages that were read but not dispatched yet, in the order they were received. Large
+        // messages are parsed in the background while the next message is read.
+        let mut parsing = FuturesOrdered::new();
+
+        let err = 'recv: loop {
+            let read = {
+                // The read future is only dropped once it completes or the reader is replaced, so
+                // a partially read message is never lost while dispatching the messages parsed in
+                // the meantime.
+                let read = async {
+                    match reader.as_mut() {
+                        Some(reader) => {
+                            transport
+                                .recv_server_body(reader, &mut recv_buffer, &mut content_buffer)
+                                .await
+                        }
+                        None => future::pending().await,
+                    }
+                };
+                tokio::pin!(read);
+                loop {
+                    tokio::select! {
+                        biased;
+                        Some(parsed) = parsing.next() => {
+                            let msg = match parsed {
+                                Ok(msg) => msg,
+                                Err(err) if transport.skips_parse_error(&err) => {
+                                    transport.stats.parse_error();
+                                    match transport.record_parse_failure() {
+                                        Ok(()) => continue,
+                                        Err(err) => break 'recv err,
+                                    }
+                                }
+                                Err(err) => break 'recv err,
+                            };
+                            transport.count_received(&msg);
+                            if let Err(err) = transport.record_parse_result(&msg) {
+                                break 'recv err;
+                            }
+                            if let Err(err) = transport.handle_server_message(&client_tx, msg).await {
+                                error!("{} err: <- {err:?}", transport.log_name);
+                                break 'recv err;
+                            }
+                        }
+                        Some(control) = control.recv() => break Err(control),
+                        read = &mut read => break Ok(read),
+                    }
+                }
+            };
+            match read {
+                Ok(Ok(())) => {
+                    parsing.push_back(transport.parse_server_message(&mut content_buffer))
+                }
+                // the body was read but can't be decoded, the stream is still in sync
+                Ok(Err(Error::Parse(err))) => {
+                    content_buffer.clear();
+                    parsing.push_back(
+                        future::ready(Ok(ServerMessage::Malformed {
+                            id: None,
+                            error: err.to_string(),
+                        }))
+                        .boxed(),
+                    )
+                }
+                Ok(Err(err)) => break err,
+                Err(control) => {
+                    // Whatever was read of the current message came from the previous reader.
+                    recv_buffer.clear();
+                    content_buffer.clear();
+                    reader = match control {
+                        ReaderControl::Pause => None,
+                        ReaderControl::Replace(new) => Some(new),
+                        ReaderControl::Close(err) => break err,
+                    };
+                }
+            }
+        };
+
+        // Dispatch whatever was read before the stream failed.
+        while let Some(parsed) = parsing.next().await {
+            let result = match parsed {
+                Ok(msg) => transport.handle_server_message(&client_tx, msg).await,
+                Err(err) if transport.skips_parse_error(&err) => Ok(()),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                error!("{} err: <- {err:?}", transport.log_name);
+                break;
+            }
+        }
+
+        let startup_failure = match err {
+            Error::StreamClosed => transport.startup.failure(transport.started).await,
+            _ => {
+                error!(
+                    "Exiting {} after unexpected error: {err:?}",
+                    &transport.log_name
+                );
+                None
+            }
+        };
+        if let Some(stderr) = &startup_failure {
+            error!("{} failed to start: {stderr}", transport.log_name);
+        }
+        transport
+            .stop_after(&client_tx, &err, startup_failure.as_deref())
+            .await;
+    }
+
+    /// Stops the transport after the recv task ended with `err`: reports the exit, fails the
+    /// pending requests and tells the consumer the server exited.
+    async fn stop_after(
+        &self,
+        client_tx: &UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+        err: &Error,
+        startup_failure: Option<&str>,
+    ) {
+        self.state.advance(TransportState::Closed);
+        self.report_exit(|| {
+            let exit_sent = self.exit_sent.load(Ordering::Relaxed);
+            ExitReason::new(err, startup_failure, exit_sent)
+        });
+
+        // Release anything held back so it isn't lost with the stream.
+        self.thaw().await;
+
+        // Close any outstanding requests, taking them all out at once so none is added or
+        // completed in the meantime.
+        let abandoned: Vec<_> = {
+            let mut pending_requests = self.pending_requests.lock();
+            #[cfg(feature = "metrics")]
+            self.metrics.record_abandoned(pending_requests.len());
+            let abandoned = pending_requests
+                .drain()
+                .filter_map(|(id, request)| {
+                    request.cancel_timeout();
+                    Some((id, request.chan?))
+                })
+                .collect();
+            self.update_busy(&pending_requests);
+            shrink_drained(&mut pending_requests, self.config.pending_shrink_threshold);
+            abandoned
+        };
+        for (id, chan) in abandoned {
+            let err = match (startup_failure, err) {
+                (Some(stderr), _) => Error::ServerFailedToStart {
+                    stderr: stderr.to_string(),
+                },
+                (None, Error::QuotaExceeded { limit }) => Error::QuotaExceeded { limit: *limit },
+                (None, Error::MessageTooLarge { advertised, limit }) => Error::MessageTooLarge {
+                    advertised: *advertised,
+                    limit: *limit,
+                },
+                (None, Error::HeaderTooLong { limit }) => Error::HeaderTooLong { limit: *limit },
+                (None, Error::ServerUnresponsive) => Error::ServerUnresponsive,
+                (None, Error::TransportClosed) => Error::TransportClosed,
+                (None, Error::ProtocolBroken { failures }) => Error::ProtocolBroken {
+                    failures: *failures,
+                },
+                (None, Error::InitializeTimeout(timeout)) => Error::InitializeTimeout(*timeout),
+                (None, Error::TaskPanicked { task, message }) => Error::TaskPanicked {
+                    task,
+                    message: message.clone(),
+                },
+                (None, _) => Error::StreamClosed,
+            };
+            match chan.send(Err(err)).await {
+                Ok(_) => (),
+                Err(_) => {
+                    error!("Could not close request on a closed channel (id={:?})", id)
+                }
+            }
+        }
+
+        // Hack: inject a terminated notification so we trigger code that needs to happen after exit
+        let notification =
+            ServerMessage::Call(jsonrpc::Call::Notification(jsonrpc::Notification {
+                jsonrpc: None,
+                method: lsp::notification::Exit::METHOD.to_string(),
+                params: jsonrpc::Params::None,
+            }));
+        match self
+            .process_server_message(client_tx, notification, &self.log_name)
+            .await
+        {
+            Ok(_) => {}
+            Err(err) => {
+                error!("err: <- {:?}", err);
+            }
+        }
+    }
+
+    /// Runs the `task` of the transport, catching a panic according to
+    /// [`TransportConfig::catch_panics`], and accounts for it ending for
+    /// [`Transport::shutdown`].
+    async fn isolate(
+        transport: Arc<Self>,
+        task: panic::Task,
+        body: impl std::future::Future<Output = ()>,
+    ) {
+        if transport.config.catch_panics {
+            if let Err(panic) = std::panic::AssertUnwindSafe(body).catch_unwind().await {
+                transport.handle_panic(task, panic).await;
+            }
+        } else {
+            body.await;
+        }
+        if task != panic::Task::Stderr {
+            transport.lifecycle.task_finished();
+        }
+    }
+
+    /// Stops the transport after a panic in `task`: the recv task is asked to close it, or, if
+    /// that's the task that panicked, it's closed right away.
+    async fn handle_panic(&self, task: panic::Task, panic: Box<dyn std::any::Any + Send>) {
+        let message = panic::message(panic.as_ref());
+        error!(
+            "{} {task} task panicked, stopping the transport: {message}",
+            self.log_name
+        );
+        let err = Error::TaskPanicked {
+            task: task.name(),
+            message,
+        };
+        match task {
+            panic::Task::Recv => {
+                if let Some(client_tx) = self.client_tx.upgrade() {
+                    self.stop_after(&client_tx, &err, None).await;
+                } else {
+                    self.report_exit(|| ExitReason::new(&err, None, false));
+                }
+            }
+            panic::Task::Send | panic::Task::Stderr => {
+                if task == panic::Task::Stderr {
+                    self.startup.record_stderr_closed();
+                }
+                let _ = self.reader_control.send(ReaderControl::Close(err));
+            }
+        }
+    }
+
+    async fn err(transport: Arc<Self>, mut server_stderr: impl AsyncBufRead + Unpin + Send) {
+        let mut recv_buffer = String::new();
+        let mut stderr_channel = transport.config.stderr_channel.clone();
+        let mut phase = transport.lifecycle.subscribe();
+        loop {
+            let line = tokio::select! {
+                line = transport.recv_server_error(&mut server_stderr, &mut recv_buffer) => line,
+                () = shutdown::reached(&mut phase, shutdown::Phase::Stopped) => break,
+            };
+            match line {
+                Ok(_) => {
+                    if let Some(channel) = &stderr_channel {
+                        let line = recv_buffer.trim_end_matches(['\r', '\n']).to_string();
+                        match channel.try_send(line) {
+                            Ok(()) => (),
+                            Err(TrySendError::Full(line)) => {
+                                warn!(
+                                    "{} stderr channel is full, dropping {line:?}",
+                                    transport.log_name
+                                )
+                            }
+                            Err(TrySendError::Closed(_)) => stderr_channel = None,
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("{} err: <- {err:?}", transport.log_name);
+                    break;
+                }
+            }
+        }
+        transport.startup.record_stderr_closed();
+    }
+
+    async fn send(
+        transport: Arc<Self>,
+        mut server_stdin: impl AsyncWrite + Unpin + Send,
+        client_tx: UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+        mut client_rx: outbound::OutgoingReceiver,
+        initialize_notify: Arc<InitializeSignal>,
+    ) {
+        let mut pending_messages: Vec<Payload> = Vec::new();
+        // the requests among `pending_messages`
+        let mut held_requests = 0;
+        let mut is_pending = true;
+        // requests waiting for the previous one to complete with `serialize_requests`, and the
+        // cancellations waiting for them
+        let mut queued_requests: VecDeque<Payload> = VecDeque::new();
+        let mut busy = transport.busy();
+        let mut initialized = initialize_notify.subscribe();
+        // notifications held back for a batch with `notification_batch_size`
+        let mut batch: Vec<jsonrpc::Notification> = Vec::new();
+        let mut phase = transport.lifecycle.subscribe();
+        // whether `shutdown` was called, the loop then ends once the queue is empty
+        let mut closing = false;
+        let mut coalescer = coalesce::ChangeCoalescer::new(transport.config.coalesce_did_change);
+        // with `initialize_timeout`, the branch is disabled without one
+        let initialize_timeout = transport.config.initialize_timeout.unwrap_or_default();
+        let initialize_expired = transport.sleep(initialize_timeout);
+        tokio::pin!(initialize_expired);
+
+        // Determine if a message is allowed to be sent early
+        fn is_initialize(payload: &Payload) -> bool {
+            use lsp::{
+                notification::Initialized,
+                request::{Initialize, Request},
+            };
+            match payload {
+                Payload::Request {
+                    value: jsonrpc::MethodCall { method, .. },
+                    ..
+                } if method == Initialize::METHOD => true,
+                Payload::Notification(jsonrpc::Notification { method, .. })
+                    if method == Initialized::METHOD =>
+                {
+                    true
+                }
+                // requests rejected by `PreInitRequestHandling::Reject`
+                Payload::Response(jsonrpc::Output::Failure(jsonrpc::Failure { error, .. }))
+                    if error.code.code() == SERVER_NOT_INITIALIZED =>
+                {
+                    true
+                }
+                _ => false,
+            }
+        }
+
+        fn is_request(payload: &Payload) -> bool {
+            matches!(
+                payload,
+                Payload::Request { .. } | Payload::DetachedRequest(_)
+            )
+        }
+
+        fn is_shutdown(payload: &Payload) -> bool {
+            use lsp::request::{Request, Shutdown};
+            matches!(payload, Payload::Request { value: jsonrpc::MethodCall { method, .. }, .. } if method == Shutdown::METHOD)
+        }
+
+        // TODO: events that use capabilities need to do the right thing
+
+        loop {
+            if closing && client_rx.is_empty() && coalescer.is_empty() && queued_requests.is_empty()
+            {
+                if let Err(err) = transport
+                    .send_notification_batch(&mut server_stdin, &mut batch)
+                    .await
+                {
+                    error!("{} err: <- {err:?}", transport.log_name);
+                }
+                break;
+            }
+            tokio::select! {
+                biased;
+                // closing, then stopped if the server doesn't close the stream in time
+                () = shutdown::reached(&mut phase, if closing { shutdown::Phase::Stopped } else { shutdown::Phase::Closing }) => {
+                    if closing {
+                        break;
+                    }
+                    closing = true;
+                }
+                Ok(()) = initialized.wait_for(|initialized| *initialized).map(|initialized| initialized.map(drop)), if is_pending => {
+                    // server successfully initialized
+                    is_pending = false;
+                    transport.state.advance(TransportState::Ready);
+
+                    // Hack: inject an initialized notification so we trigger code that needs to happen after init
+                    let notification = ServerMessage::Call(jsonrpc::Call::Notification(jsonrpc::Notification {
+                        jsonrpc: None,
 
                         method: lsp::notification::Initialized::METHOD.to_string(),
                         params: jsonrpc::Params::None,
                     }));
-                    let language_server_name = &transport.name;
+                    let language_server_name = &transport.log_name;
                     match transport.process_server_message(&client_tx, notification, language_server_name).await {
                         Ok(_) => {}
                         Err(err) => {
                             error!("{language_server_name} err: <- {err:?}");
                         }
                     }
+                    transport.release_pre_init_calls(&client_tx).await;
+
+                    // drain the pending queue and send payloads to server, followed by the
+                    // warm-up request
+                    held_requests = 0;
+                    for msg in pending_messages.drain(..).chain(transport.warm_up_request()) {
+                        log::info!("Draining pending message {:?}", msg);
+                        if transport.config.serialize_requests
+                            && (is_request(&msg) || priority::cancels_queued(&msg, &queued_requests))
+                        {
+                            queued_requests.push_back(msg);
+                            continue;
+                        }
+                        match transport.send_payload_to_server(&mut server_stdin, msg).await {
+                            Ok(_) => {}
+                            Err(err) => {
+                                error!("{language_server_name} err: <- {err:?}");
+                            }
+                        }
+                    }
+                }
+                () = &mut initialize_expired, if is_pending && transport.config.initialize_timeout.is_some() => {
+                    let timeout = initialize_timeout;
+                    error!("{} wasn't initialized within {timeout:?}, stopping it", transport.log_name);
+                    for msg in pending_messages.drain(..) {
+                        if let Payload::Request { chan, .. } = msg {
+                            let _ = chan.send(Err(Error::InitializeTimeout(timeout))).await;
+                        }
+                    }
+                    // fails the `initialize` request and anything else pending
+                    let _ = transport
+                        .reader_control
+                        .send(ReaderControl::Close(Error::InitializeTimeout(timeout)));
+                    break;
+                }
+                Ok(()) = busy.wait_for(|busy| !busy).map(|idle| idle.map(drop)), if !queued_requests.is_empty() => {
+                    let msg = queued_requests.pop_front().unwrap();
+                    if let Err(err) = transport.send_notification_batch(&mut server_stdin, &mut batch).await {
+                        error!("{} err: <- {err:?}", transport.log_name);
+                    }
+                    if let Err(err) = transport.send_payload_to_server(&mut server_stdin, msg).await {
+                        error!("{} err: <- {err:?}", transport.log_name);
+                    }
+                    // the cancellations queued behind the request it just sent
+                    while let Some(Payload::Notification(_)) = queued_requests.front() {
+                        let msg = queued_requests.pop_front().unwrap();
+                        if let Err(err) = transport.send_payload_to_server(&mut server_stdin, msg).await {
+                            error!("{} err: <- {err:?}", transport.log_name);
+                        }
+                    }
+                }
+                msg = coalescer.recv(&mut client_rx, &transport.outbound) => {
+                    if let Some(msg) = msg {
+                        if is_pending && is_shutdown(&msg) {
+                            log::info!("Language server not initialized, shutting down");
+                            break;
+                        } else if is_pending && priority::cancels_queued(&msg, &pending_messages) {
+                            // sent once its request is
+                            pending_messages.push(msg);
+                        } else if is_pending && !is_initialize(&msg) {
+                            // ignore notifications
+                            if let Payload::Notification(_) = msg {
+                                continue;
+                            }
+
+                            log::info!("Language server not initialized, delaying request");
+                            if is_request(&msg) {
+                                held_requests += 1;
+                            }
+                            pending_messages.push(msg);
+                            transport
+                                .limit_pre_init_requests(&mut pending_messages, &mut held_requests)
+                                .await;
+                        } else if transport.config.serialize_requests
+                            && is_request(&msg)
+                            && (*busy.borrow() || !queued_requests.is_empty())
+                        {
+                            queued_requests.push_back(msg);
+                        } else if priority::cancels_queued(&msg, &queued_requests) {
+                            // sent right after its request
+                            queued_requests.push_back(msg);
+                        } else if transport.is_batched(&msg) {
+                            let Payload::Notification(notification) = msg else {
+                                unreachable!()
+                            };
+                            batch.push(notification);
+                        } else {
+                            if let Err(err) = transport.send_notification_batch(&mut server_stdin, &mut batch).await {
+                                error!("{} err: <- {err:?}", transport.log_name);
+                            }
+                            match transport.send_payload_to_server(&mut server_stdin, msg).await {
+                                Ok(_) => {}
+                                Err(err) => {
+                                    error!("{} err: <- {err:?}", transport.log_name);
+                                }
+                            }
+                        }
+                        // the batch ends once nothing else is queued right away
+                        if transport.config.notification_batch_size.is_some_and(|size| batch.len() >= size)
+                            || (!batch.is_empty() && client_rx.is_empty())
+                        {
+                            if let Err(err) = transport.send_notification_batch(&mut server_stdin, &mut batch).await {
+                                error!("{} err: <- {err:?}", transport.log_name);
+                            }
+                        }
+                    } else {
+                        // channel closed
+                        break;
+                    }
+                }
+            }
+        }
+        if closing {
+            // tell the server there's nothing more to read
+            let _ = server_stdin.shutdown().await;
+        }
+    }
+}
+
+/// Like [`AsyncWriteExt::write_all`] for all of `slices`, which are handed to the writer
+/// together so they can be written with a single call.
+async fn write_all_vectored<W: AsyncWrite + Unpin + ?Sized>(
+    writer: &mut W,
+    mut slices: &mut [std::io::IoSlice<'_>],
+) -> std::io::Result<()> {
+    // skip leading empty slices, which would look like a zero length write
+    std::io::IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match writer.write_vectored(slices).await {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(written) => std::io::IoSlice::advance_slices(&mut slices, written),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    type ClientRx = UnboundedReceiver<(LanguageServerId, jsonrpc::Call)>;
+
+    fn transport(
+        config: TransportConfig,
+    ) -> (
+        Transport,
+        UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+        ClientRx,
+    ) {
+        let (client_tx, client_rx) = unbounded_channel();
+        let (server_tx, _) = unbounded_channel();
+        let transport = Transport::new(
+            LanguageServerId::default(),
+            "test".to_string(),
+            config,
+            &client_tx,
+            &server_tx,
+            unbounded_channel().0,
+        );
+        (transport, client_tx, client_rx)
+    }
+
+    /// The server end of a transport started over in-memory pipes.
+    struct FakeServer {
+        reader: tokio::io::BufReader<tokio::io::DuplexStream>,
+        writer: tokio::io::DuplexStream,
+    }
+
+    impl FakeServer {
+        async fn recv(&mut self) -> Value {
+            serde_json::from_str(&self.recv_body().await).unwrap()
+        }
+
+        async fn recv_body(&mut self) -> String {
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                self.reader.read_line(&mut line).await.unwrap();
+                match line.trim().split_once(": ") {
+                    Some(("Content-Length", len)) => content_length = len.parse().unwrap(),
+                    _ if line == "\r\n" => break,
+                    _ => panic!("unexpected header {line:?}"),
+                }
+            }
+            let mut body = vec![0; content_length];
+            self.reader.read_exact(&mut body).await.unwrap();
+            String::from_utf8(body).unwrap()
+        }
+
+        async fn send(&mut self, body: &str) {
+            self.writer.write_all(&framed(body)).await.unwrap();
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn start(
+        config: TransportConfig,
+        server_stdin: impl FnOnce(tokio::io::DuplexStream) -> Box<dyn AsyncWrite + Unpin + Send>,
+    ) -> (
+        InboundReceiver,
+        UnboundedSender<Payload>,
+        Arc<InitializeSignal>,
+        Arc<Transport>,
+        FakeServer,
+    ) {
+        let (server_stdin_tx, server_stdin_rx) = tokio::io::duplex(64 * 1024);
+        let (server_stdout_tx, server_stdout_rx) = tokio::io::duplex(64 * 1024);
+        let (rx, tx, notify, transport) = Transport::start(
+            tokio::io::BufReader::new(server_stdout_rx),
+            server_stdin(server_stdin_tx),
+            tokio::io::empty(),
+            LanguageServerId::default(),
+            "test".to_string(),
+            config,
+        );
+        let server = FakeServer {
+            reader: tokio::io::BufReader::new(server_stdin_rx),
+            writer: server_stdout_tx,
+        };
+        (rx, tx, notify, transport, server)
+    }
+
+    fn request(id: u64, method: &str) -> (Payload, tokio::sync::mpsc::Receiver<Result<Value>>) {
+        let (chan, rx) = tokio::sync::mpsc::channel(1);
+        let value = jsonrpc::MethodCall {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: method.to_string(),
+            params: jsonrpc::Params::None,
+            id: jsonrpc::Id::Num(id),
+        };
+        (
+            Payload::Request {
+                chan,
+                value,
+                group: None,
+            },
+            rx,
+        )
+    }
+
+    fn notification(method: &str) -> ServerMessage {
+        ServerMessage::Call(jsonrpc::Call::Notification(jsonrpc::Notification {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: method.to_string(),
+            params: jsonrpc::Params::None,
+        }))
+    }
+
+    fn response(id: u64) -> ServerMessage {
+        ServerMessage::Output(jsonrpc::Output::Success(jsonrpc::Success {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            result: Value::from(id),
+            id: jsonrpc::Id::Num(id),
+        }))
+    }
+
+    fn method(call: jsonrpc::Call) -> String {
+        match call {
+            jsonrpc::Call::MethodCall(call) => call.method,
+            jsonrpc::Call::Notification(notification) => notification.method,
+            jsonrpc::Call::Invalid { .. } => panic!("unexpected invalid call"),
+        }
+    }
+
+    fn framed(body: &str) -> Vec<u8> {
+        format!("Content-Length: {}\r\n\r\n{body}", body.len()).into_bytes()
+    }
+
+    async fn parse(transport: &Transport, input: &[u8]) -> Result<ServerMessage> {
+        let mut reader = input;
+        transport
+            .recv_server_message(&mut reader, &mut String::new(), &mut Vec::new())
+            .await
+    }
+
+    #[tokio::test]
+    async fn jsonrpc_version_check() {
+        let with = framed(r#"{"jsonrpc":"2.0","result":1,"id":1}"#);
+        let without = framed(r#"{"result":1,"id":1}"#);
+        let notification = framed(r#"{"method":"exit"}"#);
+
+        let (lenient, ..) = transport(TransportConfig::default());
+        for input in [&with, &without, &notification] {
+            let msg = parse(&lenient, input).await.unwrap();
+            assert!(lenient.check_jsonrpc_version(&msg).is_ok());
+        }
+        assert!(lenient.warned_missing_version.load(Ordering::Relaxed));
+
+        let (strict, ..) = transport(
+            TransportConfig::default().jsonrpc_version_check(JsonRpcVersionCheck::Strict),
+        );
+        let msg = parse(&strict, &with).await.unwrap();
+        assert!(strict.check_jsonrpc_version(&msg).is_ok());
+        for input in [&without, &notification] {
+            let msg = parse(&strict, input).await.unwrap();
+            assert!(strict.check_jsonrpc_version(&msg).is_err());
+        }
+
+        let mismatched = framed(r#"{"jsonrpc":"1.0","result":1,"id":1}"#);
+        assert!(parse(&lenient, &mismatched).await.is_err());
+
+        // a rejected response still completes its request
+        let config = TransportConfig::default().jsonrpc_version_check(JsonRpcVersionCheck::Strict);
+        let (_rx, tx, _notify, _transport, mut server) = start(config, |w| Box::new(w));
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        server.send(r#"{"result":{},"id":0}"#).await;
+        assert!(matches!(response.recv().await, Some(Err(Error::Other(_)))));
+    }
+
+    #[tokio::test]
+    async fn bare_lf_headers() {
+        let (transport, ..) = transport(TransportConfig::default());
+        let mut content = Vec::new();
+        let mut reader = &b"Content-Length: 2\n\nok"[..];
+        transport
+            .recv_server_body(&mut reader, &mut String::new(), &mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, b"ok");
+
+        let body = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let input = format!("Content-Length: {}\n\n{body}", body.len());
+        assert!(matches!(
+            parse(&transport, input.as_bytes()).await,
+            Ok(ServerMessage::Call(jsonrpc::Call::Notification(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn lenient_headers() {
+        let (transport, ..) = transport(TransportConfig::default());
+        for headers in [
+            "content-length: 5\r\n\r\n",
+            "Content-Length:5\r\n\r\n",
+            "CONTENT-LENGTH :  5 \r\n\r\n",
+            "Content-Type: application/vscode-jsonrpc; charset=utf-8\r\nContent-Length: 5\r\n\r\n",
+        ] {
+            let input = format!("{headers}[1,2]");
+            let mut content = Vec::new();
+            transport
+                .recv_server_body(&mut input.as_bytes(), &mut String::new(), &mut content)
+                .await
+                .unwrap();
+            assert_eq!(content, b"[1,2]", "{headers:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn extra_blank_lines() {
+        let input = "Content-Length: 5\r\n\r\n\r\n\n[1,2]";
+        let (strict, ..) = transport(TransportConfig::default());
+        let mut content = Vec::new();
+        strict
+            .recv_server_body(&mut input.as_bytes(), &mut String::new(), &mut content)
+            .await
+            .unwrap();
+        assert!(content.starts_with(b"\r\n\n"));
+
+        let (quirks, ..) = transport(TransportConfig::default().skip_extra_blank_lines(true));
+        quirks
+            .recv_server_body(&mut input.as_bytes(), &mut String::new(), &mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, b"[1,2]");
+
+        // only a few are skipped
+        let input = format!("Content-Length: 2\r\n\r\n{}ok", "\r\n".repeat(5));
+        quirks
+            .recv_server_body(&mut input.as_bytes(), &mut String::new(), &mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, b"\r\n");
+    }
+
+    #[tokio::test]
+    async fn offloaded_parsing_keeps_order() {
+        let (transport, client_tx, mut client_rx) =
+            transport(TransportConfig::default().parse_offload_threshold(Some(64)));
+        let large = format!(
+            r#"{{"jsonrpc":"2.0","method":"large","params":["{}"]}}"#,
+            "x".repeat(1024)
+        );
+        let mut input = framed(&large);
+        input.extend(framed(r#"{"jsonrpc":"2.0","method":"small"}"#));
+
+        let (_control, control_rx) = unbounded_channel();
+        let input = Box::new(std::io::Cursor::new(input));
+        Transport::recv(Arc::new(transport), input, control_rx, client_tx).await;
+        for expected in ["large", "small", "exit"] {
+            assert_eq!(method(client_rx.recv().await.unwrap().1), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn detached_request_response_is_discarded() {
+        let (transport, client_tx, mut client_rx) = transport(TransportConfig::default());
+        let transport = Arc::new(transport);
+        let mut written = Vec::new();
+        transport
+            .send_payload_to_server(
+                &mut written,
+                Payload::DetachedRequest(jsonrpc::MethodCall {
+                    jsonrpc: Some(jsonrpc::Version::V2),
+                    method: "workspace/executeCommand".to_string(),
+                    params: jsonrpc::Params::None,
+                    id: jsonrpc::Id::Num(7),
+                }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            written,
+            framed(r#"{"jsonrpc":"2.0","method":"workspace/executeCommand","id":7}"#)
+        );
+        assert!(transport.pending_requests.lock()[&jsonrpc::Id::Num(7)]
+            .chan
+            .is_none());
+
+        transport
+            .dispatch_server_message(&client_tx, response(7))
+            .await
+            .unwrap();
+        assert!(transport.pending_requests.lock().is_empty());
+        assert!(client_rx.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_timeout_cancels_request() {
+        use std::time::Duration;
+
+        let config = TransportConfig::default().request_timeout(Some(Duration::from_secs(5)));
+        let (mut rx, tx, notify, transport, mut server) = start(config, |w| Box::new(w));
+        let (payload, _response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        // answered in time: the timer is cancelled
+        let (payload, mut answered) = request(1, "textDocument/hover");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":1}"#)
+            .await;
+        assert_eq!(answered.recv().await.unwrap().unwrap(), Value::Null);
+
+        let (payload, mut unanswered) = request(2, "textDocument/hover");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        assert!(matches!(
+            unanswered.recv().await.unwrap(),
+            Err(Error::Timeout(jsonrpc::Id::Num(2)))
+        ));
+        let cancel = server.recv().await;
+        assert_eq!(cancel["method"], "$/cancelRequest");
+        assert_eq!(cancel["params"]["id"], 2);
+        assert!(transport.pending_requests.lock().is_empty());
+        assert!(
+            transport
+                .completed
+                .get(&jsonrpc::Id::Num(2))
+                .unwrap()
+                .abandoned
+        );
+
+        // a late response is discarded, and request 1 never timed out
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":2}"#)
+            .await;
+        let (payload, _response) = request(3, "textDocument/hover");
+        tx.send(payload).unwrap();
+        assert_eq!(server.recv().await["id"], 3);
+        assert!(unanswered.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout_extends_across_suspend() {
+        use std::time::Duration;
+        use tokio::time::{advance, sleep};
+
+        let (transport, ..) =
+            transport(TransportConfig::default().extend_deadlines_on_suspend(true));
+        let transport = Arc::new(transport);
+        tokio::spawn(Transport::watch_suspend(Arc::downgrade(&transport)));
+
+        // Without a suspension the timeout expires as usual.
+        assert_eq!(
+            transport
+                .timeout(Duration::from_secs(10), future::pending::<()>())
+                .await,
+            None
+        );
+
+        // The clock jumps by a minute while a request is waiting: only the five seconds spent
+        // awake count against its timeout.
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(2)).await;
+            advance(Duration::from_secs(60)).await;
+            sleep(Duration::from_secs(3)).await;
+            tx.send(()).unwrap();
+        });
+        assert!(transport
+            .timeout(Duration::from_secs(10), rx)
+            .await
+            .is_some());
+
+        let (transport, ..) = self::transport(TransportConfig::default());
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(2)).await;
+            advance(Duration::from_secs(60)).await;
+            sleep(Duration::from_secs(60)).await;
+            drop(tx);
+        });
+        assert!(transport
+            .timeout(Duration::from_secs(10), rx)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn batch_is_processed_in_order() {
+        let (transport, client_tx, mut client_rx) = transport(TransportConfig::default());
+        let (chan, mut rx) = tokio::sync::mpsc::channel(1);
+        transport.pending_requests.lock().insert(
+            jsonrpc::Id::Num(1),
+            PendingRequest {
+                chan: Some(chan),
+                method: "test".to_string(),
+                sent: tokio::time::Instant::now(),
+                group: None,
+                timeout: None,
+                warned_slow: false,
+            },
+        );
+
+        let batch = framed(
+            r#" [{"jsonrpc":"2.0","method":"a"},{"jsonrpc":"2.0","result":1,"id":1},{"jsonrpc":"2.0","method":"b"}]"#,
+        );
+        let msg = parse(&transport, &batch).await.unwrap();
+        assert_eq!(
+            msg,
+            ServerMessage::Batch(vec![notification("a"), response(1), notification("b")])
+        );
+
+        transport.freeze().await;
+        transport
+            .handle_server_message(&client_tx, msg)
+            .await
+            .unwrap();
+        // frozen messages are buffered one by one, keeping their order
+        assert_eq!(transport.frozen.lock().await.as_ref().unwrap().len(), 3);
+        transport.thaw().await;
+
+        assert_eq!(method(client_rx.try_recv().unwrap().1), "a");
+        assert_eq!(rx.try_recv().unwrap().unwrap(), Value::from(1));
+        assert_eq!(method(client_rx.try_recv().unwrap().1), "b");
+        assert!(client_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn batch_from_server() {
+        let (mut rx, tx, notify, _transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+        let (payload, _response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        let (first, mut first_response) = request(1, "textDocument/hover");
+        let (second, mut second_response) = request(2, "textDocument/hover");
+        tx.send(first).unwrap();
+        tx.send(second).unwrap();
+        server.recv().await;
+        server.recv().await;
+        // a malformed element only fails the request it answers
+        server
+            .send(concat!(
+                r#"[{"jsonrpc":"2.0","result":"hover","id":1},"#,
+                r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"type":3,"message":"hi"}},"#,
+                r#"{"jsonrpc":"2.0","error":"not an object","id":2}]"#,
+            ))
+            .await;
+        assert_eq!(
+            first_response.recv().await.unwrap().unwrap(),
+            Value::from("hover")
+        );
+        assert_eq!(method(rx.recv().await.unwrap().1), "window/logMessage");
+        assert!(matches!(
+            second_response.recv().await.unwrap(),
+            Err(Error::Parse(_))
+        ));
+
+        // the stream is still in sync
+        let (third, mut third_response) = request(3, "textDocument/hover");
+        tx.send(third).unwrap();
+        server.recv().await;
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":3}"#)
+            .await;
+        assert_eq!(third_response.recv().await.unwrap().unwrap(), Value::Null);
+    }
+
+    #[tokio::test]
+    async fn instrumented_writer() {
+        use std::sync::atomic::AtomicUsize;
+        use std::{pin::Pin, task};
+
+        struct Counting<W> {
+            inner: W,
+            written: Arc<AtomicUsize>,
+        }
+
+        impl<W: AsyncWrite + Unpin> AsyncWrite for Counting<W> {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut task::Context<'_>,
+                buf: &[u8],
+            ) -> task::Poll<std::io::Result<usize>> {
+                let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+                if let task::Poll::Ready(Ok(written)) = poll {
+                    self.written.fetch_add(written, Ordering::Relaxed);
+                }
+                poll
+            }
+
+            fn poll_flush(
+                mut self: Pin<&mut Self>,
+                cx: &mut task::Context<'_>,
+            ) -> task::Poll<std::io::Result<()>> {
+                Pin::new(&mut self.inner).poll_flush(cx)
+            }
+
+            fn poll_shutdown(
+                mut self: Pin<&mut Self>,
+                cx: &mut task::Context<'_>,
+            ) -> task::Poll<std::io::Result<()>> {
+                Pin::new(&mut self.inner).poll_shutdown(cx)
+            }
+        }
+
+        let written = Arc::new(AtomicUsize::new(0));
+        let counter = written.clone();
+        let (_rx, tx, _notify, _transport, mut server) =
+            start(TransportConfig::default(), move |inner| {
+                Box::new(Counting {
+                    inner,
+                    written: counter,
+                })
+            });
+
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        let sent = server.recv().await;
+        assert_eq!(sent["method"], "initialize");
+        assert_eq!(
+            written.load(Ordering::Relaxed),
+            framed(r#"{"jsonrpc":"2.0","method":"initialize","id":0}"#).len()
+        );
+
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        assert_eq!(
+            response.recv().await.unwrap().unwrap(),
+            serde_json::json!({})
+        );
+    }
+
+    #[tokio::test]
+    async fn freeze_buffers_until_thaw() {
+        let (transport, client_tx, mut client_rx) = transport(TransportConfig::default());
+        let (chan, mut rx) = tokio::sync::mpsc::channel(1);
+        transport.pending_requests.lock().insert(
+            jsonrpc::Id::Num(1),
+            PendingRequest {
+                chan: Some(chan),
+                method: "test".to_string(),
+                sent: tokio::time::Instant::now(),
+                group: None,
+                timeout: None,
+                warned_slow: false,
+            },
+        );
+
+        transport.freeze().await;
+        for msg in [notification("a"), response(1), notification("b")] {
+            transport
+                .dispatch_server_message(&client_tx, msg)
+                .await
+                .unwrap();
+        }
+        assert!(client_rx.try_recv().is_err());
+        assert!(rx.try_recv().is_err());
+
+        transport.thaw().await;
+        assert_eq!(method(client_rx.try_recv().unwrap().1), "a");
+        assert_eq!(rx.try_recv().unwrap().unwrap(), Value::from(1));
+        assert_eq!(method(client_rx.try_recv().unwrap().1), "b");
+    }
+
+    #[tokio::test]
+    async fn freeze_overflow_thaws() {
+        let (transport, client_tx, mut client_rx) =
+            transport(TransportConfig::default().freeze_capacity(2));
+
+        transport.freeze().await;
+        for method in ["a", "b", "c"] {
+            transport
+                .dispatch_server_message(&client_tx, notification(method))
+                .await
+                .unwrap();
+        }
+        for expected in ["a", "b", "c"] {
+            assert_eq!(method(client_rx.try_recv().unwrap().1), expected);
+        }
+        assert!(transport.frozen.lock().await.is_none());
+    }
+
+    #[test]
+    fn elapsed_prefix() {
+        assert_eq!(Elapsed(None).to_string(), "");
+        assert_eq!(
+            Elapsed(Some(std::time::Duration::from_millis(1234))).to_string(),
+            "+1.234s "
+        );
+    }
+
+    #[tokio::test]
+    async fn byte_order_mark() {
+        let (transport, _, _) = transport(TransportConfig::default());
+        let expected = ServerMessage::Output(jsonrpc::Output::Success(jsonrpc::Success {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            result: Value::Null,
+            id: jsonrpc::Id::Num(1),
+        }));
+        for body in [
+            "\u{feff}{\"jsonrpc\":\"2.0\",\"result\":null,\"id\":1}",
+            "\u{feff} \r\n{\"jsonrpc\":\"2.0\",\"result\":null,\"id\":1}",
+        ] {
+            // the BOM is part of the framed content
+            let mut input = framed(body);
+            input.extend_from_slice(&framed(r#"{"jsonrpc":"2.0","result":null,"id":1}"#));
+            let mut reader = input.as_slice();
+            for _ in 0..2 {
+                let msg = transport
+                    .recv_server_message(&mut reader, &mut String::new(), &mut Vec::new())
+                    .await
+                    .unwrap();
+                assert_eq!(msg, expected);
+            }
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let parse = |json: &str| ServerMessage::parse(json.as_bytes()).unwrap();
+        assert_eq!(
+            parse(r#"{"jsonrpc":"2.0","result":1,"id":1,"x-vendor":{"elapsed":3}}"#),
+            ServerMessage::Output(jsonrpc::Output::Success(jsonrpc::Success {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                result: Value::from(1),
+                id: jsonrpc::Id::Num(1),
+            }))
+        );
+        assert!(matches!(
+            parse(r#"{"jsonrpc":"2.0","error":{"code":-32601,"message":"no"},"id":2,"x":1}"#),
+            ServerMessage::Output(jsonrpc::Output::Failure(_))
+        ));
+        assert_eq!(
+            parse(r#"{"jsonrpc":"2.0","method":"workspace/configuration","id":3,"x":1}"#),
+            ServerMessage::Call(jsonrpc::Call::MethodCall(jsonrpc::MethodCall {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: "workspace/configuration".to_string(),
+                params: jsonrpc::Params::None,
+                id: jsonrpc::Id::Num(3),
+            }))
+        );
+        assert_eq!(
+            parse(r#"{"jsonrpc":"2.0","method":"$/progress","params":[],"x":1}"#),
+            ServerMessage::Call(jsonrpc::Call::Notification(jsonrpc::Notification {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: "$/progress".to_string(),
+                params: jsonrpc::Params::Array(Vec::new()),
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn null_and_missing_results() {
+        assert_eq!(
+            ServerMessage::parse(br#"{"jsonrpc":"2.0","result":null,"id":1}"#).unwrap(),
+            ServerMessage::Output(jsonrpc::Output::Success(jsonrpc::Success {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                result: Value::Null,
+                id: jsonrpc::Id::Num(1),
+            }))
+        );
+        assert_eq!(
+            ServerMessage::parse(br#"{"jsonrpc":"2.0","id":2}"#).unwrap(),
+            ServerMessage::ResultMissing {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                id: jsonrpc::Id::Num(2),
+            }
+        );
+
+        let (mut rx, tx, notify, _transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+        let (payload, _response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        let (payload, mut null) = request(1, "textDocument/hover");
+        tx.send(payload).unwrap();
+        let (payload, mut missing) = request(2, "textDocument/hover");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        server.recv().await;
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":1}"#)
+            .await;
+        server.send(r#"{"jsonrpc":"2.0","id":2}"#).await;
+        assert_eq!(null.recv().await.unwrap().unwrap(), Value::Null);
+        assert!(matches!(
+            missing.recv().await.unwrap(),
+            Err(Error::ResultMissing)
+        ));
+    }
+
+    #[tokio::test]
+    async fn warm_up_request() {
+        let config =
+            TransportConfig::default().warm_up_request("workspace/symbol", jsonrpc::Params::None);
+        let (mut rx, tx, notify, transport, mut server) = start(config, |w| Box::new(w));
+        let (payload, _response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        let (payload, _response) = request(1, "textDocument/hover");
+        tx.send(payload).unwrap();
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        let mut sent = [server.recv().await, server.recv().await];
+        sent.sort_by_key(|msg| msg["method"].to_string());
+        assert_eq!(sent[0]["method"], "textDocument/hover");
+        assert_eq!(sent[1]["method"], "workspace/symbol");
+        assert_eq!(sent[1]["id"], "helix-warm-up");
+
+        // the response is discarded
+        server
+            .send(r#"{"jsonrpc":"2.0","result":[],"id":"helix-warm-up"}"#)
+            .await;
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{}}"#)
+            .await;
+        assert_eq!(method(rx.recv().await.unwrap().1), "window/logMessage");
+        assert_eq!(transport.pending_requests.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn captured_initialize_params() {
+        let (_rx, tx, _notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+        let (payload, _response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        assert_eq!(transport.sent_initialize_params(), None);
+
+        let config = TransportConfig::default().capture_initialize_params(true);
+        let (_rx, tx, _notify, transport, mut server) = start(config, |w| Box::new(w));
+        let (mut payload, _response) = request(0, "initialize");
+        if let Payload::Request { value, .. } = &mut payload {
+            let mut params = serde_json::Map::new();
+            params.insert("processId".to_string(), Value::from(1));
+            value.params = jsonrpc::Params::Map(params);
+        }
+        tx.send(payload).unwrap();
+        assert_eq!(server.recv().await["params"]["processId"], 1);
+        assert_eq!(
+            transport.sent_initialize_params(),
+            Some(serde_json::json!({ "processId": 1 }))
+        );
+    }
+
+    #[tokio::test]
+    async fn pre_init_server_notifications() {
+        let config = TransportConfig::default()
+            .buffer_pre_init_notifications(true)
+            .pre_init_requests(PreInitRequestHandling::Buffer);
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{}}"#)
+            .await;
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"window/workDoneProgress/create","params":{"token":"t"},"id":1}"#)
+            .await;
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{}}"#)
+            .await;
+        // the initialize response still goes through
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        response.recv().await.unwrap().unwrap();
+        assert_eq!(method(rx.recv().await.unwrap().1), "window/logMessage");
+
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        assert_eq!(
+            method(rx.recv().await.unwrap().1),
+            "textDocument/publishDiagnostics"
+        );
+        assert_eq!(
+            method(rx.recv().await.unwrap().1),
+            "window/workDoneProgress/create"
+        );
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{}}"#)
+            .await;
+        assert_eq!(
+            method(rx.recv().await.unwrap().1),
+            "textDocument/publishDiagnostics"
+        );
+    }
+
+    #[tokio::test]
+    async fn pre_init_server_requests() {
+        const CREATE: &str = r#"{"jsonrpc":"2.0","method":"window/workDoneProgress/create","params":{"token":"t"},"id":1}"#;
+
+        let config = TransportConfig::default().pre_init_requests(PreInitRequestHandling::Buffer);
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+        let (payload, _response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        server.send(CREATE).await;
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{}}"#)
+            .await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        // notifications aren't held back
+        assert_eq!(method(rx.recv().await.unwrap().1), "window/logMessage");
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        assert_eq!(
+            method(rx.recv().await.unwrap().1),
+            "window/workDoneProgress/create"
+        );
+
+        let config = TransportConfig::default().pre_init_requests(PreInitRequestHandling::Reject);
+        let (mut rx, tx, _notify, _transport, mut server) = start(config, |w| Box::new(w));
+        let (payload, _response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        server.send(CREATE).await;
+        let rejection = server.recv().await;
+        assert_eq!(rejection["id"], 1);
+        assert_eq!(rejection["error"]["code"], -32002);
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{}}"#)
+            .await;
+        assert_eq!(method(rx.recv().await.unwrap().1), "window/logMessage");
+    }
+
+    #[test]
+    fn serialization_round_trips() {
+        let params = serde_json::json!({
+            "text": "fn main() {\n\t\"é\u{1F600}\"\n}\u{7f}",
+            "version": null,
+            "range": [0, -1, 1.5, 1e300, u64::MAX],
+            "nested": { "empty": {}, "list": [] },
+        });
+        let Value::Object(map) = params.clone() else {
+            unreachable!()
+        };
+        let call = |params| jsonrpc::MethodCall {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: "textDocument/didChange".to_string(),
+            params,
+            id: jsonrpc::Id::Str("a\"b".to_string()),
+        };
+        let payloads = [
+            Payload::DetachedRequest(call(jsonrpc::Params::None)),
+            Payload::DetachedRequest(call(jsonrpc::Params::Map(map.clone()))),
+            Payload::Notification(jsonrpc::Notification {
+                jsonrpc: None,
+                method: "exit".to_string(),
+                params: jsonrpc::Params::Array(vec![Value::Null, params.clone()]),
+            }),
+            Payload::Response(jsonrpc::Output::Success(jsonrpc::Success {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                result: Value::Null,
+                id: jsonrpc::Id::Num(1),
+            })),
+            Payload::Response(jsonrpc::Output::Success(jsonrpc::Success {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                result: params.clone(),
+                id: jsonrpc::Id::Null,
+            })),
+            Payload::Response(jsonrpc::Output::Failure(jsonrpc::Failure {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                error: jsonrpc::Error {
+                    code: jsonrpc::ErrorCode::InvalidParams,
+                    message: "invalid".to_string(),
+                    data: Some(params),
+                },
+                id: jsonrpc::Id::Num(2),
+            })),
+        ];
+
+        for payload in payloads {
+            let json = payload.to_json_string().unwrap();
+            match &payload {
+                Payload::Request { value, .. } | Payload::DetachedRequest(value) => {
+                    assert_eq!(
+                        &serde_json::from_str::<jsonrpc::MethodCall>(&json).unwrap(),
+                        value
+                    );
+                    assert_eq!(serde_json::to_string(value).unwrap(), json);
+                }
+                Payload::Notification(value) => {
+                    assert_eq!(
+                        &serde_json::from_str::<jsonrpc::Notification>(&json).unwrap(),
+                        value
+                    );
+                    assert_eq!(serde_json::to_string(value).unwrap(), json);
+                }
+                Payload::Response(output) => {
+                    assert_eq!(
+                        &serde_json::from_str::<jsonrpc::Output>(&json).unwrap(),
+                        output
+                    );
+                    assert_eq!(
+                        serde_json::from_str::<Value>(&json).unwrap(),
+                        serde_json::to_value(output).unwrap()
+                    );
+                }
+            }
+            // the server side parses with sonic-rs too
+            assert!(
+                matches!(
+                    ServerMessage::parse(json.as_bytes()),
+                    Ok(ServerMessage::Call(_) | ServerMessage::Output(_))
+                ),
+                "{json}"
+            );
+        }
+    }
+
+    #[test]
+    fn write_json_reuses_buffer() {
+        let (long, _rx) = request(1, &"a".repeat(256));
+        let short = Payload::Notification(jsonrpc::Notification {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: "initialized".to_string(),
+            params: jsonrpc::Params::None,
+        });
+        let mut buffer = b"stale".to_vec();
+        long.write_json(&mut buffer).unwrap();
+        assert_eq!(buffer, long.to_json_string().unwrap().as_bytes());
+        let capacity = buffer.capacity();
+
+        short.write_json(&mut buffer).unwrap();
+        assert_eq!(buffer, short.to_json_string().unwrap().as_bytes());
+        assert_eq!(buffer.capacity(), capacity);
+    }
+
+    #[tokio::test]
+    async fn panicking_handler_stops_transport() {
+        struct Panicking;
+
+        impl ServerRequestHandler for Panicking {
+            fn handles(&self, _method: &str) -> bool {
+                true
+            }
+
+            fn handle(&self, method: String, _params: jsonrpc::Params) -> HandlerFuture {
+                panic!("can't handle {method}")
+            }
+        }
+
+        let (reasons_tx, mut reasons) = unbounded_channel();
+        let config = TransportConfig::default()
+            .request_handler(Arc::new(Panicking))
+            .on_exit(move |reason| {
+                let _ = reasons_tx.send(reason);
+            });
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        let (hover, mut response) = request(1, "textDocument/hover");
+        tx.send(hover).unwrap();
+        server.recv().await;
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"workspace/configuration","params":{},"id":7}"#)
+            .await;
+
+        assert!(matches!(
+            response.recv().await,
+            Some(Err(Error::TaskPanicked { task: "recv", message })) if message == "can't handle workspace/configuration"
+        ));
+        assert_eq!(method(rx.recv().await.unwrap().1), "exit");
+        let reason = reasons.recv().await.unwrap();
+        assert!(
+            matches!(&reason, ExitReason::Error(err) if err.contains("panicked")),
+            "{reason:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_responses() {
+        let (mut rx, tx, notify, _transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        let (hover, mut hover_response) = request(1, "textDocument/hover");
+        let (completion, mut completion_response) = request(2, "textDocument/completion");
+        tx.send(hover).unwrap();
+        tx.send(completion).unwrap();
+        server.recv().await;
+        server.recv().await;
+
+        // only the request whose id could be recovered fails
+        server
+            .send(r#"{"jsonrpc":"2.0","id":1,"result":{"contents":"#)
+            .await;
+        assert!(matches!(
+            hover_response.recv().await.unwrap(),
+            Err(Error::Parse(_))
+        ));
+
+        // a message without a recoverable id is skipped
+        server.send(r#"{"jsonrpc":"2.0",garbage"#).await;
+        // a malformed request of the server is answered, the ids of its requests are its own
+        server
+            .send(r#"{"jsonrpc":"2.0","id":2,"method":"workspace/configuration","params":5}"#)
+            .await;
+        let answer = server.recv().await;
+        assert_eq!(answer["id"], 2);
+        assert_eq!(answer["error"]["code"], -32602);
+        server.send(r#"{"jsonrpc":"2.0","result":2,"id":2}"#).await;
+        assert_eq!(completion_response.recv().await.unwrap().unwrap(), 2);
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{}}"#)
+            .await;
+        assert_eq!(method(rx.recv().await.unwrap().1), "window/logMessage");
+    }
+
+    #[tokio::test]
+    async fn bounded_outgoing_channel() {
+        // a tiny pipe the server doesn't read from blocks the send task on the first write
+        let (server_stdin_tx, server_stdin_rx) = tokio::io::duplex(64);
+        let (server_stdout_tx, server_stdout_rx) = tokio::io::duplex(64 * 1024);
+        let (mut rx, tx, notify, _transport) = Transport::start_bounded(
+            tokio::io::BufReader::new(server_stdout_rx),
+            server_stdin_tx,
+            tokio::io::empty(),
+            1,
+            LanguageServerId::default(),
+            "test".to_string(),
+            TransportConfig::default(),
+        );
+        let mut server = FakeServer {
+            reader: tokio::io::BufReader::new(server_stdin_rx),
+            writer: server_stdout_tx,
+        };
+
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).await.unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        let change = |version: usize| {
+            Payload::Notification(jsonrpc::Notification {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: "textDocument/didChange".to_string(),
+                params: jsonrpc::Params::Array(vec![Value::from(version)]),
+            })
+        };
+        tx.send(change(1)).await.unwrap();
+        // wait for the send task to take it and block writing it
+        while tx.capacity() == 0 {
+            tokio::task::yield_now().await;
+        }
+        tx.send(change(2)).await.unwrap();
+        assert!(matches!(tx.try_send(change(3)), Err(TrySendError::Full(_))));
+
+        // the transport's own payloads don't wait for room in the bounded channel
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{}}"#)
+            .await;
+        assert_eq!(method(rx.recv().await.unwrap().1), "window/logMessage");
+
+        for version in 1..=2 {
+            assert_eq!(server.recv().await["params"], serde_json::json!([version]));
+        }
+        tx.send(change(3)).await.unwrap();
+        assert_eq!(server.recv().await["params"], serde_json::json!([3]));
+    }
+
+    #[tokio::test]
+    async fn clean_shutdown() {
+        let (mut rx, tx, notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        let exit = || {
+            Payload::Notification(jsonrpc::Notification {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: "exit".to_string(),
+                params: jsonrpc::Params::None,
+            })
+        };
+        transport.enqueue(&tx, exit(), None).await.unwrap();
+        let shutdown = tokio::spawn({
+            let transport = transport.clone();
+            async move { transport.shutdown(std::time::Duration::from_secs(5)).await }
+        });
+
+        // the queued payload is still sent, then the stream is closed
+        assert_eq!(server.recv().await["method"], "exit");
+        let mut rest = String::new();
+        assert_eq!(server.reader.read_line(&mut rest).await.unwrap(), 0);
+        assert!(matches!(
+            transport.try_enqueue(&tx, exit(), None),
+            Err(Error::StreamClosed)
+        ));
+        drop(server);
+
+        assert_eq!(shutdown.await.unwrap(), ShutdownOutcome::Clean);
+        assert_eq!(method(rx.recv().await.unwrap().1), "exit");
+    }
+
+    #[tokio::test]
+    async fn send_request_allocates_ids() {
+        let (mut rx, tx, notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+
+        let (id, mut initialize) = transport
+            .send_request(&tx, "initialize", jsonrpc::Params::None)
+            .unwrap();
+        assert_eq!(id, jsonrpc::Id::Num(0));
+        assert_eq!(server.recv().await["id"], 0);
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        assert!(initialize.recv().await.unwrap().is_ok());
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        // ids supplied by the caller don't advance the counter
+        let (hover, _response) = request(7, "textDocument/hover");
+        tx.send(hover).unwrap();
+        assert_eq!(server.recv().await["id"], 7);
+        let (id, mut response) = transport
+            .send_request(&tx, "textDocument/hover", jsonrpc::Params::None)
+            .unwrap();
+        assert_eq!(id, jsonrpc::Id::Num(1));
+        let sent = server.recv().await;
+        assert_eq!(
+            (&sent["method"], &sent["id"]),
+            (&"textDocument/hover".into(), &1.into())
+        );
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":1}"#)
+            .await;
+        assert_eq!(response.recv().await.unwrap().unwrap(), Value::Null);
+    }
+
+    #[tokio::test]
+    async fn cancel_pending_request() {
+        let (mut rx, tx, notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        let (hover, mut response) = request(1, "textDocument/hover");
+        tx.send(hover).unwrap();
+        server.recv().await;
+        assert!(transport.cancel(&jsonrpc::Id::Num(1)).await);
+        assert!(matches!(
+            response.recv().await.unwrap(),
+            Err(Error::Cancelled(jsonrpc::Id::Num(1)))
+        ));
+        assert_eq!(
+            server.recv_body().await,
+            r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":1}}"#
+        );
+        assert!(!transport.cancel(&jsonrpc::Id::Num(1)).await);
+
+        // the server's late answer is discarded
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":1}"#)
+            .await;
+        let (hover, mut response) = request(2, "textDocument/hover");
+        tx.send(hover).unwrap();
+        assert_eq!(server.recv().await["id"], 2);
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":2}"#)
+            .await;
+        assert_eq!(response.recv().await.unwrap().unwrap(), Value::Null);
+        assert!(!transport.cancel(&jsonrpc::Id::Num(2)).await);
+    }
+
+    #[tokio::test]
+    async fn server_cancels_its_request() {
+        use lsp::request::{WorkDoneProgressCreate, WorkspaceConfiguration};
+
+        let router =
+            RequestRouter::new().route::<WorkspaceConfiguration, _, _>(|_| future::pending());
+        let config = TransportConfig::default()
+            .request_handler(Arc::new(router))
+            .pre_init_requests(PreInitRequestHandling::Buffer);
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+
+        // a request held back until the server is initialized
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"window/workDoneProgress/create","params":{"token":1},"id":1}"#)
+            .await;
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":1}}"#)
+            .await;
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"window/workDoneProgress/create","params":{"token":2},"id":2}"#)
+            .await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        // the answer is sent once the server is initialized, like any other
+        let cancelled = server.recv().await;
+        assert_eq!(
+            (&cancelled["id"], &cancelled["error"]["code"]),
+            (&1.into(), &(-32800).into())
+        );
+        let jsonrpc::Call::MethodCall(create) = rx.recv().await.unwrap().1 else {
+            panic!("expected a request");
+        };
+        assert_eq!(
+            (create.method.as_str(), create.id),
+            (
+                <WorkDoneProgressCreate as lsp::request::Request>::METHOD,
+                jsonrpc::Id::Num(2)
+            )
+        );
+
+        // a request being answered by the handler
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"workspace/configuration","params":{"items":[]},"id":3}"#)
+            .await;
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":3}}"#)
+            .await;
+        let cancelled = server.recv().await;
+        assert_eq!(
+            (&cancelled["id"], &cancelled["error"]["code"]),
+            (&3.into(), &(-32800).into())
+        );
+
+        // cancelling requests the transport doesn't know is left to the consumer
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":2}}"#)
+            .await;
+        assert_eq!(method(rx.recv().await.unwrap().1), "$/cancelRequest");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coalesced_did_change() {
+        use std::time::Duration;
+
+        let config =
+            TransportConfig::default().coalesce_did_change(Some(Duration::from_millis(10)));
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        let change = |uri: &str, version: i64, change: Value| {
+            let Value::Object(params) = serde_json::json!({
+                "textDocument": { "uri": uri, "version": version },
+                "contentChanges": [change],
+            }) else {
+                unreachable!()
+            };
+            Payload::Notification(jsonrpc::Notification {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: "textDocument/didChange".to_string(),
+                params: jsonrpc::Params::Map(params),
+            })
+        };
+        let full = |text: &str| serde_json::json!({ "text": text });
+        for (version, text) in [(1, "a"), (2, "ab"), (3, "abc")] {
+            tx.send(change("file:///a", version, full(text))).unwrap();
+        }
+        tx.send(change("file:///b", 1, full("b"))).unwrap();
+        let (hover, _response) = request(1, "textDocument/hover");
+        tx.send(hover).unwrap();
+        let incremental = serde_json::json!({
+            "range": { "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 0 } },
+            "text": "x",
+        });
+        tx.send(change("file:///a", 4, incremental.clone()))
+            .unwrap();
+        tx.send(change("file:///a", 5, incremental)).unwrap();
+
+        // the latest full change of each document in a row, in order with everything else
+        let sent = server.recv().await;
+        assert_eq!(sent["params"]["textDocument"]["uri"], "file:///a");
+        assert_eq!(sent["params"]["contentChanges"][0]["text"], "abc");
+        let sent = server.recv().await;
+        assert_eq!(sent["params"]["textDocument"]["uri"], "file:///b");
+        assert_eq!(server.recv().await["id"], 1);
+        for version in [4, 5] {
+            assert_eq!(
+                server.recv().await["params"]["textDocument"]["version"],
+                version
+            );
+        }
+
+        // a lone change is sent once the window passed
+        tx.send(change("file:///a", 6, full("abcx"))).unwrap();
+        let start = tokio::time::Instant::now();
+        assert_eq!(server.recv().await["params"]["textDocument"]["version"], 6);
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[tokio::test]
+    async fn vectored_framing() {
+        use std::{
+            io::IoSlice,
+            pin::Pin,
+            task::{Context, Poll},
+        };
+
+        /// Takes at most `limit` bytes per call, counting the calls.
+        #[derive(Default)]
+        struct Trickle {
+            written: Vec<u8>,
+            limit: usize,
+            calls: usize,
+        }
+
+        impl AsyncWrite for Trickle {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<std::io::Result<usize>> {
+                self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+            }
+
+            fn poll_write_vectored(
+                mut self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                bufs: &[IoSlice<'_>],
+            ) -> Poll<std::io::Result<usize>> {
+                self.calls += 1;
+                let mut written = 0;
+                for buf in bufs {
+                    let len = buf.len().min(self.limit - written);
+                    self.written.extend_from_slice(&buf[..len]);
+                    written += len;
+                }
+                Poll::Ready(Ok(written))
+            }
+
+            fn is_write_vectored(&self) -> bool {
+                true
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_shutdown(
+                self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let (transport, _, _) = transport(TransportConfig::default());
+        let body = r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#;
+
+        let mut writer = Trickle {
+            limit: usize::MAX,
+            ..Trickle::default()
+        };
+        transport
+            .send_bytes_to_server(&mut writer, body.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(writer.written, framed(body));
+        assert_eq!(writer.calls, 1);
+
+        // partial writes resume where they stopped, across the header and the body
+        let mut writer = Trickle {
+            limit: 7,
+            ..Trickle::default()
+        };
+        transport
+            .send_bytes_to_server(&mut writer, body.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(writer.written, framed(body));
+        assert_eq!(writer.calls, framed(body).len().div_ceil(7));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn initialize_timeout() {
+        use std::time::Duration;
+
+        let exits = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let config = TransportConfig::default()
+            .initialize_timeout(Some(Duration::from_secs(5)))
+            .on_exit({
+                let exits = exits.clone();
+                move |reason| exits.lock().push(reason)
+            });
+        let (mut rx, tx, _notify, transport, mut server) = start(config, |w| Box::new(w));
+        let start = tokio::time::Instant::now();
+        let (initialize, mut initialize_response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        assert_eq!(server.recv().await["method"], "initialize");
+        let (hover, mut hover_response) = request(1, "textDocument/hover");
+        tx.send(hover).unwrap();
+
+        // the server never answers
+        for response in [&mut hover_response, &mut initialize_response] {
+            assert!(matches!(
+                response.recv().await.unwrap(),
+                Err(Error::InitializeTimeout(timeout)) if timeout == Duration::from_secs(5)
+            ));
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        assert_eq!(method(rx.recv().await.unwrap().1), "exit");
+        assert_eq!(*exits.lock(), [ExitReason::InitializeTimeout]);
+        let (hover, _response) = request(2, "textDocument/hover");
+        assert!(transport.try_enqueue(&tx, hover, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn max_pre_init_requests() {
+        let config = TransportConfig::default().max_pre_init_requests(Some(2));
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+
+        let mut responses: Vec<_> = (1..=4)
+            .map(|id| {
+                let (hover, response) = request(id, "textDocument/hover");
+                tx.send(hover).unwrap();
+                response
+            })
+            .collect();
+        // the oldest requests make room for the newer ones
+        for response in &mut responses[..2] {
+            assert!(matches!(
+                response.recv().await.unwrap(),
+                Err(Error::QueueFull)
+            ));
+        }
+
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        for id in [3, 4] {
+            assert_eq!(server.recv().await["id"], id);
+            server
+                .send(&format!(r#"{{"jsonrpc":"2.0","result":null,"id":{id}}}"#))
+                .await;
+        }
+        for response in &mut responses[2..] {
+            assert_eq!(response.recv().await.unwrap().unwrap(), Value::Null);
+        }
+    }
+
+    #[tokio::test]
+    async fn stray_newline_between_messages() {
+        let (transport, _, _) = transport(TransportConfig::default());
+        let mut input = framed(r#"{"jsonrpc":"2.0","method":"first"}"#);
+        input.extend_from_slice(b"\n\r\n");
+        input.extend(framed(r#"{"jsonrpc":"2.0","method":"second"}"#));
+
+        let mut reader = input.as_slice();
+        let (mut buffer, mut content) = (String::new(), Vec::new());
+        for expected in ["first", "second"] {
+            let msg = transport
+                .recv_server_message(&mut reader, &mut buffer, &mut content)
+                .await
+                .unwrap();
+            let ServerMessage::Call(call) = msg else {
+                panic!("expected a notification, got {msg:?}");
+            };
+            assert_eq!(method(call), expected);
+        }
+        assert!(transport.warned_stray_bytes.load(Ordering::Relaxed));
+        assert!(reader.is_empty());
+    }
+
+    #[tokio::test]
+    async fn middleware() {
+        #[derive(Default)]
+        struct Recorder(parking_lot::Mutex<Vec<String>>);
+
+        fn id(output: &jsonrpc::Output) -> &jsonrpc::Id {
+            match output {
+                jsonrpc::Output::Success(success) => &success.id,
+                jsonrpc::Output::Failure(failure) => &failure.id,
+            }
+        }
+
+        impl TransportMiddleware for Recorder {
+            fn on_send(&self, message: OutboundMessage<'_>) {
+                let message = match message {
+                    OutboundMessage::Request(call) => format!("-> {} {:?}", call.method, call.id),
+                    OutboundMessage::Notification(notification) => {
+                        format!("-> {}", notification.method)
+                    }
+                    OutboundMessage::Response(output) => format!("-> {:?}", id(output)),
+                };
+                self.0.lock().push(message);
+            }
+
+            fn on_recv(&self, message: ReceivedMessage<'_>) {
+                let message = match message {
+                    ReceivedMessage::Output(output) => format!("<- {:?}", id(output)),
+                    ReceivedMessage::Call(call) => format!("<- {}", method(call.clone())),
+                    message => format!("<- {message:?}"),
+                };
+                self.0.lock().push(message);
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let config = TransportConfig::default()
+            .middleware(recorder.clone())
+            .document_lifecycle_check(DocumentLifecycleCheck::Reject);
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        server
+            .send(r#"[{"jsonrpc":"2.0","method":"window/logMessage","params":{}},{"jsonrpc":"2.0","result":null,"id":5}]"#)
+            .await;
+        assert_eq!(method(rx.recv().await.unwrap().1), "window/logMessage");
+        // not observed since it's never sent
+        tx.send(Payload::Notification(jsonrpc::Notification {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: "textDocument/didChange".to_string(),
+            params: jsonrpc::Params::Map(
+                serde_json::json!({
+                    "textDocument": {"uri": "file:///closed", "version": 1},
+                    "contentChanges": [],
+                })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ),
+        }))
+        .unwrap();
+        tx.send(Payload::Notification(jsonrpc::Notification {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: "exit".to_string(),
+            params: jsonrpc::Params::None,
+        }))
+        .unwrap();
+        server.recv().await;
+
+        assert_eq!(
+            *recorder.0.lock(),
+            [
+                "-> initialize Num(0)",
+                "<- Num(0)",
+                "<- window/logMessage",
+                "<- Num(5)",
+                "-> exit",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_failure_limit() {
+        let config = TransportConfig::default().parse_failure_limit(Some(ParseFailureLimit {
+            max_failures: 5,
+            window: std::time::Duration::from_secs(60),
+        }));
+        let (mut rx, tx, _notify, _transport, mut server) = start(config, |w| Box::new(w));
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+
+        for _ in 0..4 {
+            server.send("{garbage").await;
+        }
+        // a message that parses ends the streak
+        server.send(r#"{"jsonrpc":"2.0","method":"a"}"#).await;
+        assert_eq!(method(rx.recv().await.unwrap().1), "a");
+        // a banner skipped as garbage isn't a failure
+        for _ in 0..5 {
+            server.writer.write_all(b"not a header\r\n").await.unwrap();
+        }
+        // but the malformed elements of a batch are, one by one
+        server
+            .send(r#"[{"jsonrpc":"2.0","id":{}},{"jsonrpc":"2.0","id":{}}]"#)
+            .await;
+        server.send("{garbage").await;
+        server.send("{garbage").await;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert!(response.try_recv().is_err());
+        server.send("{garbage").await;
+
+        assert!(matches!(
+            response.recv().await.unwrap(),
+            Err(Error::ProtocolBroken { failures: 5 })
+        ));
+        assert_eq!(method(rx.recv().await.unwrap().1), "exit");
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let (mut rx, tx, notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        response.recv().await.unwrap().unwrap();
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        tx.send(Payload::Notification(jsonrpc::Notification {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: "initialized".to_string(),
+            params: jsonrpc::Params::None,
+        }))
+        .unwrap();
+        assert_eq!(server.recv().await["method"], "initialized");
+
+        server.writer.write_all(b"not a header\r\n").await.unwrap();
+        server.send("{garbage").await;
+        server
+            .send(r#"[{"jsonrpc":"2.0","method":"a"},{"jsonrpc":"2.0","method":"b","id":1}]"#)
+            .await;
+        assert_eq!(method(rx.recv().await.unwrap().1), "a");
+        assert_eq!(method(rx.recv().await.unwrap().1), "b");
+        assert_eq!(
+            transport.stats(),
+            TransportStats {
+                requests_sent: 1,
+                notifications_sent: 1,
+                responses_received: 1,
+                // the injected `initialized` notification isn't counted
+                server_calls_received: 2,
+                parse_errors: 1,
+                garbage_lines_skipped: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn raw_tap() {
+        let (tap, mut tapped) = tokio::sync::mpsc::unbounded_channel();
+        let config = TransportConfig::default().raw_tap(tap);
+        let (_rx, tx, _notify, _transport, mut server) = start(config, |w| Box::new(w));
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        let sent = server.recv_body().await;
+        // the exact bytes, down to the whitespace parsing drops
+        let received = "{ \"jsonrpc\": \"2.0\", \"result\": {}, \"id\": 0 }";
+        server.send(received).await;
+        response.recv().await.unwrap().unwrap();
+
+        let outgoing = tapped.recv().await.unwrap();
+        assert_eq!(outgoing.direction, Direction::Outgoing);
+        assert_eq!(outgoing.bytes, sent.as_bytes());
+        let incoming = tapped.recv().await.unwrap();
+        assert_eq!(incoming.direction, Direction::Incoming);
+        assert_eq!(incoming.bytes, received.as_bytes());
+        assert!(incoming.timestamp >= outgoing.timestamp);
+    }
+
+    #[tokio::test]
+    async fn joined_tasks() {
+        let (_rx, tx, _notify, transport, server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+        let tasks = transport.take_tasks().unwrap();
+        assert!(transport.take_tasks().is_none());
+        assert!(tasks.stderr.is_some());
+        assert!(!tasks.recv.is_finished());
+
+        // the recv task ends with the stream, the send task with the channel
+        drop(server);
+        drop(tx);
+        assert!(tasks.join().await);
+    }
+
+    #[tokio::test]
+    async fn close_wedged_server() {
+        let (mut rx, tx, _notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+        let tasks = transport.take_tasks().unwrap();
+
+        // the server never reads, so writing this blocks on the full pipe
+        let mut params = serde_json::Map::new();
+        params.insert("padding".to_string(), Value::from("x".repeat(256 * 1024)));
+        let (chan, mut response) = tokio::sync::mpsc::channel(1);
+        tx.send(Payload::Request {
+            chan,
+            value: jsonrpc::MethodCall {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: "initialize".to_string(),
+                params: jsonrpc::Params::Map(params),
+                id: jsonrpc::Id::Num(0),
+            },
+            group: None,
+        })
+        .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert!(!tasks.send.is_finished());
+
+        transport.close();
+        let joined = tokio::time::timeout(std::time::Duration::from_secs(5), tasks.join());
+        assert!(joined.await.unwrap());
+        assert!(matches!(
+            response.recv().await,
+            Some(Err(Error::TransportClosed))
+        ));
+        assert_eq!(method(rx.recv().await.unwrap().1), "exit");
+        // the server reads EOF once what was written before is consumed
+        let mut written = Vec::new();
+        server.reader.read_to_end(&mut written).await.unwrap();
+        assert!(written.len() < 256 * 1024);
+    }
 
-                    // drain the pending queue and send payloads to server
-                    for msg in pending_messages.drain(..) {
-                        log::info!("Draining pending message {:?}", msg);
-                        match transport.send_payload_to_server(&mut server_stdin, msg).await {
-                            Ok(_) => {}
-                            Err(err) => {
-                                error!("{language_server_name} err: <- {err:?}");
-                            }
-                        }
-                    }
-                }
-                msg = client_rx.recv() => {
-                    if let Some(msg) = msg {
-                        if is_pending && is_shutdown(&msg) {
-                            log::info!("Language server not initialized, shutting down");
-                            break;
-                        } else if is_pending && !is_initialize(&msg) {
-                            // ignore notifications
-                            if let Payload::Notification(_) = msg {
-                                continue;
-                            }
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_of_hung_server_times_out() {
+        let (mut rx, tx, notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
 
-                            log::info!("Language server not initialized, delaying request");
-                            pending_messages.push(msg);
-                        } else {
-                            match transport.send_payload_to_server(&mut server_stdin, msg).await {
-                                Ok(_) => {}
-                                Err(err) => {
-                                    error!("{} err: <- {err:?}", transport.name);
-                                }
-                            }
-                        }
-                    } else {
-                        // channel closed
-                        break;
-                    }
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        let (hover, mut response) = request(1, "textDocument/hover");
+        tx.send(hover).unwrap();
+        server.recv().await;
+
+        // the server never closes its end
+        assert_eq!(
+            transport.shutdown(std::time::Duration::from_secs(5)).await,
+            ShutdownOutcome::TimedOut
+        );
+        assert!(matches!(
+            response.recv().await,
+            Some(Err(Error::StreamClosed))
+        ));
+        assert_eq!(method(rx.recv().await.unwrap().1), "exit");
+        transport.lifecycle.finished().await;
+    }
+
+    #[tokio::test]
+    async fn duplicate_initialize_response() {
+        let (mut rx, tx, _notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        assert_eq!(server.recv().await["method"], "initialize");
+        server
+            .send(r#"{"jsonrpc":"2.0","result":{"capabilities":{"hoverProvider":true}},"id":0}"#)
+            .await;
+        server
+            .send(r#"{"jsonrpc":"2.0","result":{"capabilities":{}},"id":0}"#)
+            .await;
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{}}"#)
+            .await;
+        assert_eq!(method(rx.recv().await.unwrap().1), "window/logMessage");
+
+        // only the first response is delivered
+        let result = response.recv().await.unwrap().unwrap();
+        assert_eq!(result["capabilities"]["hoverProvider"], true);
+        assert!(response.try_recv().is_err());
+        assert!(transport
+            .startup
+            .is_initialize_response(&jsonrpc::Id::Num(0)));
+    }
+
+    #[tokio::test]
+    async fn batched_notifications() {
+        let config = TransportConfig::default().notification_batch_size(Some(2));
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+        let (payload, _response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        for method in ["$/first", "$/second", "$/third"] {
+            tx.send(Payload::Notification(jsonrpc::Notification {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: method.to_string(),
+                params: jsonrpc::Params::None,
+            }))
+            .unwrap();
+        }
+        let (payload, _response) = request(1, "textDocument/hover");
+        tx.send(payload).unwrap();
+
+        assert_eq!(
+            server.recv_body().await,
+            r#"[{"jsonrpc":"2.0","method":"$/first"},{"jsonrpc":"2.0","method":"$/second"}]"#
+        );
+        // the request ends the batch, the notification before it is sent on its own
+        assert_eq!(
+            server.recv_body().await,
+            r#"{"jsonrpc":"2.0","method":"$/third"}"#
+        );
+        assert_eq!(server.recv().await["method"], "textDocument/hover");
+    }
+
+    #[tokio::test]
+    async fn failover_to_standby() {
+        let primary = start(TransportConfig::default(), |w| Box::new(w));
+        let standby = start(TransportConfig::default(), |w| Box::new(w));
+        let (mut primary_server, mut standby_server) = (primary.4, standby.4);
+        let (mut rx, tx, notify) = failover::start_with_standby(
+            (primary.0, primary.1, primary.2, primary.3),
+            (standby.0, standby.1, standby.2, standby.3),
+        );
+
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        assert_eq!(primary_server.recv().await["method"], "initialize");
+        primary_server
+            .send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#)
+            .await;
+        response.recv().await.unwrap().unwrap();
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        // losing the primary re-initializes the standby behind the same channels
+        drop(primary_server);
+        let initialize = standby_server.recv().await;
+        assert_eq!(initialize["method"], "initialize");
+        standby_server
+            .send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#)
+            .await;
+        assert_eq!(standby_server.recv().await["method"], "initialized");
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        let (payload, _response) = request(1, "textDocument/hover");
+        tx.send(payload).unwrap();
+        assert_eq!(standby_server.recv().await["method"], "textDocument/hover");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_crashed_server() {
+        use reconnect::{ReconnectingTransport, RestartPolicy};
+        use std::time::Duration;
+
+        let (servers_tx, mut servers) = unbounded_channel();
+        let spawn = move || {
+            let (rx, tx, notify, transport, server) =
+                start(TransportConfig::default(), |w| Box::new(w));
+            servers_tx.send(server).unwrap();
+            future::ready(Ok((rx, tx, notify, transport)))
+        };
+        let policy = RestartPolicy {
+            max_restarts: 1,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(1),
+        };
+        let (mut rx, tx, notify, reconnecting) =
+            ReconnectingTransport::start(spawn, policy).await.unwrap();
+        let mut server = servers.recv().await.unwrap();
+
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        assert_eq!(server.recv().await["method"], "initialize");
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        response.recv().await.unwrap().unwrap();
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        let (payload, mut in_flight) = request(1, "textDocument/hover");
+        tx.send(payload).unwrap();
+        server.recv().await;
+
+        // the crash fails the request in flight, then the server is restarted after the backoff
+        let crashed = tokio::time::Instant::now();
+        drop(server);
+        assert!(matches!(
+            in_flight.recv().await.unwrap(),
+            Err(Error::StreamClosed)
+        ));
+        let mut server = servers.recv().await.unwrap();
+        assert!(crashed.elapsed() >= Duration::from_secs(1));
+        assert_eq!(reconnecting.restarts(), 1);
+        assert_eq!(server.recv().await["method"], "initialize");
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        assert_eq!(server.recv().await["method"], "initialized");
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        let (payload, _response) = request(2, "textDocument/hover");
+        tx.send(payload).unwrap();
+        assert_eq!(server.recv().await["id"], 2);
+
+        // out of restarts, the crash is reported
+        drop(server);
+        assert_eq!(method(rx.recv().await.unwrap().1), "exit");
+        assert!(rx.recv().await.is_none());
+        assert_eq!(reconnecting.restarts(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn initialize_signal_is_never_missed() {
+        for i in 0..50 {
+            let (mut rx, tx, notify, _transport, mut server) =
+                start(TransportConfig::default(), |w| Box::new(w));
+            // competes for the signal like the client's deferred workspace folder handling
+            let waiter = tokio::spawn({
+                let notify = notify.clone();
+                async move { notify.notified().await }
+            });
+
+            let (payload, _response) = request(0, "initialize");
+            tx.send(payload).unwrap();
+            assert_eq!(server.recv().await["method"], "initialize");
+            let (payload, _response) = request(1, "textDocument/hover");
+            tx.send(payload).unwrap();
+            match i % 3 {
+                // before the send task saw the held back request
+                0 => notify.notify(),
+                // from another thread while the send task is busy
+                1 => {
+                    let notify = notify.clone();
+                    tokio::spawn(async move { notify.notify() });
+                }
+                _ => {
+                    tokio::task::yield_now().await;
+                    notify.notify();
                 }
             }
+            server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+
+            assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+            assert_eq!(server.recv().await["method"], "textDocument/hover");
+            waiter.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn offloaded_serialization() {
+        let config = TransportConfig::default().serialize_offload_threshold(Some(16));
+        let (_rx, tx, _notify, _transport, mut server) = start(config, |w| Box::new(w));
+
+        let (mut large, _large_response) = request(0, "initialize");
+        if let Payload::Request { value, .. } = &mut large {
+            let mut params = serde_json::Map::new();
+            params.insert("text".to_string(), Value::from("x".repeat(64)));
+            value.params = jsonrpc::Params::Map(params);
+        }
+        let (small, _small_response) = request(1, "initialize");
+        tx.send(large).unwrap();
+        tx.send(small).unwrap();
+
+        // the offloaded message is still written before the following ones
+        let sent = server.recv().await;
+        assert_eq!(sent["params"]["text"], "x".repeat(64));
+        assert_eq!(server.recv().await["id"], 1);
+    }
+
+    #[tokio::test]
+    async fn wait_for_notification() {
+        let (rx, _tx, _notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+
+        let waiting = tokio::spawn({
+            let transport = transport.clone();
+            async move {
+                transport
+                    .wait_for_notification("$/progress", std::time::Duration::from_secs(5), |notification| {
+                        matches!(&notification.params, jsonrpc::Params::Map(params) if params["value"]["kind"] == "end")
+                    })
+                    .await
+            }
+        });
+        tokio::task::yield_now().await;
+
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"$/progress","params":{"token":1,"value":{"kind":"begin"}}}"#)
+            .await;
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"$/progress","params":{"token":1,"value":{"kind":"end"}}}"#)
+            .await;
+        let notification = waiting.await.unwrap().unwrap();
+        assert_eq!(notification.method, "$/progress");
+
+        // the notifications are still delivered to the consumer
+        let methods: Vec<_> = rx.take(2).map(|(_, call)| method(call)).collect().await;
+        assert_eq!(methods, ["$/progress", "$/progress"]);
+
+        let timed_out = transport
+            .wait_for_notification("$/progress", std::time::Duration::from_millis(10), |_| true)
+            .await;
+        assert!(timed_out.is_none());
+    }
+
+    #[tokio::test]
+    async fn busy_transitions() {
+        let (_rx, tx, _notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+        let mut busy = transport.busy();
+        assert!(!*busy.borrow_and_update());
+
+        let (first, mut first_response) = request(0, "initialize");
+        let (second, mut second_response) = request(1, "initialize");
+        tx.send(first).unwrap();
+        tx.send(second).unwrap();
+        server.recv().await;
+        server.recv().await;
+        busy.changed().await.unwrap();
+        assert!(*busy.borrow_and_update());
+
+        // answering one of the two requests doesn't change anything
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":0}"#)
+            .await;
+        first_response.recv().await.unwrap().unwrap();
+        assert!(!busy.has_changed().unwrap());
+
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":1}"#)
+            .await;
+        second_response.recv().await.unwrap().unwrap();
+        busy.changed().await.unwrap();
+        assert!(!*busy.borrow_and_update());
+    }
+
+    #[tokio::test]
+    async fn pending_count() {
+        let (mut rx, tx, notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+        assert_eq!(transport.pending_count(), 0);
+        assert!(transport.oldest_pending().is_none());
+
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        assert_eq!(transport.pending_count(), 1);
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        response.recv().await.unwrap().unwrap();
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        assert_eq!(transport.pending_count(), 0);
+
+        let (first, mut first_response) = request(1, "workspace/symbol");
+        let (second, mut second_response) = request(2, "textDocument/hover");
+        tx.send(first).unwrap();
+        tx.send(second).unwrap();
+        server.recv().await;
+        server.recv().await;
+        assert_eq!(transport.pending_count(), 2);
+        let (method, _) = transport.oldest_pending().unwrap();
+        assert_eq!(method, "workspace/symbol");
+
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":1}"#)
+            .await;
+        first_response.recv().await.unwrap().unwrap();
+        assert_eq!(transport.pending_count(), 1);
+        let (method, _) = transport.oldest_pending().unwrap();
+        assert_eq!(method, "textDocument/hover");
+
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":2}"#)
+            .await;
+        second_response.recv().await.unwrap().unwrap();
+        assert_eq!(transport.pending_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn newline_json_codec() {
+        let config = TransportConfig::default().codec(Arc::new(NewlineJsonCodec));
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        let mut line = String::new();
+        server.reader.read_line(&mut line).await.unwrap();
+        assert_eq!(
+            line,
+            "{\"jsonrpc\":\"2.0\",\"method\":\"initialize\",\"id\":0}\n"
+        );
+        server
+            .writer
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"result\":{},\"id\":0}\n")
+            .await
+            .unwrap();
+        response.recv().await.unwrap().unwrap();
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        server
+            .writer
+            .write_all(b"\n{\"jsonrpc\":\"2.0\",\"method\":\"window/logMessage\"}\n")
+            .await
+            .unwrap();
+        assert_eq!(method(rx.recv().await.unwrap().1), "window/logMessage");
+    }
+
+    #[tokio::test]
+    async fn request_future() {
+        let (mut rx, _tx, notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+        let response = transport.request("initialize", jsonrpc::Params::None);
+        let request = server.recv().await;
+        assert_eq!(request["method"], "initialize");
+        server
+            .send(&format!(
+                r#"{{"jsonrpc":"2.0","result":{{"capabilities":{{}}}},"id":{}}}"#,
+                request["id"]
+            ))
+            .await;
+        assert_eq!(
+            response.await.unwrap(),
+            serde_json::json!({ "capabilities": {} })
+        );
+
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        // the request is sent even if the future is never awaited
+        drop(transport.request("workspace/symbol", jsonrpc::Params::None));
+        assert_eq!(server.recv().await["method"], "workspace/symbol");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn gzip_bodies() {
+        let config = TransportConfig::default()
+            .gzip_threshold(Some(64))
+            .serialize_offload_threshold(Some(64));
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        server
+            .send(r#"{"jsonrpc":"2.0","result":{"capabilities":{"experimental":{"contentEncodings":["gzip"]}}},"id":0}"#)
+            .await;
+        response.recv().await.unwrap().unwrap();
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        // a compressed body from the server
+        let body = encoding::gzip(br#"{"jsonrpc":"2.0","method":"compressed"}"#).unwrap();
+        let mut input = format!(
+            "Content-Length: {}\r\nContent-Encoding: gzip\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        input.extend(body);
+        server.writer.write_all(&input).await.unwrap();
+        assert_eq!(method(rx.recv().await.unwrap().1), "compressed");
+
+        // bodies that can't be decoded are skipped like bodies that aren't JSON
+        for encoding in ["br", "gzip"] {
+            let body = "not compressed";
+            let input = format!(
+                "Content-Length: {}\r\nContent-Encoding: {encoding}\r\n\r\n{body}",
+                body.len()
+            );
+            server.writer.write_all(input.as_bytes()).await.unwrap();
+        }
+        server.send(r#"{"jsonrpc":"2.0","method":"after"}"#).await;
+        assert_eq!(method(rx.recv().await.unwrap().1), "after");
+
+        // a large body to the server, small ones are sent as they are
+        let (small, _small_response) = request(1, "a");
+        let (large, _large_response) = request(2, &"b".repeat(100));
+        tx.send(small).unwrap();
+        tx.send(large).unwrap();
+        assert_eq!(server.recv().await["method"], "a");
+        let mut headers = String::new();
+        while !headers.ends_with("\r\n\r\n") {
+            server.reader.read_line(&mut headers).await.unwrap();
+        }
+        let (length, encoding) = headers
+            .strip_prefix("Content-Length: ")
+            .unwrap()
+            .split_once("\r\n")
+            .unwrap();
+        assert_eq!(encoding, "Content-Encoding: gzip\r\n\r\n");
+        let mut content = vec![0; length.parse().unwrap()];
+        server.reader.read_exact(&mut content).await.unwrap();
+        encoding::ContentEncoding::Gzip
+            .decode(&mut content, usize::MAX)
+            .unwrap();
+        let sent: Value = serde_json::from_slice(&content).unwrap();
+        assert_eq!(sent["method"], "b".repeat(100));
+    }
+
+    #[tokio::test]
+    async fn transport_state() {
+        let (mut rx, tx, notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+        assert_eq!(transport.state(), TransportState::Uninitialized);
+
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        assert_eq!(transport.state(), TransportState::Initializing);
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        response.recv().await.unwrap().unwrap();
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        assert_eq!(transport.state(), TransportState::Ready);
+
+        let (payload, _response) = request(1, "shutdown");
+        tx.send(payload).unwrap();
+        server.recv().await;
+        assert_eq!(transport.state(), TransportState::Draining);
+
+        drop(server);
+        assert_eq!(method(rx.recv().await.unwrap().1), "exit");
+        assert_eq!(transport.state(), TransportState::Closed);
+    }
+
+    #[tokio::test]
+    async fn payload_json_matches_wire() {
+        let (_rx, tx, notify, _transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+
+        let (mut initialize, _response) = request(0, "initialize");
+        if let Payload::Request { value, .. } = &mut initialize {
+            value.params = jsonrpc::Params::Array(vec![Value::from("a\"b"), Value::from(1.5)]);
+        }
+        let expected = initialize.to_json_string().unwrap();
+        tx.send(initialize).unwrap();
+        assert_eq!(server.recv_body().await, expected);
+        notify.notify();
+
+        let payloads = [
+            Payload::Notification(jsonrpc::Notification {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: "initialized".to_string(),
+                params: jsonrpc::Params::Map(Default::default()),
+            }),
+            Payload::Response(jsonrpc::Output::Failure(jsonrpc::Failure {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                error: jsonrpc::Error::invalid_params("nope"),
+                id: jsonrpc::Id::Str("1".to_string()),
+            })),
+            Payload::DetachedRequest(jsonrpc::MethodCall {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: "shutdown".to_string(),
+                params: jsonrpc::Params::None,
+                id: jsonrpc::Id::Num(1),
+            }),
+        ];
+        for payload in payloads {
+            let expected = payload.to_json_string().unwrap();
+            tx.send(payload).unwrap();
+            assert_eq!(server.recv_body().await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn serialized_requests() {
+        let config = TransportConfig::default().serialize_requests(true);
+        let (_rx, tx, notify, transport, mut server) = start(config, |w| Box::new(w));
+
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+
+        let (first, _first_response) = request(1, "textDocument/hover");
+        let (second, mut second_response) = request(2, "textDocument/hover");
+        tx.send(first).unwrap();
+        tx.send(second).unwrap();
+        tx.send(Payload::Notification(jsonrpc::Notification {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: "$/ping".to_string(),
+            params: jsonrpc::Params::None,
+        }))
+        .unwrap();
+        let cancel = cancel::notification(&jsonrpc::Id::Num(2)).unwrap();
+        tx.send(Payload::Notification(cancel)).unwrap();
+
+        assert_eq!(server.recv().await["id"], 1);
+        // the notification isn't held back by the queued request
+        assert_eq!(server.recv().await["method"], "$/ping");
+        assert_eq!(transport.pending_requests.lock().len(), 1);
+
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":1}"#)
+            .await;
+        assert_eq!(server.recv().await["id"], 2);
+        // but the cancellation of the queued request is, and follows it right away
+        let cancel = server.recv().await;
+        assert_eq!(
+            (&cancel["method"], &cancel["params"]["id"]),
+            (&serde_json::json!("$/cancelRequest"), &serde_json::json!(2))
+        );
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":2}"#)
+            .await;
+        second_response.recv().await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancellation_waits_for_initialization() {
+        let (mut rx, tx, notify, _transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+
+        let (hover, _hover_response) = request(1, "textDocument/hover");
+        tx.send(hover).unwrap();
+        let cancel = cancel::notification(&jsonrpc::Id::Num(1)).unwrap();
+        tx.send(Payload::Notification(cancel)).unwrap();
+
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        // held back with its request rather than dropped like other notifications
+        assert_eq!(server.recv().await["id"], 1);
+        let cancel = server.recv().await;
+        assert_eq!(
+            (&cancel["method"], &cancel["params"]["id"]),
+            (&serde_json::json!("$/cancelRequest"), &serde_json::json!(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limit_keeps_progress_end() {
+        use std::time::Duration;
+
+        let config = TransportConfig::default().limit_notifications(
+            "$/progress",
+            1,
+            Duration::from_secs(60),
+        );
+        let (mut rx, _tx, _notify, _transport, mut server) = start(config, |w| Box::new(w));
+
+        for kind in ["begin", "report", "end"] {
+            server
+                .send(&format!(
+                    r#"{{"jsonrpc":"2.0","method":"$/progress","params":{{"token":1,"value":{{"kind":"{kind}"}}}}}}"#
+                ))
+                .await;
+        }
+        for kind in ["begin", "end"] {
+            let (_, jsonrpc::Call::Notification(notification)) = rx.recv().await.unwrap() else {
+                panic!("expected a notification");
+            };
+            let jsonrpc::Params::Map(params) = notification.params else {
+                panic!("expected parameters");
+            };
+            assert_eq!(params["value"]["kind"], kind);
+        }
+    }
+
+    #[tokio::test]
+    async fn content_length_undercount() {
+        let (mut rx, _tx, _notify, _transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+
+        let body = r#"{"jsonrpc":"2.0","method":"first","params":{"a":[1,2]}}"#;
+        let mut input = format!("Content-Length: {}\r\n\r\n{body}", body.len() - 3).into_bytes();
+        input.extend(framed(r#"{"jsonrpc":"2.0","method":"second"}"#));
+        server.writer.write_all(&input).await.unwrap();
+
+        assert_eq!(method(rx.recv().await.unwrap().1), "first");
+        assert_eq!(method(rx.recv().await.unwrap().1), "second");
+
+        // a truncated body directly followed by a header is left to the parse step
+        let mut input = framed(&body[..body.len() - 3]);
+        input.extend(framed(r#"{"jsonrpc":"2.0","method":"third"}"#));
+        server.writer.write_all(&input).await.unwrap();
+        assert_eq!(method(rx.recv().await.unwrap().1), "third");
+    }
+
+    #[tokio::test]
+    async fn request_handler() {
+        use lsp::request::{WorkDoneProgressCreate, WorkspaceConfiguration};
+
+        let router =
+            RequestRouter::new().route::<WorkspaceConfiguration, _, _>(|params| async move {
+                Ok(vec![Value::from(params.items.len()); params.items.len()])
+            });
+        let config = TransportConfig::default().request_handler(Arc::new(router));
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+
+        // responses are only sent once the server is initialized
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"workspace/configuration","params":{"items":[{},{}]},"id":1}"#)
+            .await;
+        assert_eq!(
+            server.recv().await,
+            serde_json::json!({"jsonrpc": "2.0", "result": [2, 2], "id": 1})
+        );
+
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"workspace/configuration","params":{},"id":2}"#)
+            .await;
+        assert_eq!(server.recv().await["error"]["code"], -32602);
+
+        // requests without a route are still forwarded
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"window/workDoneProgress/create","params":{"token":1},"id":3}"#)
+            .await;
+        assert_eq!(
+            method(rx.recv().await.unwrap().1),
+            <WorkDoneProgressCreate as lsp::request::Request>::METHOD
+        );
+    }
+
+    #[cfg(feature = "inbound-hook")]
+    #[tokio::test]
+    async fn inbound_hook() {
+        let (hook, mut observed) = tokio::sync::mpsc::channel(1);
+        let config = TransportConfig::default().inbound_hook(hook);
+        let (mut rx, _tx, _notify, _transport, mut server) = start(config, |w| Box::new(w));
+
+        server
+            .send(r#"[{"jsonrpc":"2.0","method":"first"},{"jsonrpc":"2.0","method":"second"}]"#)
+            .await;
+        // the consumer still receives everything while the full hook drops the second message
+        assert_eq!(method(rx.recv().await.unwrap().1), "first");
+        assert_eq!(method(rx.recv().await.unwrap().1), "second");
+        match observed.recv().await.unwrap() {
+            InboundMessage::Call(call) => assert_eq!(method(call), "first"),
+            msg => panic!("unexpected {msg:?}"),
+        }
+        assert!(observed.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn server_failed_to_start() {
+        let (server_stdin_tx, _server_stdin_rx) = tokio::io::duplex(1024);
+        let (server_stdout_tx, server_stdout_rx) = tokio::io::duplex(1024);
+        let (mut server_stderr_tx, server_stderr_rx) = tokio::io::duplex(1024);
+        let (_rx, tx, _notify, transport) = Transport::start(
+            tokio::io::BufReader::new(server_stdout_rx),
+            server_stdin_tx,
+            tokio::io::BufReader::new(server_stderr_rx),
+            LanguageServerId::default(),
+            "test".to_string(),
+            TransportConfig::default(),
+        );
+
+        let (initialize, mut response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        // wait for the initialize request to be sent
+        while transport.pending_requests.lock().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        server_stderr_tx
+            .write_all(b"error: missing configuration\n")
+            .await
+            .unwrap();
+        drop(server_stderr_tx);
+        drop(server_stdout_tx);
+
+        match response.recv().await.unwrap() {
+            Err(Error::ServerFailedToStart { stderr }) => {
+                assert_eq!(stderr, "error: missing configuration")
+            }
+            result => panic!("unexpected {result:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn replace_reader() {
+        let (mut rx, _tx, _notify, transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+
+        server.send(r#"{"jsonrpc":"2.0","method":"first"}"#).await;
+        assert_eq!(method(rx.recv().await.unwrap().1), "first");
+
+        // the rest of this message never arrives
+        server
+            .writer
+            .write_all(b"Content-Length: 40\r\n\r\n{\"jsonrpc\"")
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        transport.pause_reader().unwrap();
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        transport
+            .replace_reader(tokio::io::BufReader::new(reader))
+            .unwrap();
+        writer
+            .write_all(&framed(r#"{"jsonrpc":"2.0","method":"second"}"#))
+            .await
+            .unwrap();
+        assert_eq!(method(rx.recv().await.unwrap().1), "second");
+
+        // the previous stream was dropped
+        assert!(server.writer.write_all(b"\r\n").await.is_err());
+    }
+
+    #[test]
+    fn shrink_drained_map() {
+        let mut map: HashMap<u64, ()> = (0..1000).map(|i| (i, ())).collect();
+        let large = map.capacity();
+
+        map.retain(|&i, _| i < large as u64 / 2);
+        shrink_drained(&mut map, Some(64));
+        assert!(map.capacity() > large / 2, "half full maps are kept");
+
+        map.retain(|&i, _| i < 10);
+        shrink_drained(&mut map, None);
+        assert!(map.capacity() > large / 2);
+        shrink_drained(&mut map, Some(64));
+        assert!(map.capacity() < 64);
+        assert!(map.capacity() >= 20);
+    }
+
+    #[tokio::test]
+    async fn non_rpc_json() {
+        let body = r#""Listening on port 8080""#;
+        assert!(matches!(
+            ServerMessage::parse(body.as_bytes()),
+            Err(Error::NonRpcJson(preview)) if preview == body
+        ));
+        assert!(matches!(
+            ServerMessage::parse(b"Listening"),
+            Err(Error::Parse(_))
+        ));
+
+        for (handling, expected) in [
+            (NonRpcJsonHandling::Close, &["exit"][..]),
+            (NonRpcJsonHandling::Skip, &["next", "exit"][..]),
+        ] {
+            let (transport, client_tx, mut client_rx) =
+                transport(TransportConfig::default().non_rpc_json(handling));
+            let mut input = framed(body);
+            input.extend(framed(r#"{"jsonrpc":"2.0","method":"next"}"#));
+
+            let (_control, control_rx) = unbounded_channel();
+            let input = Box::new(std::io::Cursor::new(input));
+            Transport::recv(Arc::new(transport), input, control_rx, client_tx).await;
+            for expected in expected {
+                assert_eq!(method(client_rx.recv().await.unwrap().1), *expected);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn byte_quota() {
+        let config = TransportConfig::default().byte_quota(Some(100));
+        let (mut rx, tx, _notify, _transport, mut server) = start(config, |w| Box::new(w));
+
+        let (initialize, mut response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server
+            .send(&format!(
+                r#"{{"jsonrpc":"2.0","method":"log","params":["{}"]}}"#,
+                "x".repeat(40)
+            ))
+            .await;
+
+        assert!(matches!(
+            response.recv().await.unwrap(),
+            Err(Error::QuotaExceeded { limit: 100 })
+        ));
+        assert_eq!(method(rx.recv().await.unwrap().1), "exit");
+    }
+
+    #[tokio::test]
+    async fn header_too_long() {
+        let (default, _, _) = transport(TransportConfig::default());
+        let input = vec![b'x'; 10 * 1024 * 1024];
+        let (mut buffer, mut content) = (String::new(), Vec::new());
+        let result = default
+            .recv_server_message(&mut input.as_slice(), &mut buffer, &mut content)
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::HeaderTooLong { limit }) if limit == DEFAULT_MAX_HEADER_LINE
+        ));
+        // only the limit was buffered
+        assert_eq!(buffer.len(), DEFAULT_MAX_HEADER_LINE + 1);
+        assert!(buffer.capacity() < 2 * 1024 * 1024);
+
+        // garbage lines up to the limit are still skipped
+        let (transport, _, _) = transport(TransportConfig::default().max_header_line(24));
+        let mut input = format!("{}\n", "x".repeat(24)).into_bytes();
+        input.extend(framed(r#"{"jsonrpc":"2.0","method":"log"}"#));
+        let msg = transport
+            .recv_server_message(&mut input.as_slice(), &mut buffer, &mut content)
+            .await
+            .unwrap();
+        assert!(matches!(msg, ServerMessage::Call(_)));
+        let input = format!("{}\n", "x".repeat(25));
+        assert!(matches!(
+            transport
+                .recv_server_message(&mut input.as_bytes(), &mut buffer, &mut content)
+                .await,
+            Err(Error::HeaderTooLong { limit: 24 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn message_too_large() {
+        let config = TransportConfig::default().max_message_size(1024);
+        let (mut rx, tx, _notify, _transport, mut server) = start(config, |w| Box::new(w));
+
+        let (initialize, mut response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server
+            .writer
+            .write_all(b"Content-Length: 999999999999\r\n\r\n{")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            response.recv().await.unwrap(),
+            Err(Error::MessageTooLarge {
+                advertised: 999999999999,
+                limit: 1024
+            })
+        ));
+        assert_eq!(method(rx.recv().await.unwrap().1), "exit");
+    }
+
+    #[tokio::test]
+    async fn latency_channel() {
+        let (latencies_tx, mut latencies) = tokio::sync::mpsc::channel(1);
+        let config = TransportConfig::default().latency_channel(latencies_tx);
+        let (mut rx, tx, notify, transport, mut server) = start(config, |w| Box::new(w));
+
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        assert_eq!(latencies.recv().await.unwrap().0, "initialize");
+
+        let (hover, mut response) = request(1, "textDocument/hover");
+        tx.send(hover).unwrap();
+        server.recv().await;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        server
+            .send(r#"{"jsonrpc":"2.0","result":null,"id":1}"#)
+            .await;
+        response.recv().await.unwrap().unwrap();
+        let (method, latency) = latencies.recv().await.unwrap();
+        assert_eq!(method, "textDocument/hover");
+        assert!(latency >= std::time::Duration::from_millis(10));
+        assert!(transport.pending_requests.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stderr_channel() {
+        let (lines_tx, mut lines) = tokio::sync::mpsc::channel(1);
+        let config = TransportConfig::default().stderr_channel(lines_tx);
+        let (mut server_stderr_tx, server_stderr_rx) = tokio::io::duplex(1024);
+        let (_server_stdout_tx, server_stdout_rx) = tokio::io::duplex(1024);
+        let (_rx, _tx, _notify, _transport) = Transport::start(
+            tokio::io::BufReader::new(server_stdout_rx),
+            tokio::io::sink(),
+            tokio::io::BufReader::new(server_stderr_rx),
+            LanguageServerId::default(),
+            "test".to_string(),
+            config,
+        );
+
+        server_stderr_tx.write_all(b"indexing\r\n").await.unwrap();
+        assert_eq!(lines.recv().await.unwrap(), "indexing");
+
+        // a full channel drops lines rather than blocking the transport
+        server_stderr_tx.write_all(b"one\ntwo\n").await.unwrap();
+        assert_eq!(lines.recv().await.unwrap(), "one");
+        server_stderr_tx.write_all(b"three\n").await.unwrap();
+        assert_eq!(lines.recv().await.unwrap(), "three");
+    }
+
+    #[tokio::test]
+    async fn dropped_stderr_receiver() {
+        let (lines_tx, lines) = tokio::sync::mpsc::channel(8);
+        let config = TransportConfig::default()
+            .stderr_channel(lines_tx)
+            .stderr_history(Some(8));
+        let (mut server_stderr_tx, server_stderr_rx) = tokio::io::duplex(1024);
+        let (server_stdin_tx, server_stdin_rx) = tokio::io::duplex(64 * 1024);
+        let (server_stdout_tx, server_stdout_rx) = tokio::io::duplex(64 * 1024);
+        let (mut rx, tx, notify, transport) = Transport::start(
+            tokio::io::BufReader::new(server_stdout_rx),
+            server_stdin_tx,
+            tokio::io::BufReader::new(server_stderr_rx),
+            LanguageServerId::default(),
+            "test".to_string(),
+            config,
+        );
+        let mut server = FakeServer {
+            reader: tokio::io::BufReader::new(server_stdin_rx),
+            writer: server_stdout_tx,
+        };
+
+        // forwarding stops, but stderr is still read and the transport keeps running
+        drop(lines);
+        server_stderr_tx.write_all(b"one\ntwo\n").await.unwrap();
+        let (initialize, mut response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+        assert!(response.recv().await.unwrap().is_ok());
+        while transport.recent_stderr().len() < 2 {
+            tokio::task::yield_now().await;
         }
+        assert_eq!(transport.recent_stderr(), ["one", "two"]);
+    }
+
+    #[tokio::test]
+    async fn stderr_history_after_crash() {
+        let (exited_tx, mut exited) = unbounded_channel();
+        let config = TransportConfig::default()
+            .stderr_history(Some(2))
+            .on_exit(move |reason| {
+                let _ = exited_tx.send(reason);
+            });
+        let (mut server_stderr_tx, server_stderr_rx) = tokio::io::duplex(1024);
+        let (server_stdout_tx, server_stdout_rx) = tokio::io::duplex(1024);
+        let (_rx, tx, _notify, transport) = Transport::start(
+            tokio::io::BufReader::new(server_stdout_rx),
+            tokio::io::sink(),
+            tokio::io::BufReader::new(server_stderr_rx),
+            LanguageServerId::default(),
+            "test".to_string(),
+            config,
+        );
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+
+        // the oldest line is evicted
+        server_stderr_tx
+            .write_all(b"loading config\nparsing workspace\npanicked at src/main.rs:1\n")
+            .await
+            .unwrap();
+        drop(server_stderr_tx);
+        drop(server_stdout_tx);
+
+        assert!(matches!(
+            exited.recv().await,
+            Some(ExitReason::FailedToStart { .. })
+        ));
+        assert_eq!(
+            transport.recent_stderr(),
+            ["parsing workspace", "panicked at src/main.rs:1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn collapsed_stderr_history() {
+        let (lines_tx, mut lines) = tokio::sync::mpsc::channel(8);
+        let config = TransportConfig::default()
+            .stderr_channel(lines_tx)
+            .stderr_history(Some(2))
+            .collapse_repeated_stderr(true);
+        let (mut server_stderr_tx, server_stderr_rx) = tokio::io::duplex(1024);
+        let (_server_stdout_tx, server_stdout_rx) = tokio::io::duplex(1024);
+        let (_rx, _tx, _notify, transport) = Transport::start(
+            tokio::io::BufReader::new(server_stdout_rx),
+            tokio::io::sink(),
+            tokio::io::BufReader::new(server_stderr_rx),
+            LanguageServerId::default(),
+            "test".to_string(),
+            config,
+        );
+
+        server_stderr_tx
+            .write_all(b"starting\nretrying\r\nretrying\nretrying\n")
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            lines.recv().await.unwrap();
+        }
+        // the history holds the line repeated in a loop without losing the one before it
+        assert_eq!(transport.recent_stderr(), ["starting", "retrying (×3)"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn health_check() {
+        use std::time::Duration;
+
+        let check = HealthCheck {
+            idle: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+        };
+        let config = TransportConfig::default().health_check(Some(check));
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        // idle without pending requests is fine
+        tokio::time::sleep(Duration::from_secs(60)).await;
+
+        let (hover, mut response) = request(1, "textDocument/hover");
+        tx.send(hover).unwrap();
+        server.recv().await;
+        let started = tokio::time::Instant::now();
+        let ping = server.recv().await;
+        assert_eq!(ping["method"], "$/helix/ping");
+        assert!(started.elapsed() >= check.idle);
+
+        assert!(matches!(
+            response.recv().await.unwrap(),
+            Err(Error::ServerUnresponsive)
+        ));
+        assert!(started.elapsed() >= check.idle + check.timeout);
+        assert_eq!(method(rx.recv().await.unwrap().1), "exit");
+    }
+
+    #[tokio::test]
+    async fn id_reuse() {
+        let (mut rx, tx, notify, _transport, mut server) =
+            start(TransportConfig::default(), |w| Box::new(w));
+
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        let (first, mut first_response) = request(1, "textDocument/hover");
+        tx.send(first).unwrap();
+        server.recv().await;
+        // a string id doesn't complete the request with the same numeric id
+        server
+            .send(r#"{"jsonrpc":"2.0","result":"other","id":"1"}"#)
+            .await;
+        server
+            .send(r#"{"jsonrpc":"2.0","result":"first","id":1}"#)
+            .await;
+        assert_eq!(first_response.recv().await.unwrap().unwrap(), "first");
+
+        // a duplicate of the response is discarded rather than kept for the next request
+        server
+            .send(r#"{"jsonrpc":"2.0","result":"stale","id":1}"#)
+            .await;
+        server.send(r#"{"jsonrpc":"2.0","method":"sync"}"#).await;
+        assert_eq!(method(rx.recv().await.unwrap().1), "sync");
+
+        let (second, mut second_response) = request(1, "textDocument/hover");
+        tx.send(second).unwrap();
+        server.recv().await;
+        server
+            .send(r#"{"jsonrpc":"2.0","result":"second","id":1}"#)
+            .await;
+        assert_eq!(second_response.recv().await.unwrap().unwrap(), "second");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_request_warning() {
+        use std::time::Duration;
+
+        let (slow_tx, mut slow) = tokio::sync::mpsc::unbounded_channel();
+        let config = TransportConfig::default()
+            .slow_request_warning(Some(SlowRequestWarning {
+                threshold: Duration::from_millis(30),
+                sweep_interval: Duration::from_millis(5),
+            }))
+            .on_slow_request(move |method, id, pending| {
+                let _ = slow_tx.send((method.to_string(), id.clone(), pending));
+            });
+        let (_rx, tx, _notify, transport, mut server) = start(config, |w| Box::new(w));
+        let (payload, mut response) = request(0, "initialize");
+        tx.send(payload).unwrap();
+        server.recv().await;
+
+        let (method, id, pending) = slow.recv().await.unwrap();
+        assert_eq!((method.as_str(), id), ("initialize", jsonrpc::Id::Num(0)));
+        assert!(pending >= Duration::from_millis(30));
+
+        // reported only once, and the request keeps waiting
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(slow.try_recv().is_err());
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        assert_eq!(
+            response.recv().await.unwrap().unwrap(),
+            serde_json::json!({})
+        );
+
+        // the sweeps end with the transport, letting go of it
+        let watchers = Arc::weak_count(&transport);
+        transport.close();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(Arc::weak_count(&transport), watchers - 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_sweep_interval() {
+        TransportConfig::default().slow_request_warning(Some(SlowRequestWarning {
+            threshold: std::time::Duration::from_secs(1),
+            sweep_interval: std::time::Duration::ZERO,
+        }));
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_health_check_idle() {
+        TransportConfig::default().health_check(Some(HealthCheck {
+            idle: std::time::Duration::ZERO,
+            timeout: std::time::Duration::from_secs(1),
+        }));
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_rate_limit_window() {
+        TransportConfig::default().limit_notifications("$/progress", 10, std::time::Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn dispatch_failure_stops_the_transport() {
+        let (reasons_tx, mut reasons) = unbounded_channel();
+        let config = TransportConfig::default().on_exit(move |reason| {
+            let _ = reasons_tx.send(reason);
+        });
+        let (rx, tx, _notify, transport, mut server) = start(config, |w| Box::new(w));
+        let (initialize, mut response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+
+        // the consumer is gone, so the notification can't be forwarded
+        drop(rx);
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"type":3,"message":"hi"}}"#)
+            .await;
+
+        assert!(matches!(reasons.recv().await, Some(ExitReason::Error(_))));
+        // the pending requests fail rather than waiting forever
+        assert!(matches!(
+            response.recv().await.unwrap(),
+            Err(Error::StreamClosed)
+        ));
+        assert_eq!(transport.state(), TransportState::Closed);
+    }
+
+    #[tokio::test]
+    async fn on_exit() {
+        let (reasons_tx, mut reasons) = unbounded_channel();
+        let config = TransportConfig::default().on_exit(move |reason| {
+            let _ = reasons_tx.send(reason);
+        });
+        let (mut rx, tx, notify, _transport, mut server) = start(config, |w| Box::new(w));
+
+        let (initialize, _response) = request(0, "initialize");
+        tx.send(initialize).unwrap();
+        server.recv().await;
+        server.send(r#"{"jsonrpc":"2.0","result":{},"id":0}"#).await;
+        notify.notify();
+        assert_eq!(method(rx.recv().await.unwrap().1), "initialized");
+
+        tx.send(Payload::Notification(jsonrpc::Notification {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: "exit".to_string(),
+            params: jsonrpc::Params::None,
+        }))
+        .unwrap();
+        server.recv().await;
+        drop(server);
+
+        assert_eq!(method(rx.recv().await.unwrap().1), "exit");
+        assert_eq!(reasons.recv().await, Some(ExitReason::Shutdown));
+        assert!(reasons.try_recv().is_err());
     }
 }
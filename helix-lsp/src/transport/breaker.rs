@@ -0,0 +1,95 @@
+//! Giving up on a server that writes nothing but garbage, see
+//! [`TransportConfig::parse_failure_limit`].
+//!
+//! [`TransportConfig::parse_failure_limit`]: super::TransportConfig::parse_failure_limit
+
+use crate::{Error, Result};
+use parking_lot::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How many messages in a row may fail to parse before the transport stops with
+/// [`Error::ProtocolBroken`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseFailureLimit {
+    /// The number of consecutive failures that stops the transport.
+    pub max_failures: usize,
+    /// The failures must happen within this long of the first one. A streak spread over a
+    /// longer time starts over, so a server occasionally writing a stray line is never stopped.
+    pub window: Duration,
+}
+
+impl Default for ParseFailureLimit {
+    fn default() -> Self {
+        Self {
+            max_failures: 10,
+            window: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The current streak of parse failures.
+#[derive(Debug)]
+pub(super) struct ParseFailures {
+    limit: ParseFailureLimit,
+    /// The number of failures in a row and when the first of them happened.
+    streak: Mutex<(usize, Instant)>,
+}
+
+impl ParseFailures {
+    pub(super) fn new(limit: ParseFailureLimit) -> Self {
+        Self {
+            limit,
+            streak: Mutex::new((0, Instant::now())),
+        }
+    }
+
+    /// Records a message that parsed, ending the streak.
+    pub(super) fn record_success(&self) {
+        self.streak.lock().0 = 0;
+    }
+
+    /// Records a message that failed to parse, failing once the limit is reached.
+    pub(super) fn record_failure(&self) -> Result<()> {
+        let mut streak = self.streak.lock();
+        let (failures, since) = &mut *streak;
+        let now = Instant::now();
+        if *failures == 0 || now.duration_since(*since) > self.limit.window {
+            *failures = 0;
+            *since = now;
+        }
+        *failures += 1;
+        if *failures >= self.limit.max_failures {
+            return Err(Error::ProtocolBroken {
+                failures: *failures,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_within_the_window() {
+        let failures = ParseFailures::new(ParseFailureLimit {
+            max_failures: 3,
+            window: Duration::from_secs(10),
+        });
+        assert!(failures.record_failure().is_ok());
+        assert!(failures.record_failure().is_ok());
+        failures.record_success();
+        assert!(failures.record_failure().is_ok());
+
+        // the streak starts over once it's older than the window
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(failures.record_failure().is_ok());
+        assert!(failures.record_failure().is_ok());
+        assert!(matches!(
+            failures.record_failure(),
+            Err(Error::ProtocolBroken { failures: 3 })
+        ));
+    }
+}
@@ -0,0 +1,84 @@
+//! `$/cancelRequest` in both directions, see [`Transport::cancel`](super::Transport::cancel).
+
+use crate::{jsonrpc, lsp};
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio::task::AbortHandle;
+
+/// The LSP error code for requests that were answered because they were cancelled.
+const REQUEST_CANCELLED: i64 = -32800;
+
+pub(super) fn is_cancel(notification: &jsonrpc::Notification) -> bool {
+    notification.method == <lsp::notification::Cancel as lsp::notification::Notification>::METHOD
+}
+
+/// The `$/cancelRequest` notification for request `id`, `None` for the `null` id which can't
+/// be cancelled.
+pub(super) fn notification(id: &jsonrpc::Id) -> Option<jsonrpc::Notification> {
+    let id = match id {
+        jsonrpc::Id::Num(num) => Value::from(*num),
+        jsonrpc::Id::Str(str) => Value::from(str.as_str()),
+        jsonrpc::Id::Null => return None,
+    };
+    let mut params = serde_json::Map::new();
+    params.insert("id".to_string(), id);
+    Some(jsonrpc::Notification {
+        jsonrpc: Some(jsonrpc::Version::V2),
+        method: <lsp::notification::Cancel as lsp::notification::Notification>::METHOD.to_string(),
+        params: jsonrpc::Params::Map(params),
+    })
+}
+
+/// The id of the request a `$/cancelRequest` notification cancels.
+pub(super) fn cancelled_id(notification: &jsonrpc::Notification) -> Option<jsonrpc::Id> {
+    let jsonrpc::Params::Map(params) = &notification.params else {
+        return None;
+    };
+    match params.get("id")? {
+        Value::Number(num) => num.as_u64().map(jsonrpc::Id::Num),
+        Value::String(str) => Some(jsonrpc::Id::Str(str.clone())),
+        _ => None,
+    }
+}
+
+/// The answer to a server request that was cancelled before it was handled.
+pub(super) fn cancelled_response(id: jsonrpc::Id) -> jsonrpc::Output {
+    jsonrpc::Output::Failure(jsonrpc::Failure {
+        jsonrpc: Some(jsonrpc::Version::V2),
+        error: jsonrpc::Error {
+            code: jsonrpc::ErrorCode::ServerError(REQUEST_CANCELLED),
+            message: "request was cancelled".to_string(),
+            data: None,
+        },
+        id,
+    })
+}
+
+/// The server requests being answered by a [`TransportConfig::request_handler`], so the server
+/// can cancel them.
+///
+/// [`TransportConfig::request_handler`]: super::TransportConfig::request_handler
+#[derive(Debug, Default)]
+pub(super) struct HandledRequests {
+    tasks: parking_lot::Mutex<HashMap<jsonrpc::Id, AbortHandle>>,
+}
+
+impl HandledRequests {
+    pub(super) fn insert(&self, id: jsonrpc::Id, task: AbortHandle) {
+        let mut tasks = self.tasks.lock();
+        // answered requests are only pruned here to keep the handler tasks independent
+        tasks.retain(|_, task| !task.is_finished());
+        tasks.insert(id, task);
+    }
+
+    /// Stops handling request `id`, returning whether it was still being handled.
+    pub(super) fn abort(&self, id: &jsonrpc::Id) -> bool {
+        match self.tasks.lock().remove(id) {
+            Some(task) if !task.is_finished() => {
+                task.abort();
+                true
+            }
+            _ => false,
+        }
+    }
+}
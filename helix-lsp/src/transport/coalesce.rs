@@ -0,0 +1,135 @@
+//! Coalescing `textDocument/didChange` notifications, see
+//! [`TransportConfig::coalesce_did_change`](super::TransportConfig::coalesce_did_change).
+
+use super::{outbound, Payload};
+use crate::{jsonrpc, lsp};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A `didChange` notification waiting for the debounce window to pass.
+#[derive(Debug)]
+struct Held {
+    payload: Payload,
+    uri: String,
+    version: i64,
+    deadline: Instant,
+}
+
+/// Sits between the outgoing channel and the send task. A `didChange` replacing the whole
+/// text of a document is held back for the debounce window, and replaced if a newer one for
+/// the same document comes right after it. Anything else releases it first, so messages are
+/// never reordered.
+#[derive(Debug)]
+pub(super) struct ChangeCoalescer {
+    window: Option<Duration>,
+    held: Option<Held>,
+    /// The message that released `held`, returned next.
+    next: Option<Payload>,
+}
+
+impl ChangeCoalescer {
+    pub(super) fn new(window: Option<Duration>) -> Self {
+        Self {
+            window,
+            held: None,
+            next: None,
+        }
+    }
+
+    /// Receives the next payload to send, `None` once the channel is closed and nothing is
+    /// held back. Cancellation safe.
+    pub(super) async fn recv(
+        &mut self,
+        rx: &mut outbound::OutgoingReceiver,
+        queue: &outbound::OutboundQueue,
+    ) -> Option<Payload> {
+        loop {
+            let payload = match (self.next.take(), &self.held) {
+                (Some(payload), _) => payload,
+                (None, Some(held)) => {
+                    let deadline = held.deadline;
+                    tokio::select! {
+                        biased;
+                        payload = rx.recv(queue) => match payload {
+                            Some(payload) => payload,
+                            None => return self.held.take().map(|held| held.payload),
+                        },
+                        () = tokio::time::sleep_until(deadline) => {
+                            return self.held.take().map(|held| held.payload);
+                        }
+                    }
+                }
+                (None, None) => rx.recv(queue).await?,
+            };
+            let Some(window) = self.window else {
+                return Some(payload);
+            };
+            let Some((uri, version)) = full_change(&payload) else {
+                match self.held.take() {
+                    Some(held) => {
+                        self.next = Some(payload);
+                        return Some(held.payload);
+                    }
+                    None => return Some(payload),
+                }
+            };
+            match self.held.take() {
+                Some(held) if held.uri == uri && held.version < version => {
+                    log::trace!("coalescing didChange of {uri} (version {})", held.version);
+                    self.held = Some(Held {
+                        payload,
+                        uri,
+                        version,
+                        deadline: held.deadline,
+                    });
+                }
+                Some(held) => {
+                    self.next = Some(payload);
+                    return Some(held.payload);
+                }
+                None => {
+                    self.held = Some(Held {
+                        payload,
+                        uri,
+                        version,
+                        deadline: Instant::now() + window,
+                    })
+                }
+            }
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.held.is_none() && self.next.is_none()
+    }
+}
+
+/// The document and version of a `didChange` notification made of a single change replacing
+/// the whole text, which a later one makes obsolete. Incremental changes build on each other
+/// and are never coalesced.
+fn full_change(payload: &Payload) -> Option<(String, i64)> {
+    use lsp::notification::{DidChangeTextDocument, Notification as _};
+
+    let Payload::Notification(jsonrpc::Notification {
+        method,
+        params: jsonrpc::Params::Map(params),
+        ..
+    }) = payload
+    else {
+        return None;
+    };
+    if method != DidChangeTextDocument::METHOD {
+        return None;
+    }
+    let [change] = params.get("contentChanges")?.as_array()?.as_slice() else {
+        return None;
+    };
+    if change.get("range").is_some_and(|range| !range.is_null()) {
+        return None;
+    }
+    let document = params.get("textDocument")?;
+    Some((
+        document.get("uri")?.as_str()?.to_string(),
+        document.get("version")?.as_i64()?,
+    ))
+}
@@ -0,0 +1,453 @@
+//! Framing the messages exchanged with the server, see [`MessageCodec`].
+
+#[cfg(feature = "gzip")]
+use super::encoding::ContentEncoding;
+use super::{write_all_vectored, DEFAULT_MAX_HEADER_LINE};
+use crate::{Error, Result};
+use anyhow::Context;
+use futures_util::future::{BoxFuture, FutureExt};
+use std::fmt;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite};
+
+/// The eventual result of reading or writing a message with a [`MessageCodec`].
+pub type CodecFuture<'a, T> = BoxFuture<'a, Result<T>>;
+
+/// The byte stream of the server, as seen by a [`MessageCodec`].
+pub type CodecReader<'a> = dyn AsyncBufRead + Unpin + Send + 'a;
+
+/// The byte stream to the server, as seen by a [`MessageCodec`].
+pub type CodecWriter<'a> = dyn AsyncWrite + Unpin + Send + 'a;
+
+/// The most blank lines skipped after the headers with [`LspCodec::skip_extra_blank_lines`].
+const MAX_EXTRA_BLANK_LINES: usize = 4;
+
+/// Splits the byte streams of the server into messages, for servers that don't frame them with
+/// a `Content-Length` header like the protocol defines. Set with
+/// [`TransportConfig::codec`](super::TransportConfig::codec).
+///
+/// The codec only deals with the bytes: the transport still serializes and parses the messages,
+/// correlates them and logs them.
+pub trait MessageCodec: Send + Sync + 'static {
+    /// Reads the body of the next message into `content`, replacing its contents. Fails with
+    /// [`Error::StreamClosed`] once the stream ends, and with [`Error::MessageTooLarge`] rather
+    /// than reading a message of more than `max_size` bytes.
+    fn read_message<'a>(
+        &'a self,
+        reader: &'a mut CodecReader<'_>,
+        content: &'a mut Vec<u8>,
+        max_size: usize,
+    ) -> CodecFuture<'a, ()>;
+
+    /// Writes the message `body` with its framing, returning the number of bytes written. The
+    /// transport flushes the writer afterwards.
+    fn write_message<'a>(
+        &'a self,
+        writer: &'a mut CodecWriter<'_>,
+        body: &'a [u8],
+    ) -> CodecFuture<'a, usize>;
+}
+
+impl fmt::Debug for dyn MessageCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MessageCodec")
+    }
+}
+
+/// The framing of the protocol: `Content-Length` and optional `Content-Type` headers, a blank
+/// line, then the body. This is what a transport uses without a codec, configured with
+/// [`TransportConfig::max_header_line`](super::TransportConfig::max_header_line) and
+/// [`TransportConfig::skip_extra_blank_lines`](super::TransportConfig::skip_extra_blank_lines).
+/// Lines that aren't headers, e.g. logging some servers write to stdout, are skipped, and a
+/// bare LF is accepted wherever CRLF is expected. Shared with the Debug Adapter Protocol.
+#[derive(Debug, Clone, Copy)]
+pub struct LspCodec {
+    max_header_line: usize,
+    skip_extra_blank_lines: bool,
+}
+
+impl Default for LspCodec {
+    fn default() -> Self {
+        Self {
+            max_header_line: DEFAULT_MAX_HEADER_LINE,
+            skip_extra_blank_lines: false,
+        }
+    }
+}
+
+/// The headers of a message read by [`LspCodec::read_headers`].
+#[derive(Debug)]
+pub(super) struct Headers {
+    pub(super) content_length: usize,
+    /// The bytes read for the message, including skipped lines and, once
+    /// [`LspCodec::read_body`] read it, the body.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub(super) read: usize,
+    /// Fails for an unsupported `Content-Encoding`, once the body is read so the stream stays
+    /// in sync.
+    #[cfg(feature = "gzip")]
+    pub(super) encoding: Result<Option<ContentEncoding>>,
+}
+
+impl LspCodec {
+    /// Fail with [`Error::HeaderTooLong`] when a header line, or a line skipped between
+    /// messages, is longer than `bytes`. Defaults to 1 MiB.
+    pub fn max_header_line(mut self, bytes: usize) -> Self {
+        self.max_header_line = bytes;
+        self
+    }
+
+    /// Skip up to a few extra blank lines between the headers and the body.
+    pub fn skip_extra_blank_lines(mut self, enabled: bool) -> Self {
+        self.skip_extra_blank_lines = enabled;
+        self
+    }
+
+    /// Reads the headers of the next message, using `buffer` for the lines. Lines that aren't
+    /// headers are handed to `garbage` and skipped unless it fails.
+    pub(super) async fn read_headers(
+        &self,
+        reader: &mut (impl AsyncBufRead + Unpin + ?Sized),
+        buffer: &mut String,
+        mut garbage: impl FnMut(&str) -> Result<()>,
+    ) -> Result<Headers> {
+        let mut content_length = None;
+        #[cfg(feature = "gzip")]
+        let mut encoding = Ok(None);
+        let mut read_total = 0;
+        // whether only blank lines were read so far
+        let mut blank = true;
+        let limit = self.max_header_line;
+        loop {
+            buffer.clear();
+            // one byte more than the limit tells a line at the limit from a longer one
+            let read = (&mut *reader)
+                .take((limit as u64).saturating_add(1))
+                .read_line(buffer)
+                .await?;
+            if read == 0 {
+                return Err(Error::StreamClosed);
+            }
+            if read > limit && !buffer.ends_with('\n') {
+                return Err(Error::HeaderTooLong { limit });
+            }
+            read_total += read;
+
+            if buffer.trim().is_empty() && blank {
+                // whitespace some servers write after the body of the previous message
+                continue;
+            }
+            blank = false;
+
+            if buffer == "\r\n" || buffer == "\n" {
+                // look for an empty line, tolerating a bare LF from non-conformant servers
+                break;
+            }
+
+            let header = buffer.trim();
+
+            let parts = header
+                .split_once(':')
+                .map(|(name, value)| (name.trim(), value.trim()));
+
+            match parts {
+                Some((name, value)) if name.eq_ignore_ascii_case("Content-Length") => {
+                    content_length = Some(value.parse().context("invalid content length")?);
+                }
+                // the only encoding the protocol defines is UTF-8, which is assumed regardless
+                Some((name, _)) if name.eq_ignore_ascii_case("Content-Type") => {}
+                #[cfg(feature = "gzip")]
+                Some((name, value)) if name.eq_ignore_ascii_case("Content-Encoding") => {
+                    encoding = ContentEncoding::parse(value);
+                }
+                Some((_, _)) => {}
+                // Workaround: Some non-conformant language servers will output logging and other
+                // garbage into the same stream as JSON-RPC messages. This can also happen from
+                // shell scripts that spawn the server.
+                None => garbage(header)?,
+            }
+        }
+
+        Ok(Headers {
+            content_length: content_length.context("missing content length")?,
+            read: read_total,
+            #[cfg(feature = "gzip")]
+            encoding,
+        })
+    }
+
+    /// Reads the body announced by `headers` into `content`. Fails with
+    /// [`Error::MessageTooLarge`] rather than reading more than `max_size` bytes.
+    pub(super) async fn read_body(
+        &self,
+        reader: &mut (impl AsyncBufRead + Unpin + ?Sized),
+        headers: &mut Headers,
+        content: &mut Vec<u8>,
+        max_size: usize,
+    ) -> Result<()> {
+        let content_length = headers.content_length;
+        if content_length > max_size {
+            // Skipping the body could mean reading gigabytes, and a length this far off
+            // suggests the stream is out of sync anyway: stop rather than guess where the next
+            // message starts.
+            return Err(Error::MessageTooLarge {
+                advertised: content_length,
+                limit: max_size,
+            });
+        }
+        if self.skip_extra_blank_lines && content_length > 0 {
+            headers.read += skip_blank_lines(reader).await?;
+        }
+        content.resize(content_length, 0);
+        reader.read_exact(content).await?;
+        headers.read += content_length;
+        Ok(())
+    }
+}
+
+/// Consumes up to [`MAX_EXTRA_BLANK_LINES`] blank lines in front of a body, returning the
+/// number of bytes consumed.
+async fn skip_blank_lines(reader: &mut (impl AsyncBufRead + Unpin + ?Sized)) -> Result<usize> {
+    let mut skipped = 0;
+    for _ in 0..MAX_EXTRA_BLANK_LINES {
+        let buffered = reader.fill_buf().await?;
+        let len = if buffered.starts_with(b"\r\n") {
+            2
+        } else if buffered.starts_with(b"\n") {
+            1
+        } else {
+            break;
+        };
+        reader.consume(len);
+        skipped += len;
+    }
+    Ok(skipped)
+}
+
+/// Turns the failure to decode a body that was read into an [`Error::Parse`], which the
+/// transport skips like a body that isn't valid JSON.
+#[cfg(feature = "gzip")]
+pub(super) fn undecodable(err: Error) -> Error {
+    Error::Parse(err.into())
+}
+
+impl MessageCodec for LspCodec {
+    fn read_message<'a>(
+        &'a self,
+        reader: &'a mut CodecReader<'_>,
+        content: &'a mut Vec<u8>,
+        max_size: usize,
+    ) -> CodecFuture<'a, ()> {
+        async move {
+            let mut headers = self
+                .read_headers(reader, &mut String::new(), |_| Ok(()))
+                .await?;
+            self.read_body(reader, &mut headers, content, max_size)
+                .await?;
+            #[cfg(feature = "gzip")]
+            if let Some(encoding) = headers.encoding.map_err(undecodable)? {
+                encoding.decode(content, max_size).map_err(undecodable)?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn write_message<'a>(
+        &'a self,
+        writer: &'a mut CodecWriter<'_>,
+        body: &'a [u8],
+    ) -> CodecFuture<'a, usize> {
+        async move {
+            let mut digits = itoa::Buffer::new();
+            let header = content_length_header(body.len(), b"", &mut digits);
+            write_framed(writer, header, body).await
+        }
+        .boxed()
+    }
+}
+
+/// The headers for a body of `len` bytes: the `Content-Length` header, the `extra` headers,
+/// each ending with CRLF, and the blank line ending the headers. Returned in parts that are
+/// written as they are, so sending a message doesn't allocate a string for its header.
+pub(super) fn content_length_header<'a>(
+    len: usize,
+    extra: &'a [u8],
+    digits: &'a mut itoa::Buffer,
+) -> [&'a [u8]; 5] {
+    [
+        b"Content-Length: ",
+        digits.format(len).as_bytes(),
+        b"\r\n",
+        extra,
+        b"\r\n",
+    ]
+}
+
+/// Writes `header` and `body` in one go, returning the number of bytes written.
+pub(super) async fn write_framed(
+    writer: &mut (impl AsyncWrite + Unpin + ?Sized),
+    header: [&[u8]; 5],
+    body: &[u8],
+) -> Result<usize> {
+    let [length, digits, end_of_length, extra, end_of_headers] = header;
+    let mut slices =
+        [length, digits, end_of_length, extra, end_of_headers, body].map(std::io::IoSlice::new);
+    let written = slices.iter().map(|slice| slice.len()).sum();
+    write_all_vectored(writer, &mut slices).await?;
+    Ok(written)
+}
+
+/// One message per line, as some experimental servers write them. Blank lines between messages
+/// are skipped. The transport never writes a line break inside a message, so the messages
+/// written need no escaping.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NewlineJsonCodec;
+
+impl MessageCodec for NewlineJsonCodec {
+    fn read_message<'a>(
+        &'a self,
+        reader: &'a mut CodecReader<'_>,
+        content: &'a mut Vec<u8>,
+        max_size: usize,
+    ) -> CodecFuture<'a, ()> {
+        async move {
+            loop {
+                content.clear();
+                // room for the message and its line break, one byte more tells a message at
+                // the limit from a longer one
+                let read = (&mut *reader)
+                    .take((max_size as u64).saturating_add(3))
+                    .read_until(b'\n', content)
+                    .await?;
+                if read == 0 {
+                    return Err(Error::StreamClosed);
+                }
+                let line_break = if content.ends_with(b"\r\n") {
+                    2
+                } else {
+                    usize::from(content.ends_with(b"\n"))
+                };
+                if content.len() - line_break > max_size {
+                    return Err(Error::MessageTooLarge {
+                        advertised: content.len() - line_break,
+                        limit: max_size,
+                    });
+                }
+                content.truncate(content.len() - line_break);
+                if !content.trim_ascii().is_empty() {
+                    return Ok(());
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn write_message<'a>(
+        &'a self,
+        writer: &'a mut CodecWriter<'_>,
+        body: &'a [u8],
+    ) -> CodecFuture<'a, usize> {
+        async move {
+            let mut slices = [std::io::IoSlice::new(body), std::io::IoSlice::new(b"\n")];
+            write_all_vectored(writer, &mut slices).await?;
+            Ok(body.len() + 1)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_all(codec: &dyn MessageCodec, mut input: &[u8], max_size: usize) -> Vec<String> {
+        let mut messages = Vec::new();
+        let mut content = Vec::new();
+        loop {
+            match codec.read_message(&mut input, &mut content, max_size).await {
+                Ok(()) => messages.push(String::from_utf8(content.clone()).unwrap()),
+                Err(Error::StreamClosed) => return messages,
+                Err(err) => {
+                    messages.push(err.to_string());
+                    return messages;
+                }
+            }
+        }
+    }
+
+    async fn write(codec: &dyn MessageCodec, body: &str) -> Vec<u8> {
+        let mut output = Vec::new();
+        let written = codec
+            .write_message(&mut output, body.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(written, output.len());
+        output
+    }
+
+    #[test]
+    fn content_length_header_bytes() {
+        for len in [0, 1, 9, 10, 4096, 123_456_789, usize::MAX] {
+            let mut digits = itoa::Buffer::new();
+            assert_eq!(
+                content_length_header(len, b"", &mut digits).concat(),
+                format!("Content-Length: {len}\r\n\r\n").as_bytes()
+            );
+        }
+        let mut digits = itoa::Buffer::new();
+        assert_eq!(
+            content_length_header(2, b"Content-Type: x\r\n", &mut digits).concat(),
+            b"Content-Length: 2\r\nContent-Type: x\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn lsp_codec() {
+        let codec = LspCodec::default();
+        let output = write(&codec, "[1,2]").await;
+        assert_eq!(output, b"Content-Length: 5\r\n\r\n[1,2]");
+        let mut input = output.clone();
+        input.extend_from_slice(b"content-length: 2\r\nContent-Type: x\r\n\r\n{}");
+        // bare LFs and lines that aren't headers are tolerated
+        input.extend_from_slice(b"\nserver log line\nContent-Length: 2\n\n[]");
+        assert_eq!(read_all(&codec, &input, 1024).await, ["[1,2]", "{}", "[]"]);
+
+        let messages = read_all(&codec, &output, 4).await;
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("more than the limit"), "{messages:?}");
+
+        let codec = LspCodec::default().max_header_line(8);
+        assert!(matches!(
+            codec
+                .read_message(&mut &output[..], &mut Vec::new(), 1024)
+                .await,
+            Err(Error::HeaderTooLong { limit: 8 })
+        ));
+
+        // no limit at all
+        let codec = LspCodec::default().max_header_line(usize::MAX);
+        assert_eq!(read_all(&codec, &output, 1024).await, ["[1,2]"]);
+    }
+
+    #[tokio::test]
+    async fn newline_json_codec() {
+        let output = write(&NewlineJsonCodec, "[1,2]").await;
+        assert_eq!(output, b"[1,2]\n");
+        let input = b"[1,2]\n\r\n{}\r\n[3]";
+        assert_eq!(
+            read_all(&NewlineJsonCodec, input, 1024).await,
+            ["[1,2]", "{}", "[3]"]
+        );
+
+        // a message at the limit is fine, a longer one isn't
+        assert_eq!(read_all(&NewlineJsonCodec, b"[1]\n", 3).await, ["[1]"]);
+        let messages = read_all(&NewlineJsonCodec, b"[1,2]\n", 3).await;
+        assert!(messages[0].contains("more than the limit"), "{messages:?}");
+
+        // no limit at all
+        assert_eq!(
+            read_all(&NewlineJsonCodec, b"[1,2]\n", usize::MAX).await,
+            ["[1,2]"]
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! Remembering the requests completed last, to tell a duplicate or late response from one to a
+//! request that was never sent, see
+//! [`TransportConfig::duplicate_response_window`](super::TransportConfig::duplicate_response_window).
+
+use crate::jsonrpc;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+
+/// The ids and methods of the last requests that were completed, oldest first.
+#[derive(Debug)]
+pub(super) struct RecentlyCompleted {
+    capacity: usize,
+    requests: Mutex<Recent>,
+}
+
+#[derive(Debug, Default)]
+struct Recent {
+    order: VecDeque<jsonrpc::Id>,
+    methods: HashMap<jsonrpc::Id, Completed>,
+}
+
+/// A request that was completed recently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Completed {
+    pub(super) method: String,
+    /// Whether the request timed out or was cancelled rather than answered.
+    pub(super) abandoned: bool,
+}
+
+impl RecentlyCompleted {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            requests: Mutex::default(),
+        }
+    }
+
+    /// Records that request `id` of `method` was answered, or `abandoned`, forgetting the
+    /// oldest one beyond the capacity.
+    pub(super) fn record(&self, id: &jsonrpc::Id, method: &str, abandoned: bool) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut requests = self.requests.lock();
+        let Recent { order, methods } = &mut *requests;
+        let completed = Completed {
+            method: method.to_string(),
+            abandoned,
+        };
+        if methods.insert(id.clone(), completed).is_some() {
+            // an id the consumer reused, still remembered from the first time
+            return;
+        }
+        order.push_back(id.clone());
+        if order.len() > self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                methods.remove(&oldest);
+            }
+        }
+    }
+
+    /// Request `id` if it was completed recently.
+    pub(super) fn get(&self, id: &jsonrpc::Id) -> Option<Completed> {
+        self.requests.lock().methods.get(id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded() {
+        let completed = RecentlyCompleted::new(2);
+        for id in 0..3 {
+            completed.record(&jsonrpc::Id::Num(id), "textDocument/hover", id == 2);
+        }
+        assert_eq!(completed.get(&jsonrpc::Id::Num(0)), None);
+        assert_eq!(
+            completed.get(&jsonrpc::Id::Num(2)),
+            Some(Completed {
+                method: "textDocument/hover".to_string(),
+                abandoned: true,
+            })
+        );
+
+        let disabled = RecentlyCompleted::new(0);
+        disabled.record(&jsonrpc::Id::Num(0), "shutdown", false);
+        assert_eq!(disabled.get(&jsonrpc::Id::Num(0)), None);
+    }
+}
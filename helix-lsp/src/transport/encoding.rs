@@ -0,0 +1,131 @@
+//! Compressed message bodies, which the protocol doesn't define but some experimental servers
+//! negotiate with a `Content-Encoding` header. Requires the `gzip` feature.
+//!
+//! Compressed bodies from the server are always accepted. Bodies sent to the server are only
+//! compressed with [`TransportConfig::gzip_threshold`], once the server listed `"gzip"` in the
+//! `capabilities.experimental.contentEncodings` of its `initialize` response.
+//!
+//! [`TransportConfig::gzip_threshold`]: super::TransportConfig::gzip_threshold
+
+use crate::{Error, Result};
+use anyhow::anyhow;
+use flate2::{
+    read::{GzDecoder, ZlibDecoder},
+    write::GzEncoder,
+    Compression,
+};
+use serde_json::Value;
+use std::io::{Read, Write};
+
+/// The `Content-Encoding` header of the messages compressed by the transport.
+pub(super) const GZIP_HEADER: &[u8] = b"Content-Encoding: gzip\r\n";
+
+/// The encoding of a message body, from its `Content-Encoding` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ContentEncoding {
+    Gzip,
+    /// The zlib format, which is what HTTP calls `deflate`.
+    Deflate,
+}
+
+impl ContentEncoding {
+    /// `None` for an uncompressed body.
+    pub(super) fn parse(value: &str) -> Result<Option<Self>> {
+        match value.to_ascii_lowercase().as_str() {
+            "identity" => Ok(None),
+            "gzip" => Ok(Some(Self::Gzip)),
+            "deflate" => Ok(Some(Self::Deflate)),
+            _ => Err(Error::Other(anyhow!(
+                "unsupported content encoding {value:?}"
+            ))),
+        }
+    }
+
+    /// Decompresses `content` in place. Fails with [`Error::MessageTooLarge`] rather than
+    /// decompressing more than `max_size` bytes.
+    pub(super) fn decode(self, content: &mut Vec<u8>, max_size: usize) -> Result<()> {
+        let mut decoded = Vec::with_capacity(content.len().saturating_mul(4).min(max_size));
+        let limit = (max_size as u64).saturating_add(1);
+        let read = match self {
+            Self::Gzip => GzDecoder::new(&content[..])
+                .take(limit)
+                .read_to_end(&mut decoded),
+            Self::Deflate => ZlibDecoder::new(&content[..])
+                .take(limit)
+                .read_to_end(&mut decoded),
+        };
+        read.map_err(|err| Error::Other(anyhow!("failed to decompress the body: {err}")))?;
+        if decoded.len() > max_size {
+            return Err(Error::MessageTooLarge {
+                advertised: decoded.len(),
+                limit: max_size,
+            });
+        }
+        *content = decoded;
+        Ok(())
+    }
+}
+
+/// Compresses `body` with gzip.
+pub(super) fn gzip(body: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), Compression::fast());
+    encoder.write_all(body)?;
+    Ok(encoder.finish()?)
+}
+
+/// Whether the `initialize` result of the server says it accepts gzip compressed bodies.
+pub(super) fn accepts_gzip(result: &Value) -> bool {
+    result
+        .pointer("/capabilities/experimental/contentEncodings")
+        .and_then(Value::as_array)
+        .is_some_and(|encodings| encodings.iter().any(|encoding| encoding == "gzip"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn round_trip() {
+        let body = format!(
+            r#"{{"jsonrpc":"2.0","result":["{}"],"id":1}}"#,
+            "x".repeat(4096)
+        );
+        let mut content = gzip(body.as_bytes()).unwrap();
+        assert!(content.len() < body.len());
+        ContentEncoding::Gzip
+            .decode(&mut content, body.len())
+            .unwrap();
+        assert_eq!(content, body.as_bytes());
+
+        let mut content = gzip(body.as_bytes()).unwrap();
+        assert!(matches!(
+            ContentEncoding::Gzip.decode(&mut content, body.len() - 1),
+            Err(Error::MessageTooLarge { .. })
+        ));
+
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body.as_bytes()).unwrap();
+        let mut content = encoder.finish().unwrap();
+        ContentEncoding::Deflate
+            .decode(&mut content, usize::MAX)
+            .unwrap();
+        assert_eq!(content, body.as_bytes());
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(
+            ContentEncoding::parse("GZIP").unwrap(),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(ContentEncoding::parse("identity").unwrap(), None);
+        assert!(ContentEncoding::parse("br").is_err());
+
+        assert!(accepts_gzip(&json!({
+            "capabilities": { "experimental": { "contentEncodings": ["deflate", "gzip"] } }
+        })));
+        assert!(!accepts_gzip(&json!({ "capabilities": {} })));
+    }
+}
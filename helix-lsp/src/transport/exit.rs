@@ -0,0 +1,117 @@
+//! Reporting why a transport stopped, see [`TransportConfig::on_exit`].
+//!
+//! [`TransportConfig::on_exit`]: super::TransportConfig::on_exit
+
+use crate::Error;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Why a transport stopped talking to its server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The server closed the stream after the client sent the `exit` notification.
+    Shutdown,
+    /// The server closed the stream without being asked to exit, e.g. because it crashed.
+    StreamClosed,
+    /// The server exited right after it was started, with this output on stderr.
+    FailedToStart { stderr: String },
+    /// The server didn't answer the ping of a [`TransportConfig::health_check`](super::TransportConfig::health_check).
+    Unresponsive,
+    /// The server wasn't initialized within the [`TransportConfig::initialize_timeout`](super::TransportConfig::initialize_timeout).
+    InitializeTimeout,
+    /// The [`TransportConfig::byte_quota`](super::TransportConfig::byte_quota) was exceeded.
+    QuotaExceeded,
+    /// The transport was stopped with [`Transport::close`](super::Transport::close).
+    Closed,
+    /// The [`TransportConfig::parse_failure_limit`](super::TransportConfig::parse_failure_limit)
+    /// was reached.
+    ProtocolBroken,
+    /// The transport stopped after an unexpected error, e.g. a malformed message.
+    Error(String),
+}
+
+impl ExitReason {
+    pub(super) fn new(err: &Error, startup_failure: Option<&str>, exit_sent: bool) -> Self {
+        match (err, startup_failure) {
+            (_, Some(stderr)) => Self::FailedToStart {
+                stderr: stderr.to_string(),
+            },
+            (Error::StreamClosed, None) if exit_sent => Self::Shutdown,
+            (Error::StreamClosed, None) => Self::StreamClosed,
+            (Error::ServerUnresponsive, None) => Self::Unresponsive,
+            (Error::InitializeTimeout(_), None) => Self::InitializeTimeout,
+            (Error::QuotaExceeded { .. }, None) => Self::QuotaExceeded,
+            (Error::TransportClosed, None) => Self::Closed,
+            (Error::ProtocolBroken { .. }, None) => Self::ProtocolBroken,
+            (err, None) => Self::Error(err.to_string()),
+        }
+    }
+}
+
+/// The callback set with [`TransportConfig::on_exit`](super::TransportConfig::on_exit).
+#[derive(Clone)]
+pub(super) struct ExitCallback(pub(super) Arc<dyn Fn(ExitReason) + Send + Sync>);
+
+impl fmt::Debug for ExitCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ExitCallback")
+    }
+}
+
+/// Makes sure the exit is reported only once, whichever teardown path gets there first.
+#[derive(Debug, Default)]
+pub(super) struct ExitOnce {
+    exited: AtomicBool,
+}
+
+impl ExitOnce {
+    pub(super) fn report(
+        &self,
+        callback: Option<&ExitCallback>,
+        reason: impl FnOnce() -> ExitReason,
+    ) {
+        if self.exited.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        if let Some(callback) = callback {
+            (callback.0)(reason());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+
+    #[test]
+    fn reports_once() {
+        let reasons = Arc::new(Mutex::new(Vec::new()));
+        let callback = {
+            let reasons = reasons.clone();
+            ExitCallback(Arc::new(move |reason| reasons.lock().push(reason)))
+        };
+        let once = ExitOnce::default();
+        once.report(Some(&callback), || {
+            ExitReason::new(&Error::StreamClosed, None, true)
+        });
+        once.report(Some(&callback), || ExitReason::StreamClosed);
+        assert_eq!(*reasons.lock(), [ExitReason::Shutdown]);
+
+        assert_eq!(
+            ExitReason::new(&Error::StreamClosed, Some("boom"), false),
+            ExitReason::FailedToStart {
+                stderr: "boom".to_string()
+            }
+        );
+        assert_eq!(
+            ExitReason::new(&Error::StreamClosed, None, false),
+            ExitReason::StreamClosed
+        );
+    }
+}
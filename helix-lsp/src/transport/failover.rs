@@ -0,0 +1,206 @@
+//! Failing over from a primary transport to a warm-standby one.
+//!
+//! Both transports are started up front (e.g. connected to two instances of a network server),
+//! but only the primary one is used. When the primary's connection is lost, the standby is
+//! re-initialized with the `initialize` request the consumer originally sent and takes over,
+//! behind the same channels the consumer was already using.
+//!
+//! # Consistency
+//!
+//! The standby server knows nothing about the session that ran on the primary:
+//!
+//! - Requests in flight on the primary when it fails are answered with
+//!   [`Error::StreamClosed`](crate::Error::StreamClosed) and are not retried.
+//! - Documents opened on the primary are not open on the standby. Once the standby is
+//!   initialized the consumer receives a second `initialized` notification, which it must
+//!   treat as a fresh start and re-open (and re-configure) everything from there.
+//! - Notifications sent while the standby is being re-initialized are dropped, like for any
+//!   server that isn't initialized yet.
+//! - The capabilities of the standby are those of the primary's `initialize` response as far
+//!   as the consumer is concerned, so both servers should run the same version.
+
+use super::{
+    inbound::InboundDepth, InboundReceiver, InitializeSignal, Payload, StartedTransport, Transport,
+};
+use crate::{jsonrpc, lsp, LanguageServerId};
+use log::{error, info, warn};
+use lsp::{
+    notification::{Exit, Initialized, Notification},
+    request::{Initialize, Request},
+};
+use std::sync::Arc;
+use tokio::sync::mpsc::{channel, unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// A started transport, as seen from a wrapper sitting between it and the consumer.
+pub(super) struct Endpoint {
+    pub(super) incoming: InboundReceiver,
+    pub(super) outgoing: UnboundedSender<Payload>,
+    pub(super) initialize_notify: Arc<InitializeSignal>,
+    pub(super) transport: Arc<Transport>,
+}
+
+impl From<StartedTransport> for Endpoint {
+    fn from((incoming, outgoing, initialize_notify, transport): StartedTransport) -> Self {
+        Self {
+            incoming,
+            outgoing,
+            initialize_notify,
+            transport,
+        }
+    }
+}
+
+/// Combines a started `primary` transport with a started `standby` that takes over when the
+/// primary's connection is lost. See the [module documentation](self) for the caveats.
+///
+/// The returned channels are used exactly like the ones of [`Transport::start`]. Calls from
+/// either server are reported with the id of the primary transport.
+pub fn start_with_standby(
+    primary: StartedTransport,
+    standby: StartedTransport,
+) -> (
+    InboundReceiver,
+    UnboundedSender<Payload>,
+    Arc<InitializeSignal>,
+) {
+    let primary = Endpoint::from(primary);
+    let initialize_notify = primary.initialize_notify.clone();
+    let (client_tx, rx) = unbounded_channel();
+    let (tx, client_rx) = unbounded_channel();
+    let depth = InboundDepth::default();
+    let incoming = InboundReceiver::new(rx, &depth);
+
+    let failover = Failover {
+        id: primary.transport.id,
+        active: primary,
+        standby: Some(standby.into()),
+        initialize: None,
+        exiting: false,
+        depth,
+    };
+    tokio::spawn(failover.run(client_tx, client_rx));
+
+    (incoming, tx, initialize_notify)
+}
+
+struct Failover {
+    id: LanguageServerId,
+    active: Endpoint,
+    standby: Option<Endpoint>,
+    /// The consumer's `initialize` request, replayed to the standby on promotion.
+    initialize: Option<jsonrpc::MethodCall>,
+    /// Whether the consumer asked the server to exit, in which case losing it is expected.
+    exiting: bool,
+    depth: InboundDepth,
+}
+
+impl Failover {
+    async fn run(
+        mut self,
+        client_tx: UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+        mut client_rx: UnboundedReceiver<Payload>,
+    ) {
+        loop {
+            tokio::select! {
+                msg = self.active.incoming.recv() => {
+                    let Some((_, call)) = msg else {
+                        break;
+                    };
+                    if is_exit(&call) && !self.exiting {
+                        if let Some(standby) = self.standby.take() {
+                            self.promote(standby);
+                            continue;
+                        }
+                    }
+                    if client_tx.send((self.id, call)).is_err() {
+                        break;
+                    }
+                    self.depth.record_forwarded(None);
+                }
+                msg = standby_recv(&mut self.standby) => {
+                    // an idle standby has nothing to say, unless its connection is lost
+                    if msg.is_none_or(|(_, call)| is_exit(&call)) {
+                        if let Some(standby) = self.standby.take() {
+                            warn!("{}: lost the standby server", standby.transport.log_name);
+                        }
+                    }
+                }
+                payload = client_rx.recv() => {
+                    let Some(payload) = payload else {
+                        break;
+                    };
+                    match &payload {
+                        Payload::Request { value, .. } if value.method == Initialize::METHOD => {
+                            self.initialize = Some(value.clone());
+                        }
+                        Payload::Notification(notification) if notification.method == Exit::METHOD => {
+                            self.exiting = true;
+                        }
+                        _ => (),
+                    }
+                    // a closed channel means the active transport failed, which the incoming
+                    // channel reports
+                    let _ = self.active.outgoing.send(payload);
+                }
+            }
+        }
+    }
+
+    fn promote(&mut self, standby: Endpoint) {
+        warn!(
+            "{}: lost the primary server, failing over to the standby server {}",
+            self.active.transport.log_name, standby.transport.log_name
+        );
+        self.active = standby;
+
+        // without it the consumer will initialize the standby itself
+        if let Some(initialize) = self.initialize.clone() {
+            reinitialize(&self.active, initialize, "standby");
+        }
+    }
+}
+
+/// Initializes the server of `endpoint` with the consumer's `initialize` request, sent to
+/// another server before, and completes the handshake once it answers. `role` names the
+/// server in the logs.
+pub(super) fn reinitialize(
+    endpoint: &Endpoint,
+    initialize: jsonrpc::MethodCall,
+    role: &'static str,
+) {
+    let (chan, mut rx) = channel(1);
+    let payload = Payload::request(chan, initialize);
+    if endpoint.outgoing.send(payload).is_err() {
+        return;
+    }
+
+    let outgoing = endpoint.outgoing.clone();
+    let initialize_notify = endpoint.initialize_notify.clone();
+    let name = endpoint.transport.log_name.to_string();
+    tokio::spawn(async move {
+        match rx.recv().await {
+            Some(Ok(_)) => {
+                info!("{name}: {role} server initialized");
+                let _ = outgoing.send(Payload::Notification(jsonrpc::Notification {
+                    jsonrpc: Some(jsonrpc::Version::V2),
+                    method: Initialized::METHOD.to_string(),
+                    params: jsonrpc::Params::Map(Default::default()),
+                }));
+                initialize_notify.notify();
+            }
+            Some(Err(err)) => error!("{name}: failed to initialize the {role} server: {err}"),
+            None => error!("{name}: failed to initialize the {role} server"),
+        }
+    });
+}
+
+async fn standby_recv(standby: &mut Option<Endpoint>) -> Option<(LanguageServerId, jsonrpc::Call)> {
+    match standby {
+        Some(standby) => standby.incoming.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+pub(super) fn is_exit(call: &jsonrpc::Call) -> bool {
+    matches!(call, jsonrpc::Call::Notification(notification) if notification.method == Exit::METHOD)
+}
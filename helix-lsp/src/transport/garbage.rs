@@ -0,0 +1,132 @@
+//! Warning about the lines skipped between messages, e.g. logging a server writes to stdout,
+//! without flooding the log with every one of them. The lines skipped without a warning are
+//! summed up once the next message arrives, so a burst isn't left unreported.
+
+use parking_lot::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// At most one warning is logged per window.
+const WARNING_WINDOW: Duration = Duration::from_secs(10);
+
+/// How much of a skipped line the warning shows.
+const SAMPLE_LEN: usize = 80;
+
+/// A warning about skipped lines, see [`GarbageLines::record`].
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct GarbageWarning {
+    /// The beginning of the line that triggered the warning.
+    pub(super) sample: String,
+    /// The lines skipped since the previous warning, this one included.
+    pub(super) skipped: u64,
+    /// The lines skipped since the transport started.
+    pub(super) total: u64,
+}
+
+/// The lines skipped since the last warning, see [`GarbageLines::flush`].
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct GarbageSummary {
+    pub(super) skipped: u64,
+    /// The lines skipped since the transport started.
+    pub(super) total: u64,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct GarbageLines {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    total: u64,
+    unreported: u64,
+    last_warning: Option<Instant>,
+}
+
+impl GarbageLines {
+    /// Counts a skipped `line`, returning a warning to log unless one was logged within the
+    /// last window.
+    pub(super) fn record(&self, line: &str) -> Option<GarbageWarning> {
+        let mut state = self.state.lock();
+        state.total += 1;
+        state.unreported += 1;
+        let now = Instant::now();
+        if state
+            .last_warning
+            .is_some_and(|last| now.duration_since(last) < WARNING_WINDOW)
+        {
+            return None;
+        }
+        state.last_warning = Some(now);
+        let line = line.trim();
+        let sample = match line.char_indices().nth(SAMPLE_LEN) {
+            Some((end, _)) => format!("{}...", &line[..end]),
+            None => line.to_string(),
+        };
+        Some(GarbageWarning {
+            sample,
+            skipped: std::mem::take(&mut state.unreported),
+            total: state.total,
+        })
+    }
+
+    /// Called once a message arrived, returning the lines skipped since the last warning, if
+    /// any, so they are reported even if no line follows to trigger the next warning.
+    pub(super) fn flush(&self) -> Option<GarbageSummary> {
+        let mut state = self.state.lock();
+        if state.unreported == 0 {
+            return None;
+        }
+        Some(GarbageSummary {
+            skipped: std::mem::take(&mut state.unreported),
+            total: state.total,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn throttled() {
+        let lines = GarbageLines::default();
+        assert_eq!(
+            lines.record("starting server\n"),
+            Some(GarbageWarning {
+                sample: "starting server".to_string(),
+                skipped: 1,
+                total: 1,
+            })
+        );
+        for _ in 0..100 {
+            assert_eq!(lines.record("indexing"), None);
+        }
+
+        tokio::time::advance(WARNING_WINDOW).await;
+        let warning = lines.record(&"x".repeat(200)).unwrap();
+        assert_eq!(warning.sample.len(), SAMPLE_LEN + 3);
+        assert_eq!((warning.skipped, warning.total), (101, 102));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flushed_by_the_next_message() {
+        let lines = GarbageLines::default();
+        assert!(lines.record("starting server").is_some());
+        assert_eq!(lines.flush(), None);
+
+        for _ in 0..3 {
+            assert_eq!(lines.record("indexing"), None);
+        }
+        assert_eq!(
+            lines.flush(),
+            Some(GarbageSummary {
+                skipped: 3,
+                total: 4,
+            })
+        );
+        assert_eq!(lines.flush(), None);
+        // still throttled until the window ends
+        assert_eq!(lines.record("indexing"), None);
+    }
+}
@@ -0,0 +1,29 @@
+//! Correlating the requests made for a single operation, see [`RequestGroup`].
+
+use std::{fmt, sync::Arc};
+
+/// A correlation id shared by the requests an operation fans out into, e.g. `prepareRename`
+/// and `rename` for a single rename.
+///
+/// The group is logged with every request of the group and its response, and with the
+/// `metrics` feature the pending requests of each group are reported in
+/// `MetricsSnapshot::pending_groups`. Cloning is cheap, so one group can be handed to every
+/// request of the operation.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestGroup(Arc<str>);
+
+impl RequestGroup {
+    pub fn new(id: impl Into<Arc<str>>) -> Self {
+        Self(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
@@ -0,0 +1,217 @@
+//! Answering requests from the server without going through the [`InboundReceiver`](super::InboundReceiver).
+
+use crate::{jsonrpc, lsp};
+use futures_util::future::{BoxFuture, FutureExt};
+use serde_json::Value;
+use std::{collections::HashMap, fmt, future::Future, sync::Arc};
+
+/// The eventual result of a request handled by a [`ServerRequestHandler`].
+pub type HandlerFuture = BoxFuture<'static, Result<Value, jsonrpc::Error>>;
+
+/// The eventual result of a request handled by a [`TypedRequestHandler`].
+pub type TypedFuture<T> = BoxFuture<'static, Result<T, jsonrpc::Error>>;
+
+/// Answers requests sent by the server, see [`TransportConfig::request_handler`](super::TransportConfig::request_handler).
+///
+/// The transport sends the response once the returned future completes, so handlers never have
+/// to build a response themselves. Requests are handled concurrently, in tasks of their own.
+pub trait ServerRequestHandler: Send + Sync + 'static {
+    /// Whether requests of `method` are handled here. Other requests are forwarded to the
+    /// [`InboundReceiver`](super::InboundReceiver) as usual.
+    fn handles(&self, method: &str) -> bool;
+
+    /// Starts handling a request of a `method` this handler [handles](Self::handles).
+    fn handle(&self, method: String, params: jsonrpc::Params) -> HandlerFuture;
+}
+
+impl fmt::Debug for dyn ServerRequestHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ServerRequestHandler")
+    }
+}
+
+type Route = Box<dyn Fn(jsonrpc::Params) -> HandlerFuture + Send + Sync>;
+
+/// A [`ServerRequestHandler`] dispatching LSP requests to an async closure per method.
+#[derive(Default)]
+pub struct RequestRouter {
+    routes: HashMap<String, Route>,
+}
+
+impl RequestRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles the requests `R` with `handler`. Requests with invalid params are answered with
+    /// an invalid params error without calling it.
+    pub fn route<R, F, Fut>(mut self, handler: F) -> Self
+    where
+        R: lsp::request::Request,
+        F: Fn(R::Params) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R::Result, jsonrpc::Error>> + Send + 'static,
+    {
+        let route = move |params: jsonrpc::Params| -> HandlerFuture {
+            let params = match params.parse::<R::Params>() {
+                Ok(params) => params,
+                Err(err) => return futures_util::future::ready(Err(err)).boxed(),
+            };
+            handler(params)
+                .map(|result| {
+                    let result = result?;
+                    serde_json::to_value(result).map_err(|err| jsonrpc::Error {
+                        code: jsonrpc::ErrorCode::InternalError,
+                        message: err.to_string(),
+                        data: None,
+                    })
+                })
+                .boxed()
+        };
+        self.routes.insert(R::METHOD.to_string(), Box::new(route));
+        self
+    }
+}
+
+/// Typed handlers for the requests servers send the most, so they don't have to be matched by
+/// method. Registered with [`RequestRouter::typed`], which parses the params and serializes the
+/// results.
+pub trait TypedRequestHandler: Send + Sync + 'static {
+    /// `workspace/configuration`: the settings of each requested section, in order.
+    fn configuration(&self, params: lsp::ConfigurationParams) -> TypedFuture<Vec<Value>>;
+
+    /// `window/workDoneProgress/create`: prepares for the `$/progress` notifications of a new
+    /// token.
+    fn create_work_done_progress(
+        &self,
+        params: lsp::WorkDoneProgressCreateParams,
+    ) -> TypedFuture<()>;
+
+    /// `client/registerCapability`: registers capabilities dynamically.
+    fn register_capability(&self, params: lsp::RegistrationParams) -> TypedFuture<()>;
+
+    /// `window/showMessageRequest`: shows a message and returns the action the user picked,
+    /// if any.
+    fn show_message_request(
+        &self,
+        params: lsp::ShowMessageRequestParams,
+    ) -> TypedFuture<Option<lsp::MessageActionItem>>;
+}
+
+impl RequestRouter {
+    /// Handles the requests of [`TypedRequestHandler`] with `handler`, replacing any route
+    /// added for them before.
+    pub fn typed(self, handler: impl TypedRequestHandler) -> Self {
+        use lsp::request::{
+            RegisterCapability, ShowMessageRequest, WorkDoneProgressCreate, WorkspaceConfiguration,
+        };
+
+        let handler = Arc::new(handler);
+        let configuration = handler.clone();
+        let progress = handler.clone();
+        let registration = handler.clone();
+        self.route::<WorkspaceConfiguration, _, _>(move |params| {
+            configuration.configuration(params)
+        })
+        .route::<WorkDoneProgressCreate, _, _>(move |params| {
+            progress.create_work_done_progress(params)
+        })
+        .route::<RegisterCapability, _, _>(move |params| registration.register_capability(params))
+        .route::<ShowMessageRequest, _, _>(move |params| handler.show_message_request(params))
+    }
+}
+
+impl fmt::Debug for RequestRouter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.routes.keys()).finish()
+    }
+}
+
+impl ServerRequestHandler for RequestRouter {
+    fn handles(&self, method: &str) -> bool {
+        self.routes.contains_key(method)
+    }
+
+    fn handle(&self, method: String, params: jsonrpc::Params) -> HandlerFuture {
+        match self.routes.get(&method) {
+            Some(route) => route(params),
+            None => futures_util::future::ready(Err(jsonrpc::Error {
+                code: jsonrpc::ErrorCode::MethodNotFound,
+                message: format!("Method not found: {method}"),
+                data: None,
+            }))
+            .boxed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::ready;
+    use serde_json::json;
+
+    struct Editor;
+
+    impl TypedRequestHandler for Editor {
+        fn configuration(&self, params: lsp::ConfigurationParams) -> TypedFuture<Vec<Value>> {
+            let sections = params.items.into_iter().map(|item| json!(item.section));
+            ready(Ok(sections.collect())).boxed()
+        }
+
+        fn create_work_done_progress(
+            &self,
+            _params: lsp::WorkDoneProgressCreateParams,
+        ) -> TypedFuture<()> {
+            ready(Ok(())).boxed()
+        }
+
+        fn register_capability(&self, _params: lsp::RegistrationParams) -> TypedFuture<()> {
+            ready(Err(jsonrpc::Error::invalid_params("no registrations"))).boxed()
+        }
+
+        fn show_message_request(
+            &self,
+            params: lsp::ShowMessageRequestParams,
+        ) -> TypedFuture<Option<lsp::MessageActionItem>> {
+            ready(Ok(params
+                .actions
+                .and_then(|actions| actions.into_iter().next())))
+            .boxed()
+        }
+    }
+
+    fn params(value: Value) -> jsonrpc::Params {
+        jsonrpc::Params::Map(value.as_object().unwrap().clone())
+    }
+
+    #[tokio::test]
+    async fn typed_requests() {
+        let router = RequestRouter::new().typed(Editor);
+        assert!(router.handles("workspace/configuration"));
+        assert!(!router.handles("workspace/applyEdit"));
+
+        let configuration = router.handle(
+            "workspace/configuration".to_string(),
+            params(json!({ "items": [{ "section": "rust" }, {}] })),
+        );
+        assert_eq!(configuration.await, Ok(json!(["rust", null])));
+        let progress = router.handle(
+            "window/workDoneProgress/create".to_string(),
+            params(json!({ "token": 1 })),
+        );
+        assert_eq!(progress.await, Ok(Value::Null));
+        let registration = router.handle(
+            "client/registerCapability".to_string(),
+            params(json!({ "registrations": [] })),
+        );
+        assert_eq!(
+            registration.await,
+            Err(jsonrpc::Error::invalid_params("no registrations"))
+        );
+        let action = router.handle(
+            "window/showMessageRequest".to_string(),
+            params(json!({ "type": 3, "message": "reload?", "actions": [{ "title": "yes" }] })),
+        );
+        assert_eq!(action.await, Ok(json!({ "title": "yes" })));
+    }
+}
@@ -0,0 +1,117 @@
+//! Telling an idle server from a hung one, see [`TransportConfig::health_check`].
+//!
+//! [`TransportConfig::health_check`]: super::TransportConfig::health_check
+
+use super::{Payload, ReaderControl, Transport};
+use crate::{jsonrpc, Error};
+use parking_lot::Mutex;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Weak,
+    },
+    time::Duration,
+};
+use tokio::{sync::mpsc::channel, time::Instant};
+
+/// The method of the ping request. Servers answer it with a "method not found" error, which
+/// proves they are alive just as well as a result.
+const PING_METHOD: &str = "$/helix/ping";
+
+/// When to check whether a server that stopped sending anything is still alive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// How long the server may send nothing while requests are pending before it's pinged.
+    /// Must not be zero.
+    pub idle: Duration,
+    /// How long the server has to answer the ping. Must not be zero.
+    pub timeout: Duration,
+}
+
+/// Since when the server has been silent.
+#[derive(Debug)]
+pub(super) struct Activity {
+    /// When the last message was received, or requests became pending after the server was
+    /// silent because nothing was expected from it.
+    silent_since: Mutex<Instant>,
+    pings: AtomicU64,
+}
+
+impl Activity {
+    pub(super) fn new() -> Self {
+        Self {
+            silent_since: Mutex::new(Instant::now()),
+            pings: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn reset(&self) {
+        *self.silent_since.lock() = Instant::now();
+    }
+
+    fn idle_since(&self) -> Instant {
+        *self.silent_since.lock()
+    }
+}
+
+impl Transport {
+    pub(super) async fn watch_health(transport: Weak<Self>, check: HealthCheck) {
+        loop {
+            let Some(deadline) = transport
+                .upgrade()
+                .map(|transport| transport.activity.idle_since() + check.idle)
+            else {
+                return;
+            };
+            tokio::time::sleep_until(deadline).await;
+
+            let Some(transport) = transport.upgrade() else {
+                return;
+            };
+            // Being idle is fine while nothing is expected from the server, and requests are
+            // held back until it's initialized.
+            if transport.activity.idle_since() + check.idle > Instant::now()
+                || !*transport.busy.borrow()
+                || !transport.startup.is_initialized()
+            {
+                drop(transport);
+                tokio::time::sleep(check.idle.min(Duration::from_secs(1))).await;
+                continue;
+            }
+
+            if !transport.ping(check.timeout).await {
+                log::error!(
+                    "{} didn't answer a ping within {:?} after being silent for {:?} with requests pending",
+                    transport.log_name,
+                    check.timeout,
+                    check.idle
+                );
+                let _ = transport
+                    .reader_control
+                    .send(ReaderControl::Close(Error::ServerUnresponsive));
+                return;
+            }
+        }
+    }
+
+    /// Sends a ping request, returning whether the server answered in time. A transport that
+    /// closed in the meantime is considered alive, its failure is reported already.
+    async fn ping(&self, timeout: Duration) -> bool {
+        let Some(server_tx) = self.server_tx.upgrade() else {
+            return true;
+        };
+        let n = self.activity.pings.fetch_add(1, Ordering::Relaxed);
+        let (chan, mut rx) = channel(1);
+        let value = jsonrpc::MethodCall {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: PING_METHOD.to_string(),
+            params: jsonrpc::Params::None,
+            id: jsonrpc::Id::Str(format!("helix-ping-{n}")),
+        };
+        if server_tx.send(Payload::request(chan, value)).is_err() {
+            return true;
+        }
+        drop(server_tx);
+        tokio::time::timeout(timeout, rx.recv()).await.is_ok()
+    }
+}
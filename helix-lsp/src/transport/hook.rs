@@ -0,0 +1,36 @@
+//! Observing every message received from the server, for tests and tooling.
+
+use super::{ReceivedMessage, TransportMiddleware};
+use crate::jsonrpc;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+/// A copy of a message received from the server. Batches are reported message by message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InboundMessage {
+    /// A response to a request of the client.
+    Output(jsonrpc::Output),
+    /// A request or notification from the server.
+    Call(jsonrpc::Call),
+    /// A response to a request of the client with neither a result nor an error.
+    ResultMissing(jsonrpc::Id),
+}
+
+/// Hands a copy of every message to the channel set with
+/// [`TransportConfig::inbound_hook`](super::TransportConfig::inbound_hook).
+#[derive(Debug, Clone)]
+pub(super) struct InboundHook(pub(super) Sender<InboundMessage>);
+
+impl TransportMiddleware for InboundHook {
+    fn on_recv(&self, message: ReceivedMessage<'_>) {
+        let msg = match message {
+            ReceivedMessage::Output(output) => InboundMessage::Output(output.clone()),
+            ReceivedMessage::ResultMissing(id) => InboundMessage::ResultMissing(id.clone()),
+            ReceivedMessage::Call(call) => InboundMessage::Call(call.clone()),
+            ReceivedMessage::Malformed { .. } | ReceivedMessage::MalformedCall { .. } => return,
+        };
+        // never wait for the observer: this runs on the receiving loop
+        if let Err(TrySendError::Full(msg)) = self.0.try_send(msg) {
+            log::warn!("inbound hook is full, dropping {msg:?}");
+        }
+    }
+}
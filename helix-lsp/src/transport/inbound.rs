@@ -0,0 +1,149 @@
+//! The channel carrying server calls to the consumer of a [`Transport`](super::Transport), and
+//! detection of a consumer that can't keep up with it.
+
+use crate::{jsonrpc, LanguageServerId};
+use futures_util::Stream;
+use parking_lot::Mutex;
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// How many forwarded messages pass between two samples of the channel depth.
+const SAMPLE_INTERVAL: u64 = 64;
+/// How many consecutive growing samples above the threshold mark the consumer as lagging.
+const LAGGING_SAMPLES: u32 = 3;
+
+/// The receiving end of the calls sent by the server.
+///
+/// This is a thin wrapper around an unbounded channel that additionally counts the messages
+/// taken out of it, so that the transport can tell how far behind its consumer is.
+#[derive(Debug)]
+pub struct InboundReceiver {
+    rx: UnboundedReceiver<(LanguageServerId, jsonrpc::Call)>,
+    consumed: Arc<AtomicU64>,
+}
+
+impl InboundReceiver {
+    pub(super) fn new(
+        rx: UnboundedReceiver<(LanguageServerId, jsonrpc::Call)>,
+        depth: &InboundDepth,
+    ) -> Self {
+        Self {
+            rx,
+            consumed: depth.consumed.clone(),
+        }
+    }
+
+    /// Receives the next call, see [`UnboundedReceiver::recv`].
+    pub async fn recv(&mut self) -> Option<(LanguageServerId, jsonrpc::Call)> {
+        let msg = self.rx.recv().await;
+        if msg.is_some() {
+            self.consumed.fetch_add(1, Ordering::Relaxed);
+        }
+        msg
+    }
+}
+
+impl Stream for InboundReceiver {
+    type Item = (LanguageServerId, jsonrpc::Call);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.rx.poll_recv(cx);
+        if let Poll::Ready(Some(_)) = poll {
+            self.consumed.fetch_add(1, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+/// Tracks how many messages are waiting in the inbound channel.
+#[derive(Debug, Default)]
+pub(super) struct InboundDepth {
+    forwarded: AtomicU64,
+    consumed: Arc<AtomicU64>,
+    trend: Mutex<Trend>,
+}
+
+#[derive(Debug, Default)]
+struct Trend {
+    last_depth: u64,
+    growing: u32,
+    warned: bool,
+}
+
+impl InboundDepth {
+    pub(super) fn depth(&self) -> u64 {
+        let consumed = self.consumed.load(Ordering::Relaxed);
+        self.forwarded
+            .load(Ordering::Relaxed)
+            .saturating_sub(consumed)
+    }
+
+    /// Records a message sent to the consumer. Returns the current depth when the consumer
+    /// just started lagging behind: the depth exceeded `threshold` and kept growing over
+    /// several samples. It isn't reported again until the depth drops below half the threshold.
+    pub(super) fn record_forwarded(&self, threshold: Option<usize>) -> Option<u64> {
+        let forwarded = self.forwarded.fetch_add(1, Ordering::Relaxed) + 1;
+        let threshold = threshold? as u64;
+        if forwarded % SAMPLE_INTERVAL != 0 {
+            return None;
+        }
+
+        let depth = self.depth();
+        let mut trend = self.trend.lock();
+        if depth < threshold / 2 {
+            *trend = Trend::default();
+        } else if depth >= threshold && depth > trend.last_depth {
+            trend.growing += 1;
+        } else if depth < trend.last_depth {
+            trend.growing = 0;
+        }
+        trend.last_depth = depth;
+
+        if trend.growing >= LAGGING_SAMPLES && !trend.warned {
+            trend.warned = true;
+            return Some(depth);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_growing_depth() {
+        let depth = InboundDepth::default();
+        let mut lagging = Vec::new();
+        for _ in 0..SAMPLE_INTERVAL * 8 {
+            lagging.extend(depth.record_forwarded(Some(200)));
+        }
+        // samples up to 192 are below the threshold, 256, 320 and 384 grow above it
+        assert_eq!(lagging, [384]);
+
+        // catching up re-arms the warning
+        depth
+            .consumed
+            .store(depth.forwarded.load(Ordering::Relaxed), Ordering::Relaxed);
+        for _ in 0..SAMPLE_INTERVAL {
+            assert_eq!(depth.record_forwarded(Some(200)), None);
+        }
+        assert!(!depth.trend.lock().warned);
+    }
+
+    #[test]
+    fn disabled_without_threshold() {
+        let depth = InboundDepth::default();
+        for _ in 0..SAMPLE_INTERVAL * 8 {
+            assert_eq!(depth.record_forwarded(None), None);
+        }
+        assert_eq!(depth.depth(), SAMPLE_INTERVAL * 8);
+    }
+}
@@ -0,0 +1,76 @@
+//! Signalling that the server answered the `initialize` request.
+
+use tokio::sync::watch;
+
+/// Tells the send task of a [`Transport`](super::Transport) that the server is initialized, so
+/// the messages held back until then can be sent.
+///
+/// Unlike a [`tokio::sync::Notify`] permit the signal is never consumed: it stays set once
+/// [`notify`](Self::notify) was called, so any number of tasks can wait for it, before or after
+/// the fact, without taking it away from the send task.
+#[derive(Debug)]
+pub struct InitializeSignal {
+    initialized: watch::Sender<bool>,
+}
+
+impl Default for InitializeSignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InitializeSignal {
+    pub fn new() -> Self {
+        Self {
+            initialized: watch::Sender::new(false),
+        }
+    }
+
+    /// Marks the server as initialized, waking everyone waiting in [`notified`](Self::notified).
+    pub fn notify(&self) {
+        self.initialized.send_replace(true);
+    }
+
+    pub fn is_notified(&self) -> bool {
+        *self.initialized.borrow()
+    }
+
+    /// Waits until [`notify`](Self::notify) is called, returning immediately if it was already.
+    /// Cancellation safe.
+    pub async fn notified(&self) {
+        let mut initialized = self.subscribe();
+        // the sender lives in `self`, so this can't fail
+        let _ = initialized.wait_for(|initialized| *initialized).await;
+    }
+
+    pub(super) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.initialized.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn latches_for_every_waiter() {
+        let signal = Arc::new(InitializeSignal::new());
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                let signal = signal.clone();
+                tokio::spawn(async move { signal.notified().await })
+            })
+            .collect();
+        tokio::task::yield_now().await;
+        assert!(!signal.is_notified());
+
+        signal.notify();
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+        // waiting after the fact doesn't block
+        signal.notified().await;
+        assert!(signal.is_notified());
+    }
+}
@@ -0,0 +1,134 @@
+//! Round-trip latency distributions, see [`Transport::latency_stats`](super::Transport::latency_stats).
+
+use std::time::Duration;
+
+/// Values below `1 << SUB_BUCKET_BITS` microseconds are recorded exactly, larger ones in
+/// buckets whose width is at most 1/32 of their lower bound, so quantiles are within about 3%.
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/// Round-trip latency statistics of the requests of one method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyStats {
+    /// The number of responses.
+    pub count: u64,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// A log-linear histogram of latencies in microseconds, in the spirit of HDR histograms.
+#[derive(Debug, Default, Clone)]
+pub(super) struct LatencyHistogram {
+    /// Grown on demand up to the bucket of the largest latency.
+    counts: Vec<u64>,
+    count: u64,
+    min: u64,
+    max: u64,
+    sum: u128,
+}
+
+impl LatencyHistogram {
+    pub(super) fn observe(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let bucket = bucket(micros);
+        if self.counts.len() <= bucket {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+        self.min = if self.count == 0 {
+            micros
+        } else {
+            self.min.min(micros)
+        };
+        self.max = self.max.max(micros);
+        self.count += 1;
+        self.sum += micros as u128;
+    }
+
+    pub(super) fn stats(&self) -> Option<LatencyStats> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(LatencyStats {
+            count: self.count,
+            min: Duration::from_micros(self.min),
+            max: Duration::from_micros(self.max),
+            mean: Duration::from_micros((self.sum / self.count as u128) as u64),
+            p50: self.quantile(0.50),
+            p95: self.quantile(0.95),
+            p99: self.quantile(0.99),
+        })
+    }
+
+    /// The upper bound of the bucket holding the `q` quantile, clamped to the observed range.
+    fn quantile(&self, q: f64) -> Duration {
+        let rank = ((q * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let micros = upper_bound(bucket).clamp(self.min, self.max);
+                return Duration::from_micros(micros);
+            }
+        }
+        Duration::from_micros(self.max)
+    }
+}
+
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exponent = u64::BITS - 1 - micros.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let sub_bucket = (micros >> shift) & (SUB_BUCKETS - 1);
+    ((shift as u64 + 1) * SUB_BUCKETS + sub_bucket) as usize
+}
+
+fn upper_bound(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let shift = bucket / SUB_BUCKETS - 1;
+    let sub_bucket = bucket % SUB_BUCKETS;
+    let lower = (SUB_BUCKETS + sub_bucket) << shift;
+    lower.saturating_add((1 << shift) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.stats(), None);
+        for millis in 1..=100 {
+            histogram.observe(Duration::from_millis(millis));
+        }
+        let stats = histogram.stats().unwrap();
+        assert_eq!(stats.count, 100);
+        assert_eq!(stats.min, Duration::from_millis(1));
+        assert_eq!(stats.max, Duration::from_millis(100));
+        assert_eq!(stats.mean, Duration::from_micros(50_500));
+        for (quantile, expected) in [(stats.p50, 50), (stats.p95, 95), (stats.p99, 99)] {
+            let expected = Duration::from_millis(expected);
+            assert!(
+                quantile >= expected && quantile <= expected + expected / 32,
+                "{quantile:?} isn't close to {expected:?}"
+            );
+        }
+
+        // every latency falls at or below the upper bound of its bucket
+        for micros in 0..SUB_BUCKETS * 4 {
+            assert!(upper_bound(bucket(micros)) >= micros);
+            assert_eq!(bucket(upper_bound(bucket(micros))), bucket(micros));
+        }
+        assert_eq!(upper_bound(bucket(u64::MAX)), u64::MAX);
+    }
+}
@@ -0,0 +1,122 @@
+//! Tracking of the documents opened on the server, to catch document notifications sent out of
+//! order by the consumer.
+
+use crate::{jsonrpc, lsp};
+use lsp::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument,
+    Notification, WillSaveTextDocument,
+};
+use parking_lot::Mutex;
+use std::collections::HashSet;
+
+/// What to do with a `textDocument/*` notification sent out of order, e.g. a `didChange` for a
+/// document that was never opened or was already closed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DocumentLifecycleCheck {
+    /// Don't track documents.
+    #[default]
+    Disabled,
+    /// Send the notification anyway, logging a warning.
+    Warn,
+    /// Don't send the notification, logging an error.
+    Reject,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct DocumentTracker {
+    open: Mutex<HashSet<String>>,
+}
+
+impl DocumentTracker {
+    /// Records an outgoing notification, returning why it is out of order if it is.
+    pub(super) fn check(&self, notification: &jsonrpc::Notification) -> Result<(), String> {
+        let method = notification.method.as_str();
+        let opens = method == DidOpenTextDocument::METHOD;
+        let closes = method == DidCloseTextDocument::METHOD;
+        let requires_open = closes
+            || [
+                DidChangeTextDocument::METHOD,
+                DidSaveTextDocument::METHOD,
+                WillSaveTextDocument::METHOD,
+            ]
+            .contains(&method);
+        if !opens && !requires_open {
+            return Ok(());
+        }
+
+        let Some(uri) = document_uri(&notification.params) else {
+            return Err(format!("`{method}` without a text document uri"));
+        };
+        let mut open = self.open.lock();
+        if opens {
+            if !open.insert(uri.to_string()) {
+                return Err(format!("`{method}` for {uri}, which is already open"));
+            }
+        } else if closes {
+            if !open.remove(uri) {
+                return Err(format!("`{method}` for {uri}, which isn't open"));
+            }
+        } else if !open.contains(uri) {
+            return Err(format!("`{method}` for {uri}, which isn't open"));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the `textDocument.uri` member shared by the params of all document notifications.
+fn document_uri(params: &jsonrpc::Params) -> Option<&str> {
+    let jsonrpc::Params::Map(params) = params else {
+        return None;
+    };
+    params.get("textDocument")?.get("uri")?.as_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn notification(method: &str, uri: &str) -> jsonrpc::Notification {
+        let params = json!({"textDocument": {"uri": uri}});
+        jsonrpc::Notification {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: method.to_string(),
+            params: jsonrpc::Params::Map(params.as_object().unwrap().clone()),
+        }
+    }
+
+    #[test]
+    fn tracks_open_documents() {
+        let tracker = DocumentTracker::default();
+        let (a, b) = ("file:///a.rs", "file:///b.rs");
+
+        assert!(tracker
+            .check(&notification("textDocument/didChange", a))
+            .is_err());
+        assert!(tracker
+            .check(&notification("textDocument/didOpen", a))
+            .is_ok());
+        assert!(tracker
+            .check(&notification("textDocument/didOpen", a))
+            .is_err());
+        assert!(tracker
+            .check(&notification("textDocument/didChange", a))
+            .is_ok());
+        assert!(tracker
+            .check(&notification("textDocument/didSave", b))
+            .is_err());
+        assert!(tracker
+            .check(&notification("textDocument/didClose", a))
+            .is_ok());
+        assert!(tracker
+            .check(&notification("textDocument/didSave", a))
+            .is_err());
+        assert!(tracker
+            .check(&notification("textDocument/didClose", a))
+            .is_err());
+        // other notifications aren't tracked
+        assert!(tracker
+            .check(&notification("workspace/didChangeConfiguration", a))
+            .is_ok());
+    }
+}
@@ -0,0 +1,142 @@
+//! The name of a transport as it appears in its log lines.
+
+use crate::LanguageServerId;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, LazyLock,
+    },
+};
+
+/// How the name of a transport appears in its log lines.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum LogNameFormat {
+    /// Only the name of the server, e.g. `rust-analyzer`.
+    Name,
+    /// The name and id of the server, e.g. `rust-analyzer#3`.
+    NameAndId,
+    /// The name and id while another server of the same name is running, the name otherwise.
+    #[default]
+    Auto,
+    /// A custom name built from the name and id of the server.
+    Custom(fn(&str, LanguageServerId) -> String),
+}
+
+/// The number of running transports of each name.
+static RUNNING: LazyLock<Mutex<HashMap<String, Arc<AtomicUsize>>>> =
+    LazyLock::new(Default::default);
+
+#[derive(Debug)]
+pub(super) struct LogName {
+    name: String,
+    id: LanguageServerId,
+    format: LogNameFormat,
+    custom: Option<String>,
+    running: Arc<AtomicUsize>,
+}
+
+impl LogName {
+    pub(super) fn new(name: String, id: LanguageServerId, format: LogNameFormat) -> Self {
+        // counted under the lock, so a transport of the same name being dropped can't remove
+        // the entry in between
+        let running = {
+            let mut running = RUNNING.lock();
+            let count = running.entry(name.clone()).or_default();
+            count.fetch_add(1, Ordering::Relaxed);
+            count.clone()
+        };
+        Self {
+            custom: match format {
+                LogNameFormat::Custom(format) => Some(format(&name, id)),
+                _ => None,
+            },
+            name,
+            id,
+            format,
+            running,
+        }
+    }
+
+    /// The name of the server, without the id.
+    #[cfg(feature = "metrics")]
+    pub(super) fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write_with_id(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the slot of the id, leaving out its version
+        let index = slotmap::Key::data(&self.id).as_ffi() as u32;
+        write!(f, "{}#{index}", self.name)
+    }
+}
+
+impl fmt::Display for LogName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.format {
+            LogNameFormat::Name => f.write_str(&self.name),
+            LogNameFormat::NameAndId => self.write_with_id(f),
+            LogNameFormat::Auto if self.running.load(Ordering::Relaxed) > 1 => {
+                self.write_with_id(f)
+            }
+            LogNameFormat::Auto => f.write_str(&self.name),
+            LogNameFormat::Custom(_) => f.write_str(self.custom.as_deref().unwrap_or_default()),
+        }
+    }
+}
+
+impl Drop for LogName {
+    fn drop(&mut self) {
+        let mut running = RUNNING.lock();
+        if self.running.fetch_sub(1, Ordering::Relaxed) == 1 {
+            running.remove(&self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slotmap::SlotMap;
+
+    #[test]
+    fn formats() {
+        let mut ids = SlotMap::<LanguageServerId, ()>::with_key();
+        let (first, second) = (ids.insert(()), ids.insert(()));
+
+        let name = LogName::new("log-name-test".to_string(), first, LogNameFormat::Name);
+        assert_eq!(name.to_string(), "log-name-test");
+        let name = LogName::new(
+            "log-name-test".to_string(),
+            second,
+            LogNameFormat::NameAndId,
+        );
+        assert_eq!(name.to_string(), "log-name-test#2");
+        let name = LogName::new(
+            "log-name-test".to_string(),
+            first,
+            LogNameFormat::Custom(|name, _| format!("[{name}]")),
+        );
+        assert_eq!(name.to_string(), "[log-name-test]");
+    }
+
+    #[test]
+    fn auto_includes_id_of_same_named_servers() {
+        let mut ids = SlotMap::<LanguageServerId, ()>::with_key();
+        let (first, second) = (ids.insert(()), ids.insert(()));
+
+        let first = LogName::new("auto-log-name-test".to_string(), first, LogNameFormat::Auto);
+        assert_eq!(first.to_string(), "auto-log-name-test");
+        let second = LogName::new(
+            "auto-log-name-test".to_string(),
+            second,
+            LogNameFormat::Auto,
+        );
+        assert_eq!(first.to_string(), "auto-log-name-test#1");
+        assert_eq!(second.to_string(), "auto-log-name-test#2");
+        drop(first);
+        assert_eq!(second.to_string(), "auto-log-name-test");
+    }
+}
@@ -0,0 +1,592 @@
+//! Metrics collected by a [`Transport`](super::Transport).
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use super::{
+    latency::{LatencyHistogram, LatencyStats},
+    RequestGroup,
+};
+
+/// The most groups reported in [`MetricsSnapshot::pending_groups`], which bounds the number of
+/// distinct `group` labels.
+const MAX_REPORTED_GROUPS: usize = 32;
+/// The group the requests of the groups beyond [`MAX_REPORTED_GROUPS`] are reported under.
+const OTHER_GROUPS: &str = "(other)";
+
+#[derive(Debug, Default)]
+pub(super) struct Metrics {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    pending: AtomicU64,
+    peak_pending: AtomicU64,
+    slow_consumer_warnings: AtomicU64,
+    methods: Mutex<HashMap<String, MethodMetrics>>,
+    latencies: Mutex<HashMap<String, LatencyHistogram>>,
+    /// Pending requests of each [`RequestGroup`], removed once none are left.
+    groups: Mutex<HashMap<RequestGroup, u64>>,
+    dropped_notifications: Mutex<HashMap<String, u64>>,
+    serialization: Mutex<Histogram>,
+    parsing: Mutex<Histogram>,
+}
+
+impl Metrics {
+    pub(super) fn record_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_request(&self, method: &str, group: Option<&RequestGroup>) {
+        let pending = self.pending.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_pending.fetch_max(pending, Ordering::Relaxed);
+        if let Some(group) = group {
+            *self.groups.lock().entry(group.clone()).or_default() += 1;
+        }
+        let mut methods = self.methods.lock();
+        match methods.get_mut(method) {
+            Some(metrics) => metrics.requests += 1,
+            None => {
+                methods.insert(
+                    method.to_string(),
+                    MethodMetrics {
+                        requests: 1,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+    }
+
+    pub(super) fn record_response(
+        &self,
+        method: &str,
+        group: Option<&RequestGroup>,
+        latency: Duration,
+        is_error: bool,
+    ) {
+        self.release_pending(group);
+        if let Some(metrics) = self.methods.lock().get_mut(method) {
+            metrics.responses += 1;
+            metrics.errors += is_error as u64;
+            metrics.latency += latency;
+        }
+        let mut latencies = self.latencies.lock();
+        match latencies.get_mut(method) {
+            Some(histogram) => histogram.observe(latency),
+            None => {
+                let mut histogram = LatencyHistogram::default();
+                histogram.observe(latency);
+                latencies.insert(method.to_string(), histogram);
+            }
+        }
+    }
+
+    /// Records a request given up on before it was answered, because it timed out or was
+    /// cancelled. Unlike a response it's neither an error nor a latency sample.
+    pub(super) fn record_abandoned_request(&self, method: &str, group: Option<&RequestGroup>) {
+        self.release_pending(group);
+        if let Some(metrics) = self.methods.lock().get_mut(method) {
+            metrics.abandoned += 1;
+        }
+    }
+
+    /// Takes a request that is no longer waiting for a response out of the pending ones.
+    fn release_pending(&self, group: Option<&RequestGroup>) {
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+        if let Some(group) = group {
+            let mut groups = self.groups.lock();
+            if let Some(pending) = groups.get_mut(group) {
+                *pending -= 1;
+                if *pending == 0 {
+                    groups.remove(group);
+                }
+            }
+        }
+    }
+
+    pub(super) fn record_slow_consumer(&self) {
+        self.slow_consumer_warnings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_dropped_notification(&self, method: &str) {
+        let mut dropped = self.dropped_notifications.lock();
+        match dropped.get_mut(method) {
+            Some(count) => *count += 1,
+            None => {
+                dropped.insert(method.to_string(), 1);
+            }
+        }
+    }
+
+    /// Runs `serialize`, recording the time it takes.
+    pub(super) fn time_serialization<T>(&self, serialize: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let serialized = serialize();
+        self.serialization.lock().observe(started.elapsed());
+        serialized
+    }
+
+    /// Runs `parse`, recording the time it takes.
+    pub(super) fn time_parsing<T>(&self, parse: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let parsed = parse();
+        self.parsing.lock().observe(started.elapsed());
+        parsed
+    }
+
+    pub(super) fn latency_stats(&self, method: &str) -> Option<LatencyStats> {
+        self.latencies.lock().get(method)?.stats()
+    }
+
+    /// Records requests that were dropped without ever receiving a response.
+    pub(super) fn record_abandoned(&self, count: usize) {
+        self.pending.fetch_sub(count as u64, Ordering::Relaxed);
+        self.groups.lock().clear();
+    }
+
+    /// The groups with the most pending requests, the others summed up under
+    /// [`OTHER_GROUPS`].
+    fn pending_groups(&self) -> BTreeMap<String, u64> {
+        let mut groups: Vec<_> = self
+            .groups
+            .lock()
+            .iter()
+            .map(|(group, &pending)| (group.to_string(), pending))
+            .collect();
+        groups.sort_unstable_by(|(a, a_pending), (b, b_pending)| {
+            b_pending.cmp(a_pending).then_with(|| a.cmp(b))
+        });
+        let other: u64 = groups
+            .iter()
+            .skip(MAX_REPORTED_GROUPS)
+            .map(|(_, pending)| pending)
+            .sum();
+        groups.truncate(MAX_REPORTED_GROUPS);
+        let mut pending_groups: BTreeMap<_, _> = groups.into_iter().collect();
+        if other > 0 {
+            *pending_groups.entry(OTHER_GROUPS.to_string()).or_default() += other;
+        }
+        pending_groups
+    }
+
+    pub(super) fn snapshot(&self, server: &str, inbound_depth: u64) -> MetricsSnapshot {
+        MetricsSnapshot {
+            server: server.to_string(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            pending: self.pending.load(Ordering::Relaxed),
+            peak_pending: self.peak_pending.load(Ordering::Relaxed),
+            inbound_depth,
+            slow_consumer_warnings: self.slow_consumer_warnings.load(Ordering::Relaxed),
+            methods: self
+                .methods
+                .lock()
+                .iter()
+                .map(|(method, metrics)| (method.clone(), metrics.clone()))
+                .collect(),
+            pending_groups: self.pending_groups(),
+            dropped_notifications: self
+                .dropped_notifications
+                .lock()
+                .iter()
+                .map(|(method, &count)| (method.clone(), count))
+                .collect(),
+            serialization: self.serialization.lock().clone(),
+            parsing: self.parsing.lock().clone(),
+        }
+    }
+}
+
+/// Per-method request statistics.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MethodMetrics {
+    /// Requests sent to the server.
+    pub requests: u64,
+    /// Responses received, including errors.
+    pub responses: u64,
+    /// Responses that were errors.
+    pub errors: u64,
+    /// The summed round-trip time of all responses.
+    pub latency: Duration,
+    /// Requests given up on before they were answered, because they timed out or were
+    /// cancelled. They aren't counted as responses.
+    pub abandoned: u64,
+}
+
+/// A distribution of durations, in the buckets bounded by [`Histogram::BOUNDS`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    /// The number of observations in each bucket: at most the bound of the bucket and more
+    /// than the bound of the previous one. The last bucket has no upper bound.
+    pub buckets: [u64; Histogram::BOUNDS.len() + 1],
+    /// The number of observations.
+    pub count: u64,
+    /// The sum of all observations.
+    pub sum: Duration,
+}
+
+impl Histogram {
+    /// The upper bounds of the buckets.
+    pub const BOUNDS: [Duration; 6] = [
+        Duration::from_micros(10),
+        Duration::from_micros(100),
+        Duration::from_millis(1),
+        Duration::from_millis(10),
+        Duration::from_millis(100),
+        Duration::from_secs(1),
+    ];
+
+    fn observe(&mut self, duration: Duration) {
+        let bucket = Self::BOUNDS
+            .iter()
+            .position(|&bound| duration <= bound)
+            .unwrap_or(Self::BOUNDS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += duration;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            cumulative += count;
+            let le = match Self::BOUNDS.get(bucket) {
+                Some(bound) => bound.as_secs_f64().to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, r#"{name}_bucket{{{labels},le="{le}"}} {cumulative}"#);
+        }
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum.as_secs_f64());
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+/// A point in time copy of the metrics of a [`Transport`](super::Transport).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// The name of the language server.
+    pub server: String,
+    /// Bytes written to the server, including headers.
+    pub bytes_sent: u64,
+    /// Bytes read from the server, including headers.
+    pub bytes_received: u64,
+    /// Requests currently waiting for a response.
+    pub pending: u64,
+    /// The most requests that were waiting for a response at the same time.
+    pub peak_pending: u64,
+    /// Server calls waiting to be taken out of the [`InboundReceiver`](super::InboundReceiver).
+    pub inbound_depth: u64,
+    /// How often the consumer of server calls was detected to be falling behind.
+    pub slow_consumer_warnings: u64,
+    /// Request statistics keyed by method.
+    pub methods: BTreeMap<String, MethodMetrics>,
+    /// Requests currently waiting for a response, keyed by their [`RequestGroup`].
+    /// Ungrouped requests aren't included. Only the groups with the most pending requests are
+    /// reported, the requests of the others are summed up under `"(other)"`, so that the number
+    /// of distinct groups stays bounded.
+    pub pending_groups: BTreeMap<String, u64>,
+    /// Notifications dropped by [`TransportConfig::limit_notifications`](super::TransportConfig::limit_notifications),
+    /// keyed by method.
+    pub dropped_notifications: BTreeMap<String, u64>,
+    /// Time spent serializing messages to the server, excluding writing them.
+    pub serialization: Histogram,
+    /// Time spent parsing messages from the server, excluding reading them.
+    pub parsing: Histogram,
+}
+
+impl MetricsSnapshot {
+    /// Renders the metrics in the Prometheus text exposition format. Each metric family is
+    /// rendered as one block: its `HELP` and `TYPE` lines, then all of its samples.
+    pub fn render_prometheus(&self) -> String {
+        let server = escape_label(&self.server);
+        let mut out = String::new();
+
+        family(
+            &mut out,
+            "helix_lsp_requests_total",
+            "counter",
+            "Requests sent to the language server.",
+        );
+        for (method, metrics) in &self.methods {
+            let _ = writeln!(
+                out,
+                r#"helix_lsp_requests_total{{server="{server}",method="{}"}} {}"#,
+                escape_label(method),
+                metrics.requests
+            );
+        }
+        family(
+            &mut out,
+            "helix_lsp_request_errors_total",
+            "counter",
+            "Requests that the language server answered with an error.",
+        );
+        for (method, metrics) in &self.methods {
+            let _ = writeln!(
+                out,
+                r#"helix_lsp_request_errors_total{{server="{server}",method="{}"}} {}"#,
+                escape_label(method),
+                metrics.errors
+            );
+        }
+        family(
+            &mut out,
+            "helix_lsp_requests_abandoned_total",
+            "counter",
+            "Requests that timed out or were cancelled before the language server answered.",
+        );
+        for (method, metrics) in &self.methods {
+            let _ = writeln!(
+                out,
+                r#"helix_lsp_requests_abandoned_total{{server="{server}",method="{}"}} {}"#,
+                escape_label(method),
+                metrics.abandoned
+            );
+        }
+        family(
+            &mut out,
+            "helix_lsp_request_duration_seconds",
+            "summary",
+            "Round-trip time of requests to the language server.",
+        );
+        for (method, metrics) in &self.methods {
+            let labels = format!(r#"server="{server}",method="{}""#, escape_label(method));
+            let _ = writeln!(
+                out,
+                "helix_lsp_request_duration_seconds_sum{{{labels}}} {}",
+                metrics.latency.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "helix_lsp_request_duration_seconds_count{{{labels}}} {}",
+                metrics.responses
+            );
+        }
+        family(
+            &mut out,
+            "helix_lsp_dropped_notifications_total",
+            "counter",
+            "Notifications from the language server dropped by a rate limit.",
+        );
+        for (method, dropped) in &self.dropped_notifications {
+            let _ = writeln!(
+                out,
+                r#"helix_lsp_dropped_notifications_total{{server="{server}",method="{}"}} {dropped}"#,
+                escape_label(method)
+            );
+        }
+        family(
+            &mut out,
+            "helix_lsp_group_pending_requests",
+            "gauge",
+            "Requests of a request group waiting for a response from the language server.",
+        );
+        for (group, pending) in &self.pending_groups {
+            let _ = writeln!(
+                out,
+                r#"helix_lsp_group_pending_requests{{server="{server}",group="{}"}} {pending}"#,
+                escape_label(group)
+            );
+        }
+
+        for (name, kind, help, value) in [
+            (
+                "helix_lsp_bytes_sent_total",
+                "counter",
+                "Bytes written to the language server.",
+                self.bytes_sent,
+            ),
+            (
+                "helix_lsp_bytes_received_total",
+                "counter",
+                "Bytes read from the language server.",
+                self.bytes_received,
+            ),
+            (
+                "helix_lsp_pending_requests",
+                "gauge",
+                "Requests waiting for a response from the language server.",
+                self.pending,
+            ),
+            (
+                "helix_lsp_peak_pending_requests",
+                "gauge",
+                "The most requests waiting for a response from the language server at once.",
+                self.peak_pending,
+            ),
+            (
+                "helix_lsp_inbound_queue_depth",
+                "gauge",
+                "Messages from the language server waiting to be processed.",
+                self.inbound_depth,
+            ),
+            (
+                "helix_lsp_slow_consumer_warnings_total",
+                "counter",
+                "Times the consumer of language server messages fell behind.",
+                self.slow_consumer_warnings,
+            ),
+        ] {
+            family(&mut out, name, kind, help);
+            let _ = writeln!(out, r#"{name}{{server="{server}"}} {value}"#);
+        }
+
+        let labels = format!(r#"server="{server}""#);
+        family(
+            &mut out,
+            "helix_lsp_serialization_duration_seconds",
+            "histogram",
+            "Time spent serializing messages to the language server.",
+        );
+        self.serialization.render(
+            &mut out,
+            "helix_lsp_serialization_duration_seconds",
+            &labels,
+        );
+        family(
+            &mut out,
+            "helix_lsp_parse_duration_seconds",
+            "histogram",
+            "Time spent parsing messages from the language server.",
+        );
+        self.parsing
+            .render(&mut out, "helix_lsp_parse_duration_seconds", &labels);
+        out
+    }
+}
+
+/// Starts the block of the metric family `name`.
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_prometheus() {
+        let metrics = Metrics::default();
+        let group = RequestGroup::new("hover 1");
+        metrics.record_request("textDocument/hover", Some(&group));
+        metrics.record_request("textDocument/hover", None);
+        metrics.record_response(
+            "textDocument/hover",
+            None,
+            Duration::from_millis(250),
+            false,
+        );
+        metrics.record_sent(100);
+        metrics.record_received(40);
+        metrics.record_dropped_notification("$/progress");
+        metrics.parsing.lock().observe(Duration::from_micros(50));
+        metrics.parsing.lock().observe(Duration::from_secs(2));
+
+        let rendered = metrics.snapshot("rust \"analyzer\"", 3).render_prometheus();
+        let labels = r#"server="rust \"analyzer\"",method="textDocument/hover""#;
+        for line in [
+            "# TYPE helix_lsp_requests_total counter".to_string(),
+            format!("helix_lsp_requests_total{{{labels}}} 2"),
+            format!("helix_lsp_request_errors_total{{{labels}}} 0"),
+            format!("helix_lsp_request_duration_seconds_sum{{{labels}}} 0.25"),
+            format!("helix_lsp_request_duration_seconds_count{{{labels}}} 1"),
+            r#"helix_lsp_bytes_sent_total{server="rust \"analyzer\""} 100"#.to_string(),
+            r#"helix_lsp_bytes_received_total{server="rust \"analyzer\""} 40"#.to_string(),
+            r#"helix_lsp_pending_requests{server="rust \"analyzer\""} 1"#.to_string(),
+            r#"helix_lsp_peak_pending_requests{server="rust \"analyzer\""} 2"#.to_string(),
+            r#"helix_lsp_group_pending_requests{server="rust \"analyzer\"",group="hover 1"} 1"#.to_string(),
+            r#"helix_lsp_inbound_queue_depth{server="rust \"analyzer\""} 3"#.to_string(),
+            r#"helix_lsp_dropped_notifications_total{server="rust \"analyzer\"",method="$/progress"} 1"#.to_string(),
+            r#"helix_lsp_parse_duration_seconds_bucket{server="rust \"analyzer\"",le="0.00001"} 0"#.to_string(),
+            r#"helix_lsp_parse_duration_seconds_bucket{server="rust \"analyzer\"",le="0.0001"} 1"#.to_string(),
+            r#"helix_lsp_parse_duration_seconds_bucket{server="rust \"analyzer\"",le="1"} 1"#.to_string(),
+            r#"helix_lsp_parse_duration_seconds_bucket{server="rust \"analyzer\"",le="+Inf"} 2"#.to_string(),
+            r#"helix_lsp_parse_duration_seconds_sum{server="rust \"analyzer\""} 2.00005"#.to_string(),
+            r#"helix_lsp_parse_duration_seconds_count{server="rust \"analyzer\""} 2"#.to_string(),
+            r#"helix_lsp_serialization_duration_seconds_count{server="rust \"analyzer\""} 0"#.to_string(),
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "missing {line:?} in\n{rendered}"
+            );
+        }
+
+        // every family is a single block, its samples following its `TYPE` line
+        let mut families = Vec::new();
+        for line in rendered.lines() {
+            if let Some(family) = line.strip_prefix("# TYPE ") {
+                let (name, _) = family.split_once(' ').unwrap();
+                assert!(!families.contains(&name), "{name} is rendered twice");
+                families.push(name);
+            } else if !line.starts_with('#') {
+                let family = families.last().expect("a sample before any family");
+                assert!(line.starts_with(family), "{line:?} outside of {family}");
+            }
+        }
+
+        // groups are only reported while they have pending requests
+        metrics.record_response("textDocument/hover", Some(&group), Duration::ZERO, false);
+        assert!(metrics.snapshot("server", 0).pending_groups.is_empty());
+    }
+
+    #[test]
+    fn abandoned_requests() {
+        let metrics = Metrics::default();
+        let group = RequestGroup::new("hover 1");
+        metrics.record_request("textDocument/hover", Some(&group));
+        metrics.record_abandoned_request("textDocument/hover", Some(&group));
+
+        let snapshot = metrics.snapshot("server", 0);
+        assert_eq!(snapshot.pending, 0);
+        assert!(snapshot.pending_groups.is_empty());
+        assert_eq!(
+            snapshot.methods["textDocument/hover"],
+            MethodMetrics {
+                requests: 1,
+                abandoned: 1,
+                ..Default::default()
+            }
+        );
+        // no latency sample either
+        assert!(metrics.latencies.lock().is_empty());
+        let rendered = snapshot.render_prometheus();
+        assert!(rendered.lines().any(|line| line
+            == r#"helix_lsp_requests_abandoned_total{server="server",method="textDocument/hover"} 1"#));
+    }
+
+    #[test]
+    fn bounded_groups() {
+        let metrics = Metrics::default();
+        let busiest = RequestGroup::new("rename");
+        metrics.record_request("textDocument/rename", Some(&busiest));
+        for i in 0..MAX_REPORTED_GROUPS + 4 {
+            metrics.record_request(
+                "textDocument/hover",
+                Some(&RequestGroup::new(i.to_string())),
+            );
+        }
+        metrics.record_request("textDocument/rename", Some(&busiest));
+
+        let groups = metrics.snapshot("server", 0).pending_groups;
+        assert_eq!(groups.len(), MAX_REPORTED_GROUPS + 1);
+        assert_eq!(groups["rename"], 2);
+        assert_eq!(groups[OTHER_GROUPS], 5);
+        assert_eq!(groups.values().sum::<u64>(), MAX_REPORTED_GROUPS as u64 + 6);
+    }
+}
@@ -0,0 +1,96 @@
+//! Observing the messages a transport exchanges, see [`TransportMiddleware`].
+
+use super::{Payload, ServerMessage};
+use crate::jsonrpc;
+use std::fmt;
+
+/// Observes every message exchanged with the server, e.g. to record the traffic with
+/// timestamps for a protocol debugging panel. Set with
+/// [`TransportConfig::middleware`](super::TransportConfig::middleware).
+///
+/// Both methods are called synchronously on the loops sending and receiving messages, so they
+/// must be fast and must never block: hand the message to a channel or a buffer rather than
+/// doing any I/O. The messages can't be modified.
+pub trait TransportMiddleware: Send + Sync + 'static {
+    /// Called with every message right before it's written to the server. Messages rejected
+    /// before that, e.g. by [`TransportConfig::document_lifecycle_check`], aren't passed. The
+    /// notifications of a batch are passed one by one.
+    ///
+    /// [`TransportConfig::document_lifecycle_check`]: super::TransportConfig::document_lifecycle_check
+    fn on_send(&self, _message: OutboundMessage<'_>) {}
+
+    /// Called with every message from the server right after it's parsed, before it's
+    /// processed. The messages of a batch are passed one by one.
+    fn on_recv(&self, _message: ReceivedMessage<'_>) {}
+}
+
+impl fmt::Debug for dyn TransportMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TransportMiddleware")
+    }
+}
+
+/// A message sent to the server, see [`TransportMiddleware::on_send`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutboundMessage<'a> {
+    /// A request, including those whose response is discarded.
+    Request(&'a jsonrpc::MethodCall),
+    Notification(&'a jsonrpc::Notification),
+    /// A response to a request of the server.
+    Response(&'a jsonrpc::Output),
+}
+
+impl<'a> OutboundMessage<'a> {
+    pub(super) fn new(payload: &'a Payload) -> Self {
+        match payload {
+            Payload::Request { value, .. } | Payload::DetachedRequest(value) => {
+                Self::Request(value)
+            }
+            Payload::Notification(notification) => Self::Notification(notification),
+            Payload::Response(output) => Self::Response(output),
+        }
+    }
+}
+
+/// A message received from the server, see [`TransportMiddleware::on_recv`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReceivedMessage<'a> {
+    /// A response to a request of the client.
+    Output(&'a jsonrpc::Output),
+    /// A request or notification from the server.
+    Call(&'a jsonrpc::Call),
+    /// A response to a request of the client with neither a result nor an error.
+    ResultMissing(&'a jsonrpc::Id),
+    /// A message that failed to parse, with the id of the request it answers if that could be
+    /// recovered.
+    Malformed {
+        id: Option<&'a jsonrpc::Id>,
+        error: &'a str,
+    },
+    /// A request or notification from the server that failed to parse, with the id of the
+    /// request if that could be recovered. The transport answers the request with an error.
+    MalformedCall {
+        id: Option<&'a jsonrpc::Id>,
+        error: &'a str,
+    },
+}
+
+impl<'a> ReceivedMessage<'a> {
+    /// `None` for batches, whose messages are observed one by one.
+    pub(super) fn new(msg: &'a ServerMessage) -> Option<Self> {
+        Some(match msg {
+            ServerMessage::Output(output) => Self::Output(output),
+            ServerMessage::ResultMissing { id, .. } => Self::ResultMissing(id),
+            ServerMessage::Call(call) => Self::Call(call),
+            ServerMessage::Malformed { id, error } => Self::Malformed {
+                id: id.as_ref(),
+                error,
+            },
+            ServerMessage::MalformedCall { id, error, .. } => Self::MalformedCall {
+                id: id.as_ref(),
+                error,
+            },
+            ServerMessage::Batch(_) => return None,
+        })
+    }
+}
@@ -0,0 +1,312 @@
+//! An in-memory language server, to test code driving a [`Transport`] without spawning a
+//! process. Requires the `test-util` feature.
+//!
+//! [`MockTransport::start`] starts a real transport over in-memory pipes, so the messages go
+//! through the same framing and parsing as with a real server. The [`MockTransport`] is the
+//! server's end of the pipes: canned messages pushed with [`MockTransport::send`] are received
+//! by the transport, and [`MockTransport::recv`] returns what the transport wrote.
+//!
+//! ```
+//! use helix_lsp::{mock::MockTransport, TransportConfig};
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let ((mut incoming, _tx, _initialize_notify, _transport), mut server) =
+//!     MockTransport::start(TransportConfig::default());
+//! server
+//!     .send(&serde_json::json!({
+//!         "jsonrpc": "2.0",
+//!         "method": "window/logMessage",
+//!         "params": { "type": 3, "message": "hello" },
+//!     }))
+//!     .await
+//!     .unwrap();
+//! let (_, call) = incoming.recv().await.unwrap();
+//! # });
+//! ```
+
+use super::{MessageCodec, StartedTransport, Transport, TransportConfig};
+use crate::{jsonrpc, Error, LanguageServerId, Result};
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::io::{AsyncWriteExt, BufReader, DuplexStream};
+
+/// The size of the in-memory pipes. Writes block while the other end is behind by that much.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// A message the transport wrote to the server, see [`MockTransport::recv`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum WrittenMessage {
+    Request(jsonrpc::MethodCall),
+    Notification(jsonrpc::Notification),
+    /// A response to a request of the server.
+    Response(jsonrpc::Output),
+    /// Messages sent as a single batch, see
+    /// [`TransportConfig::notification_batch_size`].
+    Batch(Vec<WrittenMessage>),
+}
+
+impl WrittenMessage {
+    /// The method of a request or notification.
+    pub fn method(&self) -> Option<&str> {
+        match self {
+            Self::Request(call) => Some(&call.method),
+            Self::Notification(notification) => Some(&notification.method),
+            Self::Response(_) | Self::Batch(_) => None,
+        }
+    }
+}
+
+/// The server end of a transport started with [`MockTransport::start`].
+///
+/// Dropping it closes the server's stdout, which the transport handles like a server that
+/// exited.
+#[derive(Debug)]
+pub struct MockTransport {
+    /// What the transport writes to the server's stdin.
+    stdin: BufReader<DuplexStream>,
+    stdout: DuplexStream,
+    stderr: DuplexStream,
+    /// The framing of the transport, which the server uses too.
+    codec: Arc<dyn MessageCodec>,
+    /// The [`TransportConfig::max_message_size`] of the transport, which the server applies too.
+    max_message_size: usize,
+}
+
+impl MockTransport {
+    /// Starts a transport with `config`, connected to the returned mock server.
+    pub fn start(config: TransportConfig) -> (StartedTransport, Self) {
+        let codec = config
+            .codec
+            .clone()
+            .unwrap_or_else(|| Arc::new(config.lsp_codec));
+        let max_message_size = config.max_message_size;
+        let (stdin_tx, stdin_rx) = tokio::io::duplex(PIPE_CAPACITY);
+        let (stdout_tx, stdout_rx) = tokio::io::duplex(PIPE_CAPACITY);
+        let (stderr_tx, stderr_rx) = tokio::io::duplex(PIPE_CAPACITY);
+        let started = Transport::start(
+            BufReader::new(stdout_rx),
+            stdin_tx,
+            BufReader::new(stderr_rx),
+            LanguageServerId::default(),
+            "mock".to_string(),
+            config,
+        );
+        let server = Self {
+            stdin: BufReader::new(stdin_rx),
+            stdout: stdout_tx,
+            stderr: stderr_tx,
+            codec,
+            max_message_size,
+        };
+        (started, server)
+    }
+
+    /// Sends a message to the transport, framed like the transport frames its own, e.g. a
+    /// response, a notification or a request of the server.
+    pub async fn send(&mut self, message: &Value) -> Result<()> {
+        let body = serde_json::to_vec(message)?;
+        self.codec.write_message(&mut self.stdout, &body).await?;
+        Ok(())
+    }
+
+    /// Writes `bytes` to the server's stdout as is, e.g. to test how malformed headers or
+    /// garbage between messages are handled.
+    pub async fn send_raw(&mut self, bytes: &[u8]) -> Result<()> {
+        self.stdout.write_all(bytes).await?;
+        Ok(())
+    }
+
+    /// Answers request `id` with `result`.
+    pub async fn respond(&mut self, id: jsonrpc::Id, result: Value) -> Result<()> {
+        let output = jsonrpc::Output::Success(jsonrpc::Success {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            result,
+            id,
+        });
+        self.send(&serde_json::to_value(output)?).await
+    }
+
+    /// Writes `line` to the server's stderr.
+    pub async fn send_stderr(&mut self, line: &str) -> Result<()> {
+        self.stderr.write_all(line.as_bytes()).await?;
+        self.stderr.write_all(b"\n").await?;
+        Ok(())
+    }
+
+    /// Reads the next message the transport wrote to the server. Fails with
+    /// [`Error::StreamClosed`] once the transport closed the server's stdin.
+    pub async fn recv(&mut self) -> Result<WrittenMessage> {
+        let mut body = Vec::new();
+        self.codec
+            .read_message(&mut self.stdin, &mut body, self.max_message_size)
+            .await?;
+        parse(serde_json::from_slice(&body)?)
+    }
+
+    /// Receives the `initialize` request and answers it with `result`, which is typically
+    /// `{"capabilities": {...}}`. The consumer still has to notify the [`InitializeSignal`]
+    /// once it processed the response.
+    ///
+    /// [`InitializeSignal`]: super::InitializeSignal
+    pub async fn initialize(&mut self, result: Value) -> Result<jsonrpc::MethodCall> {
+        let WrittenMessage::Request(call) = self.recv().await? else {
+            return Err(Error::Other(anyhow::anyhow!(
+                "expected the initialize request"
+            )));
+        };
+        self.respond(call.id.clone(), result).await?;
+        Ok(call)
+    }
+}
+
+fn parse(value: Value) -> Result<WrittenMessage> {
+    if let Value::Array(batch) = value {
+        return batch
+            .into_iter()
+            .map(parse)
+            .collect::<Result<_>>()
+            .map(WrittenMessage::Batch);
+    }
+    if value.get("method").is_none() {
+        return Ok(WrittenMessage::Response(serde_json::from_value(value)?));
+    }
+    match serde_json::from_value(value)? {
+        jsonrpc::Call::MethodCall(call) => Ok(WrittenMessage::Request(call)),
+        jsonrpc::Call::Notification(notification) => Ok(WrittenMessage::Notification(notification)),
+        jsonrpc::Call::Invalid { id } => {
+            Err(Error::Other(anyhow::anyhow!("invalid call with id {id:?}")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{NewlineJsonCodec, Payload};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn request_response() {
+        let ((mut incoming, tx, notify, transport), mut server) =
+            MockTransport::start(TransportConfig::default());
+
+        let (chan, mut response) = tokio::sync::mpsc::channel(1);
+        tx.send(Payload::Request {
+            chan,
+            value: jsonrpc::MethodCall {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: "initialize".to_string(),
+                params: jsonrpc::Params::None,
+                id: transport.next_id(),
+            },
+            group: None,
+        })
+        .unwrap();
+        let call = server
+            .initialize(json!({ "capabilities": {} }))
+            .await
+            .unwrap();
+        assert_eq!(call.method, "initialize");
+        assert_eq!(
+            response.recv().await.unwrap().unwrap(),
+            json!({ "capabilities": {} })
+        );
+        notify.notify();
+        let (_, initialized) = incoming.recv().await.unwrap();
+        assert!(matches!(initialized, jsonrpc::Call::Notification(n) if n.method == "initialized"));
+
+        // a server request goes through the real parser, garbage included
+        server.send_raw(b"not a header\r\n").await.unwrap();
+        server
+            .send(&json!({
+                "jsonrpc": "2.0",
+                "method": "workspace/configuration",
+                "params": { "items": [] },
+                "id": 7,
+            }))
+            .await
+            .unwrap();
+        let (_, call) = incoming.recv().await.unwrap();
+        let jsonrpc::Call::MethodCall(call) = call else {
+            panic!("expected a request, got {call:?}");
+        };
+        assert_eq!(call.id, jsonrpc::Id::Num(7));
+
+        tx.send(Payload::Response(jsonrpc::Output::Success(
+            jsonrpc::Success {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                result: json!([]),
+                id: call.id,
+            },
+        )))
+        .unwrap();
+        let WrittenMessage::Response(jsonrpc::Output::Success(success)) =
+            server.recv().await.unwrap()
+        else {
+            panic!("expected a response");
+        };
+        assert_eq!(success.result, json!([]));
+
+        // the server exiting reaches the consumer as an `exit` notification
+        drop(server);
+        let (_, exit) = incoming.recv().await.unwrap();
+        assert!(matches!(exit, jsonrpc::Call::Notification(n) if n.method == "exit"));
+    }
+
+    #[tokio::test]
+    async fn newline_json_codec() {
+        let config = TransportConfig::default().codec(Arc::new(NewlineJsonCodec));
+        let ((mut incoming, tx, notify, transport), mut server) = MockTransport::start(config);
+        let (chan, mut response) = tokio::sync::mpsc::channel(1);
+        tx.send(Payload::request(
+            chan,
+            jsonrpc::MethodCall {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: "initialize".to_string(),
+                params: jsonrpc::Params::None,
+                id: transport.next_id(),
+            },
+        ))
+        .unwrap();
+        let call = server.initialize(json!({})).await.unwrap();
+        assert_eq!(call.method, "initialize");
+        assert_eq!(response.recv().await.unwrap().unwrap(), json!({}));
+        notify.notify();
+        let (_, initialized) = incoming.recv().await.unwrap();
+        assert!(matches!(initialized, jsonrpc::Call::Notification(n) if n.method == "initialized"));
+    }
+
+    #[tokio::test]
+    async fn batches() {
+        let config = TransportConfig::default().notification_batch_size(Some(8));
+        let ((mut incoming, tx, notify, transport), mut server) = MockTransport::start(config);
+        let (chan, _response) = tokio::sync::mpsc::channel(1);
+        tx.send(Payload::Request {
+            chan,
+            value: jsonrpc::MethodCall {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: "initialize".to_string(),
+                params: jsonrpc::Params::None,
+                id: transport.next_id(),
+            },
+            group: None,
+        })
+        .unwrap();
+        server.initialize(json!({})).await.unwrap();
+        notify.notify();
+        incoming.recv().await.unwrap();
+
+        for method in ["a", "b"] {
+            tx.send(Payload::Notification(jsonrpc::Notification {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                method: method.to_string(),
+                params: jsonrpc::Params::None,
+            }))
+            .unwrap();
+        }
+        let WrittenMessage::Batch(batch) = server.recv().await.unwrap() else {
+            panic!("expected a batch");
+        };
+        let methods: Vec<_> = batch.iter().map(|msg| msg.method().unwrap()).collect();
+        assert_eq!(methods, ["a", "b"]);
+    }
+}
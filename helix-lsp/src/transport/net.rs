@@ -0,0 +1,164 @@
+//! Transports to language servers listening on a socket rather than talking over stdio.
+
+use super::{StartedTransport, Transport, TransportConfig};
+use crate::{Error, LanguageServerId, Result};
+use std::future::Future;
+use tokio::io::BufReader;
+
+impl Transport {
+    /// Connects to a language server listening on `addr` (e.g. `127.0.0.1:9257`) and starts
+    /// talking to it, see [`Transport::start`]. Fails with [`Error::ConnectTimeout`] if the
+    /// connection isn't established within [`TransportConfig::connect_timeout`].
+    pub async fn connect_tcp(
+        addr: &str,
+        id: LanguageServerId,
+        name: String,
+        config: TransportConfig,
+    ) -> Result<StartedTransport> {
+        let stream = connect(addr, &config, tokio::net::TcpStream::connect(addr)).await?;
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self::start_without_stderr(
+            BufReader::new(reader),
+            writer,
+            id,
+            name,
+            config,
+        ))
+    }
+
+    /// Connects to a language server listening on the unix socket at `path` and starts
+    /// talking to it, see [`Transport::connect_tcp`].
+    #[cfg(unix)]
+    pub async fn connect_unix(
+        path: &std::path::Path,
+        id: LanguageServerId,
+        name: String,
+        config: TransportConfig,
+    ) -> Result<StartedTransport> {
+        let addr = path.display().to_string();
+        let stream = connect(&addr, &config, tokio::net::UnixStream::connect(path)).await?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self::start_without_stderr(
+            BufReader::new(reader),
+            writer,
+            id,
+            name,
+            config,
+        ))
+    }
+}
+
+async fn connect<S>(
+    addr: &str,
+    config: &TransportConfig,
+    connect: impl Future<Output = std::io::Result<S>>,
+) -> Result<S> {
+    match tokio::time::timeout(config.connect_timeout, connect).await {
+        Ok(stream) => Ok(stream?),
+        Err(_) => Err(Error::ConnectTimeout(addr.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn closed_port_fails_promptly() {
+        // find a port nobody listens on
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let config = TransportConfig::default().connect_timeout(Duration::from_secs(5));
+        let started = Instant::now();
+        let result =
+            Transport::connect_tcp(&addr, LanguageServerId::default(), "test".into(), config).await;
+        assert!(matches!(
+            result,
+            Err(Error::IO(_) | Error::ConnectTimeout(_))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn connects() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let accept = tokio::spawn(async move { listener.accept().await.unwrap() });
+
+        let result = Transport::connect_tcp(
+            &addr,
+            LanguageServerId::default(),
+            "test".into(),
+            TransportConfig::default(),
+        )
+        .await;
+        assert!(result.is_ok());
+        accept.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn initialize_over_tcp() {
+        use crate::{jsonrpc, transport::Payload};
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).await.unwrap();
+                match header.trim_end().split_once(": ") {
+                    Some(("Content-Length", value)) => length = value.parse().unwrap(),
+                    _ if header.trim_end().is_empty() => break,
+                    _ => (),
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).await.unwrap();
+            let response = r#"{"jsonrpc":"2.0","result":{"capabilities":{}},"id":0}"#;
+            let message = format!("Content-Length: {}\r\n\r\n{response}", response.len());
+            writer.write_all(message.as_bytes()).await.unwrap();
+            (String::from_utf8(body).unwrap(), reader, writer)
+        });
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (reader, writer) = stream.into_split();
+        let (_rx, tx, _notify, _transport) = Transport::start_without_stderr(
+            BufReader::new(reader),
+            writer,
+            LanguageServerId::default(),
+            "test".into(),
+            TransportConfig::default(),
+        );
+        let (chan, mut response) = tokio::sync::mpsc::channel(1);
+        tx.send(Payload::Request {
+            chan,
+            value: jsonrpc::MethodCall {
+                jsonrpc: Some(jsonrpc::Version::V2),
+                id: jsonrpc::Id::Num(0),
+                method: "initialize".to_string(),
+                params: jsonrpc::Params::None,
+            },
+            group: None,
+        })
+        .unwrap();
+
+        let (body, _reader, _writer) = server.await.unwrap();
+        assert!(body.contains(r#""method":"initialize""#), "{body}");
+        let result = tokio::time::timeout(Duration::from_secs(5), response.recv())
+            .await
+            .unwrap();
+        assert_eq!(
+            result.unwrap().unwrap(),
+            serde_json::json!({ "capabilities": {} })
+        );
+    }
+}
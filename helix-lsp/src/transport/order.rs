@@ -0,0 +1,78 @@
+//! Diagnosing servers that answer requests far out of the order they were sent in.
+//!
+//! Answering out of order is legal, requests are matched to responses by id. A response
+//! overtaking many earlier requests of the same method can still point at queueing issues in
+//! the server, which is what [`TransportConfig::response_order_window`] reports.
+//!
+//! [`TransportConfig::response_order_window`]: super::TransportConfig::response_order_window
+
+use crate::jsonrpc;
+use parking_lot::Mutex;
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Debug, Default)]
+struct MethodOrder {
+    next: u64,
+    /// Sequence numbers of the requests waiting for a response.
+    pending: BTreeSet<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Orders {
+    methods: HashMap<String, MethodOrder>,
+    /// The method and sequence number of every pending request.
+    requests: HashMap<jsonrpc::Id, (String, u64)>,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct ResponseOrder {
+    orders: Mutex<Orders>,
+}
+
+impl ResponseOrder {
+    pub(super) fn record_sent(&self, id: &jsonrpc::Id, method: &str) {
+        let mut orders = self.orders.lock();
+        let order = orders.methods.entry(method.to_string()).or_default();
+        let seq = order.next;
+        order.next += 1;
+        order.pending.insert(seq);
+        orders
+            .requests
+            .insert(id.clone(), (method.to_string(), seq));
+    }
+
+    /// Records the response to request `id`, returning how many earlier requests of the same
+    /// method it overtook.
+    pub(super) fn record_response(&self, id: &jsonrpc::Id) -> usize {
+        let mut orders = self.orders.lock();
+        let Some((method, seq)) = orders.requests.remove(id) else {
+            return 0;
+        };
+        let Some(order) = orders.methods.get_mut(&method) else {
+            return 0;
+        };
+        order.pending.remove(&seq);
+        order.pending.range(..seq).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_overtaken_requests() {
+        let order = ResponseOrder::default();
+        for id in 0..4 {
+            order.record_sent(&jsonrpc::Id::Num(id), "textDocument/hover");
+        }
+        order.record_sent(&jsonrpc::Id::Num(4), "textDocument/completion");
+
+        assert_eq!(order.record_response(&jsonrpc::Id::Num(3)), 3);
+        assert_eq!(order.record_response(&jsonrpc::Id::Num(0)), 0);
+        assert_eq!(order.record_response(&jsonrpc::Id::Num(2)), 1);
+        // methods are tracked independently
+        assert_eq!(order.record_response(&jsonrpc::Id::Num(4)), 0);
+        assert_eq!(order.record_response(&jsonrpc::Id::Num(7)), 0);
+    }
+}
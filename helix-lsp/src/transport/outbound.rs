@@ -0,0 +1,404 @@
+//! Accounting of the payloads waiting for the send task, against
+//! [`TransportConfig::outbound_capacity`](super::TransportConfig::outbound_capacity).
+
+use super::{priority::PriorityBuffer, Payload};
+use crate::{Error, Result};
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{
+    mpsc::{unbounded_channel, Receiver, UnboundedReceiver, UnboundedSender},
+    Notify,
+};
+
+/// What to do with a payload when [`TransportConfig::outbound_capacity`] payloads are already
+/// waiting to be written to the server.
+///
+/// Which strategy is safe depends on the payload:
+///
+/// - Requests and responses are never dropped: [`Drop`](Self::Drop) fails them like
+///   [`Fail`](Self::Fail).
+/// - Notifications whose order matters, like `textDocument/didChange` for incremental syncs,
+///   must use [`Wait`](Self::Wait): dropping or failing one of them desyncs the server.
+/// - Notifications that are superseded by the next one, like `$/setTrace` or
+///   `workspace/didChangeConfiguration`, can use any strategy.
+///
+/// [`TransportConfig::outbound_capacity`]: super::TransportConfig::outbound_capacity
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FullChannelStrategy {
+    /// Wait for the send task to catch up. Synchronous helpers can't wait and queue the payload
+    /// anyway, exceeding the capacity.
+    #[default]
+    Wait,
+    /// Fail with [`Error::ChannelFull`].
+    Fail,
+    /// Drop notifications with a warning, fail anything else with [`Error::ChannelFull`].
+    Drop,
+}
+
+/// The `capacity` of an unbounded [`OutboundQueue`].
+const UNBOUNDED: usize = usize::MAX;
+
+/// The payloads the consumer reserved room for wait in a channel of their own, apart from the
+/// payloads sent straight through the channel returned by
+/// [`Transport::start`](super::Transport::start), so that the send task only releases the room
+/// of payloads that reserved it.
+#[derive(Debug)]
+pub(super) struct OutboundQueue {
+    /// Adjustable at runtime, [`UNBOUNDED`] for no limit.
+    capacity: AtomicUsize,
+    depth: AtomicUsize,
+    dequeued: Notify,
+    reserved_tx: UnboundedSender<Payload>,
+    /// Taken by the [`OutgoingReceiver`] of the send task.
+    reserved_rx: Mutex<Option<UnboundedReceiver<Payload>>>,
+}
+
+/// Whether [`OutboundQueue::try_reserve`] made room for a payload.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Reserved {
+    Yes,
+    /// The queue is full and the payload should be dropped.
+    Drop,
+}
+
+impl OutboundQueue {
+    pub(super) fn new(capacity: Option<usize>) -> Self {
+        let (reserved_tx, reserved_rx) = unbounded_channel();
+        Self {
+            capacity: AtomicUsize::new(capacity.unwrap_or(UNBOUNDED)),
+            depth: AtomicUsize::new(0),
+            dequeued: Notify::new(),
+            reserved_tx,
+            reserved_rx: Mutex::new(Some(reserved_rx)),
+        }
+    }
+
+    pub(super) fn capacity(&self) -> Option<usize> {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        (capacity != UNBOUNDED).then_some(capacity)
+    }
+
+    /// Changes the capacity. Payloads queued beyond a lowered capacity stay queued, new ones
+    /// wait or fail until the depth is below it.
+    pub(super) fn set_capacity(&self, capacity: Option<usize>) {
+        self.capacity
+            .store(capacity.unwrap_or(UNBOUNDED), Ordering::Relaxed);
+        // a raised capacity may make room for waiting payloads
+        self.dequeued.notify_waiters();
+    }
+
+    /// Reserves room for `payload` without waiting, queueing it beyond the capacity for
+    /// [`FullChannelStrategy::Wait`].
+    pub(super) fn try_reserve(
+        &self,
+        payload: &Payload,
+        strategy: FullChannelStrategy,
+    ) -> Result<Reserved> {
+        if self.reserve() {
+            return Ok(Reserved::Yes);
+        }
+        match (strategy, payload) {
+            (FullChannelStrategy::Wait, _) => {
+                self.push();
+                Ok(Reserved::Yes)
+            }
+            (FullChannelStrategy::Drop, Payload::Notification(_)) => Ok(Reserved::Drop),
+            (FullChannelStrategy::Fail | FullChannelStrategy::Drop, _) => Err(Error::ChannelFull),
+        }
+    }
+
+    /// Reserves room for `payload`, waiting for it with [`FullChannelStrategy::Wait`].
+    pub(super) async fn wait_reserve(
+        &self,
+        payload: &Payload,
+        strategy: FullChannelStrategy,
+    ) -> Result<Reserved> {
+        if strategy != FullChannelStrategy::Wait {
+            return self.try_reserve(payload, strategy);
+        }
+        loop {
+            let dequeued = self.dequeued.notified();
+            tokio::pin!(dequeued);
+            // register before checking so a payload dequeued in between isn't missed
+            dequeued.as_mut().enable();
+            if self.reserve() {
+                return Ok(Reserved::Yes);
+            }
+            dequeued.await;
+        }
+    }
+
+    /// Accounts for a payload regardless of the capacity, e.g. a response to the server.
+    pub(super) fn push(&self) {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Releases the room of a payload reserved with [`OutboundQueue::try_reserve`],
+    /// [`OutboundQueue::wait_reserve`] or [`OutboundQueue::push`].
+    pub(super) fn pop(&self) {
+        let depth = self.depth.fetch_sub(1, Ordering::Relaxed);
+        debug_assert_ne!(depth, 0, "released more payloads than were reserved");
+        self.dequeued.notify_waiters();
+    }
+
+    /// Sends a payload that reserved room to the send task, releasing the room if the send
+    /// task is gone.
+    pub(super) fn send(&self, payload: Payload) -> Result<()> {
+        self.reserved_tx.send(payload).map_err(|_| {
+            self.pop();
+            Error::StreamClosed
+        })
+    }
+
+    fn reserve(&self) -> bool {
+        let Some(capacity) = self.capacity() else {
+            self.push();
+            return true;
+        };
+        self.depth
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                (depth < capacity).then_some(depth + 1)
+            })
+            .is_ok()
+    }
+}
+
+/// The payloads for the send task.
+///
+/// With [`Transport::start`](super::Transport::start) everything goes through one unbounded
+/// channel. With [`Transport::start_bounded`](super::Transport::start_bounded) the payloads
+/// of the consumer come through the bounded channel, and the unbounded one only carries the
+/// payloads of the transport itself.
+///
+/// The payloads queued with room reserved in the [`OutboundQueue`] come through a channel of
+/// their own, and are taken before the others.
+///
+/// Whatever is waiting in the channels is moved to a [`PriorityBuffer`], so that high priority
+/// payloads overtake the others.
+#[derive(Debug)]
+pub(super) struct OutgoingReceiver {
+    reserved: UnboundedReceiver<Payload>,
+    unbounded: UnboundedReceiver<Payload>,
+    bounded: Option<Bounded>,
+    buffer: PriorityBuffer,
+}
+
+#[derive(Debug)]
+struct Bounded {
+    rx: Receiver<Payload>,
+    /// Keeps the unbounded channel open for the transport, which only holds a weak sender,
+    /// when nobody else has the strong one.
+    _unbounded_tx: UnboundedSender<Payload>,
+}
+
+impl OutgoingReceiver {
+    pub(super) fn unbounded(queue: &OutboundQueue, unbounded: UnboundedReceiver<Payload>) -> Self {
+        Self {
+            reserved: Self::take_reserved(queue),
+            unbounded,
+            bounded: None,
+            buffer: PriorityBuffer::default(),
+        }
+    }
+
+    pub(super) fn bounded(
+        queue: &OutboundQueue,
+        unbounded: UnboundedReceiver<Payload>,
+        bounded: Receiver<Payload>,
+        unbounded_tx: &UnboundedSender<Payload>,
+    ) -> Self {
+        Self {
+            reserved: Self::take_reserved(queue),
+            unbounded,
+            bounded: Some(Bounded {
+                rx: bounded,
+                _unbounded_tx: unbounded_tx.clone(),
+            }),
+            buffer: PriorityBuffer::default(),
+        }
+    }
+
+    fn take_reserved(queue: &OutboundQueue) -> UnboundedReceiver<Payload> {
+        queue
+            .reserved_rx
+            .lock()
+            .take()
+            .expect("a transport has a single send task")
+    }
+
+    /// The next payload, from any channel. `None` once the channel of the consumer is closed
+    /// and everything received was returned. Cancellation safe.
+    pub(super) async fn recv(&mut self, queue: &OutboundQueue) -> Option<Payload> {
+        self.drain(queue);
+        if let Some(payload) = self.buffer.pop() {
+            return Some(payload);
+        }
+        let payload = match &mut self.bounded {
+            None => tokio::select! {
+                biased;
+                // never closed while the queue holds the sender
+                Some(payload) = self.reserved.recv() => {
+                    queue.pop();
+                    Some(payload)
+                }
+                payload = self.unbounded.recv() => payload,
+            },
+            Some(bounded) => tokio::select! {
+                biased;
+                Some(payload) = self.reserved.recv() => {
+                    queue.pop();
+                    Some(payload)
+                }
+                // never closed while `bounded` holds the sender
+                Some(payload) = self.unbounded.recv() => Some(payload),
+                payload = bounded.rx.recv() => payload,
+            },
+        }?;
+        // a high priority payload right behind it still goes first
+        self.buffer.push(payload);
+        self.drain(queue);
+        self.buffer.pop()
+    }
+
+    /// Moves the payloads waiting in the channels to the buffer, releasing the room of the
+    /// reserved ones. Only as many payloads as the bounded channel holds are taken from it, or
+    /// the consumer would never wait for room.
+    fn drain(&mut self, queue: &OutboundQueue) {
+        while let Ok(payload) = self.reserved.try_recv() {
+            queue.pop();
+            self.buffer.push(payload);
+        }
+        while let Ok(payload) = self.unbounded.try_recv() {
+            self.buffer.push(payload);
+        }
+        if let Some(bounded) = &mut self.bounded {
+            while self.buffer.len() < bounded.rx.max_capacity() {
+                let Ok(payload) = bounded.rx.try_recv() else {
+                    break;
+                };
+                self.buffer.push(payload);
+            }
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+            && self.reserved.is_empty()
+            && self.unbounded.is_empty()
+            && self
+                .bounded
+                .as_ref()
+                .is_none_or(|bounded| bounded.rx.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jsonrpc;
+
+    fn notification() -> Payload {
+        Payload::Notification(jsonrpc::Notification {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: "$/setTrace".to_string(),
+            params: jsonrpc::Params::None,
+        })
+    }
+
+    fn response() -> Payload {
+        Payload::Response(jsonrpc::Output::Success(jsonrpc::Success {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            result: serde_json::Value::Null,
+            id: jsonrpc::Id::Num(0),
+        }))
+    }
+
+    #[tokio::test]
+    async fn full_channel_strategies() {
+        let queue = OutboundQueue::new(Some(1));
+        let wait = FullChannelStrategy::Wait;
+        assert_eq!(
+            queue.try_reserve(&notification(), wait).unwrap(),
+            Reserved::Yes
+        );
+
+        let drop = FullChannelStrategy::Drop;
+        assert_eq!(
+            queue.try_reserve(&notification(), drop).unwrap(),
+            Reserved::Drop
+        );
+        assert!(matches!(
+            queue.try_reserve(&response(), drop),
+            Err(Error::ChannelFull)
+        ));
+        assert!(matches!(
+            queue.try_reserve(&notification(), FullChannelStrategy::Fail),
+            Err(Error::ChannelFull)
+        ));
+
+        let payload = notification();
+        let waiting = queue.wait_reserve(&payload, wait);
+        tokio::pin!(waiting);
+        assert!(futures_util::poll!(waiting.as_mut()).is_pending());
+        queue.pop();
+        assert_eq!(waiting.await.unwrap(), Reserved::Yes);
+
+        // synchronous helpers queue beyond the capacity rather than waiting
+        assert_eq!(
+            queue.try_reserve(&notification(), wait).unwrap(),
+            Reserved::Yes
+        );
+        assert_eq!(queue.depth.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn only_reserved_payloads_release_room() {
+        let queue = OutboundQueue::new(Some(1));
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut rx = OutgoingReceiver::unbounded(&queue, rx);
+        let fail = FullChannelStrategy::Fail;
+
+        tx.send(notification()).unwrap();
+        queue.try_reserve(&response(), fail).unwrap();
+        queue.send(response()).unwrap();
+        assert!(queue.try_reserve(&response(), fail).is_err());
+
+        // the reserved payload goes first and releases its room
+        assert!(matches!(rx.recv(&queue).await, Some(Payload::Response(_))));
+        queue.try_reserve(&response(), fail).unwrap();
+        // the payload sent directly doesn't release the room reserved since
+        assert!(matches!(
+            rx.recv(&queue).await,
+            Some(Payload::Notification(_))
+        ));
+        assert_eq!(queue.depth.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn adjustable_capacity() {
+        let queue = OutboundQueue::new(None);
+        let fail = FullChannelStrategy::Fail;
+        for _ in 0..3 {
+            queue.try_reserve(&notification(), fail).unwrap();
+        }
+
+        // lowering the capacity only applies backpressure to new payloads
+        queue.set_capacity(Some(3));
+        assert_eq!(queue.capacity(), Some(3));
+        assert!(matches!(
+            queue.try_reserve(&notification(), fail),
+            Err(Error::ChannelFull)
+        ));
+        let payload = notification();
+        let waiting = queue.wait_reserve(&payload, FullChannelStrategy::Wait);
+        tokio::pin!(waiting);
+        assert!(futures_util::poll!(waiting.as_mut()).is_pending());
+
+        // raising it makes room for waiting payloads
+        queue.set_capacity(Some(4));
+        assert_eq!(waiting.await.unwrap(), Reserved::Yes);
+        queue.set_capacity(None);
+        assert_eq!(queue.capacity(), None);
+        queue.try_reserve(&notification(), fail).unwrap();
+    }
+}
@@ -0,0 +1,42 @@
+//! Containing panics in the tasks of a transport, see
+//! [`TransportConfig::catch_panics`](super::TransportConfig::catch_panics).
+
+use std::{any::Any, fmt};
+
+/// A task spawned by [`Transport::start`](super::Transport::start).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Task {
+    /// Reads and dispatches the messages of the server.
+    Recv,
+    /// Writes the messages to the server.
+    Send,
+    /// Reads the stderr output of the server.
+    Stderr,
+}
+
+impl Task {
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            Self::Recv => "recv",
+            Self::Send => "send",
+            Self::Stderr => "stderr",
+        }
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The message a panic was raised with, if it was raised with one.
+pub(super) fn message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
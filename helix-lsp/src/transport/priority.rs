@@ -0,0 +1,170 @@
+//! Sending `shutdown`, `exit` and `$/cancelRequest` ahead of the rest of the outgoing traffic.
+//!
+//! Behind a burst of `didChange` notifications a `shutdown` request, or the cancellation of a
+//! request the user gave up on, would only be written once everything before it is. The send
+//! task picks its payloads from a [`PriorityBuffer`] instead, which lets these through first.
+//! A cancellation never overtakes its request though, wherever the request is waiting, see
+//! [`cancels_queued`].
+
+use super::{cancel, Payload};
+use crate::lsp;
+use lsp::{
+    notification::{Exit, Notification},
+    request::{Request, Shutdown},
+};
+use std::collections::VecDeque;
+
+/// How many high priority payloads are sent in a row while normal ones are waiting, so a
+/// flood of cancellations can't hold back the rest forever.
+const MAX_PRIORITY_STREAK: usize = 16;
+
+/// The payloads the send task received but hasn't sent yet. Payloads of the same priority are
+/// sent in the order they were received.
+#[derive(Debug, Default)]
+pub(super) struct PriorityBuffer {
+    high: VecDeque<Payload>,
+    normal: VecDeque<Payload>,
+    /// The high priority payloads sent since the last normal one.
+    streak: usize,
+}
+
+impl PriorityBuffer {
+    pub(super) fn push(&mut self, payload: Payload) {
+        if self.is_high_priority(&payload) {
+            self.high.push_back(payload);
+        } else {
+            self.normal.push_back(payload);
+        }
+    }
+
+    pub(super) fn pop(&mut self) -> Option<Payload> {
+        let high =
+            !self.high.is_empty() && (self.normal.is_empty() || self.streak < MAX_PRIORITY_STREAK);
+        if high {
+            if !self.normal.is_empty() {
+                self.streak += 1;
+            }
+            self.high.pop_front()
+        } else {
+            self.streak = 0;
+            self.normal.pop_front()
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.high.len() + self.normal.len()
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.high.is_empty() && self.normal.is_empty()
+    }
+
+    fn is_high_priority(&self, payload: &Payload) -> bool {
+        match payload {
+            Payload::Request { value, .. } | Payload::DetachedRequest(value) => {
+                value.method == Shutdown::METHOD
+            }
+            Payload::Notification(notification) if notification.method == Exit::METHOD => true,
+            Payload::Notification(notification) if cancel::is_cancel(notification) => {
+                !cancels_queued(payload, &self.normal)
+            }
+            Payload::Notification(_) | Payload::Response(_) => false,
+        }
+    }
+}
+
+/// Whether `payload` is a `$/cancelRequest` for one of the requests in `queue`. The server
+/// would ignore a cancellation arriving before its request, so it has to wait behind it.
+pub(super) fn cancels_queued<'a>(
+    payload: &Payload,
+    queue: impl IntoIterator<Item = &'a Payload>,
+) -> bool {
+    let Payload::Notification(notification) = payload else {
+        return false;
+    };
+    if !cancel::is_cancel(notification) {
+        return false;
+    }
+    let Some(id) = cancel::cancelled_id(notification) else {
+        return false;
+    };
+    queue.into_iter().any(|queued| match queued {
+        Payload::Request { value, .. } | Payload::DetachedRequest(value) => value.id == id,
+        Payload::Notification(_) | Payload::Response(_) => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jsonrpc;
+
+    fn notification(method: &str) -> Payload {
+        Payload::Notification(jsonrpc::Notification {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: method.to_string(),
+            params: jsonrpc::Params::None,
+        })
+    }
+
+    fn request(id: u64, method: &str) -> Payload {
+        Payload::DetachedRequest(jsonrpc::MethodCall {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: method.to_string(),
+            params: jsonrpc::Params::None,
+            id: jsonrpc::Id::Num(id),
+        })
+    }
+
+    fn cancel(id: u64) -> Payload {
+        Payload::Notification(cancel::notification(&jsonrpc::Id::Num(id)).unwrap())
+    }
+
+    fn drain(buffer: &mut PriorityBuffer) -> Vec<String> {
+        std::iter::from_fn(|| buffer.pop())
+            .map(|payload| match payload {
+                Payload::Notification(notification) if cancel::is_cancel(&notification) => {
+                    format!("cancel {:?}", cancel::cancelled_id(&notification).unwrap())
+                }
+                Payload::Notification(notification) => notification.method,
+                Payload::Request { value, .. } | Payload::DetachedRequest(value) => value.method,
+                Payload::Response(_) => "response".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn high_priority_first() {
+        let mut buffer = PriorityBuffer::default();
+        buffer.push(request(1, "textDocument/hover"));
+        buffer.push(notification("textDocument/didChange"));
+        // its request is still queued
+        buffer.push(cancel(1));
+        buffer.push(cancel(0));
+        buffer.push(request(2, "shutdown"));
+        buffer.push(notification("exit"));
+        assert_eq!(
+            drain(&mut buffer),
+            [
+                "cancel Num(0)",
+                "shutdown",
+                "exit",
+                "textDocument/hover",
+                "textDocument/didChange",
+                "cancel Num(1)",
+            ]
+        );
+    }
+
+    #[test]
+    fn normal_payloads_are_not_starved() {
+        let mut buffer = PriorityBuffer::default();
+        buffer.push(notification("textDocument/didSave"));
+        for id in 0..MAX_PRIORITY_STREAK as u64 + 2 {
+            buffer.push(cancel(id));
+        }
+        let order = drain(&mut buffer);
+        assert_eq!(order[MAX_PRIORITY_STREAK], "textDocument/didSave");
+        assert_eq!(order.len(), MAX_PRIORITY_STREAK + 3);
+    }
+}
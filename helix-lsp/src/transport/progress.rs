@@ -0,0 +1,114 @@
+//! Routing `$/progress` notifications for client-generated work done tokens back to the caller
+//! of the request that carried the token.
+
+use crate::{jsonrpc, lsp};
+use lsp::notification::{Notification, Progress};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+#[derive(Debug, Default)]
+pub(super) struct ProgressRoutes {
+    routes: Mutex<HashMap<lsp::ProgressToken, UnboundedSender<lsp::WorkDoneProgress>>>,
+}
+
+impl ProgressRoutes {
+    pub(super) fn register(
+        &self,
+        token: lsp::ProgressToken,
+    ) -> UnboundedReceiver<lsp::WorkDoneProgress> {
+        let (tx, rx) = unbounded_channel();
+        self.routes.lock().insert(token, tx);
+        rx
+    }
+
+    pub(super) fn unregister(&self, token: &lsp::ProgressToken) {
+        self.routes.lock().remove(token);
+    }
+
+    /// Hands a `$/progress` notification to the caller that registered its token, returning
+    /// whether it did. The route is removed once the progress ends or the caller stopped
+    /// listening.
+    pub(super) fn route(&self, notification: &jsonrpc::Notification) -> bool {
+        if notification.method != Progress::METHOD {
+            return false;
+        }
+        let mut routes = self.routes.lock();
+        if routes.is_empty() {
+            return false;
+        }
+        let Ok(params) = notification.params.clone().parse::<lsp::ProgressParams>() else {
+            return false;
+        };
+        let Some(tx) = routes.get(&params.token) else {
+            return false;
+        };
+        let lsp::ProgressParamsValue::WorkDone(progress) = params.value;
+        let end = matches!(progress, lsp::WorkDoneProgress::End(_));
+        if tx.send(progress).is_err() || end {
+            routes.remove(&params.token);
+        }
+        true
+    }
+}
+
+/// Whether `notification` is a `$/progress` notification ending a progress.
+pub(super) fn is_end(notification: &jsonrpc::Notification) -> bool {
+    let jsonrpc::Params::Map(params) = &notification.params else {
+        return false;
+    };
+    notification.method == Progress::METHOD
+        && params
+            .get("value")
+            .and_then(|value| value.get("kind"))
+            .is_some_and(|kind| kind == "end")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn progress(token: &str, value: serde_json::Value) -> jsonrpc::Notification {
+        jsonrpc::Notification {
+            jsonrpc: Some(jsonrpc::Version::V2),
+            method: Progress::METHOD.to_string(),
+            params: jsonrpc::Params::Map(
+                json!({ "token": token, "value": value })
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            ),
+        }
+    }
+
+    #[test]
+    fn routes_registered_tokens_until_the_end() {
+        let routes = ProgressRoutes::default();
+        let token = lsp::ProgressToken::String("helix/work-done/1".to_string());
+        let mut rx = routes.register(token);
+
+        let begin = progress(
+            "helix/work-done/1",
+            json!({ "kind": "begin", "title": "Finding references" }),
+        );
+        assert!(routes.route(&begin));
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            lsp::WorkDoneProgress::Begin(_)
+        ));
+
+        // other tokens are left to the consumer
+        let other = progress("indexing", json!({ "kind": "end" }));
+        assert!(!routes.route(&other));
+
+        let end = progress("helix/work-done/1", json!({ "kind": "end" }));
+        assert!(is_end(&end) && !is_end(&begin));
+        assert!(routes.route(&end));
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            lsp::WorkDoneProgress::End(_)
+        ));
+        assert!(!routes.route(&end));
+    }
+}
@@ -0,0 +1,91 @@
+//! Accounting of the bytes exchanged with a server against [`TransportConfig::byte_quota`].
+//!
+//! [`TransportConfig::byte_quota`]: super::TransportConfig::byte_quota
+
+use crate::{Error, Result};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+#[derive(Debug)]
+pub(super) struct ByteQuota {
+    limit: u64,
+    /// Bytes sent and received so far.
+    used: AtomicU64,
+    /// Set once the quota is exceeded, after which nothing is accounted anymore.
+    exceeded: AtomicBool,
+}
+
+impl ByteQuota {
+    pub(super) fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    pub(super) fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Accounts for `bytes` about to be written, failing without accounting them if that
+    /// would exceed the quota so the message is never written.
+    pub(super) fn record_sent(&self, bytes: usize) -> Result<()> {
+        self.check()?;
+        let bytes = bytes as u64;
+        let result = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used + bytes).filter(|&used| used <= self.limit)
+            });
+        match result {
+            Ok(_) => Ok(()),
+            Err(_) => self.exceed(),
+        }
+    }
+
+    /// Accounts for `bytes` that were read already.
+    pub(super) fn record_received(&self, bytes: usize) -> Result<()> {
+        self.check()?;
+        let used = self.used.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64;
+        if used > self.limit {
+            return self.exceed();
+        }
+        Ok(())
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.exceeded.load(Ordering::Relaxed) {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+
+    fn exceed(&self) -> Result<()> {
+        self.exceeded.store(true, Ordering::Relaxed);
+        Err(self.error())
+    }
+
+    fn error(&self) -> Error {
+        Error::QuotaExceeded { limit: self.limit }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_is_shared_and_sticky() {
+        let quota = ByteQuota::new(100);
+        quota.record_sent(60).unwrap();
+        quota.record_received(30).unwrap();
+        assert!(matches!(
+            quota.record_sent(20),
+            Err(Error::QuotaExceeded { limit: 100 })
+        ));
+        // the rejected message wasn't accounted, but nothing goes through anymore
+        assert_eq!(quota.used.load(Ordering::Relaxed), 90);
+        assert!(quota.record_sent(1).is_err());
+        assert!(quota.record_received(1).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Rate limiting of notifications from servers that flood a single method.
+
+use super::Transport;
+use parking_lot::Mutex;
+use std::{collections::HashMap, sync::Weak, time::Duration};
+use tokio::time::Instant;
+
+/// Allows at most `max` notifications of a method every `per`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub max: u32,
+    pub per: Duration,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    per: Duration,
+    count: u32,
+    dropped: u64,
+}
+
+/// What to do with a notification.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Verdict {
+    Forward,
+    /// Forward it, and report the notifications dropped during the previous window.
+    ForwardAfterDropping(u64),
+    /// Drop it, `first` is set for the first notification dropped in the current window.
+    Drop {
+        first: bool,
+    },
+}
+
+#[derive(Debug, Default)]
+pub(super) struct NotificationLimiter {
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+impl NotificationLimiter {
+    pub(super) fn check(&self, method: &str, limit: RateLimit) -> Verdict {
+        let now = Instant::now();
+        let mut windows = self.windows.lock();
+        let new_window = || Window {
+            start: now,
+            per: limit.per,
+            count: 0,
+            dropped: 0,
+        };
+        // the method is only copied the first time it's seen
+        let window = match windows.get_mut(method) {
+            Some(window) => window,
+            None => windows.entry(method.to_string()).or_insert_with(new_window),
+        };
+
+        let mut dropped = 0;
+        if now.duration_since(window.start) >= limit.per {
+            dropped = window.dropped;
+            *window = new_window();
+        }
+
+        if window.count < limit.max {
+            window.count += 1;
+            match dropped {
+                0 => Verdict::Forward,
+                dropped => Verdict::ForwardAfterDropping(dropped),
+            }
+        } else {
+            window.dropped += 1;
+            Verdict::Drop {
+                first: window.dropped == 1,
+            }
+        }
+    }
+
+    /// Takes the number of notifications dropped during the windows that ended, by method, so
+    /// they're reported even if no notification of the method follows.
+    fn take_dropped(&self) -> Vec<(String, u64)> {
+        let now = Instant::now();
+        self.windows
+            .lock()
+            .iter_mut()
+            .filter(|(_, window)| {
+                window.dropped > 0 && now.duration_since(window.start) >= window.per
+            })
+            .map(|(method, window)| (method.clone(), std::mem::take(&mut window.dropped)))
+            .collect()
+    }
+}
+
+impl Transport {
+    /// Reports the notifications dropped by [`TransportConfig::limit_notifications`] once their
+    /// window ends, checking every `period`.
+    ///
+    /// [`TransportConfig::limit_notifications`]: super::TransportConfig::limit_notifications
+    pub(super) async fn watch_dropped_notifications(transport: Weak<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let Some(transport) = transport.upgrade() else {
+                return;
+            };
+            for (method, dropped) in transport.notification_limiter.take_dropped() {
+                transport.report_dropped_notifications(&method, dropped);
+            }
+        }
+    }
+
+    pub(super) fn report_dropped_notifications(&self, method: &str, dropped: u64) {
+        if let Some(limit) = self.config.notification_limits.get(method) {
+            log::warn!(
+                "{}: dropped {dropped} `{method}` notifications exceeding {} per {:?}",
+                self.log_name,
+                limit.max,
+                limit.per
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_per_window() {
+        let limiter = NotificationLimiter::default();
+        let limit = RateLimit {
+            max: 2,
+            per: Duration::from_secs(1),
+        };
+
+        assert_eq!(limiter.check("$/progress", limit), Verdict::Forward);
+        assert_eq!(limiter.check("$/progress", limit), Verdict::Forward);
+        assert_eq!(
+            limiter.check("$/progress", limit),
+            Verdict::Drop { first: true }
+        );
+        assert_eq!(
+            limiter.check("$/progress", limit),
+            Verdict::Drop { first: false }
+        );
+        // other methods are limited independently
+        assert_eq!(limiter.check("window/logMessage", limit), Verdict::Forward);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(
+            limiter.check("$/progress", limit),
+            Verdict::ForwardAfterDropping(2)
+        );
+        assert_eq!(limiter.check("$/progress", limit), Verdict::Forward);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn takes_dropped_once_the_window_ends() {
+        let limiter = NotificationLimiter::default();
+        let limit = RateLimit {
+            max: 1,
+            per: Duration::from_secs(1),
+        };
+        limiter.check("$/progress", limit);
+        limiter.check("$/progress", limit);
+        limiter.check("window/logMessage", limit);
+        assert_eq!(limiter.take_dropped(), []);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(limiter.take_dropped(), [("$/progress".to_string(), 1)]);
+        assert_eq!(limiter.take_dropped(), []);
+        // already reported
+        assert_eq!(limiter.check("$/progress", limit), Verdict::Forward);
+    }
+}
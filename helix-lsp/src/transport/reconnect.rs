@@ -0,0 +1,254 @@
+//! Restarting a server that crashed, behind the channels the consumer is already using.
+//!
+//! A [`ReconnectingTransport`] starts the server with a spawn closure, which typically spawns
+//! the server process and calls [`Transport::start`]. When the server closes the stream without
+//! being asked to exit, it's spawned again after a backoff and re-initialized with the
+//! `initialize` request the consumer originally sent, like the standby of
+//! [`failover`](super::failover).
+//!
+//! # Consistency
+//!
+//! The restarted server knows nothing about the session of the one that crashed:
+//!
+//! - Requests in flight when the server crashes are answered with
+//!   [`Error::StreamClosed`](crate::Error::StreamClosed), so the caller can retry them.
+//! - Once the restarted server is initialized the consumer receives a second `initialized`
+//!   notification, which it must treat as a fresh start and re-open (and re-configure)
+//!   everything from there.
+//! - Notifications sent while the server is restarted are dropped, like for any server that
+//!   isn't initialized yet. Requests are held back until it's initialized.
+//!
+//! Once [`RestartPolicy::max_restarts`] is reached the transport gives up and the consumer
+//! receives the `exit` notification of the last crash, as without restarts. The incoming
+//! channel is closed after any `exit` notification the consumer receives.
+
+use super::{
+    failover::{is_exit, reinitialize, Endpoint},
+    inbound::InboundDepth,
+    InboundReceiver, InitializeSignal, Payload, StartedTransport, Transport,
+};
+use crate::{jsonrpc, lsp, LanguageServerId, Result};
+use futures_util::future::{BoxFuture, FutureExt};
+use log::{error, warn};
+use lsp::{
+    notification::{Exit, Notification},
+    request::{Initialize, Request},
+};
+use parking_lot::Mutex;
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+type Spawn = Box<dyn FnMut() -> BoxFuture<'static, Result<StartedTransport>> + Send>;
+
+/// How often and how fast a [`ReconnectingTransport`] restarts a crashed server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// How many times the server is restarted in total before giving up, counting restarts
+    /// that failed to spawn it.
+    pub max_restarts: usize,
+    /// How long to wait before the first restart. The delay doubles with every restart.
+    pub initial_backoff: Duration,
+    /// The longest delay between two restarts.
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// The delay before the `restart`th restart, counting from 1.
+    fn backoff(&self, restart: usize) -> Duration {
+        let exponent = u32::try_from(restart.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(self.max_backoff)
+    }
+}
+
+/// The state of a transport restarted when its server crashes. See the
+/// [module documentation](self) for the caveats.
+#[derive(Debug, Clone)]
+pub struct ReconnectingTransport {
+    restarts: Arc<AtomicUsize>,
+    transport: Arc<Mutex<Arc<Transport>>>,
+}
+
+impl ReconnectingTransport {
+    /// Starts the server with `spawn`, which is called again for every restart. Fails if the
+    /// server can't be started the first time.
+    ///
+    /// The returned channels are used exactly like the ones of [`Transport::start`]. Calls
+    /// from every server are reported with the id of the first transport.
+    pub async fn start<F, Fut>(
+        mut spawn: F,
+        policy: RestartPolicy,
+    ) -> Result<(
+        InboundReceiver,
+        UnboundedSender<Payload>,
+        Arc<InitializeSignal>,
+        Self,
+    )>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<StartedTransport>> + Send + 'static,
+    {
+        let active = Endpoint::from(spawn().await?);
+        let initialize_notify = active.initialize_notify.clone();
+        let (client_tx, rx) = unbounded_channel();
+        let (tx, client_rx) = unbounded_channel();
+        let depth = InboundDepth::default();
+        let incoming = InboundReceiver::new(rx, &depth);
+        let handle = Self {
+            restarts: Arc::default(),
+            transport: Arc::new(Mutex::new(active.transport.clone())),
+        };
+
+        let reconnect = Reconnect {
+            id: active.transport.id,
+            active,
+            spawn: Box::new(move || spawn().boxed()),
+            policy,
+            handle: handle.clone(),
+            initialize: None,
+            exiting: false,
+            depth,
+        };
+        tokio::spawn(reconnect.run(client_tx, client_rx));
+
+        Ok((incoming, tx, initialize_notify, handle))
+    }
+
+    /// How many times the server was restarted so far.
+    pub fn restarts(&self) -> usize {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    /// The transport to the server currently running.
+    pub fn transport(&self) -> Arc<Transport> {
+        self.transport.lock().clone()
+    }
+}
+
+struct Reconnect {
+    id: LanguageServerId,
+    active: Endpoint,
+    spawn: Spawn,
+    policy: RestartPolicy,
+    handle: ReconnectingTransport,
+    /// The consumer's `initialize` request, replayed to every restarted server.
+    initialize: Option<jsonrpc::MethodCall>,
+    /// Whether the consumer asked the server to exit, in which case losing it is expected.
+    exiting: bool,
+    depth: InboundDepth,
+}
+
+impl Reconnect {
+    async fn run(
+        mut self,
+        client_tx: UnboundedSender<(LanguageServerId, jsonrpc::Call)>,
+        mut client_rx: UnboundedReceiver<Payload>,
+    ) {
+        loop {
+            tokio::select! {
+                msg = self.active.incoming.recv() => {
+                    let Some((_, call)) = msg else {
+                        break;
+                    };
+                    let exit = is_exit(&call);
+                    if exit && !self.exiting && self.restart().await {
+                        continue;
+                    }
+                    if client_tx.send((self.id, call)).is_err() {
+                        break;
+                    }
+                    self.depth.record_forwarded(None);
+                    if exit {
+                        // the server is gone for good, close the channels
+                        break;
+                    }
+                }
+                payload = client_rx.recv() => {
+                    let Some(payload) = payload else {
+                        break;
+                    };
+                    match &payload {
+                        Payload::Request { value, .. } if value.method == Initialize::METHOD => {
+                            self.initialize = Some(value.clone());
+                        }
+                        Payload::Notification(notification) if notification.method == Exit::METHOD => {
+                            self.exiting = true;
+                        }
+                        _ => (),
+                    }
+                    // a closed channel means the server crashed, which the incoming channel
+                    // reports
+                    let _ = self.active.outgoing.send(payload);
+                }
+            }
+        }
+    }
+
+    /// Replaces the crashed server with a new one, returning `false` once the
+    /// [`RestartPolicy::max_restarts`] are used up.
+    async fn restart(&mut self) -> bool {
+        let name = self.active.transport.log_name.to_string();
+        loop {
+            let restart = self.handle.restarts.load(Ordering::Relaxed) + 1;
+            if restart > self.policy.max_restarts {
+                error!(
+                    "{name}: server crashed, giving up after {} restarts",
+                    self.policy.max_restarts
+                );
+                return false;
+            }
+            self.handle.restarts.store(restart, Ordering::Relaxed);
+            let backoff = self.policy.backoff(restart);
+            warn!("{name}: server crashed, restarting it in {backoff:?} (restart {restart})");
+            tokio::time::sleep(backoff).await;
+
+            match (self.spawn)().await {
+                Ok(started) => {
+                    self.active = Endpoint::from(started);
+                    *self.handle.transport.lock() = self.active.transport.clone();
+                    // without it the consumer will initialize the server itself
+                    if let Some(initialize) = self.initialize.clone() {
+                        reinitialize(&self.active, initialize, "restarted");
+                    }
+                    return true;
+                }
+                Err(err) => error!("{name}: failed to restart the server: {err}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = RestartPolicy {
+            max_restarts: 100,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        };
+        let backoffs: Vec<_> = (1..=6).map(|restart| policy.backoff(restart)).collect();
+        assert_eq!(backoffs, [1, 2, 4, 8, 10, 10].map(Duration::from_secs));
+        assert_eq!(policy.backoff(usize::MAX), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,272 @@
+//! Replaying recorded client traffic against a language server, e.g. to check that a new server
+//! version still answers the same requests the same way.
+//!
+//! A recording is a list of [`RecordedMessage`]s, stored as one JSON object per line, which
+//! [`record_session`] writes from the [`TransportConfig::raw_tap`] of a transport. Only the
+//! outgoing requests and notifications are replayed: responses to server requests are skipped
+//! since the ids of the replayed server's requests won't match the recorded ones.
+//!
+//! [`TransportConfig::raw_tap`]: super::TransportConfig::raw_tap
+
+use super::{Direction, InitializeSignal, Payload, RawMessage};
+use crate::{jsonrpc, lsp, Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc::{channel, Receiver, UnboundedReceiver, UnboundedSender},
+    time::{sleep_until, timeout, Instant},
+};
+
+/// A single message in a recorded session.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// Milliseconds since the start of the recording.
+    pub offset_ms: u64,
+    pub direction: Direction,
+    pub message: Value,
+}
+
+/// Parses a recording stored as one [`RecordedMessage`] per line. Empty lines are ignored.
+pub fn parse_recording(input: &str) -> Result<Vec<RecordedMessage>> {
+    input
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(Error::from))
+        .collect()
+}
+
+/// Writes the messages handed to a [`TransportConfig::raw_tap`] to `out` as a recording, one
+/// [`RecordedMessage`] per line, until the transport drops the tap. The messages of a batch are
+/// recorded one by one, messages that aren't JSON are skipped.
+///
+/// [`TransportConfig::raw_tap`]: super::TransportConfig::raw_tap
+pub async fn record_session(
+    mut tap: UnboundedReceiver<RawMessage>,
+    mut out: impl AsyncWrite + Unpin,
+) -> Result<()> {
+    let mut start = None;
+    let mut line = Vec::new();
+    while let Some(raw) = tap.recv().await {
+        let start = *start.get_or_insert(raw.timestamp);
+        let message = match serde_json::from_slice(&raw.bytes) {
+            Ok(Value::Array(batch)) => batch,
+            Ok(message) => vec![message],
+            Err(err) => {
+                log::debug!("Not recording a message that isn't JSON: {err}");
+                continue;
+            }
+        };
+        let offset_ms = raw.timestamp.duration_since(start).as_millis() as u64;
+        for message in message {
+            let recorded = RecordedMessage {
+                offset_ms,
+                direction: raw.direction,
+                message,
+            };
+            line.clear();
+            serde_json::to_writer(&mut line, &recorded)?;
+            line.push(b'\n');
+            out.write_all(&line).await?;
+        }
+    }
+    out.flush().await?;
+    Ok(())
+}
+
+/// How closely replaying follows the timing of the recording.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReplayTiming {
+    /// Send every message as soon as the previous one was sent.
+    #[default]
+    AsFastAsPossible,
+    /// Wait until each message's recorded offset from the start of the replay.
+    Original,
+}
+
+/// The response the server gave to a replayed request.
+#[derive(Debug)]
+pub struct ReplayedResponse {
+    pub id: jsonrpc::Id,
+    pub method: String,
+    pub result: Result<Value>,
+}
+
+/// Sends the outgoing messages of `recording` through a transport started with
+/// [`Transport::start`](super::Transport::start) and collects the responses in request order.
+///
+/// The recorded `initialize` request is awaited before anything else is sent and
+/// `initialize_notify` is notified once it completes, mirroring what [`Client`](crate::Client)
+/// does. Requests that aren't answered within `response_timeout` fail with [`Error::Timeout`].
+pub async fn replay_session(
+    server_tx: &UnboundedSender<Payload>,
+    initialize_notify: &InitializeSignal,
+    recording: &[RecordedMessage],
+    timing: ReplayTiming,
+    response_timeout: Duration,
+) -> Result<Vec<ReplayedResponse>> {
+    use lsp::request::{Initialize, Request};
+
+    let start = Instant::now();
+    let mut pending: Vec<(jsonrpc::Id, String, Receiver<Result<Value>>)> = Vec::new();
+    let mut responses = Vec::new();
+
+    for recorded in recording {
+        if recorded.direction != Direction::Outgoing {
+            continue;
+        }
+        if timing == ReplayTiming::Original {
+            sleep_until(start + Duration::from_millis(recorded.offset_ms)).await;
+        }
+
+        match serde_json::from_value::<jsonrpc::Call>(recorded.message.clone())? {
+            jsonrpc::Call::MethodCall(call) => {
+                let (tx, rx) = channel(1);
+                let id = call.id.clone();
+                let method = call.method.clone();
+                server_tx
+                    .send(Payload::request(tx, call))
+                    .map_err(|e| Error::Other(e.into()))?;
+
+                if method == Initialize::METHOD {
+                    let result = wait_for_response(&id, rx, response_timeout).await;
+                    if result.is_ok() {
+                        initialize_notify.notify();
+                    }
+                    responses.push(ReplayedResponse { id, method, result });
+                } else {
+                    pending.push((id, method, rx));
+                }
+            }
+            jsonrpc::Call::Notification(notification) => server_tx
+                .send(Payload::Notification(notification))
+                .map_err(|e| Error::Other(e.into()))?,
+            jsonrpc::Call::Invalid { id } => {
+                log::debug!("Skipping recorded message that isn't a request (id={id})");
+            }
+        }
+    }
+
+    for (id, method, rx) in pending {
+        let result = wait_for_response(&id, rx, response_timeout).await;
+        responses.push(ReplayedResponse { id, method, result });
+    }
+    Ok(responses)
+}
+
+async fn wait_for_response(
+    id: &jsonrpc::Id,
+    mut rx: Receiver<Result<Value>>,
+    response_timeout: Duration,
+) -> Result<Value> {
+    timeout(response_timeout, rx.recv())
+        .await
+        .map_err(|_| Error::Timeout(id.clone()))?
+        .ok_or(Error::StreamClosed)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    const RECORDING: &str = r#"
+{"offset_ms":0,"direction":"outgoing","message":{"jsonrpc":"2.0","id":0,"method":"initialize","params":{}}}
+{"offset_ms":5,"direction":"incoming","message":{"jsonrpc":"2.0","id":0,"result":{}}}
+{"offset_ms":6,"direction":"outgoing","message":{"jsonrpc":"2.0","method":"initialized","params":{}}}
+{"offset_ms":20,"direction":"outgoing","message":{"jsonrpc":"2.0","id":1,"method":"shutdown"}}
+"#;
+
+    #[tokio::test]
+    async fn replays_outgoing_messages() {
+        let recording = parse_recording(RECORDING).unwrap();
+        assert_eq!(recording.len(), 4);
+
+        let (server_tx, mut server_rx) = unbounded_channel();
+        let notify = InitializeSignal::new();
+        let fake_server = async {
+            while let Some(payload) = server_rx.recv().await {
+                if let Payload::Request { chan, value, .. } = payload {
+                    chan.send(Ok(Value::from(value.method))).await.unwrap();
+                }
+            }
+        };
+        let replay = async {
+            let responses = replay_session(
+                &server_tx,
+                &notify,
+                &recording,
+                ReplayTiming::Original,
+                Duration::from_secs(1),
+            )
+            .await
+            .unwrap();
+            drop(server_tx);
+            responses
+        };
+
+        let (responses, ()) = tokio::join!(replay, fake_server);
+        let methods: Vec<_> = responses.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(methods, ["initialize", "shutdown"]);
+        assert_eq!(
+            responses[1].result.as_ref().unwrap(),
+            &Value::from("shutdown")
+        );
+    }
+
+    #[tokio::test]
+    async fn records_the_raw_tap() {
+        let (tap, tapped) = unbounded_channel();
+        let start = std::time::Instant::now();
+        let raw = |direction, body: &str, offset_ms| RawMessage {
+            direction,
+            bytes: body.as_bytes().to_vec(),
+            timestamp: start + Duration::from_millis(offset_ms),
+        };
+        for message in [
+            raw(
+                Direction::Outgoing,
+                r#"{"jsonrpc":"2.0","id":0,"method":"initialize","params":{}}"#,
+                0,
+            ),
+            raw(Direction::Incoming, "garbage", 2),
+            raw(
+                Direction::Incoming,
+                r#"{"jsonrpc":"2.0","id":0,"result":{}}"#,
+                5,
+            ),
+            raw(
+                Direction::Outgoing,
+                r#"[{"jsonrpc":"2.0","method":"initialized","params":{}},{"jsonrpc":"2.0","method":"exit"}]"#,
+                6,
+            ),
+        ] {
+            tap.send(message).unwrap();
+        }
+        drop(tap);
+
+        let mut out = Vec::new();
+        record_session(tapped, &mut out).await.unwrap();
+        let recording = parse_recording(std::str::from_utf8(&out).unwrap()).unwrap();
+        let recorded: Vec<_> = recording
+            .iter()
+            .map(|recorded| {
+                (
+                    recorded.offset_ms,
+                    recorded.direction,
+                    recorded.message["method"].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            recorded,
+            [
+                (0, Direction::Outgoing, Some("initialize")),
+                (5, Direction::Incoming, None),
+                (6, Direction::Outgoing, Some("initialized")),
+                (6, Direction::Outgoing, Some("exit")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Recovering the id of a message that failed to parse, so the request it answers can be
+//! failed on its own instead of stopping the transport.
+//!
+//! The server's requests have ids of their own, which may well collide with the ids of the
+//! client's requests, so only the id of a message without a `method` is taken for a response.
+
+use crate::jsonrpc;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct IdOnly {
+    id: jsonrpc::Id,
+}
+
+/// The top-level `id` of the message in `content`, which may be valid JSON of the wrong shape
+/// or not valid JSON at all.
+pub(super) fn recover_id(content: &[u8]) -> Option<jsonrpc::Id> {
+    if let Ok(IdOnly { id }) = sonic_rs::from_slice(content) {
+        return Some(id);
+    }
+    let value = scan_top_level_value(content, b"id")?;
+    match sonic_rs::from_slice(value) {
+        Ok(jsonrpc::Id::Null) | Err(_) => None,
+        Ok(id) => Some(id),
+    }
+}
+
+/// The top-level `method` of the message in `content`, like [`recover_id`]. Methods are
+/// returned as written, escapes included.
+pub(super) fn recover_method(content: &[u8]) -> Option<&str> {
+    let value = scan_top_level_value(content, b"method")?;
+    let method = value.strip_prefix(b"\"")?.strip_suffix(b"\"")?;
+    std::str::from_utf8(method).ok()
+}
+
+/// Whether the message in `content` has a top-level `method`, whatever its value, which makes
+/// it a request or a notification from the server rather than a response.
+pub(super) fn has_method(content: &[u8]) -> bool {
+    scan_top_level_key(content, b"method").is_some()
+}
+
+/// Whether the message in `content` is of the current JSON-RPC version, or doesn't say.
+pub(super) fn is_current_version(content: &[u8]) -> bool {
+    scan_top_level_key(content, b"jsonrpc")
+        .is_none_or(|start| value_at(content, start) == Some(&br#""2.0""#[..]))
+}
+
+/// Scans `content` for the member `key` of the top-level object, without requiring the rest of
+/// the message to be valid, and returns the bytes of its value if it's a string or a number.
+fn scan_top_level_value<'a>(content: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    value_at(content, scan_top_level_key(content, key)?)
+}
+
+/// Like [`scan_top_level_value`], but returns the index the value of `key` starts at.
+fn scan_top_level_key(content: &[u8], key: &[u8]) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < content.len() {
+        match content[i] {
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            b'"' => {
+                let end = string_end(content, i)?;
+                let is_key = depth == 1 && &content[i + 1..end] == key;
+                i = end + 1;
+                if is_key {
+                    let rest = skip_whitespace(content, i);
+                    if content.get(rest) == Some(&b':') {
+                        return Some(skip_whitespace(content, rest + 1));
+                    }
+                }
+                continue;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    None
+}
+
+/// The index of the quote closing the string starting at `start`.
+fn string_end(content: &[u8], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    while i < content.len() {
+        match content[i] {
+            b'\\' => i += 2,
+            b'"' => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+fn skip_whitespace(content: &[u8], mut i: usize) -> usize {
+    while content.get(i).is_some_and(u8::is_ascii_whitespace) {
+        i += 1;
+    }
+    i
+}
+
+fn value_at(content: &[u8], start: usize) -> Option<&[u8]> {
+    match content.get(start)? {
+        b'"' => Some(&content[start..=string_end(content, start)?]),
+        b'-' | b'0'..=b'9' => {
+            let len = content[start..]
+                .iter()
+                .take_while(|&&b| matches!(b, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'))
+                .count();
+            Some(&content[start..start + len])
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_top_level_id() {
+        for (content, id) in [
+            // valid JSON of the wrong shape
+            (
+                r#"{"jsonrpc":"2.0","id":3,"result":1,"extra":true}"#,
+                Some(jsonrpc::Id::Num(3)),
+            ),
+            // truncated, with ids nested in the result
+            (
+                r#"{"result":{"id":1,"items":["{\"id\":2"]}, "id" : "a\"b", "jsonrpc":"#,
+                Some(jsonrpc::Id::Str("a\"b".to_string())),
+            ),
+            (
+                r#"{"jsonrpc":"2.0","id":7.0,"result":}"#,
+                Some(jsonrpc::Id::Num(7)),
+            ),
+            (r#"{"jsonrpc":"2.0","id":null,"result":}"#, None),
+            (r#"{"jsonrpc":"2.0","result":{"id":1"#, None),
+            ("\u{1}garbage", None),
+        ] {
+            assert_eq!(recover_id(content.as_bytes()), id, "{content}");
+        }
+    }
+
+    #[test]
+    fn tells_calls_from_responses() {
+        let request = br#"{"jsonrpc":"2.0","id":1,"method":"workspace/configuration","params":5}"#;
+        assert!(has_method(request));
+        assert!(is_current_version(request));
+        assert!(has_method(br#"{"id":1,"method":5}"#));
+        assert!(!has_method(br#"{"id":1,"result":{"method":"a"}}"#));
+
+        assert!(is_current_version(br#"{"id":1,"result":}"#));
+        assert!(!is_current_version(
+            br#"{"jsonrpc":"1.0","id":1,"result":1}"#
+        ));
+        assert!(!is_current_version(br#"{"jsonrpc":2,"id":1,"result":1}"#));
+    }
+}
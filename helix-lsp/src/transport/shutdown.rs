@@ -0,0 +1,101 @@
+//! Stopping a transport within a deadline, see [`Transport::shutdown`](super::Transport::shutdown).
+
+use tokio::sync::watch;
+
+/// How [`Transport::shutdown`](super::Transport::shutdown) went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownOutcome {
+    /// The queued payloads were sent and the server closed its end of the stream.
+    Clean,
+    /// The deadline passed first. The transport was stopped regardless, but the server may
+    /// still be running and should be killed.
+    TimedOut,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Phase {
+    Running,
+    /// No new payloads are accepted, the send task exits once it sent the queued ones.
+    Closing,
+    /// Every task exits right away.
+    Stopped,
+}
+
+#[derive(Debug)]
+pub(super) struct Lifecycle {
+    phase: watch::Sender<Phase>,
+    /// The number of send and recv tasks still running.
+    running: watch::Sender<usize>,
+}
+
+impl Lifecycle {
+    pub(super) fn new() -> Self {
+        Self {
+            phase: watch::Sender::new(Phase::Running),
+            running: watch::Sender::new(0),
+        }
+    }
+
+    pub(super) fn subscribe(&self) -> watch::Receiver<Phase> {
+        self.phase.subscribe()
+    }
+
+    pub(super) fn is_closing(&self) -> bool {
+        *self.phase.borrow() != Phase::Running
+    }
+
+    pub(super) fn close(&self) {
+        self.phase.send_if_modified(|phase| {
+            let running = *phase == Phase::Running;
+            if running {
+                *phase = Phase::Closing;
+            }
+            running
+        });
+    }
+
+    pub(super) fn stop(&self) {
+        self.phase.send_replace(Phase::Stopped);
+    }
+
+    pub(super) fn task_started(&self) {
+        self.running.send_modify(|running| *running += 1);
+    }
+
+    pub(super) fn task_finished(&self) {
+        self.running
+            .send_modify(|running| *running = running.saturating_sub(1));
+    }
+
+    /// Waits for the send and recv tasks to exit.
+    pub(super) async fn finished(&self) {
+        let mut running = self.running.subscribe();
+        // the sender lives in `self`, so this can't fail
+        let _ = running.wait_for(|running| *running == 0).await;
+    }
+}
+
+/// Runs `task` until the lifecycle is stopped, dropping it even in the middle of a write to a
+/// server that doesn't read.
+pub(super) async fn until_stopped(
+    mut phase: watch::Receiver<Phase>,
+    task: impl std::future::Future<Output = ()>,
+) {
+    tokio::select! {
+        () = task => (),
+        () = reached(&mut phase, Phase::Stopped) => (),
+    }
+}
+
+/// Waits for the lifecycle to reach `phase` or a later one. Cancellation safe.
+pub(super) async fn reached(phase: &mut watch::Receiver<Phase>, target: Phase) {
+    let reached = |phase: &Phase| match target {
+        Phase::Running => true,
+        Phase::Closing => *phase != Phase::Running,
+        Phase::Stopped => *phase == Phase::Stopped,
+    };
+    if phase.wait_for(reached).await.is_err() {
+        // the transport is gone
+        std::future::pending::<()>().await;
+    }
+}
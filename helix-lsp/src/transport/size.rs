@@ -0,0 +1,145 @@
+//! Cheap estimates of the size of serialized messages.
+
+use crate::jsonrpc;
+use serde_json::{Map, Value};
+
+/// Estimates the length of `value` serialized as compact JSON without serializing it.
+///
+/// The estimate walks the value once, only scanning strings for characters that need escaping
+/// and assuming a typical width for floats, so it is exact for any value without floats. It is
+/// meant for cheap decisions such as whether serializing a message is worth
+/// moving off the async runtime, not for sizing buffers exactly.
+pub fn estimate_serialized_size(value: &Value) -> usize {
+    match value {
+        Value::Null => 4,
+        Value::Bool(true) => 4,
+        Value::Bool(false) => 5,
+        Value::Number(number) => {
+            if let Some(n) = number.as_u64() {
+                digits(n)
+            } else if let Some(n) = number.as_i64() {
+                digits(n.unsigned_abs()) + 1
+            } else {
+                // the shortest representation of an arbitrary float, e.g. 0.30000000000000004
+                18
+            }
+        }
+        Value::String(string) => {
+            let escapes: usize = string
+                .bytes()
+                .map(|byte| match byte {
+                    b'"' | b'\\' | b'\n' | b'\r' | b'\t' | 0x08 | 0x0c => 1,
+                    // \u00XX
+                    ..0x20 => 5,
+                    _ => 0,
+                })
+                .sum();
+            string.len() + escapes + 2
+        }
+        Value::Array(values) => estimate_array(values),
+        Value::Object(map) => estimate_object(map),
+    }
+}
+
+/// Estimates the serialized size of call parameters, see [`estimate_serialized_size`].
+pub(super) fn estimate_params(params: &jsonrpc::Params) -> usize {
+    match params {
+        jsonrpc::Params::None => 0,
+        jsonrpc::Params::Array(values) => estimate_array(values),
+        jsonrpc::Params::Map(map) => estimate_object(map),
+    }
+}
+
+fn estimate_array(values: &[Value]) -> usize {
+    // brackets and separating commas
+    let punctuation = 2 + values.len().saturating_sub(1);
+    punctuation + values.iter().map(estimate_serialized_size).sum::<usize>()
+}
+
+fn estimate_object(map: &Map<String, Value>) -> usize {
+    // braces, separating commas, and quotes and colon around every key
+    let punctuation = 2 + map.len().saturating_sub(1) + 3 * map.len();
+    punctuation
+        + map
+            .iter()
+            .map(|(key, value)| key.len() + estimate_serialized_size(value))
+            .sum::<usize>()
+}
+
+fn digits(n: u64) -> usize {
+    n.checked_ilog10().unwrap_or(0) as usize + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn assert_close(value: Value) {
+        let actual = serde_json::to_string(&value).unwrap().len();
+        let estimate = estimate_serialized_size(&value);
+        let error = estimate.abs_diff(actual) as f64 / actual as f64;
+        assert!(
+            error < 0.05,
+            "estimated {estimate} bytes for {actual} bytes of JSON"
+        );
+    }
+
+    #[test]
+    fn exact_for_simple_values() {
+        for value in [
+            json!(null),
+            json!(true),
+            json!(false),
+            json!(0),
+            json!(9),
+            json!(10),
+            json!(u64::MAX),
+            json!(-1),
+            json!(i64::MIN),
+            json!("text"),
+            json!("\"quoted\"\n\\ \u{1}"),
+            json!([]),
+            json!([1, "a", null]),
+            json!({}),
+            json!({"a": 1, "bc": [true, false]}),
+        ] {
+            assert_eq!(
+                estimate_serialized_size(&value),
+                serde_json::to_string(&value).unwrap().len(),
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn close_for_real_payloads() {
+        // a full document sync of a source file, escaping newlines and quotes
+        let text = "fn main() {\n    println!(\"hello, world\");\n}\n".repeat(2000);
+        assert_close(json!({
+            "textDocument": {"uri": "file:///src/main.rs", "version": 3},
+            "contentChanges": [{"text": text}],
+        }));
+
+        // a completion response
+        let items: Vec<_> = (0..500)
+            .map(|i| {
+                json!({
+                    "label": format!("item_{i}"),
+                    "kind": 3,
+                    "detail": "fn(&self) -> Option<usize>",
+                    "sortText": format!("{i:08}"),
+                    "textEdit": {
+                        "range": {"start": {"line": 12, "character": 4}, "end": {"line": 12, "character": 8}},
+                        "newText": format!("item_{i}"),
+                    },
+                })
+            })
+            .collect();
+        assert_close(json!({"isIncomplete": false, "items": items}));
+
+        // semantic tokens
+        let data: Vec<u32> = (0..20_000).map(|i| (i * 7919) % 300).collect();
+        assert_close(json!({"resultId": "1", "data": data}));
+    }
+}
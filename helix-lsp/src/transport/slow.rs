@@ -0,0 +1,90 @@
+//! Warning about requests the server takes long to answer, see
+//! [`TransportConfig::slow_request_warning`].
+//!
+//! [`TransportConfig::slow_request_warning`]: super::TransportConfig::slow_request_warning
+
+use super::{shutdown, Transport, TransportState};
+use crate::jsonrpc;
+use std::{fmt, sync::Arc, sync::Weak, time::Duration};
+
+/// When to warn about a request that is still pending. Unlike the
+/// [`TransportConfig::request_timeout`](super::TransportConfig::request_timeout) the request
+/// keeps waiting for its response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowRequestWarning {
+    /// How long a request may be pending before it's reported, once.
+    pub threshold: Duration,
+    /// How often the pending requests are checked, which delays the warning by up to this much.
+    /// Must not be zero.
+    pub sweep_interval: Duration,
+}
+
+impl Default for SlowRequestWarning {
+    fn default() -> Self {
+        Self {
+            threshold: Duration::from_secs(5),
+            sweep_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Called with the method and id of a slow request, and how long it has been pending.
+type Callback = dyn Fn(&str, &jsonrpc::Id, Duration) + Send + Sync;
+
+/// The callback set with [`TransportConfig::on_slow_request`](super::TransportConfig::on_slow_request).
+#[derive(Clone)]
+pub(super) struct SlowRequestCallback(pub(super) Arc<Callback>);
+
+impl fmt::Debug for SlowRequestCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SlowRequestCallback")
+    }
+}
+
+impl Transport {
+    pub(super) async fn watch_slow_requests(transport: Weak<Self>, warning: SlowRequestWarning) {
+        let Some(mut phase) = transport
+            .upgrade()
+            .map(|transport| transport.lifecycle.subscribe())
+        else {
+            return;
+        };
+        loop {
+            tokio::select! {
+                () = tokio::time::sleep(warning.sweep_interval) => {}
+                // the pending requests are about to fail, there's nothing left to report
+                () = shutdown::reached(&mut phase, shutdown::Phase::Closing) => return,
+            }
+            let Some(transport) = transport.upgrade() else {
+                return;
+            };
+            if transport.state() == TransportState::Closed {
+                return;
+            }
+            // reported outside of the lock, the callback may take its time
+            let slow: Vec<_> = {
+                let mut pending_requests = transport.pending_requests.lock();
+                pending_requests
+                    .iter_mut()
+                    .filter_map(|(id, request)| {
+                        let pending = request.sent.elapsed();
+                        if request.warned_slow || pending < warning.threshold {
+                            return None;
+                        }
+                        request.warned_slow = true;
+                        Some((request.method.clone(), id.clone(), pending))
+                    })
+                    .collect()
+            };
+            for (method, id, pending) in slow {
+                match &transport.config.on_slow_request {
+                    Some(callback) => (callback.0)(&method, &id, pending),
+                    None => log::warn!(
+                        "{} has been answering {method} request (id={id:?}) for {pending:?}",
+                        transport.log_name
+                    ),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Telling a server that failed to start from one that exited later on.
+
+use crate::jsonrpc;
+use parking_lot::Mutex;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+/// A server whose stdout closes this soon after launch, without having answered the initialize
+/// request, is considered to have failed to start.
+const STARTUP_WINDOW: Duration = Duration::from_secs(5);
+/// How much of the stderr output written before initialization is kept.
+const MAX_CAPTURED_STDERR: usize = 4096;
+/// How long to wait for stderr to be read completely once stdout closed.
+const STDERR_GRACE: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub(super) struct StartupWatch {
+    initialized: AtomicBool,
+    /// The id of the answered initialize request, to recognize a second response to it.
+    initialize_id: Mutex<Option<jsonrpc::Id>>,
+    stderr: Mutex<String>,
+    stderr_closed: watch::Sender<bool>,
+}
+
+impl StartupWatch {
+    pub(super) fn new() -> Self {
+        Self {
+            initialized: AtomicBool::new(false),
+            initialize_id: Mutex::new(None),
+            stderr: Mutex::new(String::new()),
+            stderr_closed: watch::Sender::new(false),
+        }
+    }
+
+    pub(super) fn record_initialized(&self, id: &jsonrpc::Id) {
+        *self.initialize_id.lock() = Some(id.clone());
+        self.initialized.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether `id` is that of the initialize request, which was answered already.
+    pub(super) fn is_initialize_response(&self, id: &jsonrpc::Id) -> bool {
+        self.initialize_id.lock().as_ref() == Some(id)
+    }
+
+    pub(super) fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn record_stderr(&self, line: &str) {
+        if self.initialized.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut stderr = self.stderr.lock();
+        let room = MAX_CAPTURED_STDERR.saturating_sub(stderr.len());
+        let mut end = line.len().min(room);
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        stderr.push_str(&line[..end]);
+    }
+
+    pub(super) fn record_stderr_closed(&self) {
+        self.stderr_closed.send_replace(true);
+    }
+
+    /// Called once stdout closed: returns the stderr output of the server if it failed to
+    /// start.
+    pub(super) async fn failure(&self, started: Instant) -> Option<String> {
+        if self.initialized.load(Ordering::Relaxed) || started.elapsed() > STARTUP_WINDOW {
+            return None;
+        }
+        let mut stderr_closed = self.stderr_closed.subscribe();
+        let _ = tokio::time::timeout(STDERR_GRACE, stderr_closed.wait_for(|closed| *closed)).await;
+        Some(self.stderr.lock().trim_end().to_string())
+    }
+}
@@ -0,0 +1,69 @@
+//! The coarse state of a transport, see [`Transport::state`](super::Transport::state).
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Where a transport is in its life, e.g. to show the status of a server or to skip requests
+/// that can't be answered anymore. The state only ever moves forward, in the order of the
+/// variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum TransportState {
+    /// The `initialize` request wasn't sent yet.
+    Uninitialized,
+    /// The `initialize` request was sent, the consumer didn't signal the server initialized
+    /// yet.
+    Initializing,
+    /// The server is initialized and payloads are sent as they come.
+    Ready,
+    /// The transport is shutting down, or the `shutdown` request or `exit` notification was
+    /// sent: new requests most likely won't be answered.
+    Draining,
+    /// The stream was closed and the consumer received the `exit` notification, or is about to.
+    Closed,
+}
+
+impl TransportState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => Self::Uninitialized,
+            1 => Self::Initializing,
+            2 => Self::Ready,
+            3 => Self::Draining,
+            _ => Self::Closed,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct StateCell(AtomicU8);
+
+impl StateCell {
+    pub(super) fn new() -> Self {
+        Self(AtomicU8::new(TransportState::Uninitialized as u8))
+    }
+
+    pub(super) fn get(&self) -> TransportState {
+        TransportState::from_u8(self.0.load(Ordering::Acquire))
+    }
+
+    /// Moves to `state`, unless the transport is already past it.
+    pub(super) fn advance(&self, state: TransportState) {
+        self.0.fetch_max(state as u8, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_moves_forward() {
+        let state = StateCell::new();
+        assert_eq!(state.get(), TransportState::Uninitialized);
+        state.advance(TransportState::Ready);
+        state.advance(TransportState::Initializing);
+        assert_eq!(state.get(), TransportState::Ready);
+        state.advance(TransportState::Closed);
+        assert_eq!(state.get(), TransportState::Closed);
+    }
+}
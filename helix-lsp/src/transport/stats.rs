@@ -0,0 +1,71 @@
+//! Counting the messages exchanged with the server, see
+//! [`Transport::stats`](super::Transport::stats).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The messages a transport exchanged with the server since it started, e.g. to spot a chatty
+/// server or one writing garbage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransportStats {
+    /// Requests written to the server, including the ones whose response is discarded.
+    pub requests_sent: u64,
+    /// Notifications written to the server, counting each notification of a batch.
+    pub notifications_sent: u64,
+    /// Responses from the server, including malformed ones that still named their request.
+    pub responses_received: u64,
+    /// Requests and notifications from the server.
+    pub server_calls_received: u64,
+    /// Messages from the server that failed to parse.
+    pub parse_errors: u64,
+    /// Lines skipped between messages because they weren't a header, e.g. logging written to
+    /// stdout.
+    pub garbage_lines_skipped: u64,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct StatsCounters {
+    requests_sent: AtomicU64,
+    notifications_sent: AtomicU64,
+    responses_received: AtomicU64,
+    server_calls_received: AtomicU64,
+    parse_errors: AtomicU64,
+    garbage_lines_skipped: AtomicU64,
+}
+
+impl StatsCounters {
+    pub(super) fn request_sent(&self) {
+        self.requests_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn notifications_sent(&self, count: usize) {
+        self.notifications_sent
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn response_received(&self) {
+        self.responses_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn server_call_received(&self) {
+        self.server_calls_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn garbage_line_skipped(&self) {
+        self.garbage_lines_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn snapshot(&self) -> TransportStats {
+        TransportStats {
+            requests_sent: self.requests_sent.load(Ordering::Relaxed),
+            notifications_sent: self.notifications_sent.load(Ordering::Relaxed),
+            responses_received: self.responses_received.load(Ordering::Relaxed),
+            server_calls_received: self.server_calls_received.load(Ordering::Relaxed),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+            garbage_lines_skipped: self.garbage_lines_skipped.load(Ordering::Relaxed),
+        }
+    }
+}
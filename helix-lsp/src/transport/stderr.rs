@@ -0,0 +1,88 @@
+//! Keeping the last lines the server wrote to stderr, see
+//! [`TransportConfig::stderr_history`](super::TransportConfig::stderr_history).
+
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+
+#[derive(Debug)]
+struct Entry {
+    line: String,
+    /// How many times in a row the line was written, with
+    /// [`TransportConfig::collapse_repeated_stderr`](super::TransportConfig::collapse_repeated_stderr).
+    repeats: usize,
+}
+
+/// The most recent stderr lines, evicting the oldest once `capacity` is reached.
+#[derive(Debug)]
+pub(super) struct StderrHistory {
+    capacity: usize,
+    collapse_repeated: bool,
+    lines: Mutex<VecDeque<Entry>>,
+}
+
+impl StderrHistory {
+    pub(super) fn new(capacity: usize, collapse_repeated: bool) -> Self {
+        Self {
+            capacity,
+            collapse_repeated,
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub(super) fn record(&self, line: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lines = self.lines.lock();
+        if self.collapse_repeated {
+            if let Some(last) = lines.back_mut().filter(|last| last.line == line) {
+                last.repeats += 1;
+                return;
+            }
+        }
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(Entry {
+            line: line.to_string(),
+            repeats: 1,
+        });
+    }
+
+    /// The recorded lines, oldest first. Collapsed lines are suffixed with their repeat count,
+    /// e.g. `retrying (×42)`.
+    pub(super) fn lines(&self) -> Vec<String> {
+        self.lines
+            .lock()
+            .iter()
+            .map(|entry| match entry.repeats {
+                1 => entry.line.clone(),
+                repeats => format!("{} (×{repeats})", entry.line),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collapses_repeated_lines() {
+        let history = StderrHistory::new(3, true);
+        for line in ["starting", "retrying", "retrying", "retrying", "connected"] {
+            history.record(line);
+        }
+        assert_eq!(history.lines(), ["starting", "retrying (×3)", "connected"]);
+
+        // only consecutive lines are collapsed, and the collapsed entry takes a single slot
+        history.record("retrying");
+        assert_eq!(history.lines(), ["retrying (×3)", "connected", "retrying"]);
+
+        let history = StderrHistory::new(2, false);
+        for line in ["retrying", "retrying", "retrying"] {
+            history.record(line);
+        }
+        assert_eq!(history.lines(), ["retrying", "retrying"]);
+    }
+}
@@ -0,0 +1,79 @@
+//! Detection of the system being suspended, so that a laptop waking up from sleep doesn't fail
+//! every request that was in flight when it went to sleep.
+//!
+//! On some platforms the monotonic clock keeps running while the system is suspended, which
+//! makes every pending timeout expire at once on resume. The transport ticks at a fixed
+//! [`PERIOD`] and treats ticks that arrive much later than expected as time spent suspended.
+
+use parking_lot::Mutex;
+use std::{future::Future, time::Duration};
+use tokio::time::Instant;
+
+/// How often the clock is sampled.
+pub(super) const PERIOD: Duration = Duration::from_secs(1);
+/// How late a tick may be before the delay is considered a suspension rather than scheduling
+/// jitter or a busy runtime.
+const TOLERANCE: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub(super) struct SuspendDetector {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    last_observed: Instant,
+    suspended: Duration,
+}
+
+impl SuspendDetector {
+    pub(super) fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                last_observed: Instant::now(),
+                suspended: Duration::ZERO,
+            }),
+        }
+    }
+
+    /// Samples the clock, returning the total time the system was detected to be suspended.
+    pub(super) fn observe(&self) -> Duration {
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        let elapsed = now.saturating_duration_since(state.last_observed);
+        if elapsed > PERIOD + TOLERANCE {
+            state.suspended += elapsed - PERIOD;
+        }
+        state.last_observed = now;
+        state.suspended
+    }
+
+    /// Waits for `future` to complete for at most `timeout`, not counting the time the system
+    /// spends suspended in the meantime.
+    pub(super) async fn timeout<F: Future>(
+        &self,
+        timeout: Duration,
+        future: F,
+    ) -> Option<F::Output> {
+        tokio::pin!(future);
+        let mut deadline = Instant::now() + timeout;
+        let mut suspended = self.observe();
+        loop {
+            if let Ok(output) = tokio::time::timeout_at(deadline, &mut future).await {
+                return Some(output);
+            }
+            // The timer may fire before the watcher noticed the suspension, so check here too.
+            let now_suspended = self.observe();
+            if now_suspended == suspended {
+                return None;
+            }
+            deadline += now_suspended - suspended;
+            suspended = now_suspended;
+        }
+    }
+
+    /// Sleeps for `duration`, not counting the time the system spends suspended.
+    pub(super) async fn sleep(&self, duration: Duration) {
+        self.timeout(duration, std::future::pending::<()>()).await;
+    }
+}
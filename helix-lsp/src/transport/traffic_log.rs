@@ -0,0 +1,104 @@
+//! How the messages exchanged with the server appear in the log, see
+//! [`TransportConfig::log_bodies`](super::TransportConfig::log_bodies).
+
+use super::salvage;
+use std::fmt;
+
+/// Messages longer than this are truncated in the log by default.
+const DEFAULT_TRUNCATE_LEN: usize = 2 * 1024;
+
+/// How much of each message exchanged with the server is logged. Whatever is left out, the
+/// length of the message and its method, or the id of the request it answers, are logged
+/// so that log lines can still be correlated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogBodies {
+    /// The whole message.
+    Full,
+    /// The first bytes of messages longer than this, e.g. completion responses or documents.
+    Truncated(usize),
+    /// Only the length and method of the messages, keeping the contents of documents out of
+    /// the log.
+    LengthOnly,
+}
+
+impl Default for LogBodies {
+    fn default() -> Self {
+        Self::Truncated(DEFAULT_TRUNCATE_LEN)
+    }
+}
+
+/// A message as it appears in a `->` or `<-` log line.
+pub(super) struct Logged<'a> {
+    pub(super) body: &'a [u8],
+    pub(super) policy: LogBodies,
+}
+
+impl fmt::Display for Logged<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.policy {
+            LogBodies::Truncated(len) if self.body.len() > len => {
+                let text = String::from_utf8_lossy(&self.body[..len]);
+                // the cut may split a character, which is replaced rather than shown
+                let text = text.trim_end_matches(char::REPLACEMENT_CHARACTER);
+                write!(f, "{text}... ({})", Summary(self.body))
+            }
+            LogBodies::Full | LogBodies::Truncated(_) => {
+                f.write_str(&String::from_utf8_lossy(self.body))
+            }
+            LogBodies::LengthOnly => write!(f, "{}", Summary(self.body)),
+        }
+    }
+}
+
+/// The length of a message and what identifies it.
+struct Summary<'a>(&'a [u8]);
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes", self.0.len())?;
+        if let Some(method) = salvage::recover_method(self.0) {
+            write!(f, ", method {method}")
+        } else if let Some(id) = salvage::recover_id(self.0) {
+            write!(f, ", id {id}")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged(body: &str, policy: LogBodies) -> String {
+        Logged {
+            body: body.as_bytes(),
+            policy,
+        }
+        .to_string()
+    }
+
+    #[test]
+    fn log_bodies() {
+        let notification =
+            r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"text":"é secret"}}"#;
+        let response = r#"{"jsonrpc":"2.0","result":["a","b","c"],"id":7}"#;
+
+        assert_eq!(logged(notification, LogBodies::Full), notification);
+        assert_eq!(logged(response, LogBodies::Truncated(100)), response);
+        // the cut falls in the middle of `é`
+        assert_eq!(
+            logged(notification, LogBodies::Truncated(68)),
+            r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"text":"... (79 bytes, method textDocument/didOpen)"#
+        );
+        assert_eq!(
+            logged(response, LogBodies::Truncated(10)),
+            r#"{"jsonrpc"... (47 bytes, id 7)"#
+        );
+        assert_eq!(
+            logged(notification, LogBodies::LengthOnly),
+            "79 bytes, method textDocument/didOpen"
+        );
+        assert_eq!(logged("garbage", LogBodies::LengthOnly), "7 bytes");
+    }
+}
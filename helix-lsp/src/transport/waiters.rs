@@ -0,0 +1,65 @@
+//! Matching inbound notifications for [`Transport::wait_for_notification`](super::Transport::wait_for_notification).
+
+use crate::jsonrpc;
+use parking_lot::Mutex;
+use std::fmt;
+use tokio::sync::oneshot;
+
+type Predicate = Box<dyn Fn(&jsonrpc::Notification) -> bool + Send>;
+
+struct Waiter {
+    method: String,
+    predicate: Predicate,
+    tx: oneshot::Sender<jsonrpc::Notification>,
+}
+
+#[derive(Default)]
+pub(super) struct NotificationWaiters {
+    waiters: Mutex<Vec<Waiter>>,
+}
+
+impl fmt::Debug for NotificationWaiters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NotificationWaiters")
+            .field("waiting", &self.waiters.lock().len())
+            .finish()
+    }
+}
+
+impl NotificationWaiters {
+    pub(super) fn register(
+        &self,
+        method: &str,
+        predicate: Predicate,
+    ) -> oneshot::Receiver<jsonrpc::Notification> {
+        let (tx, rx) = oneshot::channel();
+        let mut waiters = self.waiters.lock();
+        // forget the waiters that timed out in the meantime
+        waiters.retain(|waiter| !waiter.tx.is_closed());
+        waiters.push(Waiter {
+            method: method.to_string(),
+            predicate,
+            tx,
+        });
+        rx
+    }
+
+    /// Hands a copy of `notification` to every waiter it matches.
+    pub(super) fn notify(&self, notification: &jsonrpc::Notification) {
+        let mut waiters = self.waiters.lock();
+        if waiters.is_empty() {
+            return;
+        }
+        let mut i = 0;
+        while i < waiters.len() {
+            let waiter = &waiters[i];
+            if waiter.tx.is_closed() {
+                waiters.swap_remove(i);
+            } else if waiter.method == notification.method && (waiter.predicate)(notification) {
+                let _ = waiters.swap_remove(i).tx.send(notification.clone());
+            } else {
+                i += 1;
+            }
+        }
+    }
+}